@@ -1,7 +1,7 @@
 #![forbid(unsafe_code)]
 
 use clap::{Parser, Subcommand};
-use ntp_daemon::{ConfigUpdate, ObservableState};
+use ntp_daemon::{ConfigUpdate, ObservableState, PeerListing};
 
 #[derive(Parser)]
 #[clap(version = "0.1.0", about = "Query and configure the NTPD-rs daemon")]
@@ -17,7 +17,7 @@ enum Command {
     Peers,
     #[clap(about = "Information about the state of the daemon itself")]
     System,
-    #[clap(about = "Adjust configuration (e.g. loglevel) of the daemon")]
+    #[clap(about = "Adjust configuration (e.g. loglevel, peers) of the daemon")]
     Config(ConfigUpdate),
 }
 
@@ -53,6 +53,14 @@ async fn main() -> std::io::Result<()> {
 
             ntp_daemon::sockets::write_json(&mut stream, &config_update).await?;
 
+            if config_update.list_peers {
+                let mut msg = Vec::with_capacity(16 * 1024);
+                let output: Vec<PeerListing> =
+                    ntp_daemon::sockets::read_json(&mut stream, &mut msg).await?;
+
+                println!("{}", serde_json::to_string_pretty(&output)?);
+            }
+
             0
         }
     };
@@ -0,0 +1,63 @@
+use std::path::{Path, PathBuf};
+
+use tokio::io::AsyncWriteExt;
+
+/// Replace the file at `path` with `contents` such that a crash never leaves a partially written
+/// file behind: the contents are written to a temporary file next to it, which is then renamed
+/// over the old file. A new file gets the permissions `mode` (before the umask).
+///
+/// Both the file and the directory are synced, so the new contents survive a crash once this
+/// returns.
+pub(crate) async fn write(path: &Path, contents: &[u8], mode: u32) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&temp_path)
+        .await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+
+    tokio::fs::rename(&temp_path, path).await?;
+
+    // the rename is only durable once the directory that holds the file is synced too
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    tokio::fs::File::open(directory).await?.sync_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write() {
+        // Note: paths must be unique among tests to deal with parallelism
+        let path = std::env::temp_dir().join("ntp-test-atomic-file-1");
+        let _ = std::fs::remove_file(&path);
+
+        write(&path, b"first", 0o600).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        write(&path, b"second", 0o600).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+
+        // the temporary file is renamed, not left behind
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        assert!(!Path::new(&temp_path).exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
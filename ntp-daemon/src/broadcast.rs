@@ -0,0 +1,482 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use ntp_proto::{
+    FrequencyTolerance, NtpAssociationMode, NtpClock, NtpInstant, NtpPacket, NtpTimestamp, Peer,
+    PeerSnapshot, ReferenceId, SymmetricKey, SystemSnapshot,
+};
+use ntp_udp::UdpSocket;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::source::{SourceChannel, TimeSource};
+
+/// Number of client/server exchanges that are tried to calibrate the delay to a broadcast server
+const CALIBRATION_ATTEMPTS: usize = 4;
+/// Time to wait for the response to a calibration request
+const CALIBRATION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Listen for broadcast packets on `addr`, joining the multicast group when it is one
+pub(crate) async fn listen(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::server(addr).await?;
+
+    match addr.ip() {
+        IpAddr::V4(group) if group.is_multicast() => socket
+            .as_ref()
+            .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?,
+        IpAddr::V6(group) if group.is_multicast() => {
+            socket.as_ref().join_multicast_v6(&group, 0)?
+        }
+        _ => {}
+    }
+
+    Ok(socket)
+}
+
+/// The server whose broadcasts we use
+struct BroadcastServer {
+    addr: SocketAddr,
+    peer: Peer,
+}
+
+/// The delay measurement of a server we heard a broadcast from, see [`calibrate`]
+type Calibration = BoxFuture<'static, Option<(BroadcastServer, PeerSnapshot)>>;
+
+/// A broadcast client as a source of time, see [`BroadcastTask`]
+pub(crate) struct BroadcastSource<C> {
+    socket: UdpSocket,
+    key: Option<SymmetricKey>,
+    clock: C,
+}
+
+impl<C> BroadcastSource<C>
+where
+    C: 'static + NtpClock + Send + Sync,
+{
+    /// A broadcast client that receives broadcasts through `socket`, see [`listen`]
+    pub fn new(socket: UdpSocket, key: Option<SymmetricKey>, clock: C) -> Self {
+        BroadcastSource { socket, key, clock }
+    }
+}
+
+impl<C> TimeSource for BroadcastSource<C>
+where
+    C: 'static + NtpClock + Send + Sync,
+{
+    fn run(self, channel: SourceChannel) -> BoxFuture<'static, ()> {
+        let mut process = BroadcastTask {
+            clock: Arc::new(self.clock),
+            socket: self.socket,
+            channel,
+            key: self.key,
+            server: None,
+            calibration: None,
+        };
+
+        Box::pin(async move { process.run().await })
+    }
+}
+
+/// A broadcast client. It follows the first server that it hears a broadcast from, after
+/// measuring the delay to that server with a client/server exchange. When that server becomes
+/// unreachable, the next server that is heard is used instead.
+struct BroadcastTask<C: 'static + NtpClock + Send + Sync> {
+    clock: Arc<C>,
+    socket: UdpSocket,
+    channel: SourceChannel,
+
+    /// When set, broadcasts and calibration responses must be authenticated with this key
+    key: Option<SymmetricKey>,
+    server: Option<BroadcastServer>,
+    /// Runs while we keep receiving broadcasts, so it does not hold up resets
+    calibration: Option<Calibration>,
+}
+
+/// Measure the delay to the server at `addr` with client/server exchanges
+async fn calibrate<C: NtpClock>(
+    addr: SocketAddr,
+    key: Option<SymmetricKey>,
+    clock: Arc<C>,
+    system_snapshot: SystemSnapshot,
+    frequency_tolerance: FrequencyTolerance,
+) -> Option<(BroadcastServer, PeerSnapshot)> {
+    let listen_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = match UdpSocket::new(listen_addr, addr).await {
+        Ok(socket) => socket,
+        Err(error) => {
+            warn!(?error, ?addr, "could not connect to broadcast server");
+            return None;
+        }
+    };
+
+    let our_id = ReferenceId::from_ip(socket.as_ref().local_addr().unwrap().ip());
+    let peer_id = ReferenceId::from_ip(addr.ip());
+    let mut peer = match key {
+        Some(key) => Peer::new_with_key(our_id, peer_id, NtpInstant::now(), key),
+        None => Peer::new(our_id, peer_id, NtpInstant::now()),
+    };
+
+    for _ in 0..CALIBRATION_ATTEMPTS {
+        let packet = peer.generate_poll_message(system_snapshot);
+
+        let send_timestamp = match clock.now() {
+            Ok(send_timestamp) => send_timestamp,
+            Err(error) => {
+                warn!(?error, "could not read the clock");
+                return None;
+            }
+        };
+
+        let send_timestamp = match socket.send(&packet.serialize()).await {
+            Ok((_, Some(kernel_timestamp))) => kernel_timestamp,
+            Ok((_, None)) => send_timestamp,
+            Err(error) => {
+                warn!(?error, "calibration request could not be sent");
+                continue;
+            }
+        };
+
+        let mut buf = [0_u8; 1024];
+        let (data, recv_timestamp) =
+            match tokio::time::timeout(CALIBRATION_TIMEOUT, socket.recv(&mut buf)).await {
+                Ok(Ok((size, Some(recv_timestamp)))) if size >= 48 => {
+                    (&buf[..size], recv_timestamp)
+                }
+                _ => {
+                    debug!(?addr, "no valid response to calibration request");
+                    continue;
+                }
+            };
+
+        let packet = match NtpPacket::deserialize(data) {
+            Ok(packet) => packet,
+            Err(error) => {
+                debug!(?error, "ignoring packet that could not be parsed");
+                continue;
+            }
+        };
+
+        let result = peer.handle_incoming(
+            system_snapshot,
+            packet,
+            NtpInstant::now(),
+            frequency_tolerance,
+            send_timestamp,
+            recv_timestamp,
+        );
+
+        match result {
+            Ok(snapshot) => {
+                info!(?addr, delay = ?snapshot.statistics.delay, "calibrated broadcast server");
+                return Some((BroadcastServer { addr, peer }, snapshot));
+            }
+            Err(ignore_reason) => debug!(?ignore_reason, "calibration response ignored"),
+        }
+    }
+
+    warn!(
+        ?addr,
+        "could not calibrate the delay to the broadcast server"
+    );
+    None
+}
+
+/// Wait for the calibration that is running, forever when there is none
+async fn calibrated(
+    calibration: &mut Option<Calibration>,
+) -> Option<(BroadcastServer, PeerSnapshot)> {
+    match calibration {
+        Some(calibration) => calibration.await,
+        None => std::future::pending().await,
+    }
+}
+
+impl<C> BroadcastTask<C>
+where
+    C: 'static + NtpClock + Send + Sync,
+{
+    async fn handle_packet(&mut self, data: &[u8], addr: SocketAddr, recv_timestamp: NtpTimestamp) {
+        let packet = match NtpPacket::deserialize(data) {
+            Ok(packet) => packet,
+            Err(error) => {
+                debug!(?error, ?addr, "ignoring packet that could not be parsed");
+                return;
+            }
+        };
+
+        let server = match &mut self.server {
+            Some(server) if server.addr == addr => server,
+            Some(_) => {
+                debug!(?addr, "ignoring broadcast of another server");
+                return;
+            }
+            None if packet.header.mode == NtpAssociationMode::Broadcast => {
+                if self.calibration.is_some() {
+                    debug!(?addr, "ignoring broadcast during calibration");
+                    return;
+                }
+
+                // anyone can send us a broadcast, which must not make us contact arbitrary hosts
+                if matches!(&self.key, Some(key) if !key.verify(&packet)) {
+                    debug!(?addr, "ignoring broadcast that is not authenticated");
+                    return;
+                }
+
+                info!(?addr, "heard broadcast server");
+                self.calibration = Some(Box::pin(calibrate(
+                    addr,
+                    self.key.clone(),
+                    self.clock.clone(),
+                    self.channel.system_snapshot().await,
+                    self.channel.system_config().await.frequency_tolerance,
+                )));
+                return;
+            }
+            None => {
+                debug!(?addr, "ignoring packet that is not a broadcast");
+                return;
+            }
+        };
+
+        let system_snapshot = self.channel.system_snapshot().await;
+        let result = server.peer.handle_broadcast(
+            system_snapshot,
+            packet,
+            NtpInstant::now(),
+            self.channel.system_config().await.frequency_tolerance,
+            recv_timestamp,
+        );
+
+        match result {
+            Ok(snapshot) => {
+                debug!("broadcast accepted");
+                self.channel.measurement(snapshot).await;
+            }
+            Err(ignore_reason) => debug!(?ignore_reason, "broadcast ignored"),
+        }
+    }
+
+    /// The calibration of a server completed. Returns when its first broadcast interval ends.
+    async fn handle_calibration(
+        &mut self,
+        result: Option<(BroadcastServer, PeerSnapshot)>,
+    ) -> Option<Instant> {
+        self.calibration = None;
+        let (server, snapshot) = result?;
+
+        self.channel.measurement(snapshot).await;
+
+        // expect the next broadcast after the interval the server reports
+        let interval = server.peer.broadcast_interval().as_system_duration();
+        self.server = Some(server);
+        Some(Instant::now() + interval)
+    }
+
+    /// A broadcast interval passed. Returns when the next one ends.
+    async fn handle_interval(&mut self) -> Instant {
+        let server = match &mut self.server {
+            Some(server) => server,
+            None => return Instant::now(),
+        };
+
+        server.peer.expect_broadcast();
+        let snapshot = PeerSnapshot::from_peer(&server.peer);
+        let interval = server.peer.broadcast_interval().as_system_duration();
+
+        if !snapshot.reach.is_reachable() {
+            warn!(addr = ?server.addr, "broadcast server is unreachable");
+            self.server = None;
+        }
+
+        self.channel.snapshot(snapshot).await;
+
+        Instant::now() + interval
+    }
+
+    async fn run(&mut self) {
+        let interval_wait = tokio::time::sleep(Duration::default());
+        tokio::pin!(interval_wait);
+
+        loop {
+            // Large enough for the extension fields of authenticated broadcasts
+            let mut buf = [0_u8; 1024];
+
+            tokio::select! {
+                () = &mut interval_wait, if self.server.is_some() => {
+                    let deadline = self.handle_interval().await;
+                    interval_wait.as_mut().reset(deadline);
+                }
+                () = self.channel.reset() => {
+                    if let Some(server) = &mut self.server {
+                        server.peer.reset_measurements();
+                    }
+                    // its measurements are from before the reset, the next broadcast starts over
+                    self.calibration = None;
+                }
+                result = calibrated(&mut self.calibration) => {
+                    if let Some(deadline) = self.handle_calibration(result).await {
+                        interval_wait.as_mut().reset(deadline);
+                    }
+                }
+                result = self.socket.recv_from(&mut buf) => {
+                    match result {
+                        Ok((size, addr, Some(recv_timestamp))) if size >= 48 => {
+                            self.handle_packet(&buf[..size], addr, recv_timestamp).await
+                        }
+                        Ok((size, addr, _)) => {
+                            warn!(size, ?addr, "received a packet that is too small or has no timestamp");
+                        }
+                        Err(receive_error) => {
+                            warn!(?receive_error, "could not receive packet");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{MacAlgorithm, NtpHeader, NtpLeapIndicator, SystemConfig};
+    use tokio::sync::{mpsc, watch, RwLock};
+
+    use crate::{
+        source::{self, MsgForSystem, PeerChannels, PeerId, ResetEpoch, Usage},
+        test_clock::TestClock,
+    };
+
+    use super::*;
+
+    fn server_header(mode: NtpAssociationMode) -> NtpHeader {
+        let mut header = NtpHeader::new();
+        header.leap = NtpLeapIndicator::NoWarning;
+        header.stratum = 1;
+        header.mode = mode;
+        header.poll = 6;
+        header
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_client() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:9031").await.unwrap();
+        let socket = listen("127.0.0.1:9030".parse().unwrap()).await.unwrap();
+
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let system_config = Arc::new(RwLock::new(SystemConfig::default()));
+        let (msg_for_system_sender, mut msg_for_system_receiver) = mpsc::channel(1);
+        let (_reset_send, reset) = watch::channel(ResetEpoch::default());
+
+        let handle = source::spawn(
+            PeerId(0),
+            BroadcastSource::new(socket, None, TestClock::new()),
+            PeerChannels {
+                msg_for_system_sender,
+                system_snapshots,
+                system_config,
+                reset,
+            },
+        );
+
+        // a client/server exchange follows the first broadcast
+        let mut broadcast = server_header(NtpAssociationMode::Broadcast);
+        broadcast.transmit_timestamp = TestClock::new().now().unwrap();
+        server
+            .send_to(&broadcast.serialize(), "127.0.0.1:9030")
+            .await
+            .unwrap();
+
+        let mut buf = [0; 48];
+        let (size, client_addr) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+        let request = NtpHeader::deserialize(&buf);
+        assert_eq!(request.mode, NtpAssociationMode::Client);
+
+        let mut response = server_header(NtpAssociationMode::Server);
+        response.origin_timestamp = request.transmit_timestamp;
+        response.receive_timestamp = TestClock::new().now().unwrap();
+        response.transmit_timestamp = TestClock::new().now().unwrap();
+        server
+            .send_to(&response.serialize(), client_addr)
+            .await
+            .unwrap();
+
+        let msg = msg_for_system_receiver.recv().await.unwrap();
+        assert!(matches!(
+            msg,
+            MsgForSystem::NewMeasurement(_, _, _, Usage::Steer)
+        ));
+
+        // after which broadcasts are used as measurements
+        broadcast.transmit_timestamp = TestClock::new().now().unwrap();
+        server
+            .send_to(&broadcast.serialize(), "127.0.0.1:9030")
+            .await
+            .unwrap();
+
+        let msg = msg_for_system_receiver.recv().await.unwrap();
+        assert!(matches!(
+            msg,
+            MsgForSystem::NewMeasurement(_, _, _, Usage::Steer)
+        ));
+
+        handle.abort();
+    }
+    #[tokio::test]
+    async fn test_unauthenticated_broadcast() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:9085").await.unwrap();
+        let socket = listen("127.0.0.1:9084".parse().unwrap()).await.unwrap();
+        let key = SymmetricKey::new(1, MacAlgorithm::Sha1, vec![0x42; 20]).unwrap();
+
+        let (msg_for_system_sender, _msg_for_system_receiver) = mpsc::channel(1);
+        let (_reset_send, reset) = watch::channel(ResetEpoch::default());
+
+        let handle = source::spawn(
+            PeerId(0),
+            BroadcastSource::new(socket, Some(key.clone()), TestClock::new()),
+            PeerChannels {
+                msg_for_system_sender,
+                system_snapshots: Default::default(),
+                system_config: Default::default(),
+                reset,
+            },
+        );
+
+        let mut broadcast = NtpPacket::new(server_header(NtpAssociationMode::Broadcast));
+        broadcast.header.transmit_timestamp = TestClock::new().now().unwrap();
+        server
+            .send_to(&broadcast.serialize(), "127.0.0.1:9084")
+            .await
+            .unwrap();
+
+        // an unsigned broadcast does not make the client contact the sender
+        let mut buf = [0; 1024];
+        let request =
+            tokio::time::timeout(Duration::from_millis(500), server.recv_from(&mut buf)).await;
+        assert!(request.is_err());
+
+        // a signed broadcast does
+        key.sign(&mut broadcast);
+        server
+            .send_to(&broadcast.serialize(), "127.0.0.1:9084")
+            .await
+            .unwrap();
+
+        let (size, _) = server.recv_from(&mut buf).await.unwrap();
+        let request = NtpPacket::deserialize(&buf[..size]).unwrap();
+        assert_eq!(request.header.mode, NtpAssociationMode::Client);
+        assert!(key.verify(&request));
+
+        handle.abort();
+    }
+}
@@ -1,15 +1,18 @@
-use crate::tracing::ReloadHandle;
+use crate::{tracing::ReloadHandle, PeerCommand, PeerId, Peers};
 use ntp_proto::{NtpDuration, SystemConfig};
 use std::os::unix::fs::PermissionsExt;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
-use tokio::{net::UnixListener, sync::RwLock};
+use tokio::{
+    net::UnixListener,
+    sync::{mpsc, RwLock},
+};
 use tracing_subscriber::EnvFilter;
 
 use clap::Args;
 use serde::{Deserialize, Serialize};
 
-use super::ConfigureConfig;
+use super::{ConfigureConfig, PeerConfig};
 
 fn parse_env_filter(input: &str) -> Result<String, tracing_subscriber::filter::ParseError> {
     // run the parser to error on any invalid input
@@ -19,6 +22,13 @@ fn parse_env_filter(input: &str) -> Result<String, tracing_subscriber::filter::P
     Ok(input.to_string())
 }
 
+fn parse_peer(input: &str) -> std::io::Result<String> {
+    // validate the address, but send it as given
+    PeerConfig::try_from(input)?;
+
+    Ok(input.to_string())
+}
+
 #[derive(Debug, Args, Serialize, Deserialize)]
 pub struct ConfigUpdate {
     /// Change the log filter
@@ -34,16 +44,36 @@ pub struct ConfigUpdate {
     /// during startup, use startup_panic_threshold
     #[clap(long)]
     pub panic_threshold: Option<f64>,
+
+    /// Add a peer for the server at the given address. Can be given multiple times
+    #[clap(long, parse(try_from_str = parse_peer))]
+    #[serde(default)]
+    pub add_peer: Vec<String>,
+
+    /// Remove the peer with the given id, as shown by --list-peers. Can be given multiple times
+    #[clap(long)]
+    #[serde(default)]
+    pub remove_peer: Vec<u64>,
+
+    /// List the peers of the daemon, with their ids. This happens after any peers are added or
+    /// removed.
+    #[clap(long)]
+    #[serde(default)]
+    pub list_peers: bool,
 }
 
 pub async fn spawn(
     config: ConfigureConfig,
     system_config: Arc<RwLock<SystemConfig>>,
+    peers: Arc<RwLock<Peers>>,
+    peer_commands: mpsc::Sender<PeerCommand>,
     log_reload_handle: ReloadHandle,
 ) -> JoinHandle<std::io::Result<()>> {
     tokio::spawn(dynamic_configuration(
         config,
         system_config,
+        peers,
+        peer_commands,
         log_reload_handle,
     ))
 }
@@ -51,6 +81,8 @@ pub async fn spawn(
 async fn dynamic_configuration(
     config: ConfigureConfig,
     system_config: Arc<RwLock<SystemConfig>>,
+    peers: Arc<RwLock<Peers>>,
+    peer_commands: mpsc::Sender<PeerCommand>,
     log_reload_handle: ReloadHandle,
 ) -> std::io::Result<()> {
     // must unlink path before the bind below (otherwise we get "address already in use")
@@ -80,10 +112,39 @@ async fn dynamic_configuration(
                 .unwrap();
         }
 
-        let mut config = system_config.write().await;
-
         if let Some(panic_threshold) = operation.panic_threshold {
+            let mut config = system_config.write().await;
             config.panic_threshold = Some(NtpDuration::from_seconds(panic_threshold));
         }
+
+        for addr in operation.add_peer {
+            match PeerConfig::try_from(addr.as_str()) {
+                Ok(peer_config) => {
+                    peer_commands.send(PeerCommand::Add(peer_config)).await.ok();
+                }
+                Err(error) => tracing::warn!(?error, "ignoring invalid peer address"),
+            }
+        }
+
+        for id in operation.remove_peer {
+            peer_commands
+                .send(PeerCommand::Remove(PeerId(id)))
+                .await
+                .ok();
+        }
+
+        if operation.list_peers {
+            // the commands above are handled by the system task, wait until it has done so
+            let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+            peer_commands.send(PeerCommand::Sync(done_tx)).await.ok();
+            done_rx.await.ok();
+
+            let listing = peers.read().await.list();
+
+            // the client may not wait for the listing, that is not our problem
+            if let Err(error) = crate::sockets::write_json(&mut stream, &listing).await {
+                tracing::warn!(?error, "could not send the list of peers");
+            }
+        }
     }
 }
@@ -0,0 +1,158 @@
+use std::{collections::HashMap, path::Path};
+
+use ntp_proto::{KeyError, MacAlgorithm, SymmetricKey};
+use thiserror::Error;
+use tokio::{fs::read_to_string, io};
+
+/// Keys of at most this many characters are given as text, longer keys as hexadecimal
+const MAX_TEXT_KEY_LENGTH: usize = 20;
+
+#[derive(Error, Debug)]
+pub enum KeyFileError {
+    #[error("io error while reading key file: {0}")]
+    Io(#[from] io::Error),
+    #[error("key file line {line}: expected `<id> <algorithm> <key>`")]
+    Syntax { line: usize },
+    #[error("key file line {line}: key ids must be between 1 and 4294967295")]
+    InvalidId { line: usize },
+    #[error("key file line {line}: key {id} is defined twice")]
+    DuplicateId { line: usize, id: u32 },
+    #[error("key file line {line}: invalid hexadecimal key")]
+    InvalidHex { line: usize },
+    #[error("key file line {line}: {error}")]
+    Key { line: usize, error: KeyError },
+}
+
+fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    value
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parse a key file in the format of the ntp reference implementation. Every line defines a
+/// key as `<id> <algorithm> <key>`, where the algorithm is one of MD5, SHA1 or AES128CMAC.
+/// Keys of up to 20 characters are used as is, longer keys are read as hexadecimal.
+/// Everything after a `#` is a comment.
+pub fn parse_key_file(contents: &str) -> Result<HashMap<u32, SymmetricKey>, KeyFileError> {
+    let mut keys = HashMap::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let content = line.split('#').next().unwrap_or_default();
+
+        let parts: Vec<_> = content.split_whitespace().collect();
+        let (id, algorithm, key) = match parts[..] {
+            [] => continue,
+            [id, algorithm, key] => (id, algorithm, key),
+            _ => return Err(KeyFileError::Syntax { line: line_number }),
+        };
+
+        let id: u32 = match id.parse() {
+            // key id 0 is reserved
+            Ok(id) if id != 0 => id,
+            _ => return Err(KeyFileError::InvalidId { line: line_number }),
+        };
+
+        let algorithm: MacAlgorithm = algorithm.parse().map_err(|error| KeyFileError::Key {
+            line: line_number,
+            error,
+        })?;
+
+        let key = if key.len() <= MAX_TEXT_KEY_LENGTH {
+            key.as_bytes().to_vec()
+        } else {
+            parse_hex(key).ok_or(KeyFileError::InvalidHex { line: line_number })?
+        };
+
+        let key = SymmetricKey::new(id, algorithm, key).map_err(|error| KeyFileError::Key {
+            line: line_number,
+            error,
+        })?;
+
+        if keys.insert(id, key).is_some() {
+            return Err(KeyFileError::DuplicateId {
+                line: line_number,
+                id,
+            });
+        }
+    }
+
+    Ok(keys)
+}
+
+pub async fn read_key_file(
+    path: impl AsRef<Path>,
+) -> Result<HashMap<u32, SymmetricKey>, KeyFileError> {
+    let contents = read_to_string(path).await?;
+    parse_key_file(&contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key_file() {
+        let keys = parse_key_file(
+            "
+            # a comment
+            1 MD5 secret
+            2 SHA1 0123456789abcdef0123456789abcdef01234567 # hexadecimal
+            3 AES128CMAC 000102030405060708090a0b0c0d0e0f
+
+            4 sha1 0123456789abcdefghij
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(keys.len(), 4);
+        assert_eq!(keys[&1].algorithm(), MacAlgorithm::Md5);
+        assert_eq!(keys[&2].algorithm(), MacAlgorithm::Sha1);
+        assert_eq!(keys[&3].algorithm(), MacAlgorithm::AesCmac128);
+        assert_eq!(keys[&4].id(), 4);
+    }
+
+    #[test]
+    fn test_parse_key_file_errors() {
+        assert!(matches!(
+            parse_key_file("1 MD5"),
+            Err(KeyFileError::Syntax { line: 1 })
+        ));
+        assert!(matches!(
+            parse_key_file("\n0 MD5 secret"),
+            Err(KeyFileError::InvalidId { line: 2 })
+        ));
+        assert!(matches!(
+            parse_key_file("1 MD5 secret\n1 SHA1 secret"),
+            Err(KeyFileError::DuplicateId { line: 2, id: 1 })
+        ));
+        assert!(matches!(
+            parse_key_file("1 SHA1 0123456789abcdef0123456789abcdef0123456"),
+            Err(KeyFileError::InvalidHex { line: 1 })
+        ));
+        assert!(matches!(
+            parse_key_file("1 SHA256 secret"),
+            Err(KeyFileError::Key {
+                line: 1,
+                error: KeyError::UnknownAlgorithm
+            })
+        ));
+        // AES-CMAC keys must be 128 bits
+        assert!(matches!(
+            parse_key_file("1 AES128CMAC 000102030405060708090a0b0c0d0e0f10111213"),
+            Err(KeyFileError::Key {
+                line: 1,
+                error: KeyError::InvalidKeyLength
+            })
+        ));
+    }
+}
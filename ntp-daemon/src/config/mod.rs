@@ -1,17 +1,32 @@
 pub mod dynamic;
+mod keys;
 mod peer;
+mod refclock;
+mod roughtime;
+mod server;
 
+pub use keys::*;
 pub use peer::*;
+pub use refclock::*;
+pub use roughtime::*;
+pub use server::*;
 
 use clap::Parser;
-use ntp_proto::SystemConfig;
+use ntp_proto::{LeapSecondsList, SymmetricKey, SystemConfig};
 use serde::{de, Deserialize, Deserializer};
 use std::{
+    collections::HashMap,
     io::ErrorKind,
+    net::SocketAddr,
     path::{Path, PathBuf},
 };
 use thiserror::Error;
-use tokio::{fs::read_to_string, io};
+use tokio::{
+    fs::read_to_string,
+    io,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
 use tracing::{info, warn};
 use tracing_subscriber::filter::{self, EnvFilter};
 
@@ -59,6 +74,31 @@ pub struct CmdArgs {
 pub struct Config {
     pub peers: Vec<PeerConfig>,
     #[serde(default)]
+    pub servers: Vec<ServerConfig>,
+    #[serde(default)]
+    pub refclocks: Vec<RefclockConfig>,
+    /// Roughtime servers that cross-check the time of our peers
+    #[serde(default)]
+    pub roughtime: Vec<RoughtimeConfig>,
+    #[serde(default)]
+    pub nts_ke: Option<NtsKeConfig>,
+    /// File with the symmetric keys that peers can use
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    /// The keys read from `key_file`
+    #[serde(skip)]
+    pub keys: HashMap<u32, SymmetricKey>,
+    /// The `leap-seconds.list` file, which is trusted over servers about leap seconds until it
+    /// expires
+    #[serde(default)]
+    pub leap_seconds_file: Option<PathBuf>,
+    /// The leap seconds read from `leap_seconds_file`
+    #[serde(skip)]
+    pub leap_seconds: Option<LeapSecondsList>,
+    /// File in which the frequency of the clock is kept across restarts
+    #[serde(default)]
+    pub drift_file: Option<PathBuf>,
+    #[serde(default)]
     pub system: SystemConfig,
     #[serde(deserialize_with = "deserialize_option_env_filter", default)]
     pub log_filter: Option<EnvFilter>,
@@ -141,6 +181,70 @@ pub enum ConfigError {
     Io(#[from] io::Error),
     #[error("config toml parsing error: {0}")]
     Toml(#[from] toml::de::Error),
+    #[error("{0}")]
+    KeyFile(#[from] KeyFileError),
+    #[error("peer {addr} uses key {key}, which is not defined in the key file")]
+    UnknownKey { addr: String, key: u32 },
+    #[error("broadcasts to {addr} use key {key}, which is not defined in the key file")]
+    UnknownBroadcastKey { addr: SocketAddr, key: u32 },
+}
+
+/// Read a `leap-seconds.list` file. Without a usable file, the daemon follows the majority of
+/// its servers for leap seconds, so problems with the file are not fatal.
+async fn read_leap_seconds_file(path: &Path) -> Option<LeapSecondsList> {
+    let contents = match read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(error) => {
+            warn!(?error, ?path, "could not read the leap seconds file");
+            return None;
+        }
+    };
+
+    match LeapSecondsList::parse(&contents) {
+        Ok(list) => {
+            if !list.is_hashed() {
+                warn!(
+                    ?path,
+                    "the leap seconds file has no hash, it can not be verified"
+                );
+            }
+            Some(list)
+        }
+        Err(error) => {
+            warn!(?error, ?path, "ignoring the leap seconds file");
+            None
+        }
+    }
+}
+
+/// Time between reads of the leap seconds file, so updates of the file are used without a restart
+const LEAP_SECONDS_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+
+/// Read the leap seconds file at `path` again every day and on SIGHUP, and pass the new list to
+/// the daemon. A file that can not be used does not replace the list we already have.
+pub async fn reload_leap_seconds_file(
+    path: Option<PathBuf>,
+    sender: watch::Sender<Option<LeapSecondsList>>,
+) -> std::io::Result<()> {
+    let path = match path {
+        Some(path) => path,
+        None => std::future::pending().await,
+    };
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let start = tokio::time::Instant::now() + LEAP_SECONDS_RELOAD_INTERVAL;
+    let mut interval = tokio::time::interval_at(start, LEAP_SECONDS_RELOAD_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => info!(?path, "reloading the leap seconds file"),
+            _ = interval.tick() => {}
+        }
+
+        if let Some(list) = read_leap_seconds_file(&path).await {
+            sender.send_replace(Some(list));
+        }
+    }
 }
 
 impl Config {
@@ -184,6 +288,40 @@ impl Config {
             config.peers = peers;
         }
 
+        if let Some(key_file) = &config.key_file {
+            config.keys = read_key_file(key_file).await?;
+        }
+
+        if let Some(leap_seconds_file) = &config.leap_seconds_file {
+            config.leap_seconds = read_leap_seconds_file(leap_seconds_file).await;
+        }
+
+        for peer in &config.peers {
+            if let Some(key) = peer.key {
+                if !config.keys.contains_key(&key) {
+                    return Err(ConfigError::UnknownKey {
+                        addr: peer.addr.clone(),
+                        key,
+                    });
+                }
+            }
+        }
+
+        for broadcast in config
+            .servers
+            .iter()
+            .filter_map(|server| server.broadcast.as_ref())
+        {
+            if let Some(key) = broadcast.key {
+                if !config.keys.contains_key(&key) {
+                    return Err(ConfigError::UnknownBroadcastKey {
+                        addr: broadcast.addr,
+                        key,
+                    });
+                }
+            }
+        }
+
         Ok(config)
     }
 
@@ -194,8 +332,16 @@ impl Config {
         // using those fields should always work. This is also
         // probably a good policy in general (config should always work
         // but we may panic here to protect the user from themselves)
-        if self.peers.is_empty() {
-            warn!("No peers configured. Daemon will not do anything.");
+        if self.peers.is_empty() && self.refclocks.is_empty() {
+            if self.servers.is_empty() {
+                warn!("No peers configured. Daemon will not do anything.");
+            } else {
+                warn!("No peers configured. Daemon will serve an unsynchronized time.");
+            }
+        }
+
+        if self.nts_ke.is_none() && self.servers.iter().any(|server| server.require_nts) {
+            warn!("Servers require NTS, but no nts-ke server is configured. These servers will not answer any request.");
         }
     }
 }
@@ -213,7 +359,10 @@ mod tests {
             config.peers,
             vec![PeerConfig {
                 addr: "example.com:123".into(),
-                mode: PeerHostMode::Server
+                mode: PeerHostMode::Server,
+                certificate_authority: None,
+                key: None,
+                count: 1,
             }]
         );
 
@@ -224,7 +373,10 @@ mod tests {
             config.peers,
             vec![PeerConfig {
                 addr: "example.com:123".into(),
-                mode: PeerHostMode::Server
+                mode: PeerHostMode::Server,
+                certificate_authority: None,
+                key: None,
+                count: 1,
             }]
         );
 
@@ -235,7 +387,10 @@ mod tests {
             config.peers,
             vec![PeerConfig {
                 addr: "example.com:123".into(),
-                mode: PeerHostMode::Server
+                mode: PeerHostMode::Server,
+                certificate_authority: None,
+                key: None,
+                count: 1,
             }]
         );
 
@@ -246,11 +401,36 @@ mod tests {
             config.peers,
             vec![PeerConfig {
                 addr: "example.com:123".into(),
-                mode: PeerHostMode::Server
+                mode: PeerHostMode::Server,
+                certificate_authority: None,
+                key: None,
+                count: 1,
             }]
         );
         assert!(config.system.panic_threshold.is_none());
 
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[system.leap-smear]\nmode = \"cosine\"",
+        )
+        .unwrap();
+        let leap_smear = config.system.leap_smear.unwrap();
+        assert_eq!(leap_smear.mode, ntp_proto::LeapSmearMode::Cosine);
+        assert_eq!(
+            leap_smear.window,
+            ntp_proto::NtpDuration::from_seconds(86400.)
+        );
+
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[system]\norphan-stratum = 10\nlocal-stratum = 12",
+        )
+        .unwrap();
+        assert_eq!(config.system.orphan_stratum, Some(10));
+        assert_eq!(config.system.local_stratum, Some(12));
+
+        let config: Result<Config, _> =
+            toml::from_str("[[peers]]\naddr = \"example.com\"\n[system]\norphan-stratum = 16");
+        assert!(config.is_err());
+
         let config: Config = toml::from_str(
             r#"
             log-filter = "info"
@@ -272,16 +452,82 @@ mod tests {
 
         assert_eq!(config.configure.path, PathBuf::from("/foo/bar/configure"));
         assert_eq!(config.configure.mode, 0o123);
+        assert!(config.servers.is_empty());
 
         assert_eq!(
             config.peers,
             vec![PeerConfig {
                 addr: "example.com:123".into(),
-                mode: PeerHostMode::Server
+                mode: PeerHostMode::Server,
+                certificate_authority: None,
+                key: None,
+                count: 1,
             }]
         );
     }
 
+    #[test]
+    fn test_server_config() {
+        let config: Config = toml::from_str(
+            r#"
+            peers = []
+            servers = ["0.0.0.0:123"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.servers,
+            vec![ServerConfig {
+                addr: "0.0.0.0:123".parse().unwrap(),
+                require_nts: false,
+                broadcast: None,
+            }]
+        );
+
+        let config: Config = toml::from_str(
+            r#"
+            peers = []
+            [[servers]]
+            addr = "0.0.0.0:123"
+            [[servers]]
+            addr = "[::]:123"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.servers,
+            vec![
+                ServerConfig {
+                    addr: "0.0.0.0:123".parse().unwrap(),
+                    require_nts: false,
+                    broadcast: None,
+                },
+                ServerConfig {
+                    addr: "[::]:123".parse().unwrap(),
+                    require_nts: false,
+                    broadcast: None,
+                },
+            ]
+        );
+        assert_eq!(config.nts_ke, None);
+
+        let config: Config = toml::from_str(
+            r#"
+            peers = []
+            [[servers]]
+            addr = "0.0.0.0:123"
+            require-nts = true
+            [nts-ke]
+            addr = "0.0.0.0:4460"
+            certificate-chain = "/etc/ntpd-rs/fullchain.pem"
+            private-key = "/etc/ntpd-rs/privkey.pem"
+            "#,
+        )
+        .unwrap();
+        assert!(config.servers[0].require_nts);
+        assert_eq!(config.nts_ke.unwrap().addr, "0.0.0.0:4460".parse().unwrap());
+    }
+
     #[cfg(feature = "sentry")]
     #[test]
     fn test_sentry_config() {
@@ -341,6 +587,23 @@ mod tests {
         assert_eq!(config.peers.len(), 2);
     }
 
+    #[tokio::test]
+    async fn test_key_file_config() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("testdata/config");
+        env::set_current_dir(d).unwrap();
+
+        let config = Config::from_args(Some("keyed.toml"), vec![]).await.unwrap();
+        assert_eq!(config.keys.len(), 2);
+        assert_eq!(config.peers[0].key, Some(1));
+
+        let result = Config::from_args(Some("unknown-key.toml"), vec![]).await;
+        assert!(matches!(
+            result,
+            Err(ConfigError::UnknownKey { key: 3, .. })
+        ));
+    }
+
     #[test]
     fn clap_no_arguments() {
         use clap::Parser;
@@ -406,7 +669,10 @@ mod tests {
             parsed_empty.peers,
             vec![PeerConfig {
                 addr: "foo.nl:123".to_string(),
-                mode: PeerHostMode::Server
+                mode: PeerHostMode::Server,
+                certificate_authority: None,
+                key: None,
+                count: 1,
             }]
         );
         assert!(parsed_empty.config.is_none());
@@ -420,11 +686,17 @@ mod tests {
             vec![
                 PeerConfig {
                     addr: "foo.rs:123".to_string(),
-                    mode: PeerHostMode::Server
+                    mode: PeerHostMode::Server,
+                    certificate_authority: None,
+                    key: None,
+                    count: 1,
                 },
                 PeerConfig {
                     addr: "spam.nl:123".to_string(),
-                    mode: PeerHostMode::Server
+                    mode: PeerHostMode::Server,
+                    certificate_authority: None,
+                    key: None,
+                    count: 1,
                 },
             ]
         );
@@ -434,10 +706,10 @@ mod tests {
 
     #[test]
     fn clap_peers_invalid() {
-        let arguments = &["--", "--peer", "foo.bar:123"];
+        let arguments = &["--", "--peer", "foo.bar:ntp"];
         let parsed = CmdArgs::try_parse_from(arguments).unwrap_err();
 
-        let error = r#"failed to lookup address information"#;
+        let error = r#"invalid peer address `foo.bar:ntp`"#;
 
         assert!(parsed.to_string().contains(error));
     }
@@ -447,12 +719,12 @@ mod tests {
         let config: Result<Config, _> = toml::from_str(
             r#"
             [[peers]]
-            addr = "foo.bar:123"
+            addr = "foo bar:123"
             "#,
         );
 
         let e = config.unwrap_err();
-        let error = r#"failed to lookup address information"#;
+        let error = r#"invalid peer address `foo bar:123`"#;
 
         assert!(e.to_string().contains(error));
     }
@@ -1,13 +1,52 @@
-use std::{fmt, net::ToSocketAddrs};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
 
 use serde::{
     de::{self, MapAccess, Visitor},
     Deserialize, Deserializer,
 };
 
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+/// The default port of NTP servers
+const NTP_DEFAULT_PORT: u16 = 123;
+/// The default port of NTS key exchange servers
+const NTS_KE_DEFAULT_PORT: u16 = 4460;
+/// The number of servers used from a pool, unless configured otherwise
+const POOL_DEFAULT_COUNT: usize = 4;
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum PeerHostMode {
     Server,
+    /// A server that we reach through Network Time Security (rfc8915)
+    #[serde(alias = "nts")]
+    Nts,
+    /// A hostname that resolves to many servers, of which we use several at once
+    #[serde(alias = "pool")]
+    Pool,
+    /// A broadcast or multicast address on which we listen for servers that broadcast the time
+    #[serde(alias = "broadcast")]
+    Broadcast,
+    /// A peer with which we exchange time in both directions (symmetric active mode)
+    #[serde(alias = "symmetric")]
+    Symmetric,
+    /// A multicast group in which we look for servers, of which we use several at once
+    #[serde(alias = "manycast")]
+    Manycast,
+}
+
+impl PeerHostMode {
+    fn default_port(self) -> u16 {
+        match self {
+            PeerHostMode::Server
+            | PeerHostMode::Pool
+            | PeerHostMode::Broadcast
+            | PeerHostMode::Symmetric
+            | PeerHostMode::Manycast => NTP_DEFAULT_PORT,
+            PeerHostMode::Nts => NTS_KE_DEFAULT_PORT,
+        }
+    }
 }
 
 impl Default for PeerHostMode {
@@ -16,44 +55,86 @@ impl Default for PeerHostMode {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct PeerConfig {
-    // Invariant: `.to_socket_addrs` will succeed on this value. That means it must use a valid tld
-    // and contain a port
+    // Invariant: this value is of the form `host:port`. The host is not resolved until the peer
+    // starts, so it need not exist yet.
+    //
+    // For NTS peers, this is the address of the key exchange server. For broadcast peers, it is
+    // the address that we listen on, which is always an ip address.
     pub addr: String,
     pub mode: PeerHostMode,
+    /// The certificate authority used to validate the key exchange server of an NTS peer,
+    /// instead of the system's root certificates
+    pub certificate_authority: Option<PathBuf>,
+    /// Id of the symmetric key (from the key file) used to authenticate packets of this peer
+    pub key: Option<u32>,
+    /// The number of servers to use at the same time. Always 1, except for pools and manycast
+    /// peers.
+    pub count: usize,
+}
+
+/// Whether `host` is a syntactically valid hostname (rfc1123). A trailing dot is allowed.
+fn is_valid_hostname(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// Validate `value` as a peer address, adding `default_port` when no port is specified.
+///
+/// Hostnames are not resolved here, so that the daemon can start before the network is up.
+pub(super) fn normalize_addr(value: &str, default_port: u16) -> std::io::Result<String> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid peer address `{}`", value),
+        )
+    };
+
+    // e.g. `127.0.0.1:123` or `[::1]:123`
+    if value.parse::<SocketAddr>().is_ok() {
+        return Ok(value.to_string());
+    }
+
+    // e.g. `127.0.0.1` or `::1`
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port).to_string());
+    }
+
+    let (host, port) = match value.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+        None => (value, default_port),
+    };
+
+    if is_valid_hostname(host) {
+        Ok(format!("{}:{}", host, port))
+    } else {
+        Err(invalid())
+    }
 }
 
 impl TryFrom<&str> for PeerConfig {
     type Error = std::io::Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let mut addr = value.to_string();
-
-        match addr.to_socket_addrs() {
-            Ok(_) => {
-                // address already has a port
-                Ok(PeerConfig {
-                    addr,
-                    mode: PeerHostMode::Server,
-                })
-            }
-            Err(e) => {
-                // try to fix the address by adding the NTP port
-                addr.push_str(":123");
-
-                if addr.to_socket_addrs().is_ok() {
-                    Ok(PeerConfig {
-                        addr,
-                        mode: PeerHostMode::Server,
-                    })
-                } else {
-                    // e.g. the top-level domain does not exist
-                    // (or we just don't have an internet connection)
-                    Err(e)
-                }
-            }
-        }
+        Ok(PeerConfig {
+            addr: normalize_addr(value, NTP_DEFAULT_PORT)?,
+            mode: PeerHostMode::Server,
+            certificate_authority: None,
+            key: None,
+            count: 1,
+        })
     }
 }
 
@@ -78,21 +159,18 @@ impl<'de> Deserialize<'de> for PeerConfig {
             }
 
             fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<PeerConfig, M::Error> {
-                let mut addr = None;
+                let mut addr: Option<String> = None;
                 let mut mode = None;
-                while let Some(key) = map.next_key::<&str>()? {
-                    match key {
+                let mut certificate_authority = None;
+                let mut key = None;
+                let mut count = None;
+                while let Some(field) = map.next_key::<&str>()? {
+                    match field {
                         "addr" => {
                             if addr.is_some() {
                                 return Err(de::Error::duplicate_field("addr"));
                             }
-                            let raw: String = map.next_value()?;
-
-                            // validate: this will add the `:123` port if not port is specified
-                            let config =
-                                PeerConfig::try_from(raw.as_str()).map_err(de::Error::custom)?;
-
-                            addr = Some(config.addr);
+                            addr = Some(map.next_value()?);
                         }
                         "mode" => {
                             if mode.is_some() {
@@ -100,15 +178,87 @@ impl<'de> Deserialize<'de> for PeerConfig {
                             }
                             mode = Some(map.next_value()?);
                         }
+                        "certificate-authority" => {
+                            if certificate_authority.is_some() {
+                                return Err(de::Error::duplicate_field("certificate-authority"));
+                            }
+                            certificate_authority = Some(map.next_value()?);
+                        }
+                        "key" => {
+                            if key.is_some() {
+                                return Err(de::Error::duplicate_field("key"));
+                            }
+                            key = Some(map.next_value()?);
+                        }
+                        "count" => {
+                            if count.is_some() {
+                                return Err(de::Error::duplicate_field("count"));
+                            }
+                            count = Some(map.next_value()?);
+                        }
                         _ => {
-                            return Err(de::Error::unknown_field(key, &["addr", "mode"]));
+                            return Err(de::Error::unknown_field(
+                                field,
+                                &["addr", "mode", "certificate-authority", "key", "count"],
+                            ));
                         }
                     }
                 }
 
-                let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
-                let mode = mode.unwrap_or_default();
-                Ok(PeerConfig { addr, mode })
+                let raw_addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
+                let mode: PeerHostMode = mode.unwrap_or_default();
+
+                // validate: this will add the default port of the mode if no port is specified
+                let addr =
+                    normalize_addr(&raw_addr, mode.default_port()).map_err(de::Error::custom)?;
+
+                if mode == PeerHostMode::Broadcast && addr.parse::<SocketAddr>().is_err() {
+                    return Err(de::Error::custom(
+                        "broadcast peers must listen on an ip address",
+                    ));
+                }
+
+                if mode == PeerHostMode::Manycast
+                    && !matches!(addr.parse::<SocketAddr>(), Ok(addr) if addr.ip().is_multicast())
+                {
+                    return Err(de::Error::custom(
+                        "manycast peers must use a multicast group as address",
+                    ));
+                }
+
+                if certificate_authority.is_some() && mode != PeerHostMode::Nts {
+                    return Err(de::Error::custom(
+                        "certificate-authority is only supported for nts peers",
+                    ));
+                }
+
+                if key.is_some() && mode == PeerHostMode::Nts {
+                    return Err(de::Error::custom(
+                        "nts peers are authenticated through NTS, they cannot use a key",
+                    ));
+                }
+
+                let count = match (mode, count) {
+                    (PeerHostMode::Pool | PeerHostMode::Manycast, None) => POOL_DEFAULT_COUNT,
+                    (PeerHostMode::Pool | PeerHostMode::Manycast, Some(0)) => {
+                        return Err(de::Error::custom("a pool must use at least one server"));
+                    }
+                    (PeerHostMode::Pool | PeerHostMode::Manycast, Some(count)) => count,
+                    (_, None) => 1,
+                    (_, Some(_)) => {
+                        return Err(de::Error::custom(
+                            "count is only supported for pools and manycast peers",
+                        ));
+                    }
+                };
+
+                Ok(PeerConfig {
+                    addr,
+                    mode,
+                    certificate_authority,
+                    key,
+                    count,
+                })
             }
         }
 
@@ -147,6 +297,129 @@ mod tests {
             toml::from_str("[peer]\naddr = \"example.com\"\nmode = \"Server\"").unwrap();
         assert_eq!(test.peer.addr, "example.com:123");
         assert_eq!(test.peer.mode, PeerHostMode::Server);
+        assert_eq!(test.peer.certificate_authority, None);
+        assert_eq!(test.peer.key, None);
+
+        let test: TestConfig = toml::from_str("[peer]\naddr = \"example.com\"\nkey = 5").unwrap();
+        assert_eq!(test.peer.key, Some(5));
+    }
+
+    #[test]
+    fn test_deserialize_nts_peer() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"127.0.0.1\"\nmode = \"nts\"").unwrap();
+        assert_eq!(test.peer.addr, "127.0.0.1:4460");
+        assert_eq!(test.peer.mode, PeerHostMode::Nts);
+        assert_eq!(test.peer.certificate_authority, None);
+
+        let test: TestConfig = toml::from_str(
+            "[peer]\naddr = \"127.0.0.1:1234\"\nmode = \"Nts\"\ncertificate-authority = \"/foo/ca.pem\"",
+        )
+        .unwrap();
+        assert_eq!(test.peer.addr, "127.0.0.1:1234");
+        assert_eq!(test.peer.mode, PeerHostMode::Nts);
+        assert_eq!(
+            test.peer.certificate_authority,
+            Some(PathBuf::from("/foo/ca.pem"))
+        );
+
+        // a certificate authority is meaningless without NTS
+        let test: Result<TestConfig, _> =
+            toml::from_str("[peer]\naddr = \"127.0.0.1\"\ncertificate-authority = \"ca.pem\"");
+        assert!(test.is_err());
+
+        // and NTS peers do not need symmetric keys
+        let test: Result<TestConfig, _> =
+            toml::from_str("[peer]\naddr = \"127.0.0.1\"\nmode = \"nts\"\nkey = 1");
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_pool() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"127.0.0.1\"\nmode = \"pool\"").unwrap();
+        assert_eq!(test.peer.addr, "127.0.0.1:123");
+        assert_eq!(test.peer.mode, PeerHostMode::Pool);
+        assert_eq!(test.peer.count, 4);
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"127.0.0.1\"\nmode = \"Pool\"\ncount = 8").unwrap();
+        assert_eq!(test.peer.count, 8);
+
+        let test: Result<TestConfig, _> =
+            toml::from_str("[peer]\naddr = \"127.0.0.1\"\nmode = \"pool\"\ncount = 0");
+        assert!(test.is_err());
+
+        // a single server is never more than one association
+        let test: Result<TestConfig, _> = toml::from_str("[peer]\naddr = \"127.0.0.1\"\ncount = 2");
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_broadcast_peer() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"224.0.1.1\"\nmode = \"broadcast\"").unwrap();
+        assert_eq!(test.peer.addr, "224.0.1.1:123");
+        assert_eq!(test.peer.mode, PeerHostMode::Broadcast);
+
+        // there is no server to look up
+        let test: Result<TestConfig, _> =
+            toml::from_str("[peer]\naddr = \"example.com\"\nmode = \"broadcast\"");
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_symmetric_peer() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"time.example.com\"\nmode = \"symmetric\"\nkey = 1")
+                .unwrap();
+        assert_eq!(test.peer.addr, "time.example.com:123");
+        assert_eq!(test.peer.mode, PeerHostMode::Symmetric);
+        assert_eq!(test.peer.key, Some(1));
+    }
+
+    #[test]
+    fn test_deserialize_manycast_peer() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"239.1.1.1\"\nmode = \"manycast\"").unwrap();
+        assert_eq!(test.peer.addr, "239.1.1.1:123");
+        assert_eq!(test.peer.mode, PeerHostMode::Manycast);
+        assert_eq!(test.peer.count, POOL_DEFAULT_COUNT);
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"ff05::101\"\nmode = \"manycast\"\ncount = 2").unwrap();
+        assert_eq!(test.peer.addr, "[ff05::101]:123");
+        assert_eq!(test.peer.count, 2);
+
+        // servers are found by sending to a multicast group
+        let test: Result<TestConfig, _> =
+            toml::from_str("[peer]\naddr = \"192.168.1.1\"\nmode = \"manycast\"");
+        assert!(test.is_err());
     }
 
     #[test]
@@ -159,4 +432,28 @@ mod tests {
         assert_eq!(peer.addr, "example.com:5678");
         assert_eq!(peer.mode, PeerHostMode::Server);
     }
+
+    #[test]
+    fn test_normalize_addr() {
+        // hostnames are not resolved, so they need not exist
+        assert_eq!(
+            normalize_addr("foo.invalid", 123).unwrap(),
+            "foo.invalid:123"
+        );
+        assert_eq!(
+            normalize_addr("foo.invalid.:5678", 123).unwrap(),
+            "foo.invalid.:5678"
+        );
+        assert_eq!(normalize_addr("127.0.0.1", 123).unwrap(), "127.0.0.1:123");
+        assert_eq!(normalize_addr("::1", 123).unwrap(), "[::1]:123");
+        assert_eq!(normalize_addr("[::1]:5678", 123).unwrap(), "[::1]:5678");
+
+        assert!(normalize_addr("", 123).is_err());
+        assert!(normalize_addr("example.com:", 123).is_err());
+        assert!(normalize_addr("example.com:123456", 123).is_err());
+        assert!(normalize_addr("example..com", 123).is_err());
+        assert!(normalize_addr("exa mple.com", 123).is_err());
+        assert!(normalize_addr("-example.com", 123).is_err());
+        assert!(normalize_addr("[::1]", 123).is_err());
+    }
 }
@@ -0,0 +1,204 @@
+use std::{fmt, path::PathBuf};
+
+use serde::{de, Deserialize, Deserializer};
+
+/// The interface through which the time of a reference clock is read
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum RefclockDriver {
+    /// The shared memory segment of the NTP reference implementation, as written by e.g. gpsd
+    Shm,
+    /// Datagrams sent to a unix socket of ours, in the format of the socket driver of chrony
+    Sock,
+    /// NMEA sentences of a GPS receiver, read from a serial device or a TCP stream
+    Nmea,
+    /// The JSON reports of gpsd, read from its TCP socket
+    Gpsd,
+}
+
+/// Where gpsd listens for clients, unless configured otherwise
+pub(crate) const DEFAULT_GPSD_ADDR: &str = "localhost:2947";
+
+const fn default_refclock_precision() -> i8 {
+    // like the shared memory driver of the NTP reference implementation
+    -1
+}
+
+const fn default_refclock_interval() -> u64 {
+    16
+}
+
+const fn default_refclock_baud() -> u32 {
+    // the speed of the NMEA 0183 standard
+    4800
+}
+
+fn deserialize_refclock_stratum<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let stratum: u8 = Deserialize::deserialize(deserializer)?;
+    if stratum < 16 {
+        Ok(stratum)
+    } else {
+        Err(de::Error::custom("stratum must be between 0 and 15"))
+    }
+}
+
+fn deserialize_refclock_interval<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let interval: u64 = Deserialize::deserialize(deserializer)?;
+    if interval > 0 {
+        Ok(interval)
+    } else {
+        Err(de::Error::custom(
+            "reference clock interval must be at least one second",
+        ))
+    }
+}
+
+/// Configuration of a reference clock, whose samples are used like the measurements of a peer
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RefclockConfig {
+    pub driver: RefclockDriver,
+    /// Number of the shared memory segment
+    #[serde(default)]
+    pub unit: u32,
+    /// Path of the unix socket on which we receive samples, or of the serial device of a GPS
+    /// receiver
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Address of the TCP stream of NMEA sentences or gpsd reports, `DEFAULT_GPSD_ADDR` for gpsd
+    /// when not given
+    #[serde(default)]
+    pub addr: Option<String>,
+    /// Speed of the serial device
+    #[serde(default = "default_refclock_baud")]
+    pub baud: u32,
+    /// Stratum of the reference clock itself, we serve its time at one stratum higher
+    #[serde(default, deserialize_with = "deserialize_refclock_stratum")]
+    pub stratum: u8,
+    /// Precision of the reference clock, as log2 of seconds
+    #[serde(default = "default_refclock_precision")]
+    pub precision: i8,
+    /// Seconds added to the time of the reference clock, to correct for a known delay
+    #[serde(default)]
+    pub offset: f64,
+    /// Seconds between samples
+    #[serde(
+        default = "default_refclock_interval",
+        deserialize_with = "deserialize_refclock_interval"
+    )]
+    pub interval: u64,
+}
+
+impl fmt::Display for RefclockConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.driver {
+            RefclockDriver::Shm => write!(f, "SHM({})", self.unit),
+            RefclockDriver::Sock => match &self.path {
+                Some(path) => write!(f, "SOCK({})", path.display()),
+                None => write!(f, "SOCK"),
+            },
+            RefclockDriver::Nmea => match (&self.path, &self.addr) {
+                (Some(path), _) => write!(f, "NMEA({})", path.display()),
+                (None, Some(addr)) => write!(f, "NMEA({})", addr),
+                (None, None) => write!(f, "NMEA"),
+            },
+            RefclockDriver::Gpsd => write!(
+                f,
+                "GPSD({})",
+                self.addr.as_deref().unwrap_or(DEFAULT_GPSD_ADDR)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_refclock() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            refclock: RefclockConfig,
+        }
+
+        let test: TestConfig = toml::from_str("[refclock]\ndriver = \"shm\"").unwrap();
+        assert_eq!(
+            test.refclock,
+            RefclockConfig {
+                driver: RefclockDriver::Shm,
+                unit: 0,
+                path: None,
+                addr: None,
+                baud: 4800,
+                stratum: 0,
+                precision: -1,
+                offset: 0.0,
+                interval: 16,
+            }
+        );
+        assert_eq!(test.refclock.to_string(), "SHM(0)");
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            driver = "shm"
+            unit = 2
+            stratum = 1
+            precision = -20
+            offset = 0.125
+            interval = 4
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.refclock.unit, 2);
+        assert_eq!(test.refclock.stratum, 1);
+        assert_eq!(test.refclock.precision, -20);
+        assert_eq!(test.refclock.offset, 0.125);
+        assert_eq!(test.refclock.interval, 4);
+        assert_eq!(test.refclock.to_string(), "SHM(2)");
+
+        let test: TestConfig =
+            toml::from_str("[refclock]\ndriver = \"sock\"\npath = \"/run/chrony.ttyS0.sock\"")
+                .unwrap();
+        assert_eq!(test.refclock.driver, RefclockDriver::Sock);
+        assert_eq!(
+            test.refclock.path,
+            Some(PathBuf::from("/run/chrony.ttyS0.sock"))
+        );
+        assert_eq!(test.refclock.to_string(), "SOCK(/run/chrony.ttyS0.sock)");
+
+        let test: TestConfig =
+            toml::from_str("[refclock]\ndriver = \"nmea\"\npath = \"/dev/ttyS0\"\nbaud = 9600")
+                .unwrap();
+        assert_eq!(test.refclock.driver, RefclockDriver::Nmea);
+        assert_eq!(test.refclock.baud, 9600);
+        assert_eq!(test.refclock.to_string(), "NMEA(/dev/ttyS0)");
+
+        let test: TestConfig =
+            toml::from_str("[refclock]\ndriver = \"gpsd\"\naddr = \"[::1]:2947\"").unwrap();
+        assert_eq!(test.refclock.driver, RefclockDriver::Gpsd);
+        assert_eq!(test.refclock.addr.as_deref(), Some("[::1]:2947"));
+        assert_eq!(test.refclock.to_string(), "GPSD([::1]:2947)");
+
+        let test: TestConfig = toml::from_str("[refclock]\ndriver = \"gpsd\"").unwrap();
+        assert_eq!(test.refclock.to_string(), "GPSD(localhost:2947)");
+
+        let test: Result<TestConfig, _> =
+            toml::from_str("[refclock]\ndriver = \"shm\"\nstratum = 16");
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> =
+            toml::from_str("[refclock]\ndriver = \"shm\"\ninterval = 0");
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str("[refclock]\ndriver = \"parse\"");
+        assert!(test.is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+
+use base64::Engine;
+use serde::{de, Deserialize, Deserializer};
+
+use super::peer::normalize_addr;
+
+/// The default port of Roughtime servers
+const ROUGHTIME_DEFAULT_PORT: u16 = 2002;
+
+const fn default_roughtime_interval() -> u64 {
+    3600
+}
+
+fn deserialize_roughtime_addr<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let addr: String = Deserialize::deserialize(deserializer)?;
+    normalize_addr(&addr, ROUGHTIME_DEFAULT_PORT).map_err(de::Error::custom)
+}
+
+fn deserialize_public_key<'de, D>(deserializer: D) -> Result<[u8; 32], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let encoded: String = Deserialize::deserialize(deserializer)?;
+    let key = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(de::Error::custom)?;
+
+    key.try_into()
+        .map_err(|_| de::Error::custom("an Ed25519 public key must be 32 bytes"))
+}
+
+fn deserialize_roughtime_interval<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let interval: u64 = Deserialize::deserialize(deserializer)?;
+    if interval > 0 {
+        Ok(interval)
+    } else {
+        Err(de::Error::custom(
+            "roughtime interval must be at least one second",
+        ))
+    }
+}
+
+/// Configuration of a Roughtime server, whose signed time is a cross-check on the time of our
+/// peers
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RoughtimeConfig {
+    /// Address of the server as `host:port`
+    #[serde(deserialize_with = "deserialize_roughtime_addr")]
+    pub addr: String,
+    /// The long-term Ed25519 public key of the server, base64 encoded in the configuration
+    #[serde(deserialize_with = "deserialize_public_key")]
+    pub public_key: [u8; 32],
+    /// Seconds between queries
+    #[serde(
+        default = "default_roughtime_interval",
+        deserialize_with = "deserialize_roughtime_interval"
+    )]
+    pub interval: u64,
+}
+
+impl fmt::Display for RoughtimeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ROUGHTIME({})", self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_roughtime() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            roughtime: RoughtimeConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [roughtime]
+            addr = "roughtime.example.com"
+            public-key = "gD63hSj3ScS+wuOeGrubXlq35N1c5Lby/S+T7MNTjxo="
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.roughtime.addr, "roughtime.example.com:2002");
+        assert_eq!(test.roughtime.public_key[..4], [0x80, 0x3e, 0xb7, 0x85]);
+        assert_eq!(test.roughtime.interval, 3600);
+        assert_eq!(
+            test.roughtime.to_string(),
+            "ROUGHTIME(roughtime.example.com:2002)"
+        );
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [roughtime]
+            addr = "127.0.0.1:2003"
+            public-key = "gD63hSj3ScS+wuOeGrubXlq35N1c5Lby/S+T7MNTjxo="
+            interval = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.roughtime.addr, "127.0.0.1:2003");
+        assert_eq!(test.roughtime.interval, 60);
+
+        // too short to be a key
+        let test: Result<TestConfig, _> = toml::from_str(
+            "[roughtime]\naddr = \"127.0.0.1\"\npublic-key = \"gD63hSj3ScS+wuOeGrubXlq3\"",
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> =
+            toml::from_str("[roughtime]\naddr = \"127.0.0.1\"\npublic-key = \"not base64\"");
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            "[roughtime]\naddr = \"127.0.0.1\"\npublic-key = \"gD63hSj3ScS+wuOeGrubXlq35N1c5Lby/S+T7MNTjxo=\"\ninterval = 0",
+        );
+        assert!(test.is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use std::{fmt, net::SocketAddr, path::PathBuf};
+
+use serde::{
+    de::{self, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ServerConfig {
+    /// Address on which we listen for incoming client requests
+    pub addr: SocketAddr,
+    /// Only answer requests that are authenticated with NTS
+    pub require_nts: bool,
+    /// Also broadcast our time from this address
+    pub broadcast: Option<BroadcastConfig>,
+}
+
+impl TryFrom<&str> for ServerConfig {
+    type Error = std::net::AddrParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(ServerConfig {
+            addr: value.parse()?,
+            require_nts: false,
+            broadcast: None,
+        })
+    }
+}
+
+const fn default_broadcast_interval() -> u64 {
+    64
+}
+
+const fn default_broadcast_ttl() -> u32 {
+    1
+}
+
+/// Configuration of the broadcasts sent by a server
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BroadcastConfig {
+    /// Broadcast address or multicast group to which the broadcasts are sent
+    pub addr: SocketAddr,
+    /// Seconds between broadcasts
+    #[serde(default = "default_broadcast_interval")]
+    pub interval: u64,
+    /// Number of hops that multicast broadcasts can make
+    #[serde(default = "default_broadcast_ttl")]
+    pub ttl: u32,
+    /// Id of the key from the key file with which broadcasts are authenticated
+    pub key: Option<u32>,
+}
+
+fn default_key_storage() -> PathBuf {
+    PathBuf::from("/var/lib/ntpd-rs/nts-keys.json")
+}
+
+const fn default_key_rotation_interval() -> u64 {
+    // one day
+    86400
+}
+
+/// Configuration of the NTS key exchange server
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct NtsKeConfig {
+    /// Address on which we listen for key exchange connections
+    pub addr: SocketAddr,
+    /// PEM file with the certificate chain of the server
+    pub certificate_chain: PathBuf,
+    /// PEM file with the private key belonging to the certificate
+    pub private_key: PathBuf,
+    /// File in which the keys used to encrypt cookies are stored
+    #[serde(default = "default_key_storage")]
+    pub key_storage: PathBuf,
+    /// Seconds between rotations of the cookie encryption key
+    #[serde(default = "default_key_rotation_interval")]
+    pub key_rotation_interval: u64,
+    /// NTP server that clients should use, when it is not the key exchange server itself
+    pub ntp_server: Option<String>,
+    /// Port of the NTP server, when it is not the default port
+    pub ntp_port: Option<u16>,
+}
+
+// We have a custom deserializer for serverconfig because we
+// want to deserialize it from either a string or a map
+impl<'de> Deserialize<'de> for ServerConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ServerConfigVisitor;
+
+        impl<'de> Visitor<'de> for ServerConfigVisitor {
+            type Value = ServerConfig;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("string or map")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<ServerConfig, E> {
+                TryFrom::try_from(value).map_err(de::Error::custom)
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<ServerConfig, M::Error> {
+                let mut addr = None;
+                let mut require_nts = None;
+                let mut broadcast = None;
+                while let Some(key) = map.next_key::<&str>()? {
+                    match key {
+                        "addr" => {
+                            if addr.is_some() {
+                                return Err(de::Error::duplicate_field("addr"));
+                            }
+                            let raw: &str = map.next_value()?;
+                            addr = Some(raw.parse().map_err(de::Error::custom)?);
+                        }
+                        "require-nts" => {
+                            if require_nts.is_some() {
+                                return Err(de::Error::duplicate_field("require-nts"));
+                            }
+                            require_nts = Some(map.next_value()?);
+                        }
+                        "broadcast" => {
+                            if broadcast.is_some() {
+                                return Err(de::Error::duplicate_field("broadcast"));
+                            }
+                            let config: BroadcastConfig = map.next_value()?;
+                            if config.interval == 0 {
+                                return Err(de::Error::custom(
+                                    "broadcast interval must be at least one second",
+                                ));
+                            }
+                            broadcast = Some(config);
+                        }
+                        _ => {
+                            return Err(de::Error::unknown_field(
+                                key,
+                                &["addr", "require-nts", "broadcast"],
+                            ));
+                        }
+                    }
+                }
+
+                let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
+                let require_nts = require_nts.unwrap_or(false);
+                Ok(ServerConfig {
+                    addr,
+                    require_nts,
+                    broadcast,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(ServerConfigVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_server() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            server: ServerConfig,
+        }
+
+        let test: TestConfig = toml::from_str("server = \"0.0.0.0:123\"").unwrap();
+        assert_eq!(test.server.addr, "0.0.0.0:123".parse().unwrap());
+        assert!(!test.server.require_nts);
+
+        let test: TestConfig = toml::from_str("[server]\naddr = \"[::]:123\"").unwrap();
+        assert_eq!(test.server.addr, "[::]:123".parse().unwrap());
+        assert!(!test.server.require_nts);
+
+        let test: TestConfig =
+            toml::from_str("[server]\naddr = \"[::]:123\"\nrequire-nts = true").unwrap();
+        assert!(test.server.require_nts);
+
+        assert!(test.server.broadcast.is_none());
+
+        let test: Result<TestConfig, _> = toml::from_str("server = \"example.com\"");
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> =
+            toml::from_str("[server]\naddr = \"0.0.0.0:123\"\nfoo = 1");
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_broadcast() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            server: ServerConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            broadcast = { addr = "192.168.1.255:123" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.server.broadcast,
+            Some(BroadcastConfig {
+                addr: "192.168.1.255:123".parse().unwrap(),
+                interval: 64,
+                ttl: 1,
+                key: None,
+            })
+        );
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "[::]:123"
+            broadcast = { addr = "[ff05::101]:123", interval = 16, ttl = 4, key = 1 }
+            "#,
+        )
+        .unwrap();
+        let broadcast = test.server.broadcast.unwrap();
+        assert_eq!(broadcast.interval, 16);
+        assert_eq!(broadcast.ttl, 4);
+        assert_eq!(broadcast.key, Some(1));
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            broadcast = { addr = "192.168.1.255:123", interval = 0 }
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_nts_ke() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            nts_ke: NtsKeConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [nts_ke]
+            addr = "0.0.0.0:4460"
+            certificate-chain = "/etc/ntpd-rs/chain.pem"
+            private-key = "/etc/ntpd-rs/key.pem"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.nts_ke.addr, "0.0.0.0:4460".parse().unwrap());
+        assert_eq!(test.nts_ke.key_storage, default_key_storage());
+        assert_eq!(test.nts_ke.key_rotation_interval, 86400);
+        assert_eq!(test.nts_ke.ntp_server, None);
+        assert_eq!(test.nts_ke.ntp_port, None);
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [nts_ke]
+            addr = "0.0.0.0:4460"
+            certificate-chain = "/etc/ntpd-rs/chain.pem"
+            private-key = "/etc/ntpd-rs/key.pem"
+            key-storage = "/tmp/keys.json"
+            key-rotation-interval = 3600
+            ntp-server = "time.example.com"
+            ntp-port = 1234
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.nts_ke.key_storage, PathBuf::from("/tmp/keys.json"));
+        assert_eq!(test.nts_ke.key_rotation_interval, 3600);
+        assert_eq!(test.nts_ke.ntp_server.as_deref(), Some("time.example.com"));
+        assert_eq!(test.nts_ke.ntp_port, Some(1234));
+    }
+}
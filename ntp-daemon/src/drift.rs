@@ -0,0 +1,63 @@
+use std::{io::ErrorKind, path::Path};
+
+use tracing::warn;
+
+use crate::atomic_file;
+
+/// The kernel does not accept frequency corrections beyond this many parts per million
+const MAX_FREQUENCY_PPM: f64 = 500.0;
+
+/// Read the frequency correction stored in a drift file. Like in the NTP reference
+/// implementation, the file contains the frequency in parts per million. A missing or invalid
+/// file means the frequency has to be measured again.
+pub(crate) async fn load(path: &Path) -> Option<f64> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == ErrorKind::NotFound => return None,
+        Err(error) => {
+            warn!(?error, ?path, "could not read the drift file");
+            return None;
+        }
+    };
+
+    match contents.trim().parse::<f64>() {
+        Ok(ppm) if ppm.abs() <= MAX_FREQUENCY_PPM => Some(ppm * 1e-6),
+        _ => {
+            warn!(?path, "ignoring invalid drift file");
+            None
+        }
+    }
+}
+
+/// Write the frequency correction such that a crash never leaves a partially written file behind
+pub(crate) async fn store(path: &Path, frequency: f64) -> std::io::Result<()> {
+    let contents = format!("{:.3}\n", frequency * 1e6);
+    atomic_file::write(path, contents.as_bytes(), 0o644).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drift_file() {
+        // Note: paths must be unique among tests to deal with parallelism
+        let path = std::env::temp_dir().join("ntp-test-drift-1");
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(load(&path).await, None);
+
+        store(&path, -12.345e-6).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "-12.345\n");
+        let frequency = load(&path).await.unwrap();
+        assert!((frequency + 12.345e-6).abs() < 1e-12);
+
+        std::fs::write(&path, "fast").unwrap();
+        assert_eq!(load(&path).await, None);
+
+        std::fs::write(&path, "1000").unwrap();
+        assert_eq!(load(&path).await, None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
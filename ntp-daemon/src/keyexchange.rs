@@ -0,0 +1,597 @@
+use std::{io, path::Path, sync::Arc, time::Duration};
+
+use ntp_proto::{
+    KeyExchangeRequest, KeyExchangeResponse, KeySet, NtsKeys, NtsRecord, NtsRecordError,
+    PeerNtsData, NTS_KE_ALPN,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::RwLock,
+    task::JoinHandle,
+};
+use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
+use tracing::{debug, instrument, warn};
+
+use crate::config::NtsKeConfig;
+
+/// NTP server port used when the key exchange server does not specify one
+const NTP_DEFAULT_PORT: u16 = 123;
+
+/// Maximum duration of a single NTS key exchange
+pub(crate) const KEY_EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of cookies handed out by our key exchange server, enough to fill the client's stash
+const COOKIES_PER_KEY_EXCHANGE: usize = 8;
+
+/// Key exchange messages are small, anything larger than this is not a valid request
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Error)]
+pub enum KeyExchangeError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("tls error: {0}")]
+    Tls(#[from] rustls::Error),
+    #[error("{0}")]
+    Protocol(#[from] NtsRecordError),
+    #[error("invalid server name: {0}")]
+    InvalidServerName(String),
+    #[error("the connection was closed before the key exchange finished")]
+    ConnectionClosed,
+    #[error("the key exchange message is too large")]
+    MessageTooLarge,
+}
+
+/// The outcome of an NTS key exchange
+pub(crate) struct KeyExchangeResult {
+    /// Address of the NTP server that accepts our cookies
+    pub(crate) ntp_addr: String,
+    pub(crate) nts: PeerNtsData,
+}
+
+/// Build the TLS configuration used to contact key exchange servers.
+///
+/// When a certificate authority is given, only certificates signed by it are trusted. Otherwise
+/// we use the root certificates of the system.
+pub(crate) fn client_config(
+    certificate_authority: Option<&Path>,
+) -> io::Result<Arc<rustls::ClientConfig>> {
+    let mut roots = rustls::RootCertStore::empty();
+
+    match certificate_authority {
+        Some(path) => {
+            let mut reader = io::BufReader::new(std::fs::File::open(path)?);
+            for certificate in rustls_pemfile::certs(&mut reader)? {
+                roots
+                    .add(&rustls::Certificate(certificate))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+        None => {
+            let certificates: Vec<_> = rustls_native_certs::load_native_certs()?
+                .into_iter()
+                .map(|certificate| certificate.0)
+                .collect();
+            let (_, ignored) = roots.add_parsable_certificates(&certificates);
+            if ignored > 0 {
+                debug!(ignored, "ignored invalid root certificates");
+            }
+        }
+    }
+
+    // NTS requires TLS 1.3 or newer
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![NTS_KE_ALPN.to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+/// Build the TLS configuration of our key exchange server from PEM files
+pub(crate) fn server_config(
+    certificate_chain: &Path,
+    private_key: &Path,
+) -> io::Result<Arc<rustls::ServerConfig>> {
+    let mut reader = io::BufReader::new(std::fs::File::open(certificate_chain)?);
+    let certificates = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+
+    let mut reader = io::BufReader::new(std::fs::File::open(private_key)?);
+    let private_key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => break rustls::PrivateKey(key),
+            Some(_) => continue,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "no private key found",
+                ))
+            }
+        }
+    };
+
+    // NTS requires TLS 1.3 or newer
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    config.alpn_protocols = vec![NTS_KE_ALPN.to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+/// Read the next part of a key exchange message into `buf`
+async fn read_chunk(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+) -> Result<(), KeyExchangeError> {
+    let mut chunk = [0; 1024];
+    let n = stream.read(&mut chunk).await?;
+    if n == 0 {
+        return Err(KeyExchangeError::ConnectionClosed);
+    }
+    buf.extend_from_slice(&chunk[..n]);
+
+    if buf.len() > MAX_MESSAGE_SIZE {
+        return Err(KeyExchangeError::MessageTooLarge);
+    }
+
+    Ok(())
+}
+
+/// Split the host from a `host:port` address
+fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _port)| host);
+
+    // strip the brackets of ipv6 addresses
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Perform an NTS key exchange with the server at `addr` (formatted as `host:port`)
+#[instrument(skip(config))]
+pub(crate) async fn key_exchange(
+    addr: &str,
+    config: Arc<rustls::ClientConfig>,
+) -> Result<KeyExchangeResult, KeyExchangeError> {
+    let host = host_of(addr);
+    let server_name = rustls::ServerName::try_from(host)
+        .map_err(|_| KeyExchangeError::InvalidServerName(host.to_string()))?;
+
+    let stream = TcpStream::connect(addr).await?;
+    let mut stream = TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await?;
+
+    let mut request = Vec::new();
+    for record in NtsRecord::client_key_exchange_records() {
+        record.encode(&mut request);
+    }
+    stream.write_all(&request).await?;
+
+    let mut buf = Vec::new();
+    let response = loop {
+        read_chunk(&mut stream, &mut buf).await?;
+
+        if let Some(response) = KeyExchangeResponse::decode(&buf)? {
+            break response;
+        }
+    };
+
+    let keys = NtsKeys::extract(|output, label, context| {
+        stream
+            .get_ref()
+            .1
+            .export_keying_material(output, label, Some(context))
+            .map(|_| ())
+    })?;
+
+    if let Err(error) = stream.shutdown().await {
+        warn!(?error, "could not close the key exchange connection");
+    }
+
+    let ntp_host = response.remote.as_deref().unwrap_or(host);
+    let ntp_port = response.port.unwrap_or(NTP_DEFAULT_PORT);
+    let ntp_addr = if ntp_host.contains(':') {
+        format!("[{}]:{}", ntp_host, ntp_port)
+    } else {
+        format!("{}:{}", ntp_host, ntp_port)
+    };
+
+    debug!(
+        ntp_addr = ntp_addr.as_str(),
+        cookies = response.cookies.len(),
+        "key exchange finished"
+    );
+
+    Ok(KeyExchangeResult {
+        ntp_addr,
+        nts: PeerNtsData::new(response.cookies, keys),
+    })
+}
+
+/// Run the NTS key exchange server, handing out cookies encrypted with the newest key of `keyset`
+#[instrument(skip(config, keyset), fields(addr = ?config.addr))]
+pub(crate) async fn spawn_server(
+    config: NtsKeConfig,
+    keyset: Arc<RwLock<KeySet>>,
+) -> io::Result<JoinHandle<()>> {
+    let acceptor = TlsAcceptor::from(server_config(
+        &config.certificate_chain,
+        &config.private_key,
+    )?);
+    let listener = TcpListener::bind(config.addr).await?;
+
+    let handle = tokio::spawn(async move {
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    warn!(?error, "could not accept key exchange connection");
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let keyset = keyset.clone();
+            let ntp_server = config.ntp_server.clone();
+            let ntp_port = config.ntp_port;
+
+            tokio::spawn(async move {
+                let result = tokio::time::timeout(
+                    KEY_EXCHANGE_TIMEOUT,
+                    serve_key_exchange(acceptor, stream, keyset, ntp_server, ntp_port),
+                )
+                .await;
+
+                match result {
+                    Ok(Ok(())) => debug!(?peer_addr, "key exchange finished"),
+                    Ok(Err(error)) => debug!(?error, ?peer_addr, "key exchange failed"),
+                    Err(_) => debug!(?peer_addr, "key exchange timed out"),
+                }
+            });
+        }
+    });
+
+    Ok(handle)
+}
+
+/// Handle a single key exchange connection of a client
+async fn serve_key_exchange(
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+    keyset: Arc<RwLock<KeySet>>,
+    ntp_server: Option<String>,
+    ntp_port: Option<u16>,
+) -> Result<(), KeyExchangeError> {
+    let mut stream = acceptor.accept(stream).await?;
+
+    let mut buf = Vec::new();
+    let request = loop {
+        read_chunk(&mut stream, &mut buf).await?;
+
+        match KeyExchangeRequest::decode(&buf) {
+            Ok(Some(request)) => break request,
+            Ok(None) => continue,
+            Err(error) => {
+                // tell the client what went wrong before giving up
+                let mut response = Vec::new();
+                for record in error.server_response_records() {
+                    record.encode(&mut response);
+                }
+                stream.write_all(&response).await?;
+                stream.shutdown().await?;
+
+                return Err(error.into());
+            }
+        }
+    };
+
+    let keys = NtsKeys::extract(|output, label, context| {
+        stream
+            .get_ref()
+            .1
+            .export_keying_material(output, label, Some(context))
+            .map(|_| ())
+    })?;
+
+    let cookies = {
+        let keyset = keyset.read().await;
+        (0..COOKIES_PER_KEY_EXCHANGE)
+            .map(|_| keyset.encode_cookie(&keys))
+            .collect()
+    };
+
+    let mut response = Vec::new();
+    for record in request.response_records(cookies, ntp_server, ntp_port) {
+        record.encode(&mut response);
+    }
+    stream.write_all(&response).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A self-signed certificate authority, and a certificate for `localhost` signed by it
+    struct TestCertificates {
+        ca_path: PathBuf,
+        certificate_chain_path: PathBuf,
+        private_key_path: PathBuf,
+        server_config: Arc<rustls::ServerConfig>,
+    }
+
+    impl TestCertificates {
+        /// `name` must be unique among tests, as it determines the files the certificates are
+        /// stored in
+        fn new(name: &str) -> Self {
+            let mut ca_params = rcgen::CertificateParams::new(vec![]);
+            ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+
+            let server = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+                "localhost".into(),
+            ]))
+            .unwrap();
+
+            let ca_path = std::env::temp_dir().join(format!("ntp-test-ca-{}.pem", name));
+            std::fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+
+            let certificate_chain_path =
+                std::env::temp_dir().join(format!("ntp-test-chain-{}.pem", name));
+            std::fs::write(
+                &certificate_chain_path,
+                server.serialize_pem_with_signer(&ca).unwrap(),
+            )
+            .unwrap();
+
+            let private_key_path = std::env::temp_dir().join(format!("ntp-test-key-{}.pem", name));
+            std::fs::write(&private_key_path, server.serialize_private_key_pem()).unwrap();
+
+            let server_config = server_config(&certificate_chain_path, &private_key_path).unwrap();
+
+            TestCertificates {
+                ca_path,
+                certificate_chain_path,
+                private_key_path,
+                server_config,
+            }
+        }
+    }
+
+    /// Answer a single key exchange request with `records`, returning the keys of the session
+    async fn serve_key_exchange(
+        listener: TcpListener,
+        config: Arc<rustls::ServerConfig>,
+        records: Vec<NtsRecord>,
+    ) -> NtsKeys {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = tokio_rustls::TlsAcceptor::from(config)
+            .accept(stream)
+            .await
+            .unwrap();
+
+        // read the request up to the end of message record
+        let mut buf = Vec::new();
+        let mut request = Vec::new();
+        while !request.contains(&NtsRecord::EndOfMessage) {
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+
+            while let Some((record, size)) = NtsRecord::decode(&buf).unwrap() {
+                buf.drain(..size);
+                request.push(record);
+            }
+        }
+        assert_eq!(request, NtsRecord::client_key_exchange_records());
+
+        let mut response = Vec::new();
+        for record in records {
+            record.encode(&mut response);
+        }
+        stream.write_all(&response).await.unwrap();
+
+        NtsKeys::extract(|output, label, context| {
+            stream
+                .get_ref()
+                .1
+                .export_keying_material(output, label, Some(context))
+                .map(|_| ())
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_key_exchange() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let certificates = TestCertificates::new("keyexchange-1");
+        let listener = TcpListener::bind("127.0.0.1:9010").await.unwrap();
+
+        let server = tokio::spawn(serve_key_exchange(
+            listener,
+            certificates.server_config.clone(),
+            vec![
+                NtsRecord::NextProtocol {
+                    protocol_ids: vec![0],
+                },
+                NtsRecord::AeadAlgorithm {
+                    critical: false,
+                    algorithm_ids: vec![15],
+                },
+                NtsRecord::NewCookie {
+                    cookie_data: vec![1; 16],
+                },
+                NtsRecord::NewCookie {
+                    cookie_data: vec![2; 16],
+                },
+                NtsRecord::Server {
+                    critical: false,
+                    name: "127.0.0.2".into(),
+                },
+                NtsRecord::Port {
+                    critical: false,
+                    port: 9011,
+                },
+                NtsRecord::EndOfMessage,
+            ],
+        ));
+
+        let config = client_config(Some(&certificates.ca_path)).unwrap();
+        let result = key_exchange("localhost:9010", config).await.unwrap();
+        let server_keys = server.await.unwrap();
+
+        assert_eq!(result.ntp_addr, "127.0.0.2:9011");
+        assert_eq!(result.nts.cookie_count(), 2);
+
+        // both sides must have derived the same keys
+        let client_keys = result.nts.keys();
+        assert_eq!(client_keys.c2s.key_bytes(), server_keys.c2s.key_bytes());
+        assert_eq!(client_keys.s2c.key_bytes(), server_keys.s2c.key_bytes());
+        assert_ne!(client_keys.c2s.key_bytes(), client_keys.s2c.key_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_key_exchange_server_error() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let certificates = TestCertificates::new("keyexchange-2");
+        let listener = TcpListener::bind("127.0.0.1:9012").await.unwrap();
+
+        let server = tokio::spawn(serve_key_exchange(
+            listener,
+            certificates.server_config.clone(),
+            vec![NtsRecord::Error { errorcode: 1 }, NtsRecord::EndOfMessage],
+        ));
+
+        let config = client_config(Some(&certificates.ca_path)).unwrap();
+        let result = key_exchange("localhost:9012", config).await;
+        server.await.unwrap();
+
+        assert!(matches!(
+            result,
+            Err(KeyExchangeError::Protocol(NtsRecordError::ServerError(1)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_key_exchange_untrusted_certificate() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let certificates = TestCertificates::new("keyexchange-3");
+        let other_certificates = TestCertificates::new("keyexchange-4");
+        let listener = TcpListener::bind("127.0.0.1:9013").await.unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let result = tokio_rustls::TlsAcceptor::from(certificates.server_config)
+                .accept(stream)
+                .await;
+            assert!(result.is_err());
+        });
+
+        // the server certificate is not signed by this authority
+        let config = client_config(Some(&other_certificates.ca_path)).unwrap();
+        let result = key_exchange("localhost:9013", config).await;
+        server.await.unwrap();
+
+        assert!(matches!(result, Err(KeyExchangeError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_nts_server() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let certificates = TestCertificates::new("keyexchange-5");
+        let keyset = Arc::new(RwLock::new(KeySet::new()));
+
+        let ke_server = spawn_server(
+            NtsKeConfig {
+                addr: "127.0.0.1:9014".parse().unwrap(),
+                certificate_chain: certificates.certificate_chain_path.clone(),
+                private_key: certificates.private_key_path.clone(),
+                key_storage: PathBuf::new(),
+                key_rotation_interval: 3600,
+                ntp_server: Some("127.0.0.1".into()),
+                ntp_port: Some(9015),
+            },
+            keyset.clone(),
+        )
+        .await
+        .unwrap();
+
+        let ntp_server = crate::server::ServerTask::spawn(
+            crate::config::ServerConfig {
+                addr: "127.0.0.1:9015".parse().unwrap(),
+                require_nts: true,
+                broadcast: None,
+            },
+            Arc::new(RwLock::new(ntp_proto::SystemSnapshot::default())),
+            Default::default(),
+            Some(keyset),
+            Default::default(),
+            None,
+            ntp_os_clock::UnixNtpClock::new(),
+        )
+        .await
+        .unwrap();
+
+        let config = client_config(Some(&certificates.ca_path)).unwrap();
+        let mut result = key_exchange("localhost:9014", config).await.unwrap();
+        assert_eq!(result.ntp_addr, "127.0.0.1:9015");
+        assert_eq!(result.nts.cookie_count(), COOKIES_PER_KEY_EXCHANGE);
+
+        let socket = ntp_udp::UdpSocket::new("127.0.0.1:9016", "127.0.0.1:9015")
+            .await
+            .unwrap();
+
+        // requests without NTS are ignored
+        let mut request = ntp_proto::NtpHeader::new();
+        request.transmit_timestamp =
+            ntp_proto::NtpTimestamp::from_seconds_nanos_since_ntp_era(1, 0);
+        socket.send(&request.serialize()).await.unwrap();
+
+        request.transmit_timestamp =
+            ntp_proto::NtpTimestamp::from_seconds_nanos_since_ntp_era(2, 0);
+        let message = result.nts.protect_request(&request).unwrap();
+        socket.send(&message).await.unwrap();
+        assert_eq!(result.nts.cookie_count(), COOKIES_PER_KEY_EXCHANGE - 1);
+
+        let mut buf = [0; 1024];
+        let (size, _) = socket.recv(&mut buf).await.unwrap();
+        let response = result.nts.verify_response(&buf[..size]).unwrap();
+        assert_eq!(response.origin_timestamp, request.transmit_timestamp);
+        // the server replaced the cookie we used
+        assert_eq!(result.nts.cookie_count(), COOKIES_PER_KEY_EXCHANGE);
+
+        ke_server.abort();
+        ntp_server.abort();
+    }
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!(host_of("example.com:4460"), "example.com");
+        assert_eq!(host_of("127.0.0.1:4460"), "127.0.0.1");
+        assert_eq!(host_of("[::1]:4460"), "::1");
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ntp_proto::KeySet;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::{info, instrument, warn};
+
+use crate::atomic_file;
+
+/// The cookie keys as stored on disk, together with the moment of their last rotation
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct StoredKeySet {
+    /// Seconds since the unix epoch
+    rotated_at: u64,
+    keyset: KeySet,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+async fn load(path: &Path) -> std::io::Result<Option<StoredKeySet>> {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    match serde_json::from_slice(&contents) {
+        Ok(stored) => Ok(Some(stored)),
+        Err(error) => {
+            warn!(
+                ?error,
+                ?path,
+                "could not parse stored NTS keys, generating new keys"
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Write the keys such that a crash never leaves a partially written file behind, and such that
+/// only we can read them. Missing directories, such as `/var/lib/ntpd-rs` on a fresh install, are
+/// created as well.
+async fn store(path: &Path, stored: &StoredKeySet) -> std::io::Result<()> {
+    let contents = serde_json::to_vec(stored)?;
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)
+            .await?;
+    }
+
+    atomic_file::write(path, &contents, 0o600).await
+}
+
+/// Load the cookie keys stored at `path` (or create new ones), and keep rotating them every
+/// `rotation_interval` seconds. Every rotation is written back to `path`, so that cookies that
+/// were handed out remain valid when the daemon restarts.
+#[instrument]
+pub(crate) async fn spawn(
+    path: PathBuf,
+    rotation_interval: u64,
+) -> std::io::Result<(Arc<RwLock<KeySet>>, JoinHandle<()>)> {
+    let mut stored = match load(&path).await? {
+        Some(stored) => stored,
+        None => {
+            let stored = StoredKeySet {
+                rotated_at: unix_now(),
+                keyset: KeySet::new(),
+            };
+            store(&path, &stored).await?;
+            stored
+        }
+    };
+
+    let keyset = Arc::new(RwLock::new(stored.keyset.clone()));
+
+    let handle = tokio::spawn({
+        let keyset = keyset.clone();
+        async move {
+            // an interval of 0 would keep us rotating forever
+            let rotation_interval = rotation_interval.max(1);
+
+            loop {
+                let next_rotation = stored.rotated_at.saturating_add(rotation_interval);
+                let wait = next_rotation.saturating_sub(unix_now());
+                tokio::time::sleep(Duration::from_secs(wait)).await;
+
+                stored.keyset.rotate();
+                stored.rotated_at = unix_now();
+                *keyset.write().await = stored.keyset.clone();
+                info!("rotated NTS cookie keys");
+
+                if let Err(error) = store(&path, &stored).await {
+                    warn!(?error, ?path, "could not store NTS cookie keys");
+                }
+            }
+        }
+    });
+
+    Ok((keyset, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use ntp_proto::{AesSivCmac256, NtsKeys};
+
+    use super::*;
+
+    fn test_keys() -> NtsKeys {
+        NtsKeys {
+            c2s: AesSivCmac256::new([1; 32]),
+            s2c: AesSivCmac256::new([2; 32]),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keys_survive_restart() {
+        // Note: paths must be unique among tests to deal with parallelism
+        let path = std::env::temp_dir().join("ntp-test-keyset-1.json");
+        let _ = std::fs::remove_file(&path);
+
+        let (keyset, handle) = spawn(path.clone(), 3600).await.unwrap();
+        handle.abort();
+        let cookie = keyset.read().await.encode_cookie(&test_keys());
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let (keyset, handle) = spawn(path.clone(), 3600).await.unwrap();
+        handle.abort();
+        assert!(keyset.read().await.decode_cookie(&cookie).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_storage_directory() {
+        // Note: paths must be unique among tests to deal with parallelism
+        let dir = std::env::temp_dir().join("ntp-test-keyset-dir-3");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("nested").join("nts-keys.json");
+
+        let (_, handle) = spawn(path.clone(), 3600).await.unwrap();
+        handle.abort();
+
+        assert!(load(&path).await.unwrap().is_some());
+        let mode = std::fs::metadata(dir.join("nested"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overdue_rotation() {
+        // Note: paths must be unique among tests to deal with parallelism
+        let path = std::env::temp_dir().join("ntp-test-keyset-2.json");
+
+        let keyset = KeySet::new();
+        let cookie = keyset.encode_cookie(&test_keys());
+        let stored = StoredKeySet {
+            rotated_at: 0,
+            keyset,
+        };
+        store(&path, &stored).await.unwrap();
+
+        let (keyset, handle) = spawn(path.clone(), 3600).await.unwrap();
+
+        // the keys were due for rotation long ago
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handle.abort();
+
+        let keyset = keyset.read().await;
+        assert_ne!(keyset.encode_cookie(&test_keys())[..4], cookie[..4]);
+        // but cookies of the previous key remain valid
+        assert!(keyset.decode_cookie(&cookie).is_ok());
+
+        let stored = load(&path).await.unwrap().unwrap();
+        assert!(stored.rotated_at > 0);
+        assert!(stored.keyset.decode_cookie(&cookie).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
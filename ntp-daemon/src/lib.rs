@@ -1,12 +1,26 @@
 //#![forbid(unsafe_code)]
 
+mod atomic_file;
+mod broadcast;
 pub mod config;
+mod drift;
+mod keyexchange;
+mod keyset;
+mod manycast;
 pub mod observer;
 mod peer;
+mod refclock;
+mod resolver;
+mod roughtime;
+mod server;
 pub mod sockets;
+mod source;
 mod system;
+#[cfg(test)]
+mod test_clock;
 pub mod tracing;
 
 pub use config::dynamic::ConfigUpdate;
 pub use observer::ObservableState;
-pub use system::{spawn, ObservablePeerState, Peers};
+pub use source::PeerId;
+pub use system::{spawn, Discovery, ObservablePeerState, PeerCommand, PeerListing, Peers};
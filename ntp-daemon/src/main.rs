@@ -4,8 +4,18 @@ use clap::Parser;
 use ntp_daemon::config::{CmdArgs, Config};
 use ntp_daemon::Peers;
 use std::{error::Error, sync::Arc};
+use tokio::signal::unix::{signal, SignalKind};
 use tracing_subscriber::EnvFilter;
 
+/// Completes when the daemon is asked to stop, with either SIGINT or SIGTERM
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = CmdArgs::parse();
@@ -35,31 +45,59 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let peers_reader = Arc::new(tokio::sync::RwLock::new(Peers::default()));
     let peers_writer = peers_reader.clone();
+    let peers_lister = peers_reader.clone();
+
+    // peers added and removed over the configuration socket
+    let (peer_commands_tx, peer_commands_rx) = tokio::sync::mpsc::channel(32);
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+
+    let (leap_seconds_tx, leap_seconds_rx) =
+        tokio::sync::watch::channel(config.leap_seconds.take());
+    let leap_seconds_handle = tokio::spawn(ntp_daemon::config::reload_leap_seconds_file(
+        config.leap_seconds_file.clone(),
+        leap_seconds_tx,
+    ));
+
+    let observe_config = config.observe.clone();
+    let configure_config = config.configure.clone();
 
-    let main_loop_handle = tokio::spawn(async move {
+    let mut main_loop_handle = tokio::spawn(async move {
         ntp_daemon::spawn(
+            &config,
             main_system_config,
-            &config.peers,
+            leap_seconds_rx,
             peers_writer,
+            peer_commands_rx,
             system_writer,
+            shutdown_rx,
         )
         .await
     });
 
     let peer_state_handle =
-        ntp_daemon::observer::spawn(&config.observe, peers_reader, system_reader).await;
+        ntp_daemon::observer::spawn(&observe_config, peers_reader, system_reader).await;
 
     let dynamic_config_handle = ntp_daemon::config::dynamic::spawn(
-        config.configure,
+        configure_config,
         system_config,
+        peers_lister,
+        peer_commands_tx,
         tracing_state.reload_handle,
     )
     .await;
 
     // exit if any of the tasks has completed
     tokio::select! {
-        done = main_loop_handle => Ok(done??),
+        done = &mut main_loop_handle => Ok(done??),
         done = peer_state_handle => Ok(done??),
         done = dynamic_config_handle => Ok(done??),
+        done = leap_seconds_handle => Ok(done??),
+        done = shutdown_signal() => {
+            done?;
+            // give the main loop the chance to save its state
+            shutdown_tx.send_replace(());
+            Ok(main_loop_handle.await??)
+        }
     }
 }
@@ -0,0 +1,214 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use ntp_proto::{
+    NtpAssociationMode, NtpClock, NtpDuration, NtpHeader, NtpPacket, NtpTimestamp, SymmetricKey,
+};
+use ntp_udp::UdpSocket;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// Hop limits of the successive rounds of a discovery, which stops as soon as enough servers are
+/// found. Nearby servers are thus preferred over those further away.
+const MANYCAST_TTLS: [u32; 6] = [1, 2, 4, 8, 16, 32];
+/// Time to wait for responses in each round
+const MANYCAST_ROUND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Look for servers by sending client requests to the multicast `group`, with an expanding TTL
+/// until at least `wanted` servers that are not in `exclude` answered. The servers that answered
+/// are returned with the closest (by root distance) first.
+pub(crate) async fn discover<C: NtpClock>(
+    group: SocketAddr,
+    wanted: usize,
+    exclude: &HashSet<SocketAddr>,
+    key: Option<&SymmetricKey>,
+    clock: &C,
+) -> std::io::Result<Vec<SocketAddr>> {
+    let listen_addr: SocketAddr = match group {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::server(listen_addr).await?;
+
+    let mut found = HashMap::new();
+
+    for ttl in MANYCAST_TTLS {
+        socket.enable_broadcast(ttl)?;
+
+        let transmit_timestamp = match clock.now() {
+            Ok(now) => now,
+            Err(error) => {
+                warn!(?error, "could not read the clock");
+                break;
+            }
+        };
+
+        let mut request = NtpHeader::new();
+        request.mode = NtpAssociationMode::Client;
+        request.transmit_timestamp = transmit_timestamp;
+
+        let mut request = NtpPacket::new(request);
+        if let Some(key) = key {
+            key.sign(&mut request);
+        }
+
+        socket.send_to(&request.serialize(), group).await?;
+
+        let deadline = Instant::now() + MANYCAST_ROUND_TIMEOUT;
+        loop {
+            // Large enough for the MAC of authenticated responses
+            let mut buf = [0_u8; 1024];
+
+            let (size, addr, recv_timestamp) =
+                match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                    Err(_) => break,
+                    Ok(Ok((size, addr, recv_timestamp))) if size >= 48 => {
+                        (size, addr, recv_timestamp)
+                    }
+                    Ok(Ok((size, addr, _))) => {
+                        debug!(size, ?addr, "ignoring response that is too small");
+                        continue;
+                    }
+                    Ok(Err(error)) => {
+                        warn!(?error, "could not receive manycast response");
+                        continue;
+                    }
+                };
+
+            // The kernel does not timestamp the first packets received on a new socket when it
+            // still has to turn timestamping on. Our clock is precise enough to rank servers.
+            let recv_timestamp = match recv_timestamp {
+                Some(recv_timestamp) => recv_timestamp,
+                None => match clock.now() {
+                    Ok(now) => now,
+                    Err(error) => {
+                        warn!(?error, "could not read the clock");
+                        continue;
+                    }
+                },
+            };
+
+            let response = match NtpPacket::deserialize(&buf[..size]) {
+                Ok(response) => response,
+                Err(error) => {
+                    debug!(?error, ?addr, "ignoring response that could not be parsed");
+                    continue;
+                }
+            };
+
+            match root_distance(&response, transmit_timestamp, recv_timestamp, key) {
+                Some(distance) => {
+                    debug!(?addr, ?distance, ttl, "server answered manycast request");
+                    found.insert(addr, distance);
+                }
+                None => debug!(?addr, "ignoring unusable manycast response"),
+            }
+        }
+
+        let usable = found.keys().filter(|addr| !exclude.contains(addr)).count();
+        if usable >= wanted {
+            break;
+        }
+    }
+
+    info!(?group, servers = found.len(), "manycast discovery done");
+
+    let mut servers: Vec<_> = found.into_iter().collect();
+    servers.sort_by_key(|(_, distance)| *distance);
+    Ok(servers.into_iter().map(|(addr, _)| addr).collect())
+}
+
+/// The root distance of the server that sent `response` to our request sent at `send_time`, if
+/// the response is usable at all
+fn root_distance(
+    response: &NtpPacket,
+    send_time: NtpTimestamp,
+    recv_time: NtpTimestamp,
+    key: Option<&SymmetricKey>,
+) -> Option<NtpDuration> {
+    let header = &response.header;
+
+    let usable = header.mode == NtpAssociationMode::Server
+        && header.origin_timestamp == send_time
+        && !header.is_kiss()
+        && header.leap.is_synchronized()
+        && header.stratum < 16
+        && !matches!(key, Some(key) if !key.verify(response));
+
+    if !usable {
+        return None;
+    }
+
+    let delay = (recv_time - send_time) - (header.transmit_timestamp - header.receive_timestamp);
+
+    Some(header.root_delay / 2i64 + header.root_dispersion + delay.abs() / 2i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::NtpLeapIndicator;
+
+    use crate::test_clock::TestClock;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_discover() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:9050").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let answer = tokio::spawn(async move {
+            let mut buf = [0; 48];
+            let (_, client_addr) = server.recv_from(&mut buf).await.unwrap();
+            let request = NtpHeader::deserialize(&buf);
+            assert_eq!(request.mode, NtpAssociationMode::Client);
+
+            let mut response = NtpHeader::new();
+            response.mode = NtpAssociationMode::Server;
+            response.stratum = 1;
+            response.origin_timestamp = request.transmit_timestamp;
+            response.receive_timestamp = TestClock::new().now().unwrap();
+            response.transmit_timestamp = TestClock::new().now().unwrap();
+            server
+                .send_to(&response.serialize(), client_addr)
+                .await
+                .unwrap();
+        });
+
+        let servers = discover(server_addr, 1, &HashSet::new(), None, &TestClock::new())
+            .await
+            .unwrap();
+        assert_eq!(servers, vec![server_addr]);
+
+        answer.await.unwrap();
+    }
+
+    #[test]
+    fn test_root_distance() {
+        let seconds = |seconds: u32| NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0);
+
+        let mut response = NtpHeader::new();
+        response.mode = NtpAssociationMode::Server;
+        response.stratum = 2;
+        response.root_delay = NtpDuration::from_seconds(2.0);
+        response.root_dispersion = NtpDuration::from_seconds(1.0);
+        response.origin_timestamp = seconds(10);
+        response.receive_timestamp = seconds(11);
+        response.transmit_timestamp = seconds(12);
+
+        let distance = root_distance(&NtpPacket::new(response), seconds(10), seconds(15), None);
+        assert_eq!(distance, Some(NtpDuration::from_seconds(4.0)));
+
+        // responses to another request are not usable
+        let distance = root_distance(&NtpPacket::new(response), seconds(9), seconds(15), None);
+        assert_eq!(distance, None);
+
+        response.leap = NtpLeapIndicator::Unknown;
+        let distance = root_distance(&NtpPacket::new(response), seconds(10), seconds(15), None);
+        assert_eq!(distance, None);
+    }
+}
@@ -101,6 +101,7 @@ mod tests {
             poll_interval: PollInterval::MIN,
             precision: NtpDuration::from_seconds(1e-3),
             leap_indicator: NtpLeapIndicator::Leap59,
+            ..Default::default()
         }));
 
         let handle = tokio::spawn(async move {
@@ -164,6 +165,7 @@ mod tests {
             poll_interval: PollInterval::MIN,
             precision: NtpDuration::from_seconds(1e-3),
             leap_indicator: NtpLeapIndicator::Leap59,
+            ..Default::default()
         }));
 
         let system_writer = system_reader.clone();
@@ -1,18 +1,41 @@
-use std::{future::Future, marker::PhantomData, ops::ControlFlow, pin::Pin, sync::Arc};
+use std::{
+    future::Future,
+    marker::PhantomData,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::ControlFlow,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
+use futures::future::BoxFuture;
 use ntp_proto::{
-    IgnoreReason, NtpClock, NtpHeader, NtpInstant, NtpTimestamp, Peer, PeerSnapshot, ReferenceId,
-    SystemConfig, SystemSnapshot,
+    IgnoreReason, NtpClock, NtpInstant, NtpPacket, NtpTimestamp, NtsError, Peer, PeerNtsData,
+    PeerSnapshot, ReferenceId, SymmetricKey, SystemSnapshot,
 };
 use ntp_udp::UdpSocket;
-use tracing::{debug, instrument, warn};
+use tokio_rustls::rustls;
+use tracing::{debug, info, warn};
+
+use tokio::time::{Instant, Sleep};
 
-use tokio::{
-    net::ToSocketAddrs,
-    sync::watch,
-    time::{Instant, Sleep},
+use crate::{
+    keyexchange::{key_exchange, KeyExchangeError, KEY_EXCHANGE_TIMEOUT},
+    resolver::Resolver,
+    source::{SourceChannel, TimeSource},
 };
 
+/// Bounds on the time between attempts of the initial NTS key exchange
+const KEY_EXCHANGE_MIN_RETRY: Duration = Duration::from_secs(4);
+const KEY_EXCHANGE_MAX_RETRY: Duration = Duration::from_secs(1024);
+
+/// Bounds on the time between attempts to resolve the address of a peer when it starts
+const RESOLVE_MIN_RETRY: Duration = Duration::from_secs(1);
+const RESOLVE_MAX_RETRY: Duration = Duration::from_secs(1024);
+
+/// Time after which the address of a peer is resolved again, to follow servers that move
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(3600);
+
 /// Trait needed to allow injecting of futures other than tokio::time::Sleep for testing
 pub trait Wait: Future<Output = ()> {
     fn reset(self: Pin<&mut Self>, deadline: Instant);
@@ -24,54 +47,96 @@ impl Wait for Sleep {
     }
 }
 
-/// Only messages from the current reset epoch are valid. The system's reset epoch is incremented
-/// (with wrapping addition) on every reset. Only after a reset does the peer update its reset
-/// epoch, thereby indicating to the system that the reset was successful and the peer's messages
-/// are valid measurements again.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
-pub struct ResetEpoch(u64);
-
-impl ResetEpoch {
-    #[must_use]
-    pub const fn inc(mut self) -> Self {
-        self.0 = self.0.wrapping_add(1);
+/// The NTS state of a peer, and what is needed to renew it with a new key exchange
+struct NtsState {
+    ke_addr: String,
+    tls_config: Arc<rustls::ClientConfig>,
+    data: PeerNtsData,
+}
 
-        self
+/// Connect to the server at `addr`, retrying the lookup with increasing intervals until it succeeds
+async fn connect_retrying(resolver: &dyn Resolver, addr: &str) -> UdpSocket {
+    let mut retry = RESOLVE_MIN_RETRY;
+
+    loop {
+        match connect(resolver, addr).await {
+            Ok(socket) => return socket,
+            Err(error) => {
+                warn!(?error, ?retry, "could not resolve peer address");
+                tokio::time::sleep(retry).await;
+                retry = Ord::min(retry * 2, RESOLVE_MAX_RETRY);
+            }
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct PeerIndex {
-    pub index: usize,
+/// Resolve `addr`, and connect to the first address it resolves to that we can use
+pub(crate) async fn connect(resolver: &dyn Resolver, addr: &str) -> std::io::Result<UdpSocket> {
+    connect_any(&resolver.resolve(addr).await?).await
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum MsgForSystem {
-    /// Received a Kiss-o'-Death and must demobilize
-    MustDemobilize(PeerIndex),
-    /// Received an acceptable packet and made a new peer snapshot
-    /// A new measurement should try to trigger a clock select
-    NewMeasurement(PeerIndex, ResetEpoch, PeerSnapshot),
-    /// A snapshot may have been updated, but this should not
-    /// trigger a clock select in System
-    UpdatedSnapshot(PeerIndex, ResetEpoch, PeerSnapshot),
+/// Connect to the first of `addrs` that we can use, e.g. an IPv4 address when the host has no
+/// IPv6 connectivity
+async fn connect_any(addrs: &[SocketAddr]) -> std::io::Result<UdpSocket> {
+    let mut last_error = std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "address did not resolve to any server",
+    );
+
+    for peer_addr in addrs {
+        // the wildcard address of the other family can not reach the peer
+        let listen_addr: SocketAddr = match peer_addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        match UdpSocket::new(listen_addr, *peer_addr).await {
+            Ok(socket) => return Ok(socket),
+            Err(error) => {
+                debug!(?error, ?peer_addr, "could not connect to peer address");
+                last_error = error;
+            }
+        }
+    }
+
+    Err(last_error)
 }
 
-pub(crate) struct PeerChannels {
-    pub(crate) msg_for_system_sender: tokio::sync::mpsc::Sender<MsgForSystem>,
-    pub(crate) system_snapshots: Arc<tokio::sync::RwLock<SystemSnapshot>>,
-    pub(crate) system_config: Arc<tokio::sync::RwLock<SystemConfig>>,
-    pub(crate) reset: watch::Receiver<ResetEpoch>,
+/// Perform an NTS key exchange, and connect to the NTP server it tells us to use. Returns the
+/// address of that server, and the socket connected to it.
+async fn nts_connect(
+    ke_addr: &str,
+    tls_config: Arc<rustls::ClientConfig>,
+    resolver: &dyn Resolver,
+) -> Result<(String, UdpSocket, PeerNtsData), KeyExchangeError> {
+    let result = tokio::time::timeout(KEY_EXCHANGE_TIMEOUT, key_exchange(ke_addr, tls_config))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "key exchange timed out")
+        })??;
+
+    let socket = connect(resolver, &result.ntp_addr).await?;
+
+    Ok((result.ntp_addr, socket, result.nts))
 }
 
 pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
     _wait: PhantomData<T>,
-    index: PeerIndex,
     clock: C,
     socket: UdpSocket,
-    channels: PeerChannels,
+    channel: SourceChannel,
+
+    /// Address of the server as `host:port`, which is resolved again from time to time
+    addr: String,
+    resolver: Arc<dyn Resolver>,
+    /// Instant the address of the server was last resolved
+    last_resolved: Instant,
+    /// Whether the server was reachable when we last checked its address
+    was_reachable: bool,
 
     peer: Peer,
+    /// Only present for peers that use NTS
+    nts: Option<NtsState>,
 
     // we don't store the real origin timestamp in the packet, because that would leak our
     // system time to the network (and could make attacks easier). So instead there is some
@@ -83,8 +148,8 @@ pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
     /// Instant last poll message was sent (used for timing the wait)
     last_poll_sent: Instant,
 
-    /// Number of resets that this peer has performed
-    reset_epoch: ResetEpoch,
+    /// Number of packets received from this peer that could not be parsed
+    parse_failures: u64,
 }
 
 impl<C, T> PeerTask<C, T>
@@ -104,9 +169,134 @@ where
             .reset(self.last_poll_sent + poll_interval);
     }
 
+    /// Serialize a poll message, adding the NTS extension fields if this peer uses NTS
+    async fn serialize_poll(&mut self, packet: &NtpPacket) -> Option<Vec<u8>> {
+        let nts = match &mut self.nts {
+            Some(nts) => nts,
+            None => return Some(packet.serialize()),
+        };
+
+        if nts.data.cookie_count() == 0 {
+            info!("no NTS cookies left, performing a new key exchange");
+
+            match nts_connect(&nts.ke_addr, nts.tls_config.clone(), self.resolver.as_ref()).await {
+                Ok((addr, socket, data)) => {
+                    // the key exchange may direct us to a different NTP server
+                    self.addr = addr;
+                    self.socket = socket;
+                    self.last_resolved = Instant::now();
+                    nts.data = data;
+                }
+                Err(error) => {
+                    warn!(?error, "NTS key exchange failed");
+                    return None;
+                }
+            }
+        }
+
+        nts.data.protect_request(&packet.header)
+    }
+
+    /// Parse a received packet. For NTS peers, the packet must be authenticated.
+    fn parse_packet(&mut self, data: &[u8]) -> Option<NtpPacket> {
+        // extension fields we do not know are ignored, as required by rfc7822
+        let packet = match NtpPacket::deserialize(data) {
+            Ok(packet) => packet,
+            Err(error) => {
+                self.parse_failures += 1;
+                warn!(
+                    ?error,
+                    parse_failures = self.parse_failures,
+                    "ignoring packet that could not be parsed"
+                );
+                return None;
+            }
+        };
+
+        let nts = match &mut self.nts {
+            Some(nts) => nts,
+            None => return Some(packet),
+        };
+
+        match nts.data.verify_response(data) {
+            Ok(header) => Some(NtpPacket::new(header)),
+            Err(NtsError::Nak) => {
+                warn!("server rejected our NTS cookie, a new key exchange is needed");
+                None
+            }
+            Err(error) => {
+                debug!(?error, "ignoring packet that failed NTS validation");
+                None
+            }
+        }
+    }
+
+    /// Resolve the address of the server again when it became unreachable, or when we last
+    /// resolved it long ago. When the server moved, we continue as a new association with the
+    /// server at its new address.
+    async fn check_address(&mut self) {
+        let reachable = PeerSnapshot::from_peer(&self.peer).reach.is_reachable();
+        let became_unreachable = self.was_reachable && !reachable;
+        self.was_reachable = reachable;
+
+        if !became_unreachable && self.last_resolved.elapsed() < RESOLVE_INTERVAL {
+            return;
+        }
+
+        self.last_resolved = Instant::now();
+
+        let addrs = match self.resolver.resolve(&self.addr).await {
+            Ok(addrs) => addrs,
+            Err(error) => {
+                warn!(?error, addr = ?self.addr, "could not resolve peer address");
+                return;
+            }
+        };
+
+        // the server is still at one of its addresses
+        let current = self.socket.as_ref().peer_addr().ok();
+        if matches!(current, Some(current) if addrs.contains(&current)) {
+            return;
+        }
+
+        let socket = match connect_any(&addrs).await {
+            Ok(socket) => socket,
+            Err(error) => {
+                warn!(?error, addr = ?self.addr, "could not connect to new peer address");
+                return;
+            }
+        };
+        let new = socket.as_ref().peer_addr().unwrap();
+
+        info!(
+            ?current,
+            ?new,
+            timestamping = ?socket.timestamping_mode(),
+            "peer address changed"
+        );
+
+        let our_id = ReferenceId::from_ip(socket.as_ref().local_addr().unwrap().ip());
+        let peer_id = ReferenceId::from_ip(new.ip());
+        self.peer.reset_address(our_id, peer_id, NtpInstant::now());
+        self.socket = socket;
+        self.was_reachable = false;
+    }
+
     async fn handle_poll(&mut self, poll_wait: &mut Pin<&mut T>) {
-        let system_snapshot = *self.channels.system_snapshots.read().await;
-        let packet = self.peer.generate_poll_message(system_snapshot);
+        self.check_address().await;
+
+        let system_snapshot = self.channel.system_snapshot().await;
+        let packet = if self.peer.is_symmetric() {
+            // our symmetric peer also measures with the timestamps of our packet
+            let transmit_timestamp = match self.clock.now() {
+                Ok(ts) => ts,
+                Err(e) => panic!("`clock.now()` reported an error: {:?}", e),
+            };
+            self.peer
+                .generate_symmetric_message(system_snapshot, transmit_timestamp)
+        } else {
+            self.peer.generate_poll_message(system_snapshot)
+        };
 
         // Sent a poll, so update waiting to match deadline of next
         self.last_poll_sent = Instant::now();
@@ -114,8 +304,12 @@ where
 
         // NOTE: fitness check is not performed here, but by System
         let snapshot = PeerSnapshot::from_peer(&self.peer);
-        let msg = MsgForSystem::UpdatedSnapshot(self.index, self.reset_epoch, snapshot);
-        self.channels.msg_for_system_sender.send(msg).await.ok();
+        self.channel.snapshot(snapshot).await;
+
+        let message = match self.serialize_poll(&packet).await {
+            Some(message) => message,
+            None => return,
+        };
 
         match self.clock.now() {
             Err(e) => {
@@ -127,26 +321,32 @@ where
             }
         }
 
-        if let Err(error) = self.socket.send(&packet.serialize()).await {
-            warn!(?error, "poll message could not be sent");
+        match self.socket.send(&message).await {
+            Err(error) => warn!(?error, "poll message could not be sent"),
+            Ok((_, Some(send_timestamp))) => {
+                // the kernel knows when the message actually left, without the delay between
+                // reading the clock and the message getting through the network stack
+                self.last_send_timestamp = Some(send_timestamp);
+            }
+            Ok((_, None)) => {}
         }
     }
 
     async fn handle_packet(
         &mut self,
         poll_wait: &mut Pin<&mut T>,
-        packet: NtpHeader,
+        packet: NtpPacket,
         send_timestamp: NtpTimestamp,
         recv_timestamp: NtpTimestamp,
     ) -> ControlFlow<(), ()> {
         let ntp_instant = NtpInstant::now();
 
-        let system_snapshot = *self.channels.system_snapshots.read().await;
+        let system_snapshot = self.channel.system_snapshot().await;
         let result = self.peer.handle_incoming(
             system_snapshot,
             packet,
             ntp_instant,
-            self.channels.system_config.read().await.frequency_tolerance,
+            self.channel.system_config().await.frequency_tolerance,
             send_timestamp,
             recv_timestamp,
         );
@@ -160,13 +360,11 @@ where
 
                 // NOTE: fitness check is not performed here, but by System
 
-                let msg = MsgForSystem::NewMeasurement(self.index, self.reset_epoch, update);
-                self.channels.msg_for_system_sender.send(msg).await.ok();
+                self.channel.measurement(update).await;
             }
             Err(IgnoreReason::KissDemobilize) => {
                 warn!("Demobilizing peer connection on request of remote.");
-                let msg = MsgForSystem::MustDemobilize(self.index);
-                self.channels.msg_for_system_sender.send(msg).await.ok();
+                self.channel.demobilize().await;
 
                 return ControlFlow::Break(());
             }
@@ -180,22 +378,18 @@ where
 
     async fn run(&mut self, mut poll_wait: Pin<&mut T>) {
         loop {
-            let mut buf = [0_u8; 48];
+            // Large enough for the NTS extension fields of responses
+            let mut buf = [0_u8; 1024];
 
             tokio::select! {
                 () = &mut poll_wait => {
                     self.handle_poll(&mut poll_wait).await;
                 },
-                result = self.channels.reset.changed() => {
-                    if let Ok(()) = result {
-                        // reset the measurement state (as if this association was just created).
-                        // crucially, this sets `self.next_expected_origin = None`, meaning that
-                        // in-flight requests are ignored
-                        self.peer.reset_measurements();
-
-                        // our next measurement will have the new reset epoch
-                        self.reset_epoch = *self.channels.reset.borrow_and_update();
-                    }
+                () = self.channel.reset() => {
+                    // reset the measurement state (as if this association was just created).
+                    // crucially, this sets `self.next_expected_origin = None`, meaning that
+                    // in-flight requests are ignored
+                    self.peer.reset_measurements();
                 }
                 result = self.socket.recv(&mut buf) => {
                     let send_timestamp = match self.last_send_timestamp {
@@ -206,7 +400,15 @@ where
                         }
                     };
 
-                    if let Some((packet, recv_timestamp)) = accept_packet(result, &buf) {
+                    let parse_failures = self.parse_failures;
+                    let accepted = accept_packet(result, &buf)
+                        .and_then(|(data, recv_timestamp)| Some((self.parse_packet(data)?, recv_timestamp)));
+
+                    if self.parse_failures != parse_failures {
+                        self.channel.parse_failures(self.parse_failures).await;
+                    }
+
+                    if let Some((packet, recv_timestamp)) = accepted {
                         match self.handle_packet(&mut poll_wait, packet, send_timestamp, recv_timestamp).await{
                             ControlFlow::Continue(_) => continue,
                             ControlFlow::Break(_) => break,
@@ -218,67 +420,182 @@ where
     }
 }
 
-impl<C> PeerTask<C, Sleep>
+/// How we associate with an NTP server
+enum NtpSourceMode {
+    /// We take time from the server. Its packets are authenticated with the key when given.
+    Client(Option<SymmetricKey>),
+    /// We both take time from the peer and give time to it
+    Symmetric(Option<SymmetricKey>),
+    /// We take time from the server, authenticated with keys from an NTS key exchange
+    Nts(Arc<rustls::ClientConfig>),
+}
+
+/// An NTP server or symmetric peer as a source of time, see [`PeerTask`]
+pub(crate) struct NtpSource<C> {
+    /// Address of the server, or of its key exchange server for NTS
+    addr: String,
+    mode: NtpSourceMode,
+    resolver: Arc<dyn Resolver>,
+    clock: C,
+}
+
+impl<C> NtpSource<C>
 where
     C: 'static + NtpClock + Send,
 {
-    #[instrument(skip(clock, channels))]
-    pub async fn spawn<A: ToSocketAddrs + std::fmt::Debug>(
-        index: PeerIndex,
-        addr: A,
+    /// The server at `addr`, which authenticates its packets with `key` when given.
+    ///
+    /// The source retries resolving the address until it succeeds, so a server that cannot be
+    /// resolved yet (e.g. because the network is not up) does not prevent the daemon from starting.
+    pub fn client(
+        addr: String,
+        key: Option<SymmetricKey>,
+        resolver: Arc<dyn Resolver>,
         clock: C,
-        mut channels: PeerChannels,
-    ) -> std::io::Result<tokio::task::JoinHandle<()>> {
-        let socket = UdpSocket::new("0.0.0.0:0", addr).await?;
+    ) -> Self {
+        NtpSource {
+            addr,
+            mode: NtpSourceMode::Client(key),
+            resolver,
+            clock,
+        }
+    }
+
+    /// A symmetric active association with the peer at `addr`, which both gives time to us and
+    /// takes time from us. Its packets are authenticated with `key` when given.
+    pub fn symmetric(
+        addr: String,
+        key: Option<SymmetricKey>,
+        resolver: Arc<dyn Resolver>,
+        clock: C,
+    ) -> Self {
+        NtpSource {
+            addr,
+            mode: NtpSourceMode::Symmetric(key),
+            resolver,
+            clock,
+        }
+    }
+
+    /// A server that uses NTS. The key exchange server is at `ke_addr`.
+    ///
+    /// The source retries the initial key exchange until it succeeds, so an unavailable key
+    /// exchange server does not prevent the daemon from starting.
+    pub fn nts(
+        ke_addr: String,
+        tls_config: Arc<rustls::ClientConfig>,
+        resolver: Arc<dyn Resolver>,
+        clock: C,
+    ) -> Self {
+        NtpSource {
+            addr: ke_addr,
+            mode: NtpSourceMode::Nts(tls_config),
+            resolver,
+            clock,
+        }
+    }
+
+    async fn run(self, channel: SourceChannel) {
+        let NtpSource {
+            addr,
+            mode,
+            resolver,
+            clock,
+        } = self;
+
+        let (addr, socket, nts, key, symmetric) = match mode {
+            NtpSourceMode::Client(key) => {
+                let socket = connect_retrying(resolver.as_ref(), &addr).await;
+                (addr, socket, None, key, false)
+            }
+            NtpSourceMode::Symmetric(key) => {
+                let socket = connect_retrying(resolver.as_ref(), &addr).await;
+                (addr, socket, None, key, true)
+            }
+            NtpSourceMode::Nts(tls_config) => {
+                let ke_addr = addr;
+                let mut retry = KEY_EXCHANGE_MIN_RETRY;
+
+                let (addr, socket, data) = loop {
+                    match nts_connect(&ke_addr, tls_config.clone(), resolver.as_ref()).await {
+                        Ok(connection) => break connection,
+                        Err(error) => {
+                            warn!(?error, ?retry, "NTS key exchange failed");
+                            tokio::time::sleep(retry).await;
+                            retry = Ord::min(retry * 2, KEY_EXCHANGE_MAX_RETRY);
+                        }
+                    }
+                };
+
+                let nts = NtsState {
+                    ke_addr,
+                    tls_config,
+                    data,
+                };
+
+                (addr, socket, Some(nts), None, false)
+            }
+        };
+
+        info!(?addr, timestamping = ?socket.timestamping_mode(), "connected to peer");
+
         let our_id = ReferenceId::from_ip(socket.as_ref().local_addr().unwrap().ip());
         let peer_id = ReferenceId::from_ip(socket.as_ref().peer_addr().unwrap().ip());
 
-        let handle = tokio::spawn(async move {
-            let local_clock_time = NtpInstant::now();
-            let peer = Peer::new(our_id, peer_id, local_clock_time);
+        let local_clock_time = NtpInstant::now();
+        let peer = match key {
+            _ if symmetric => Peer::new_symmetric(our_id, peer_id, local_clock_time, key),
+            Some(key) => Peer::new_with_key(our_id, peer_id, local_clock_time, key),
+            None => Peer::new(our_id, peer_id, local_clock_time),
+        };
 
-            let poll_wait = tokio::time::sleep(std::time::Duration::default());
-            tokio::pin!(poll_wait);
+        let poll_wait = tokio::time::sleep(std::time::Duration::default());
+        tokio::pin!(poll_wait);
 
-            // Even though we currently always have reset_epoch start at
-            // the default value, we shouldn't rely on that.
-            let reset_epoch = *channels.reset.borrow_and_update();
-
-            let mut process = PeerTask {
-                _wait: PhantomData,
-                index,
-                clock,
-                channels,
-                socket,
-                peer,
-                last_send_timestamp: None,
-                last_poll_sent: Instant::now(),
-                reset_epoch,
-            };
+        let mut process = PeerTask {
+            _wait: PhantomData,
+            clock,
+            channel,
+            socket,
+            addr,
+            resolver,
+            last_resolved: Instant::now(),
+            was_reachable: false,
+            peer,
+            nts,
+            last_send_timestamp: None,
+            last_poll_sent: Instant::now(),
+            parse_failures: 0,
+        };
 
-            process.run(poll_wait).await
-        });
+        process.run(poll_wait).await
+    }
+}
 
-        Ok(handle)
+impl<C> TimeSource for NtpSource<C>
+where
+    C: 'static + NtpClock + Send,
+{
+    fn run(self, channel: SourceChannel) -> BoxFuture<'static, ()> {
+        Box::pin(NtpSource::run(self, channel))
     }
 }
 
 fn accept_packet(
     result: Result<(usize, Option<NtpTimestamp>), std::io::Error>,
-    buf: &[u8; 48],
-) -> Option<(NtpHeader, NtpTimestamp)> {
+    buf: &[u8],
+) -> Option<(&[u8], NtpTimestamp)> {
     match result {
         Ok((size, Some(recv_timestamp))) => {
             // Note: packets are allowed to be bigger when including extensions.
-            // we don't expect them, but the server may still send them. The
-            // extra bytes are guaranteed safe to ignore. `recv` truncates the messages.
+            // `recv` truncates messages that do not fit in the buffer.
             // Messages of fewer than 48 bytes are skipped entirely
             if size < 48 {
                 warn!(expected = 48, actual = size, "received packet is too small");
 
                 None
             } else {
-                Some((NtpHeader::deserialize(buf), recv_timestamp))
+                Some((&buf[..size], recv_timestamp))
             }
         }
         Ok((size, None)) => {
@@ -296,11 +613,39 @@ fn accept_packet(
 
 #[cfg(test)]
 mod tests {
-    use ntp_proto::{NtpAssociationMode, NtpDuration, NtpLeapIndicator, PollInterval};
+    use std::net::SocketAddr;
+
+    use ntp_proto::{
+        NtpAssociationMode, NtpDuration, NtpHeader, NtpLeapIndicator, PollInterval, SystemConfig,
+    };
     use tokio::sync::{mpsc, watch, RwLock};
 
+    use crate::source::{self, MsgForSystem, PeerChannels, PeerId, ResetEpoch, Usage};
+
     use super::*;
 
+    /// Resolves every address to the addresses it is given
+    #[derive(Default)]
+    struct TestResolver {
+        addrs: std::sync::Mutex<Vec<SocketAddr>>,
+    }
+
+    impl TestResolver {
+        fn set(&self, addrs: &[&str]) {
+            *self.addrs.lock().unwrap() = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+        }
+    }
+
+    impl Resolver for TestResolver {
+        fn resolve<'a>(
+            &'a self,
+            _addr: &'a str,
+        ) -> BoxFuture<'a, std::io::Result<Vec<SocketAddr>>> {
+            let addrs = self.addrs.lock().unwrap().clone();
+            Box::pin(async move { Ok(addrs) })
+        }
+    }
+
     struct TestWaitSender {
         state: Arc<std::sync::Mutex<TestWaitState>>,
     }
@@ -370,8 +715,6 @@ mod tests {
         }
     }
 
-    const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
-
     #[derive(Debug, Clone, Default)]
     struct TestClock {}
 
@@ -382,8 +725,8 @@ mod tests {
             let cur =
                 std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?;
 
-            Ok(NtpTimestamp::from_seconds_nanos_since_ntp_era(
-                EPOCH_OFFSET.wrapping_add(cur.as_secs() as u32),
+            Ok(NtpTimestamp::from_unix(
+                cur.as_secs() as i64,
                 cur.subsec_nanos(),
             ))
         }
@@ -392,6 +735,10 @@ mod tests {
             panic!("Shouldn't be called by peer");
         }
 
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            panic!("Shouldn't be called by peer");
+        }
+
         fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
             panic!("Shouldn't be called by peer");
         }
@@ -406,6 +753,10 @@ mod tests {
         ) -> Result<(), Self::Error> {
             panic!("Shouldn't be called by peer");
         }
+
+        fn set_tai(&self, _tai_offset: i32) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by peer");
+        }
     }
 
     async fn test_startup<T: Wait>(
@@ -441,21 +792,27 @@ mod tests {
         let (msg_for_system_sender, msg_for_system_receiver) = mpsc::channel(1);
         let (reset_send, reset) = watch::channel(ResetEpoch::default());
 
+        let channels = PeerChannels {
+            msg_for_system_sender,
+            system_snapshots,
+            system_config,
+            reset,
+        };
+
         let process = PeerTask {
             _wait: PhantomData,
-            index: PeerIndex { index: 0 },
             clock: TestClock {},
-            channels: PeerChannels {
-                msg_for_system_sender,
-                system_snapshots,
-                system_config,
-                reset,
-            },
+            channel: SourceChannel::new(PeerId(0), Usage::Steer, channels),
             socket,
+            addr: format!("127.0.0.1:{}", port_base + 1),
+            resolver: Arc::new(TestResolver::default()),
+            last_resolved: Instant::now(),
+            was_reachable: false,
             peer,
+            nts: None,
             last_send_timestamp: None,
             last_poll_sent: Instant::now(),
-            reset_epoch: ResetEpoch::default(),
+            parse_failures: 0,
         };
 
         (process, test_socket, msg_for_system_receiver, reset_send)
@@ -474,19 +831,19 @@ mod tests {
         let (msg_for_system_sender, mut msg_for_system_receiver) = mpsc::channel(1);
         let (_reset_send, reset) = watch::channel(epoch);
 
-        let handle = PeerTask::spawn(
-            PeerIndex { index: 0 },
-            "127.0.0.1:8003",
-            TestClock {},
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(&["127.0.0.1:8003"]);
+
+        let handle = source::spawn(
+            PeerId(0),
+            NtpSource::client("127.0.0.1:8003".into(), None, resolver, TestClock {}),
             PeerChannels {
                 msg_for_system_sender,
                 system_snapshots,
                 system_config,
                 reset,
             },
-        )
-        .await
-        .unwrap();
+        );
 
         let peer_epoch = match msg_for_system_receiver.recv().await.unwrap() {
             MsgForSystem::UpdatedSnapshot(_, peer_epoch, _) => peer_epoch,
@@ -498,6 +855,83 @@ mod tests {
         handle.abort();
     }
 
+    #[tokio::test]
+    async fn test_deferred_resolution() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let recv_socket = tokio::net::UdpSocket::bind("127.0.0.1:8014").await.unwrap();
+
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let system_config = Arc::new(RwLock::new(SystemConfig::default()));
+        let (msg_for_system_sender, mut msg_for_system_receiver) = mpsc::channel(1);
+        let (_reset_send, reset) = watch::channel(ResetEpoch::default());
+
+        // the server cannot be resolved yet
+        let resolver = Arc::new(TestResolver::default());
+
+        let handle = source::spawn(
+            PeerId(0),
+            NtpSource::client(
+                "ntp.example.com:123".into(),
+                None,
+                resolver.clone(),
+                TestClock {},
+            ),
+            PeerChannels {
+                msg_for_system_sender,
+                system_snapshots,
+                system_config,
+                reset,
+            },
+        );
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(msg_for_system_receiver.try_recv().is_err());
+
+        // once it can, the peer starts polling
+        resolver.set(&["127.0.0.1:8014"]);
+
+        let msg = msg_for_system_receiver.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _, _)));
+
+        let mut buf = [0; 48];
+        let (size, _) = recv_socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_resolves_again() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let (mut process, _socket, mut msg_recv, _reset) = test_startup(8015).await;
+        let new_socket = tokio::net::UdpSocket::bind("127.0.0.1:8017").await.unwrap();
+
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(&["127.0.0.1:8017"]);
+        process.resolver = resolver;
+        // the server was reachable, but no longer is
+        process.was_reachable = true;
+
+        let (poll_wait, poll_send) = TestWait::new();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        poll_send.notify();
+
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _, _)));
+
+        // so the poll goes to its new address
+        let mut buf = [0; 48];
+        let (size, _) = new_socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+
+        handle.abort();
+    }
+
     #[tokio::test]
     async fn test_poll_sends_state_update_and_packet() {
         // Note: Ports must be unique among tests to deal with parallelism
@@ -559,6 +993,45 @@ mod tests {
         handle.abort();
     }
 
+    #[tokio::test]
+    async fn test_connect_any() {
+        let result = connect_any(&[]).await;
+        assert!(matches!(result, Err(e) if e.kind() == std::io::ErrorKind::NotFound));
+
+        // Note: Ports must be unique among tests to deal with parallelism
+        let addrs = [
+            "[::1]:9080".parse().unwrap(),
+            "127.0.0.1:9081".parse().unwrap(),
+        ];
+        let socket = connect_any(&addrs).await.unwrap();
+
+        // when the host has no IPv6, the IPv4 address is used instead
+        let peer_addr = socket.as_ref().peer_addr().unwrap();
+        let local_addr = socket.as_ref().local_addr().unwrap();
+        assert!(addrs.contains(&peer_addr));
+        assert_eq!(local_addr.is_ipv4(), peer_addr.is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn test_parse_failures() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let (mut process, _socket, _msg_recv, _reset) = test_startup::<TestWait>(8012).await;
+
+        let mut data = NtpHeader::new().serialize().to_vec();
+        assert!(process.parse_packet(&data).is_some());
+
+        // trailing data that is not an extension field
+        data.extend_from_slice(&[0; 8]);
+        assert!(process.parse_packet(&data).is_none());
+
+        // NTPv5
+        data.truncate(48);
+        data[0] = (data[0] & !0x38) | (5 << 3);
+        assert!(process.parse_packet(&data).is_none());
+
+        assert_eq!(process.parse_failures, 2);
+    }
+
     #[tokio::test]
     async fn test_timeroundtrip() {
         // Note: Ports must be unique among tests to deal with parallelism
@@ -594,7 +1067,10 @@ mod tests {
         socket.send(&send_packet.serialize()).await.unwrap();
 
         let msg = msg_recv.recv().await.unwrap();
-        assert!(matches!(msg, MsgForSystem::NewMeasurement(_, _, _)));
+        assert!(matches!(
+            msg,
+            MsgForSystem::NewMeasurement(_, _, _, Usage::Steer)
+        ));
 
         handle.abort();
     }
@@ -0,0 +1,564 @@
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use ntp_os_clock::ShmSegment;
+use ntp_proto::{
+    NtpClock, NtpDuration, NtpInstant, NtpLeapIndicator, NtpTimestamp, PollInterval,
+    RefclockFilter, RefclockSample, ReferenceId,
+};
+use tokio::{
+    net::UnixDatagram,
+    time::{Interval, MissedTickBehavior},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::{RefclockConfig, RefclockDriver},
+    source::{SourceChannel, TimeSource},
+};
+
+use self::stream::RefclockStream;
+
+mod stream;
+
+/// Time between reads of a shared memory segment
+const SHM_READ_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of samples of a reference clock that are kept between two polls
+const REFCLOCK_FILTER_LENGTH: usize = 64;
+/// Identifies the samples of the socket driver of chrony ("SOCK")
+const SOCK_MAGIC: i32 = 0x534f434b;
+/// Size of a sample of the socket driver of chrony, on platforms with a 64 bit `time_t`
+const SOCK_SAMPLE_SIZE: usize = 40;
+
+/// What a reference clock told us
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RefclockEvent {
+    Sample(RefclockSample),
+    /// The reference clock is not synchronized anymore, e.g. because a GPS receiver lost its fix
+    Unsynchronized,
+}
+
+/// Where the samples of a reference clock come from
+pub(crate) enum RefclockSource {
+    /// A shared memory segment, which is read every `SHM_READ_INTERVAL`
+    Shm {
+        segment: ShmSegment,
+        reads: Interval,
+    },
+    /// A unix socket to which another process sends samples
+    Sock(UnixDatagram),
+    /// A serial device or TCP stream over which the reference clock sends lines of text
+    Stream(Box<RefclockStream>),
+}
+
+impl RefclockSource {
+    pub(crate) fn open(config: &RefclockConfig) -> std::io::Result<Self> {
+        match config.driver {
+            RefclockDriver::Shm => Ok(RefclockSource::Shm {
+                segment: ShmSegment::attach(config.unit)?,
+                reads: tokio::time::interval(SHM_READ_INTERVAL),
+            }),
+            RefclockDriver::Sock => {
+                let path = config.path.as_ref().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "sock reference clocks need a path",
+                    )
+                })?;
+
+                // must unlink path before the bind below (otherwise we get "address already in use")
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+
+                Ok(RefclockSource::Sock(UnixDatagram::bind(path)?))
+            }
+            RefclockDriver::Nmea | RefclockDriver::Gpsd => Ok(RefclockSource::Stream(Box::new(
+                RefclockStream::new(config)?,
+            ))),
+        }
+    }
+
+    fn reference_id(&self) -> ReferenceId {
+        match self {
+            RefclockSource::Shm { .. } => ReferenceId::SHM,
+            RefclockSource::Sock(_) => ReferenceId::SOCK,
+            RefclockSource::Stream(stream) => stream.reference_id(),
+        }
+    }
+
+    /// Wait for the next sample of the reference clock, or for it to lose synchronization.
+    /// `clock` timestamps the samples of reference clocks that do not provide the time of our
+    /// clock themselves.
+    async fn next_event<C: NtpClock>(&mut self, clock: &C) -> RefclockEvent {
+        match self {
+            RefclockSource::Shm { segment, reads } => loop {
+                reads.tick().await;
+
+                if let Some(sample) = segment.read() {
+                    return RefclockEvent::Sample(sample);
+                }
+            },
+            RefclockSource::Sock(socket) => loop {
+                // Larger than a sample, so samples that are too large are noticed
+                let mut buf = [0_u8; 64];
+
+                match socket.recv(&mut buf).await {
+                    Ok(size) => match parse_sock_sample(&buf[..size]) {
+                        Some(sample) => return RefclockEvent::Sample(sample),
+                        None => warn!(size, "ignoring invalid reference clock sample"),
+                    },
+                    Err(error) => warn!(?error, "could not receive reference clock sample"),
+                }
+            },
+            RefclockSource::Stream(stream) => stream.next_event(clock).await,
+        }
+    }
+}
+
+/// Parse a sample of the socket driver of chrony:
+///
+/// ```c
+/// struct sock_sample {
+///     struct timeval tv; // time of our clock at the moment of the sample
+///     double offset;     // offset of the reference clock to our clock, in seconds
+///     int pulse;         // whether this is a pulse, e.g. of PPS, which only marks a second
+///     int leap;          // 0 normal, 1 insert leap second, 2 delete leap second
+///     int _pad;
+///     int magic;         // SOCK_MAGIC
+/// };
+/// ```
+fn parse_sock_sample(data: &[u8]) -> Option<RefclockSample> {
+    if data.len() != SOCK_SAMPLE_SIZE {
+        return None;
+    }
+
+    let i64_at = |at: usize| i64::from_ne_bytes(data[at..at + 8].try_into().unwrap());
+    let i32_at = |at: usize| i32::from_ne_bytes(data[at..at + 4].try_into().unwrap());
+
+    let seconds = i64_at(0);
+    let micros = i64_at(8);
+    let offset = f64::from_ne_bytes(data[16..24].try_into().unwrap());
+    let pulse = i32_at(24) != 0;
+    let leap = i32_at(28);
+
+    if i32_at(36) != SOCK_MAGIC || !(0..1_000_000).contains(&micros) || !offset.is_finite() {
+        return None;
+    }
+
+    let local_time = NtpTimestamp::from_unix(seconds, micros as u32 * 1000);
+
+    // A pulse only tells us when a second starts, not which second that is. Like chrony, we
+    // assume that our clock is already within half a second of the reference clock.
+    let offset = if pulse {
+        offset - offset.round()
+    } else {
+        offset
+    };
+
+    Some(RefclockSample {
+        reference_time: local_time + NtpDuration::from_seconds(offset),
+        local_time,
+        leap: match leap {
+            0 => NtpLeapIndicator::NoWarning,
+            1 => NtpLeapIndicator::Leap61,
+            2 => NtpLeapIndicator::Leap59,
+            _ => NtpLeapIndicator::Unknown,
+        },
+    })
+}
+
+/// A reference clock as a source of time, see [`RefclockTask`]
+pub(crate) struct Refclock<C> {
+    clock: C,
+    source: RefclockSource,
+    refclock: ntp_proto::Refclock,
+    offset: NtpDuration,
+    interval: Duration,
+}
+
+impl<C> Refclock<C>
+where
+    C: 'static + NtpClock + Send + Sync,
+{
+    pub fn new(config: &RefclockConfig, source: RefclockSource, clock: C) -> Self {
+        let interval = Duration::from_secs(config.interval);
+        let refclock = ntp_proto::Refclock::new(
+            source.reference_id(),
+            config.stratum,
+            config.precision,
+            PollInterval::at_least(interval),
+            NtpInstant::now(),
+        );
+
+        Refclock {
+            clock,
+            source,
+            refclock,
+            offset: NtpDuration::from_seconds(config.offset),
+            interval,
+        }
+    }
+}
+
+impl<C> TimeSource for Refclock<C>
+where
+    C: 'static + NtpClock + Send + Sync,
+{
+    fn run(self, channel: SourceChannel) -> BoxFuture<'static, ()> {
+        let mut process = RefclockTask {
+            clock: self.clock,
+            source: self.source,
+            filter: RefclockFilter::new(REFCLOCK_FILTER_LENGTH),
+            refclock: self.refclock,
+            channel,
+            offset: self.offset,
+            interval: self.interval,
+        };
+
+        Box::pin(async move { process.run().await })
+    }
+}
+
+/// Collects the samples of a reference clock, and passes the median sample of every interval on
+/// to the system like the measurements of a peer
+struct RefclockTask<C: 'static + NtpClock + Send + Sync> {
+    clock: C,
+    source: RefclockSource,
+    filter: RefclockFilter,
+    refclock: ntp_proto::Refclock,
+    channel: SourceChannel,
+
+    /// Added to the time of the reference clock
+    offset: NtpDuration,
+    interval: Duration,
+}
+
+impl<C> RefclockTask<C>
+where
+    C: 'static + NtpClock + Send + Sync,
+{
+    async fn handle_interval(&mut self) {
+        self.refclock.expect_sample();
+
+        let sample = match self.filter.take() {
+            Some(sample) => sample,
+            None => {
+                debug!("no new samples of the reference clock");
+                self.channel.snapshot(self.refclock.snapshot()).await;
+                return;
+            }
+        };
+
+        let system_snapshot = self.channel.system_snapshot().await;
+        let result = self.refclock.handle_sample(
+            system_snapshot,
+            sample,
+            NtpInstant::now(),
+            self.channel.system_config().await.frequency_tolerance,
+        );
+
+        match result {
+            Ok(snapshot) => {
+                debug!("sample accepted");
+                self.channel.measurement(snapshot).await;
+            }
+            Err(error) => {
+                debug!(%error, "sample ignored");
+                self.channel.snapshot(self.refclock.snapshot()).await;
+            }
+        }
+    }
+
+    async fn handle_unsynchronized(&mut self) {
+        warn!("reference clock lost synchronization");
+        self.refclock.unsynchronized();
+        // the samples collected so far must not make the reference clock usable again
+        self.filter.clear();
+
+        self.channel.snapshot(self.refclock.snapshot()).await;
+    }
+
+    async fn run(&mut self) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.handle_interval().await;
+                }
+                event = self.source.next_event(&self.clock) => match event {
+                    RefclockEvent::Sample(mut sample) => {
+                        sample.reference_time += self.offset;
+                        self.filter.add(sample);
+                    }
+                    RefclockEvent::Unsynchronized => self.handle_unsynchronized().await,
+                },
+                () = self.channel.reset() => {
+                    self.refclock.reset_measurements();
+                    // samples from before a jump of our clock are off
+                    self.filter.clear();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ntp_proto::{PeerSnapshot, SystemConfig, SystemSnapshot};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::{mpsc, watch, RwLock},
+    };
+
+    use crate::{
+        source::{self, MsgForSystem, PeerChannels, PeerId, ResetEpoch, Usage},
+        test_clock::TestClock,
+    };
+
+    use super::*;
+
+    /// 2024-02-29T12:30:15Z
+    const TEST_TIME: u32 = 1709209815;
+
+    fn test_config(driver: RefclockDriver) -> RefclockConfig {
+        RefclockConfig {
+            driver,
+            unit: 0,
+            path: None,
+            addr: None,
+            baud: 4800,
+            stratum: 0,
+            precision: -20,
+            offset: 0.5,
+            interval: 1,
+        }
+    }
+
+    fn spawn_test_task(
+        config: &RefclockConfig,
+        source: RefclockSource,
+    ) -> (
+        tokio::task::JoinHandle<()>,
+        mpsc::Receiver<MsgForSystem>,
+        watch::Sender<ResetEpoch>,
+    ) {
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let system_config = Arc::new(RwLock::new(SystemConfig::default()));
+        let (msg_for_system_sender, msg_for_system_receiver) = mpsc::channel(1);
+        let (reset_send, reset) = watch::channel(ResetEpoch::default());
+
+        let handle = source::spawn(
+            PeerId(0),
+            Refclock::new(config, source, TestClock::fixed(TEST_TIME)),
+            PeerChannels {
+                msg_for_system_sender,
+                system_snapshots,
+                system_config,
+                reset,
+            },
+        );
+
+        (handle, msg_for_system_receiver, reset_send)
+    }
+
+    async fn next_measurement(receiver: &mut mpsc::Receiver<MsgForSystem>) -> PeerSnapshot {
+        // intervals without a (new) sample only update the snapshot
+        for _ in 0..5 {
+            if let MsgForSystem::NewMeasurement(_, _, snapshot, Usage::Steer) =
+                receiver.recv().await.unwrap()
+            {
+                return snapshot;
+            }
+        }
+
+        panic!("no measurement of the reference clock");
+    }
+
+    async fn next_unsynchronized(receiver: &mut mpsc::Receiver<MsgForSystem>) -> PeerSnapshot {
+        for _ in 0..5 {
+            if let MsgForSystem::UpdatedSnapshot(_, _, snapshot) = receiver.recv().await.unwrap() {
+                if snapshot.leap_indicator == NtpLeapIndicator::Unknown {
+                    return snapshot;
+                }
+            }
+        }
+
+        panic!("the reference clock stayed synchronized");
+    }
+
+    fn sock_sample(time: Duration, offset: f64, pulse: bool, leap: i32) -> Vec<u8> {
+        let mut sample = Vec::new();
+        sample.extend((time.as_secs() as i64).to_ne_bytes());
+        sample.extend((time.subsec_micros() as i64).to_ne_bytes());
+        sample.extend(offset.to_ne_bytes());
+        sample.extend((pulse as i32).to_ne_bytes());
+        sample.extend(leap.to_ne_bytes());
+        sample.extend(0_i32.to_ne_bytes());
+        sample.extend(SOCK_MAGIC.to_ne_bytes());
+        sample
+    }
+
+    #[test]
+    fn test_parse_sock_sample() {
+        let time = Duration::new(1_000_000, 250_000_000);
+        let local_time = NtpTimestamp::from_unix(1_000_000, 250_000_000);
+
+        let sample = parse_sock_sample(&sock_sample(time, 2.5, false, 1)).unwrap();
+        assert_eq!(sample.local_time, local_time);
+        assert_eq!(sample.offset(), NtpDuration::from_seconds(2.5));
+        assert_eq!(sample.leap, NtpLeapIndicator::Leap61);
+
+        // only the fraction of the offset of a pulse is known
+        let sample = parse_sock_sample(&sock_sample(time, 2.25, true, 0)).unwrap();
+        assert_eq!(sample.offset(), NtpDuration::from_seconds(0.25));
+        let sample = parse_sock_sample(&sock_sample(time, -0.75, true, 0)).unwrap();
+        assert_eq!(sample.offset(), NtpDuration::from_seconds(0.25));
+
+        let mut data = sock_sample(time, 0.0, false, 0);
+        assert!(parse_sock_sample(&data[..32]).is_none());
+        data[36] ^= 1;
+        assert!(parse_sock_sample(&data).is_none());
+
+        assert!(parse_sock_sample(&sock_sample(time, f64::NAN, false, 0)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_refclock_shm() {
+        // Note: units must be unique among tests to deal with parallelism
+        let writer = ShmSegment::attach(1001).unwrap();
+        // drop any sample left behind by an earlier run
+        writer.read();
+
+        let config = RefclockConfig {
+            unit: 1001,
+            ..test_config(RefclockDriver::Shm)
+        };
+        let source = RefclockSource::open(&config).unwrap();
+        let (handle, mut receiver, _reset_send) = spawn_test_task(&config, source);
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        writer.write(
+            now + Duration::from_secs(1),
+            now,
+            NtpLeapIndicator::NoWarning,
+        );
+
+        let snapshot = next_measurement(&mut receiver).await;
+        assert_eq!(snapshot.peer_id, ReferenceId::SHM);
+        assert_eq!(snapshot.stratum, 0);
+        let offset = snapshot.statistics.offset.to_seconds();
+        assert!((offset - 1.5).abs() < 1e-6, "offset {}", offset);
+
+        handle.abort();
+        writer.remove().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refclock_sock() {
+        // Note: paths must be unique among tests to deal with parallelism
+        let path = std::env::temp_dir().join("ntp-test-sock-1");
+
+        let config = RefclockConfig {
+            path: Some(path.clone()),
+            ..test_config(RefclockDriver::Sock)
+        };
+        let source = RefclockSource::open(&config).unwrap();
+        let (handle, mut receiver, _reset_send) = spawn_test_task(&config, source);
+
+        let client = UnixDatagram::unbound().unwrap();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        for offset in [1.0, 100.0, 1.25] {
+            let sample = sock_sample(now, offset, false, 0);
+            client.send_to(&sample, &path).await.unwrap();
+        }
+
+        // the outlier is filtered out
+        let snapshot = next_measurement(&mut receiver).await;
+        assert_eq!(snapshot.peer_id, ReferenceId::SOCK);
+        let offset = snapshot.statistics.offset.to_seconds();
+        assert!(offset < 2.0, "offset {}", offset);
+
+        handle.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refclock_nmea() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let listener = TcpListener::bind("127.0.0.1:9060").await.unwrap();
+
+        let config = RefclockConfig {
+            addr: Some("127.0.0.1:9060".into()),
+            ..test_config(RefclockDriver::Nmea)
+        };
+        let source = RefclockSource::open(&config).unwrap();
+        let (handle, mut receiver, _reset_send) = spawn_test_task(&config, source);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // the receiver is a second ahead of the test clock
+        stream
+            .write_all(b"$GPGSV,1,1,00\r\n$GPRMC,123016.00,A,,,,,,,290224,,\r\n")
+            .await
+            .unwrap();
+
+        let snapshot = next_measurement(&mut receiver).await;
+        assert_eq!(snapshot.peer_id, ReferenceId::NMEA);
+        assert_eq!(snapshot.statistics.offset, NtpDuration::from_seconds(1.5));
+
+        // losing the fix makes the reference clock unusable right away
+        stream.write_all(b"$GPRMC,,V,,,,,,,,,\r\n").await.unwrap();
+        next_unsynchronized(&mut receiver).await;
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_refclock_gpsd() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let listener = TcpListener::bind("127.0.0.1:9061").await.unwrap();
+
+        let config = RefclockConfig {
+            addr: Some("127.0.0.1:9061".into()),
+            ..test_config(RefclockDriver::Gpsd)
+        };
+        let source = RefclockSource::open(&config).unwrap();
+        let (handle, mut receiver, _reset_send) = spawn_test_task(&config, source);
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+
+        let mut watch = String::new();
+        stream.read_line(&mut watch).await.unwrap();
+        assert!(watch.starts_with("?WATCH="));
+
+        stream
+            .write_all(
+                b"{\"class\":\"VERSION\"}\n{\"class\":\"TPV\",\"mode\":3,\"time\":\"2024-02-29T12:30:16.000Z\"}\n",
+            )
+            .await
+            .unwrap();
+
+        let snapshot = next_measurement(&mut receiver).await;
+        assert_eq!(snapshot.peer_id, ReferenceId::GPSD);
+        assert_eq!(snapshot.statistics.offset, NtpDuration::from_seconds(1.5));
+
+        stream
+            .write_all(b"{\"class\":\"TPV\",\"mode\":1}\n")
+            .await
+            .unwrap();
+        next_unsynchronized(&mut receiver).await;
+
+        handle.abort();
+    }
+}
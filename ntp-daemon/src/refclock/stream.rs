@@ -0,0 +1,528 @@
+use std::{future::Future, pin::Pin, time::Duration};
+
+use ntp_proto::{NtpClock, NtpLeapIndicator, NtpTimestamp, RefclockSample, ReferenceId};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, Lines},
+    net::TcpStream,
+    time::Instant,
+};
+use tracing::{info, warn};
+
+use super::RefclockEvent;
+use crate::config::{RefclockConfig, RefclockDriver, DEFAULT_GPSD_ADDR};
+
+/// Time to wait before opening a stream again that could not be opened or was closed
+const STREAM_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Asks gpsd to send us its reports as JSON
+const GPSD_WATCH: &[u8] = b"?WATCH={\"enable\":true,\"json\":true};\n";
+
+type StreamLines = Lines<BufReader<Box<dyn AsyncRead + Send + Unpin>>>;
+type OpenFuture = Pin<Box<dyn Future<Output = std::io::Result<StreamLines>> + Send>>;
+
+/// What a line of a reference clock told us
+#[derive(Debug, PartialEq, Eq)]
+struct Report {
+    /// Whether the receiver has a fix, if the line says so
+    fix: Option<bool>,
+    /// The time at which the line started
+    time: Option<NtpTimestamp>,
+}
+
+/// A reference clock that sends its time as lines of text, such as a GPS receiver sending NMEA
+/// sentences over a serial device or gpsd sending reports over TCP
+pub(crate) struct RefclockStream {
+    config: RefclockConfig,
+    lines: Option<StreamLines>,
+    /// Opening the stream, kept here so that it survives the cancellation of `next_event`
+    opening: Option<OpenFuture>,
+    /// When to (re)open the stream, while it is not open
+    open_at: Instant,
+    /// Whether the receiver has a fix, `None` when it did not tell us yet
+    fix: Option<bool>,
+    /// The time told by the last line that told the time
+    last_time: Option<NtpTimestamp>,
+}
+
+impl RefclockStream {
+    pub(crate) fn new(config: &RefclockConfig) -> std::io::Result<Self> {
+        if config.driver == RefclockDriver::Nmea && config.path.is_some() == config.addr.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "nmea reference clocks need either a path or an address",
+            ));
+        }
+
+        Ok(RefclockStream {
+            config: config.clone(),
+            lines: None,
+            opening: None,
+            open_at: Instant::now(),
+            fix: None,
+            last_time: None,
+        })
+    }
+
+    pub(crate) fn reference_id(&self) -> ReferenceId {
+        match self.config.driver {
+            RefclockDriver::Gpsd => ReferenceId::GPSD,
+            _ => ReferenceId::NMEA,
+        }
+    }
+
+    async fn open(config: RefclockConfig, open_at: Instant) -> std::io::Result<StreamLines> {
+        tokio::time::sleep_until(open_at).await;
+
+        let stream: Box<dyn AsyncRead + Send + Unpin> = match (config.driver, &config.path) {
+            (RefclockDriver::Nmea, Some(path)) => {
+                let file = ntp_os_clock::open_serial(path, config.baud)?;
+                Box::new(tokio::fs::File::from_std(file))
+            }
+            (driver, _) => {
+                let addr = config.addr.as_deref().unwrap_or(DEFAULT_GPSD_ADDR);
+                let mut stream = TcpStream::connect(addr).await?;
+                if driver == RefclockDriver::Gpsd {
+                    stream.write_all(GPSD_WATCH).await?;
+                }
+                Box::new(stream)
+            }
+        };
+
+        Ok(BufReader::new(stream).lines())
+    }
+
+    /// Wait for the next sample of the reference clock, or for it to lose its fix. A stream that
+    /// fails is opened again after `STREAM_RETRY_INTERVAL`.
+    pub(crate) async fn next_event<C: NtpClock>(&mut self, clock: &C) -> RefclockEvent {
+        loop {
+            let lines = match &mut self.lines {
+                Some(lines) => lines,
+                None => {
+                    let opening = self.opening.get_or_insert_with(|| {
+                        Box::pin(Self::open(self.config.clone(), self.open_at))
+                    });
+                    let result = opening.await;
+                    self.opening = None;
+                    self.open_at = Instant::now() + STREAM_RETRY_INTERVAL;
+
+                    match result {
+                        Ok(lines) => {
+                            info!(refclock = %self.config, "reference clock opened");
+                            self.lines = Some(lines);
+                            self.fix = None;
+                            self.last_time = None;
+                        }
+                        Err(error) => warn!(?error, "could not open reference clock"),
+                    }
+                    continue;
+                }
+            };
+
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    warn!("reference clock closed the stream");
+                    self.lines = None;
+                    continue;
+                }
+                Err(error) => {
+                    warn!(?error, "could not read from reference clock");
+                    self.lines = None;
+                    continue;
+                }
+            };
+
+            // The moment the line arrived is our best guess of when it was sent, the configured
+            // offset corrects for the time it took to send it
+            let local_time = match clock.now() {
+                Ok(now) => now,
+                Err(error) => {
+                    warn!(?error, "could not read the clock");
+                    continue;
+                }
+            };
+
+            let report = match self.config.driver {
+                RefclockDriver::Gpsd => parse_gpsd(&line),
+                _ => parse_nmea(&line),
+            };
+
+            // most lines tell us neither the time nor anything about the fix
+            if let Some(event) = report.and_then(|report| self.handle_report(report, local_time)) {
+                return event;
+            }
+        }
+    }
+
+    /// Handle a report that arrived at `local_time`, returning the event it amounts to, if any.
+    ///
+    /// A receiver sends a burst of sentences every second, several of which may tell the time of
+    /// that second. Only the first of them arrives at a fixed delay after the start of the second,
+    /// the others are delayed further by the sentences before them. So only the first line that
+    /// tells a new time is a sample, and the configured offset calibrates the delay of that line.
+    fn handle_report(&mut self, report: Report, local_time: NtpTimestamp) -> Option<RefclockEvent> {
+        if let Some(fix) = report.fix {
+            let lost = !fix && self.fix != Some(false);
+            self.fix = Some(fix);
+
+            if lost {
+                return Some(RefclockEvent::Unsynchronized);
+            }
+        }
+
+        let reference_time = report.time?;
+        if self.last_time == Some(reference_time) {
+            return None;
+        }
+        self.last_time = Some(reference_time);
+
+        if self.fix == Some(false) {
+            return None;
+        }
+
+        Some(RefclockEvent::Sample(RefclockSample {
+            reference_time,
+            local_time,
+            leap: NtpLeapIndicator::NoWarning,
+        }))
+    }
+}
+
+/// Parse an NMEA sentence. Only the sentences that tell the time (RMC and ZDA) or whether the
+/// receiver has a fix (RMC and GGA) are used.
+fn parse_nmea(line: &str) -> Option<Report> {
+    let sentence = line.trim_end().strip_prefix('$')?;
+
+    // the checksum is optional
+    let sentence = match sentence.rsplit_once('*') {
+        Some((data, checksum)) => {
+            let checksum = u8::from_str_radix(checksum, 16).ok()?;
+            if data.bytes().fold(0, |checksum, byte| checksum ^ byte) != checksum {
+                return None;
+            }
+            data
+        }
+        None => sentence,
+    };
+
+    let fields: Vec<&str> = sentence.split(',').collect();
+
+    // The first two letters identify the talker, e.g. GP for GPS and GN for multiple systems
+    match fields[0].get(2..)? {
+        // receivers without a fix often leave the time and date empty
+        "RMC" => Some(Report {
+            fix: Some(*fields.get(2)? == "A"),
+            time: rmc_time(fields.get(1)?, fields.get(9)?),
+        }),
+        "ZDA" => {
+            let day = fields.get(2)?.parse().ok()?;
+            let month = fields.get(3)?.parse().ok()?;
+            let year = fields.get(4)?.parse().ok()?;
+
+            Some(Report {
+                fix: None,
+                time: Some(nmea_time(year, month, day, fields.get(1)?)?),
+            })
+        }
+        "GGA" => Some(Report {
+            // a fix quality of 0 means there is no fix
+            fix: Some(*fields.get(6)? != "0"),
+            time: None,
+        }),
+        _ => None,
+    }
+}
+
+/// The timestamp of the time of day and `ddmmyy` date of an RMC sentence
+fn rmc_time(time: &str, date: &str) -> Option<NtpTimestamp> {
+    let day = date.get(0..2)?.parse().ok()?;
+    let month = date.get(2..4)?.parse().ok()?;
+    let year: i64 = date.get(4..6)?.parse().ok()?;
+    // GPS receivers were not around before 1980
+    let year = if year < 80 { 2000 + year } else { 1900 + year };
+
+    nmea_time(year, month, day, time)
+}
+
+/// The timestamp of an NMEA time of day, `hhmmss` or `hhmmss.sss`, on a date
+fn nmea_time(year: i64, month: u32, day: u32, time: &str) -> Option<NtpTimestamp> {
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    if time.len() != 6 {
+        return None;
+    }
+
+    utc_timestamp(
+        year,
+        month,
+        day,
+        time.get(0..2)?.parse().ok()?,
+        time.get(2..4)?.parse().ok()?,
+        time.get(4..6)?.parse().ok()?,
+        fraction_nanos(fraction)?,
+    )
+}
+
+/// A report of gpsd, with only the fields that we use
+#[derive(Deserialize)]
+struct GpsdReport {
+    class: String,
+    #[serde(default)]
+    mode: u8,
+    time: Option<String>,
+}
+
+/// Parse a report of gpsd. Only the time-position-velocity (TPV) reports are used.
+fn parse_gpsd(line: &str) -> Option<Report> {
+    let report: GpsdReport = serde_json::from_str(line).ok()?;
+    if report.class != "TPV" {
+        return None;
+    }
+
+    Some(Report {
+        // modes 0 and 1 mean that there is no fix, 2 and 3 that there is a 2D or 3D fix
+        fix: Some(report.mode >= 2),
+        time: report.time.as_deref().and_then(iso8601_time),
+    })
+}
+
+/// The timestamp of a UTC time like `2005-06-08T10:34:48.283Z`, as gpsd reports it
+fn iso8601_time(time: &str) -> Option<NtpTimestamp> {
+    let (date, time) = time.strip_suffix('Z')?.split_once('T')?;
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+
+    let mut date = date.splitn(3, '-');
+    let mut time = time.splitn(3, ':');
+
+    utc_timestamp(
+        date.next()?.parse().ok()?,
+        date.next()?.parse().ok()?,
+        date.next()?.parse().ok()?,
+        time.next()?.parse().ok()?,
+        time.next()?.parse().ok()?,
+        time.next()?.parse().ok()?,
+        fraction_nanos(fraction)?,
+    )
+}
+
+/// The nanoseconds of the decimal fraction of a second, e.g. 250_000_000 for "25"
+fn fraction_nanos(fraction: &str) -> Option<u32> {
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    // we do not need more than nanosecond precision
+    let digits = &fraction[..fraction.len().min(9)];
+    if digits.is_empty() {
+        return Some(0);
+    }
+
+    Some(digits.parse::<u32>().ok()? * 10_u32.pow(9 - digits.len() as u32))
+}
+
+/// The timestamp of a UTC date and time
+fn utc_timestamp(
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    nanos: u32,
+) -> Option<NtpTimestamp> {
+    // the second of a leap second is 60
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // Days since the unix epoch, using the days_from_civil algorithm of
+    // http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = days * 86400 + (hour * 3600 + minute * 60 + second) as i64;
+
+    Some(NtpTimestamp::from_unix(seconds, nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix_time(seconds: u32, nanos: u32) -> NtpTimestamp {
+        NtpTimestamp::from_unix(seconds as i64, nanos)
+    }
+
+    /// An NMEA sentence with its checksum
+    fn sentence(data: &str) -> String {
+        let checksum = data.bytes().fold(0, |checksum, byte| checksum ^ byte);
+        format!("${}*{:02X}", data, checksum)
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(1970, 1, 1, 0, 0, 0, 0), Some(unix_time(0, 0)));
+        assert_eq!(
+            utc_timestamp(2000, 3, 1, 0, 0, 0, 0),
+            Some(unix_time(951868800, 0))
+        );
+        assert_eq!(
+            utc_timestamp(2024, 2, 29, 12, 30, 15, 5),
+            Some(unix_time(1709209815, 5))
+        );
+        assert_eq!(utc_timestamp(2024, 13, 1, 0, 0, 0, 0), None);
+        assert_eq!(utc_timestamp(2024, 1, 1, 24, 0, 0, 0), None);
+
+        assert_eq!(fraction_nanos(""), Some(0));
+        assert_eq!(fraction_nanos("25"), Some(250_000_000));
+        assert_eq!(fraction_nanos("1234567891"), Some(123_456_789));
+        assert_eq!(fraction_nanos("-5"), None);
+    }
+
+    #[test]
+    fn test_parse_nmea() {
+        let time = unix_time(1709209815, 250_000_000);
+
+        let rmc = sentence("GPRMC,123015.25,A,4807.038,N,01131.000,E,022.4,084.4,290224,003.1,W");
+        assert_eq!(
+            parse_nmea(&format!("{}\r\n", rmc)),
+            Some(Report {
+                fix: Some(true),
+                time: Some(time),
+            })
+        );
+
+        // sentences with a wrong checksum are dropped
+        assert_eq!(parse_nmea(&rmc.replace("*", "0*")), None);
+
+        let rmc = sentence("GNRMC,123015.25,V,,,,,,,290224,,");
+        assert_eq!(
+            parse_nmea(&rmc),
+            Some(Report {
+                fix: Some(false),
+                time: Some(time),
+            })
+        );
+
+        assert_eq!(
+            parse_nmea(&sentence("GPRMC,,V,,,,,,,,,")),
+            Some(Report {
+                fix: Some(false),
+                time: None,
+            })
+        );
+
+        // without a checksum
+        assert_eq!(
+            parse_nmea("$GPZDA,123015.25,29,02,2024,00,00"),
+            Some(Report {
+                fix: None,
+                time: Some(time),
+            })
+        );
+
+        let gga = sentence("GPGGA,123015.25,4807.038,N,01131.000,E,0,00,,,M,,M,,");
+        assert_eq!(
+            parse_nmea(&gga),
+            Some(Report {
+                fix: Some(false),
+                time: None,
+            })
+        );
+
+        assert_eq!(parse_nmea(&sentence("GPGSV,1,1,00")), None);
+        assert_eq!(parse_nmea(&sentence("GPZDA,1230,29,02,2024,00,00")), None);
+        assert_eq!(parse_nmea("garbage"), None);
+    }
+
+    #[test]
+    fn test_first_sentence_of_second() {
+        let config = RefclockConfig {
+            driver: RefclockDriver::Nmea,
+            unit: 0,
+            path: None,
+            addr: Some("localhost:10110".into()),
+            baud: 4800,
+            stratum: 0,
+            precision: -1,
+            offset: 0.0,
+            interval: 16,
+        };
+        let mut stream = RefclockStream::new(&config).unwrap();
+
+        let second = unix_time(1709209815, 0);
+        let rmc = parse_nmea(&sentence("GPRMC,123015,A,,,,,,,290224,,")).unwrap();
+        let gga = parse_nmea(&sentence("GPGGA,123015,,,,,1,08,,,M,,M,,")).unwrap();
+        let zda = parse_nmea(&sentence("GPZDA,123015,29,02,2024,00,00")).unwrap();
+
+        // the RMC sentence comes first, and is the sample of this second
+        let local_time = unix_time(1709209815, 300_000_000);
+        assert_eq!(
+            stream.handle_report(rmc, local_time),
+            Some(RefclockEvent::Sample(RefclockSample {
+                reference_time: second,
+                local_time,
+                leap: NtpLeapIndicator::NoWarning,
+            }))
+        );
+
+        // the ZDA sentence later in the burst tells the same time
+        let later = unix_time(1709209815, 400_000_000);
+        assert_eq!(stream.handle_report(gga, later), None);
+        assert_eq!(stream.handle_report(zda, later), None);
+
+        // the next second gives a new sample
+        let zda = parse_nmea(&sentence("GPZDA,123016,29,02,2024,00,00")).unwrap();
+        let local_time = unix_time(1709209816, 300_000_000);
+        assert_eq!(
+            stream.handle_report(zda, local_time),
+            Some(RefclockEvent::Sample(RefclockSample {
+                reference_time: unix_time(1709209816, 0),
+                local_time,
+                leap: NtpLeapIndicator::NoWarning,
+            }))
+        );
+
+        // no samples without a fix
+        let rmc = parse_nmea(&sentence("GPRMC,123017,V,,,,,,,290224,,")).unwrap();
+        assert_eq!(
+            stream.handle_report(rmc, local_time),
+            Some(RefclockEvent::Unsynchronized)
+        );
+        let zda = parse_nmea(&sentence("GPZDA,123017,29,02,2024,00,00")).unwrap();
+        assert_eq!(stream.handle_report(zda, local_time), None);
+    }
+
+    #[test]
+    fn test_parse_gpsd() {
+        assert_eq!(
+            parse_gpsd(
+                r#"{"class":"TPV","device":"/dev/ttyS0","mode":3,"time":"2024-02-29T12:30:15.250Z","lat":48.1}"#
+            ),
+            Some(Report {
+                fix: Some(true),
+                time: Some(unix_time(1709209815, 250_000_000)),
+            })
+        );
+
+        assert_eq!(
+            parse_gpsd(r#"{"class":"TPV","device":"/dev/ttyS0","mode":1}"#),
+            Some(Report {
+                fix: Some(false),
+                time: None,
+            })
+        );
+
+        assert_eq!(parse_gpsd(r#"{"class":"SKY","satellites":[]}"#), None);
+        assert_eq!(parse_gpsd("not json"), None);
+    }
+}
@@ -0,0 +1,19 @@
+use std::{io, net::SocketAddr};
+
+use futures::future::BoxFuture;
+
+/// Looks up the addresses of a host. Peers only use this trait, so that tests can decide what
+/// addresses a peer finds without depending on DNS.
+pub(crate) trait Resolver: Send + Sync + 'static {
+    /// Resolve `addr`, given as `host:port`
+    fn resolve<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+/// Resolves addresses with the resolver of the operating system
+pub(crate) struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move { Ok(tokio::net::lookup_host(addr).await?.collect()) })
+    }
+}
@@ -0,0 +1,406 @@
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use ntp_proto::{
+    FrequencyTolerance, NtpClock, NtpDuration, NtpInstant, NtpLeapIndicator, NtpTimestamp,
+    PeerSnapshot, PeerStatistics, PollInterval, Reach, ReferenceId,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::warn;
+
+use crate::{
+    config::RoughtimeConfig,
+    peer::connect,
+    resolver::Resolver,
+    source::{PeerId, SourceChannel, TimeSource, Usage},
+};
+
+use self::protocol::{RoughtimeResponse, NONCE_SIZE, REQUEST_SIZE};
+
+mod protocol;
+
+/// Time to wait for a valid response of a Roughtime server
+const ROUGHTIME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Roughtime counts microseconds since the unix epoch
+fn timestamp_from_micros(micros: u64) -> NtpTimestamp {
+    NtpTimestamp::from_unix(
+        (micros / 1_000_000) as i64,
+        (micros % 1_000_000) as u32 * 1000,
+    )
+}
+
+/// A Roughtime server as a source of time. Its time is only accurate to a second or so, but it is
+/// signed, so it tells us whether the time of our peers is plausible.
+///
+/// The system does not select Roughtime servers to steer the clock, their measurements only
+/// bound the offset of our clock, see [`CrossCheck`].
+pub(crate) struct RoughtimeSource<C> {
+    addr: String,
+    public_key: [u8; 32],
+    interval: Duration,
+    resolver: Arc<dyn Resolver>,
+    clock: C,
+}
+
+impl<C> RoughtimeSource<C>
+where
+    C: 'static + NtpClock + Send + Sync,
+{
+    pub(crate) fn new(config: &RoughtimeConfig, resolver: Arc<dyn Resolver>, clock: C) -> Self {
+        RoughtimeSource {
+            addr: config.addr.clone(),
+            public_key: config.public_key,
+            interval: Duration::from_secs(config.interval),
+            resolver,
+            clock,
+        }
+    }
+
+    fn now(&self) -> NtpTimestamp {
+        match self.clock.now() {
+            Ok(time) => time,
+            Err(e) => panic!("`clock.now()` reported an error: {:?}", e),
+        }
+    }
+
+    /// Ask the server for the time, and measure the offset of our clock to it
+    async fn query(&self) -> io::Result<PeerSnapshot> {
+        let socket = connect(self.resolver.as_ref(), &self.addr).await?;
+
+        let mut nonce = [0; NONCE_SIZE];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| io::Error::other("could not generate a nonce"))?;
+
+        let send_time = self.now();
+        socket.send(&protocol::request(&nonce)).await?;
+
+        // anyone can send us garbage, so keep waiting for a valid response
+        let response = tokio::time::timeout(ROUGHTIME_TIMEOUT, async {
+            // no response is larger than the request
+            let mut buf = [0_u8; REQUEST_SIZE];
+
+            loop {
+                let (size, _) = socket.recv(&mut buf).await?;
+
+                match protocol::verify_response(&buf[..size], &nonce, &self.public_key) {
+                    Ok(response) => return io::Result::Ok(response),
+                    Err(error) => warn!(?error, "ignoring invalid roughtime response"),
+                }
+            }
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no valid roughtime response"))??;
+        let recv_time = self.now();
+
+        let RoughtimeResponse { midpoint, radius } = response;
+        let delay = recv_time - send_time;
+        let radius = NtpDuration::from_seconds(radius as f64 / 1e6);
+
+        // the server read its clock at some moment between sending and receiving, which we assume
+        // to be halfway
+        let offset = timestamp_from_micros(midpoint) - (send_time + delay / 2i64);
+
+        Ok(PeerSnapshot {
+            root_distance_without_time: delay / 2i64 + radius,
+            statistics: PeerStatistics {
+                offset,
+                delay,
+                dispersion: NtpDuration::ZERO,
+                jitter: 0.0,
+            },
+            time: NtpInstant::now(),
+            stratum: 1,
+            peer_id: ReferenceId::from_ip(socket.as_ref().peer_addr()?.ip()),
+            poll_interval: PollInterval::at_least(self.interval),
+            reference_id: ReferenceId::NONE,
+            our_id: ReferenceId::from_ip(socket.as_ref().local_addr()?.ip()),
+            reach: Reach::default(),
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            root_delay: NtpDuration::ZERO,
+            root_dispersion: radius,
+        })
+    }
+
+    async fn poll_loop(self, mut channel: SourceChannel) {
+        let mut reach = Reach::default();
+        let mut last_snapshot: Option<PeerSnapshot> = None;
+
+        loop {
+            reach.poll();
+
+            match self.query().await {
+                Ok(mut snapshot) => {
+                    reach.received_packet();
+                    snapshot.reach = reach;
+                    last_snapshot = Some(snapshot);
+                    channel.measurement(snapshot).await;
+                }
+                Err(error) => {
+                    warn!(?error, addr = self.addr.as_str(), "roughtime query failed");
+
+                    if let Some(snapshot) = &mut last_snapshot {
+                        snapshot.reach = reach;
+                        channel.snapshot(*snapshot).await;
+                    }
+                }
+            }
+
+            tokio::select! {
+                () = tokio::time::sleep(self.interval) => {}
+                () = channel.reset() => {
+                    // our clock changed, so the system needs a new measurement right away
+                    last_snapshot = None;
+                }
+            }
+        }
+    }
+}
+
+impl<C> TimeSource for RoughtimeSource<C>
+where
+    C: 'static + NtpClock + Send + Sync,
+{
+    fn run(self, channel: SourceChannel) -> BoxFuture<'static, ()> {
+        Box::pin(self.poll_loop(channel))
+    }
+
+    fn usage(&self) -> Usage {
+        Usage::CrossCheck
+    }
+}
+
+/// The latest measurements of the Roughtime servers. Together they bound the offset of our clock,
+/// so that the system can refuse to step the clock to a time that the servers do not vouch for.
+#[derive(Debug, Default)]
+pub(crate) struct CrossCheck {
+    measurements: HashMap<PeerId, Option<PeerSnapshot>>,
+}
+
+impl CrossCheck {
+    /// Expect measurements of `id`, which cross-checks the time
+    pub(crate) fn add(&mut self, id: PeerId) {
+        self.measurements.entry(id).or_insert(None);
+    }
+
+    pub(crate) fn remove(&mut self, id: PeerId) {
+        self.measurements.remove(&id);
+    }
+
+    /// Whether `id` cross-checks the time, and must not be selected to steer the clock
+    pub(crate) fn contains(&self, id: PeerId) -> bool {
+        self.measurements.contains_key(&id)
+    }
+
+    pub(crate) fn update(&mut self, id: PeerId, snapshot: PeerSnapshot) {
+        self.measurements.insert(id, Some(snapshot));
+    }
+
+    /// Whether enough servers measured for their bounds to be trusted on their own, e.g. to set a
+    /// clock that has no idea of the time yet: at least two, or all of them when fewer are
+    /// expected, so that a single server cannot set our clock while others are still to answer
+    pub(crate) fn quorum(&self) -> bool {
+        let measured = self.measurements.values().flatten().count();
+        measured > 0 && measured >= Ord::min(2, self.measurements.len())
+    }
+
+    /// Forget all measurements, which are off once our clock changed
+    pub(crate) fn clear(&mut self) {
+        for measurement in self.measurements.values_mut() {
+            *measurement = None;
+        }
+    }
+
+    /// The lowest and highest offset of our clock that all measurements allow at `now`. A
+    /// measurement gets less certain as it ages, because our clock may have drifted by up to
+    /// `frequency_tolerance` since. There are no bounds without measurements. When the servers
+    /// disagree, the lowest offset is above the highest, so that no offset is allowed: one wrong
+    /// server must not lift the bounds of the others.
+    pub(crate) fn bounds(
+        &self,
+        now: NtpInstant,
+        frequency_tolerance: FrequencyTolerance,
+    ) -> Option<(NtpDuration, NtpDuration)> {
+        let mut bounds: Option<(NtpDuration, NtpDuration)> = None;
+
+        for snapshot in self.measurements.values().flatten() {
+            let uncertainty = snapshot.root_dispersion
+                + snapshot.statistics.delay / 2i64
+                + NtpInstant::abs_diff(now, snapshot.time) * frequency_tolerance;
+            let lowest = snapshot.statistics.offset - uncertainty;
+            let highest = snapshot.statistics.offset + uncertainty;
+
+            bounds = Some(match bounds {
+                None => (lowest, highest),
+                Some((low, high)) => (Ord::max(low, lowest), Ord::min(high, highest)),
+            });
+        }
+
+        bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{SystemConfig, SystemSnapshot};
+    use tokio::sync::{mpsc, watch, RwLock};
+
+    use crate::{
+        resolver::SystemResolver,
+        source::{self, MsgForSystem, PeerChannels, ResetEpoch},
+        test_clock::TestClock,
+    };
+
+    use self::protocol::TestServer;
+
+    use super::*;
+
+    /// 2024-02-29T12:30:15Z
+    const TEST_TIME: u32 = 1709209815;
+
+    fn measurement(offset: f64, radius: f64, time: NtpInstant) -> PeerSnapshot {
+        PeerSnapshot {
+            root_distance_without_time: NtpDuration::from_seconds(radius),
+            statistics: PeerStatistics {
+                offset: NtpDuration::from_seconds(offset),
+                ..Default::default()
+            },
+            time,
+            stratum: 1,
+            peer_id: ReferenceId::NONE,
+            poll_interval: PollInterval::default(),
+            reference_id: ReferenceId::NONE,
+            our_id: ReferenceId::NONE,
+            reach: Reach::default(),
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            root_delay: NtpDuration::ZERO,
+            root_dispersion: NtpDuration::from_seconds(radius),
+        }
+    }
+
+    #[test]
+    fn test_cross_check() {
+        let now = NtpInstant::now();
+        let tolerance = FrequencyTolerance::ppm(15);
+        let seconds = |(lowest, highest): (NtpDuration, NtpDuration)| {
+            (lowest.to_seconds(), highest.to_seconds())
+        };
+
+        let mut cross_check = CrossCheck::default();
+        cross_check.add(PeerId(1));
+        cross_check.add(PeerId(2));
+        assert_eq!(cross_check.bounds(now, tolerance), None);
+        assert!(!cross_check.quorum());
+
+        // one server bounds the offset, but is not trusted on its own while another is expected
+        cross_check.update(PeerId(1), measurement(10.0, 1.0, now));
+        assert!(cross_check.contains(PeerId(1)));
+        assert!(!cross_check.contains(PeerId(3)));
+        assert!(!cross_check.quorum());
+        let (lowest, highest) = seconds(cross_check.bounds(now, tolerance).unwrap());
+        assert!((lowest - 9.0).abs() < 1e-6);
+        assert!((highest - 11.0).abs() < 1e-6);
+
+        // the bounds of both servers must hold
+        cross_check.update(PeerId(2), measurement(10.5, 1.0, now));
+        assert!(cross_check.quorum());
+        let (lowest, highest) = seconds(cross_check.bounds(now, tolerance).unwrap());
+        assert!((lowest - 9.5).abs() < 1e-6);
+        assert!((highest - 11.0).abs() < 1e-6);
+
+        // our clock may have drifted since the measurements, here by up to a second per second
+        std::thread::sleep(Duration::from_millis(20));
+        let later = NtpInstant::now();
+        let (lowest, highest) = seconds(
+            cross_check
+                .bounds(later, FrequencyTolerance::ppm(1_000_000))
+                .unwrap(),
+        );
+        assert!(lowest < 9.48);
+        assert!(highest > 11.02);
+
+        // servers that disagree allow no offset at all
+        cross_check.update(PeerId(2), measurement(20.0, 1.0, now));
+        let (lowest, highest) = seconds(cross_check.bounds(now, tolerance).unwrap());
+        assert!(lowest > highest);
+
+        // a single configured server is trusted on its own
+        cross_check.remove(PeerId(2));
+        assert!(cross_check.bounds(now, tolerance).is_some());
+        assert!(!cross_check.contains(PeerId(2)));
+        assert!(cross_check.quorum());
+
+        // the servers are still known after our clock changed, just without measurements
+        cross_check.clear();
+        assert!(cross_check.contains(PeerId(1)));
+        assert_eq!(cross_check.bounds(now, tolerance), None);
+        assert!(!cross_check.quorum());
+    }
+
+    #[tokio::test]
+    async fn test_roughtime_source() {
+        let server_socket = tokio::net::UdpSocket::bind("127.0.0.1:9070").await.unwrap();
+        let micros = TEST_TIME as u64 * 1_000_000;
+        let server = TestServer::new((micros - 86_400_000_000, micros + 86_400_000_000));
+        let impostor = TestServer::new((micros - 86_400_000_000, micros + 86_400_000_000));
+
+        let config = RoughtimeConfig {
+            addr: "127.0.0.1:9070".into(),
+            public_key: server.public_key(),
+            interval: 3600,
+        };
+
+        let (msg_for_system_sender, mut msg_for_system_receiver) = mpsc::channel(1);
+        let (reset_send, reset) = watch::channel(ResetEpoch::default());
+        let handle = source::spawn(
+            PeerId(0),
+            RoughtimeSource::new(
+                &config,
+                Arc::new(SystemResolver),
+                TestClock::fixed(TEST_TIME),
+            ),
+            PeerChannels {
+                msg_for_system_sender,
+                system_snapshots: Arc::new(RwLock::new(SystemSnapshot::default())),
+                system_config: Arc::new(RwLock::new(SystemConfig::default())),
+                reset,
+            },
+        );
+
+        let mut buf = [0; 2048];
+        let (size, client) = server_socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, REQUEST_SIZE);
+
+        // a response that is not signed by the server is ignored
+        let response = &impostor.respond(&[&buf[..size]], micros + 50_000_000, 1_000_000)[0];
+        server_socket.send_to(response, client).await.unwrap();
+        let response = &server.respond(&[&buf[..size]], micros + 10_000_000, 1_000_000)[0];
+        server_socket.send_to(response, client).await.unwrap();
+
+        let snapshot = match msg_for_system_receiver.recv().await.unwrap() {
+            MsgForSystem::NewMeasurement(PeerId(0), _, snapshot, Usage::CrossCheck) => snapshot,
+            msg => panic!("unexpected message {:?}", msg),
+        };
+        assert_eq!(snapshot.statistics.offset, NtpDuration::from_seconds(10.0));
+        assert_eq!(snapshot.statistics.delay, NtpDuration::ZERO);
+        assert_eq!(snapshot.root_dispersion, NtpDuration::from_seconds(1.0));
+        assert!(snapshot.reach.is_reachable());
+
+        // after a reset, the source measures again right away
+        reset_send.send(ResetEpoch::default().inc()).unwrap();
+        let (size, client) = server_socket.recv_from(&mut buf).await.unwrap();
+        let response = &server.respond(&[&buf[..size]], micros - 5_000_000, 1_000_000)[0];
+        server_socket.send_to(response, client).await.unwrap();
+
+        match msg_for_system_receiver.recv().await.unwrap() {
+            MsgForSystem::NewMeasurement(PeerId(0), epoch, snapshot, Usage::CrossCheck) => {
+                assert_eq!(epoch, ResetEpoch::default().inc());
+                assert_eq!(snapshot.statistics.offset, NtpDuration::from_seconds(-5.0));
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,482 @@
+//! The messages of the Roughtime protocol, as served by the Roughtime servers of Google and
+//! Cloudflare.
+//!
+//! A message maps tags of 4 bytes to values. All integers are little endian:
+//!
+//! ```text
+//! u32 number of tags (n)
+//! u32 offsets of the values of the tags after the first (n - 1 of them)
+//! u32 tags, in ascending order (n of them)
+//! values, every one a multiple of 4 bytes long
+//! ```
+
+use ring::{digest, signature};
+use thiserror::Error;
+
+/// Size of the nonce of a request, which is also the size of the hashes in the Merkle tree
+pub(crate) const NONCE_SIZE: usize = 64;
+/// Requests are padded to this size, so that no response is larger than its request
+pub(crate) const REQUEST_SIZE: usize = 1024;
+const HASH_SIZE: usize = 64;
+
+/// Prefixes of signed data, so that a signature cannot be used for another purpose
+const DELEGATION_CONTEXT: &[u8] = b"RoughTime v1 delegation signature--\0";
+const RESPONSE_CONTEXT: &[u8] = b"RoughTime v1 response signature\0";
+
+/// Tags are compared as little endian integers
+const fn tag(name: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*name)
+}
+
+const SIG: u32 = tag(b"SIG\0");
+const NONC: u32 = tag(b"NONC");
+const DELE: u32 = tag(b"DELE");
+const PATH: u32 = tag(b"PATH");
+const RADI: u32 = tag(b"RADI");
+const PUBK: u32 = tag(b"PUBK");
+const MIDP: u32 = tag(b"MIDP");
+const SREP: u32 = tag(b"SREP");
+const MINT: u32 = tag(b"MINT");
+const ROOT: u32 = tag(b"ROOT");
+const CERT: u32 = tag(b"CERT");
+const MAXT: u32 = tag(b"MAXT");
+const INDX: u32 = tag(b"INDX");
+const PAD: u32 = tag(b"PAD\xff");
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum RoughtimeError {
+    #[error("malformed message")]
+    Malformed,
+    #[error("message lacks a required tag")]
+    MissingTag,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("the server did not sign our nonce")]
+    NonceNotSigned,
+    #[error("the key of the server was not valid at the time of the response")]
+    InvalidDelegation,
+}
+
+/// The tags and values of a message, in the order of their tags
+struct Message<'a> {
+    fields: Vec<(u32, &'a [u8])>,
+}
+
+impl<'a> Message<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, RoughtimeError> {
+        let u32_at = |at: usize| match data.get(at..at + 4) {
+            Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into().unwrap())),
+            None => Err(RoughtimeError::Malformed),
+        };
+
+        let count = u32_at(0)? as usize;
+        let header_size = match count {
+            0 => 4,
+            _ => count.checked_mul(8).ok_or(RoughtimeError::Malformed)?,
+        };
+        if data.len() < header_size {
+            return Err(RoughtimeError::Malformed);
+        }
+
+        let values = &data[header_size..];
+        let mut fields: Vec<(u32, &[u8])> = Vec::with_capacity(count);
+        let mut start = 0;
+        for i in 0..count {
+            let tag = u32_at(4 * count + 4 * i)?;
+            let end = match i + 1 < count {
+                true => u32_at(4 + 4 * i)? as usize,
+                false => values.len(),
+            };
+
+            if end < start || end > values.len() || end % 4 != 0 {
+                return Err(RoughtimeError::Malformed);
+            }
+            if let Some((previous, _)) = fields.last() {
+                if tag <= *previous {
+                    return Err(RoughtimeError::Malformed);
+                }
+            }
+
+            fields.push((tag, &values[start..end]));
+            start = end;
+        }
+
+        Ok(Message { fields })
+    }
+
+    fn get(&self, tag: u32) -> Result<&'a [u8], RoughtimeError> {
+        self.fields
+            .iter()
+            .find(|(field_tag, _)| *field_tag == tag)
+            .map(|(_, value)| *value)
+            .ok_or(RoughtimeError::MissingTag)
+    }
+
+    fn get_fixed<const N: usize>(&self, tag: u32) -> Result<[u8; N], RoughtimeError> {
+        self.get(tag)?
+            .try_into()
+            .map_err(|_| RoughtimeError::Malformed)
+    }
+
+    fn get_u32(&self, tag: u32) -> Result<u32, RoughtimeError> {
+        Ok(u32::from_le_bytes(self.get_fixed(tag)?))
+    }
+
+    fn get_u64(&self, tag: u32) -> Result<u64, RoughtimeError> {
+        Ok(u64::from_le_bytes(self.get_fixed(tag)?))
+    }
+}
+
+/// Encode a message. The `fields` must be in the order of their tags, and every value must be a
+/// multiple of 4 bytes long.
+fn encode(fields: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+
+    let mut offset = 0;
+    for (_, value) in fields.iter().take(fields.len().saturating_sub(1)) {
+        offset += value.len() as u32;
+        data.extend_from_slice(&offset.to_le_bytes());
+    }
+    for (tag, _) in fields {
+        data.extend_from_slice(&tag.to_le_bytes());
+    }
+    for (_, value) in fields {
+        data.extend_from_slice(value);
+    }
+
+    data
+}
+
+/// A request for the time, which the server must answer with a signature over `nonce`
+pub(crate) fn request(nonce: &[u8; NONCE_SIZE]) -> Vec<u8> {
+    // the header of a message with two tags is 16 bytes
+    let padding = [0; REQUEST_SIZE - 16 - NONCE_SIZE];
+
+    encode(&[(NONC, nonce), (PAD, &padding)])
+}
+
+fn hash_leaf(data: &[u8]) -> [u8; HASH_SIZE] {
+    let mut context = digest::Context::new(&digest::SHA512);
+    context.update(&[0]);
+    context.update(data);
+
+    context.finish().as_ref().try_into().unwrap()
+}
+
+fn hash_node(left: &[u8], right: &[u8]) -> [u8; HASH_SIZE] {
+    let mut context = digest::Context::new(&digest::SHA512);
+    context.update(&[1]);
+    context.update(left);
+    context.update(right);
+
+    context.finish().as_ref().try_into().unwrap()
+}
+
+fn verify_signature(
+    public_key: &[u8],
+    context: &[u8],
+    data: &[u8],
+    signature: &[u8],
+) -> Result<(), RoughtimeError> {
+    let mut message = context.to_vec();
+    message.extend_from_slice(data);
+
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(&message, signature)
+        .map_err(|_| RoughtimeError::InvalidSignature)
+}
+
+/// The time that a server vouches for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RoughtimeResponse {
+    /// Microseconds since the unix epoch, at some moment between our request and the response
+    pub(crate) midpoint: u64,
+    /// Microseconds, the server is sure that the true time is within this radius of the midpoint
+    pub(crate) radius: u32,
+}
+
+/// Check that `data` answers our request with `nonce`, and is signed by the server with the
+/// long-term `public_key`
+pub(crate) fn verify_response(
+    data: &[u8],
+    nonce: &[u8; NONCE_SIZE],
+    public_key: &[u8; 32],
+) -> Result<RoughtimeResponse, RoughtimeError> {
+    let response = Message::parse(data)?;
+
+    // the long-term key of the server only signs the key that signs responses
+    let certificate = Message::parse(response.get(CERT)?)?;
+    let delegation_data = certificate.get(DELE)?;
+    verify_signature(
+        public_key,
+        DELEGATION_CONTEXT,
+        delegation_data,
+        certificate.get(SIG)?,
+    )?;
+    let delegation = Message::parse(delegation_data)?;
+    let delegated_key: [u8; 32] = delegation.get_fixed(PUBK)?;
+
+    let signed_data = response.get(SREP)?;
+    verify_signature(
+        &delegated_key,
+        RESPONSE_CONTEXT,
+        signed_data,
+        response.get(SIG)?,
+    )?;
+    let signed = Message::parse(signed_data)?;
+
+    // One signature covers all requests that the server answers at once, by signing the root of
+    // a Merkle tree of their nonces. The path leads from our nonce to that root.
+    let path = response.get(PATH)?;
+    if path.len() % HASH_SIZE != 0 {
+        return Err(RoughtimeError::Malformed);
+    }
+
+    let mut index = response.get_u32(INDX)?;
+    let mut hash = hash_leaf(nonce);
+    for sibling in path.chunks(HASH_SIZE) {
+        hash = match index & 1 {
+            0 => hash_node(&hash, sibling),
+            _ => hash_node(sibling, &hash),
+        };
+        index >>= 1;
+    }
+
+    if index != 0 || signed.get(ROOT)? != hash {
+        return Err(RoughtimeError::NonceNotSigned);
+    }
+
+    let midpoint = signed.get_u64(MIDP)?;
+    let radius = signed.get_u32(RADI)?;
+    if !(delegation.get_u64(MINT)?..=delegation.get_u64(MAXT)?).contains(&midpoint) {
+        return Err(RoughtimeError::InvalidDelegation);
+    }
+
+    Ok(RoughtimeResponse { midpoint, radius })
+}
+
+/// A Roughtime server to test against, with keys that are generated on the fly
+#[cfg(test)]
+pub(crate) struct TestServer {
+    root_key: signature::Ed25519KeyPair,
+    delegated_key: signature::Ed25519KeyPair,
+    /// Microseconds since the unix epoch between which the delegated key is valid
+    validity: (u64, u64),
+}
+
+#[cfg(test)]
+impl TestServer {
+    pub(crate) fn new(validity: (u64, u64)) -> Self {
+        let generate = || {
+            let rng = ring::rand::SystemRandom::new();
+            let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+            signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+        };
+
+        TestServer {
+            root_key: generate(),
+            delegated_key: generate(),
+            validity,
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> [u8; 32] {
+        use signature::KeyPair;
+
+        self.root_key.public_key().as_ref().try_into().unwrap()
+    }
+
+    /// Answer all `requests` at once, like a busy server does
+    pub(crate) fn respond(&self, requests: &[&[u8]], midpoint: u64, radius: u32) -> Vec<Vec<u8>> {
+        use signature::KeyPair;
+
+        let sign = |key: &signature::Ed25519KeyPair, context: &[u8], data: &[u8]| {
+            let mut message = context.to_vec();
+            message.extend_from_slice(data);
+            key.sign(&message).as_ref().to_vec()
+        };
+
+        let delegation = encode(&[
+            (PUBK, self.delegated_key.public_key().as_ref()),
+            (MINT, &self.validity.0.to_le_bytes()),
+            (MAXT, &self.validity.1.to_le_bytes()),
+        ]);
+        let certificate = encode(&[
+            (SIG, &sign(&self.root_key, DELEGATION_CONTEXT, &delegation)),
+            (DELE, &delegation),
+        ]);
+
+        // the levels of the Merkle tree, from the leaves up, with the last leaf repeated to fill
+        // up the tree
+        let mut levels = vec![requests
+            .iter()
+            .map(|request| hash_leaf(Message::parse(request).unwrap().get(NONC).unwrap()))
+            .collect::<Vec<_>>()];
+        let width = requests.len().next_power_of_two();
+        let last = *levels[0].last().unwrap();
+        levels[0].resize(width, last);
+        while levels.last().unwrap().len() > 1 {
+            let level = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| hash_node(&pair[0], &pair[1]))
+                .collect();
+            levels.push(level);
+        }
+
+        let signed = encode(&[
+            (RADI, &radius.to_le_bytes()),
+            (MIDP, &midpoint.to_le_bytes()),
+            (ROOT, &levels.last().unwrap()[0]),
+        ]);
+        let signature = sign(&self.delegated_key, RESPONSE_CONTEXT, &signed);
+
+        (0..requests.len())
+            .map(|index| {
+                let path: Vec<u8> = levels[..levels.len() - 1]
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(depth, level)| level[(index >> depth) ^ 1])
+                    .collect();
+
+                encode(&[
+                    (SIG, &signature),
+                    (PATH, &path),
+                    (SREP, &signed),
+                    (CERT, &certificate),
+                    (INDX, &(index as u32).to_le_bytes()),
+                ])
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALIDITY: (u64, u64) = (1_700_000_000_000_000, 1_800_000_000_000_000);
+    const MIDPOINT: u64 = 1_709_209_815_000_000;
+
+    #[test]
+    fn test_message() {
+        let data = encode(&[(NONC, &[1; 8]), (tag(b"ZZZZ"), &[2; 4]), (PAD, &[])]);
+        assert_eq!(data.len(), 4 + 2 * 4 + 3 * 4 + 12);
+
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(message.get(NONC).unwrap(), [1; 8]);
+        assert!(message.get(PAD).unwrap().is_empty());
+        assert_eq!(message.get_u32(tag(b"ZZZZ")).unwrap(), 0x02020202);
+        assert_eq!(message.get(SIG), Err(RoughtimeError::MissingTag));
+        assert_eq!(message.get_u64(NONC).unwrap(), 0x0101010101010101);
+        assert_eq!(message.get_u32(NONC).err(), Some(RoughtimeError::Malformed));
+
+        assert!(Message::parse(&encode(&[])).unwrap().fields.is_empty());
+
+        let request = request(&[7; NONCE_SIZE]);
+        assert_eq!(request.len(), REQUEST_SIZE);
+        assert_eq!(
+            Message::parse(&request).unwrap().get(NONC).unwrap(),
+            [7; 64]
+        );
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        let data = encode(&[(NONC, &[1; 8]), (PAD, &[2; 4])]);
+
+        // truncated
+        assert!(Message::parse(&data[..2]).is_err());
+        assert!(Message::parse(&data[..8]).is_err());
+        assert!(Message::parse(&data[..data.len() - 2]).is_err());
+
+        // tags out of order
+        let mut swapped = data.clone();
+        swapped.copy_within(8..12, 12);
+        swapped[8..12].copy_from_slice(&PAD.to_le_bytes());
+        assert!(Message::parse(&swapped).is_err());
+
+        // offset past the end, and an offset that is not a multiple of 4
+        let mut past_end = data.clone();
+        past_end[4] = 16;
+        assert!(Message::parse(&past_end).is_err());
+        let mut unaligned = data.clone();
+        unaligned[4] = 6;
+        assert!(Message::parse(&unaligned).is_err());
+
+        // more tags than fit in the message
+        let mut count = data;
+        count[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Message::parse(&count).is_err());
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let server = TestServer::new(VALIDITY);
+        let nonces: Vec<_> = (0..5).map(|i| [i as u8; NONCE_SIZE]).collect();
+        let requests: Vec<_> = nonces.iter().map(request).collect();
+        let requests: Vec<_> = requests.iter().map(|r| r.as_slice()).collect();
+
+        // every response of a batch is valid, but only for its own nonce
+        let responses = server.respond(&requests, MIDPOINT, 1_000_000);
+        for (nonce, response) in nonces.iter().zip(&responses) {
+            assert!(response.len() <= REQUEST_SIZE);
+            assert_eq!(
+                verify_response(response, nonce, &server.public_key()),
+                Ok(RoughtimeResponse {
+                    midpoint: MIDPOINT,
+                    radius: 1_000_000
+                })
+            );
+            assert_eq!(
+                verify_response(response, &[9; NONCE_SIZE], &server.public_key()),
+                Err(RoughtimeError::NonceNotSigned)
+            );
+        }
+
+        // a batch of one has an empty path
+        let response = &server.respond(&requests[..1], MIDPOINT, 0)[0];
+        assert!(verify_response(response, &nonces[0], &server.public_key()).is_ok());
+    }
+
+    #[test]
+    fn test_reject_response() {
+        let server = TestServer::new(VALIDITY);
+        let nonce = [3; NONCE_SIZE];
+        let response = &server.respond(&[&request(&nonce)], MIDPOINT, 1_000_000)[0];
+
+        // signed by another server
+        let other = TestServer::new(VALIDITY);
+        assert_eq!(
+            verify_response(response, &nonce, &other.public_key()),
+            Err(RoughtimeError::InvalidSignature)
+        );
+
+        // a changed midpoint invalidates the signature of the delegated key
+        let offset = response
+            .windows(8)
+            .position(|window| window == MIDPOINT.to_le_bytes())
+            .unwrap();
+        let mut tampered = response.clone();
+        tampered[offset] ^= 1;
+        assert_eq!(
+            verify_response(&tampered, &nonce, &server.public_key()),
+            Err(RoughtimeError::InvalidSignature)
+        );
+
+        // the delegated key was not valid yet
+        let expired = TestServer::new((MIDPOINT + 1, VALIDITY.1));
+        let response = &expired.respond(&[&request(&nonce)], MIDPOINT, 1_000_000)[0];
+        assert_eq!(
+            verify_response(response, &nonce, &expired.public_key()),
+            Err(RoughtimeError::InvalidDelegation)
+        );
+
+        assert_eq!(
+            verify_response(&request(&nonce), &nonce, &server.public_key()),
+            Err(RoughtimeError::MissingTag)
+        );
+    }
+}
@@ -0,0 +1,585 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use ntp_proto::{
+    KeySet, NtpAssociationMode, NtpClock, NtpHeader, NtpPacket, NtpTimestamp, NtsServerRequest,
+    PollInterval, SymmetricKey, SystemConfig, SystemSnapshot,
+};
+use ntp_udp::UdpSocket;
+use tokio::{
+    sync::RwLock,
+    task::JoinHandle,
+    time::{Interval, MissedTickBehavior},
+};
+use tracing::{debug, info, instrument, trace, warn};
+
+use crate::config::{BroadcastConfig, ServerConfig};
+
+pub(crate) struct ServerTask<C: 'static + NtpClock + Send> {
+    socket: UdpSocket,
+    system_snapshots: Arc<RwLock<SystemSnapshot>>,
+    system_config: Arc<RwLock<SystemConfig>>,
+    /// Keys used to decrypt NTS cookies, when NTS is enabled
+    keyset: Option<Arc<RwLock<KeySet>>>,
+    require_nts: bool,
+    /// Keys with which requests that do not use NTS may be authenticated
+    keys: HashMap<u32, SymmetricKey>,
+    /// Where we broadcast our time to, when enabled
+    broadcast: Option<BroadcastConfig>,
+    /// Key with which our broadcasts are authenticated
+    broadcast_key: Option<SymmetricKey>,
+    clock: C,
+}
+
+impl<C> ServerTask<C>
+where
+    C: 'static + NtpClock + Send + Sync,
+{
+    #[instrument(skip(clock, system_snapshots, system_config, keyset, keys, broadcast_key))]
+    pub async fn spawn(
+        config: ServerConfig,
+        system_snapshots: Arc<RwLock<SystemSnapshot>>,
+        system_config: Arc<RwLock<SystemConfig>>,
+        keyset: Option<Arc<RwLock<KeySet>>>,
+        keys: HashMap<u32, SymmetricKey>,
+        broadcast_key: Option<SymmetricKey>,
+        clock: C,
+    ) -> std::io::Result<JoinHandle<()>> {
+        let socket = UdpSocket::server(config.addr).await?;
+        if let Some(broadcast) = &config.broadcast {
+            socket.enable_broadcast(broadcast.ttl)?;
+        }
+        info!(addr = ?config.addr, timestamping = ?socket.timestamping_mode(), "serving time");
+
+        let handle = tokio::spawn(async move {
+            let mut process = ServerTask {
+                socket,
+                system_snapshots,
+                system_config,
+                keyset,
+                require_nts: config.require_nts,
+                keys,
+                broadcast: config.broadcast,
+                broadcast_key,
+                clock,
+            };
+
+            process.serve().await
+        });
+
+        Ok(handle)
+    }
+
+    async fn serve(&mut self) {
+        let mut broadcast_timer = self.broadcast.as_ref().map(|broadcast| {
+            let mut timer = tokio::time::interval(Duration::from_secs(broadcast.interval));
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            timer
+        });
+
+        loop {
+            // Requests may contain extension fields, such as those used by NTS.
+            // The buffer is large enough that these do not cause truncation warnings.
+            let mut buf = [0_u8; 1024];
+
+            tokio::select! {
+                result = self.socket.recv_from(&mut buf) => {
+                    if let Some((data, peer_addr, recv_timestamp)) = accept_request(result, &buf) {
+                        self.handle_request(data, peer_addr, recv_timestamp).await;
+                    }
+                }
+                () = next_broadcast(&mut broadcast_timer) => {
+                    self.send_broadcast().await;
+                }
+            }
+        }
+    }
+
+    async fn send_broadcast(&self) {
+        let broadcast = match &self.broadcast {
+            Some(broadcast) => broadcast,
+            None => return,
+        };
+
+        let now = match self.clock.now() {
+            Ok(now) => now,
+            Err(error) => panic!("`clock.now()` reported an error: {:?}", error),
+        };
+        let system = self.system_at(now).await;
+        let interval = PollInterval::at_least(Duration::from_secs(broadcast.interval));
+
+        let header = match NtpHeader::broadcast(&system, interval, &self.clock) {
+            Ok(header) => header,
+            Err(error) => {
+                // we cannot determine the transmit timestamp
+                panic!("`clock.now()` reported an error: {:?}", error)
+            }
+        };
+
+        let mut packet = NtpPacket::new(header);
+        if let Some(key) = &self.broadcast_key {
+            key.sign(&mut packet);
+        }
+
+        if let Err(error) = self
+            .socket
+            .send_to_untimestamped(&packet.serialize(), broadcast.addr)
+            .await
+        {
+            warn!(?error, addr = ?broadcast.addr, "broadcast could not be sent");
+        }
+    }
+
+    async fn handle_request(
+        &self,
+        data: &[u8],
+        peer_addr: SocketAddr,
+        recv_timestamp: NtpTimestamp,
+    ) {
+        let keyset = match &self.keyset {
+            Some(keyset) => Some(keyset.read().await),
+            None => None,
+        };
+
+        let request = match &keyset {
+            Some(keyset) => match NtsServerRequest::decode(data, keyset) {
+                Ok(request) => request,
+                Err(error) => {
+                    debug!(?error, ?peer_addr, "ignoring malformed request");
+                    return;
+                }
+            },
+            None => match NtpPacket::deserialize(data) {
+                Ok(packet) => NtsServerRequest::Plain(packet.header),
+                Err(error) => {
+                    debug!(?error, ?peer_addr, "ignoring malformed request");
+                    return;
+                }
+            },
+        };
+
+        let packet = match &request {
+            NtsServerRequest::Plain(packet)
+            | NtsServerRequest::Authenticated(packet, _)
+            | NtsServerRequest::Rejected(packet, _) => *packet,
+        };
+
+        if !matches!(
+            packet.mode,
+            NtpAssociationMode::Client | NtpAssociationMode::SymmetricActive
+        ) {
+            // we answer client requests, and symmetric peers without keeping any state for them
+            trace!(mode = ?packet.mode, ?peer_addr, "ignoring request with unsupported mode");
+            return;
+        }
+
+        if self.require_nts && matches!(request, NtsServerRequest::Plain(_)) {
+            trace!(?peer_addr, "ignoring request without NTS");
+            return;
+        }
+
+        // a request with a MAC is answered with a MAC made with the same key
+        let key = match &request {
+            NtsServerRequest::Plain(_) => match self.request_key(data) {
+                Ok(key) => key,
+                Err(keyid) => {
+                    debug!(
+                        keyid,
+                        ?peer_addr,
+                        "ignoring request with a MAC we cannot verify"
+                    );
+                    return;
+                }
+            },
+            _ => None,
+        };
+
+        let system = self.system_at(recv_timestamp).await;
+
+        let response =
+            match NtpHeader::timestamp_response(&system, packet, recv_timestamp, &self.clock) {
+                Ok(response) => response,
+                Err(error) => {
+                    // we cannot determine the transmit timestamp
+                    panic!("`clock.now()` reported an error: {:?}", error)
+                }
+            };
+
+        let message = match (&request, &keyset) {
+            (NtsServerRequest::Authenticated(_, request), Some(keyset)) => {
+                request.encode_response(&response, keyset)
+            }
+            (NtsServerRequest::Rejected(_, request), _) => request.encode_nak(&response),
+            _ => {
+                let mut packet = NtpPacket::new(response);
+                if let Some(key) = key {
+                    key.sign(&mut packet);
+                }
+                packet.serialize()
+            }
+        };
+
+        if let Err(error) = self.socket.send_to_untimestamped(&message, peer_addr).await {
+            warn!(?error, ?peer_addr, "response could not be sent");
+        }
+    }
+
+    /// The state of the system as we serve it at `now`, see [`SystemSnapshot::at`]
+    async fn system_at(&self, now: NtpTimestamp) -> SystemSnapshot {
+        let frequency_tolerance = self.system_config.read().await.frequency_tolerance;
+        self.system_snapshots
+            .read()
+            .await
+            .at(now, frequency_tolerance)
+    }
+
+    /// The key of the MAC of a request that does not use NTS, if it has one. Fails with the key
+    /// id of a MAC that is made with a key we do not know, or that does not match the request.
+    fn request_key(&self, data: &[u8]) -> Result<Option<&SymmetricKey>, u32> {
+        let packet = match NtpPacket::deserialize(data) {
+            Ok(packet) => packet,
+            Err(_) => return Ok(None),
+        };
+
+        let keyid = match &packet.mac {
+            Some(mac) => mac.keyid,
+            None => return Ok(None),
+        };
+
+        match self.keys.get(&keyid) {
+            Some(key) if key.verify(&packet) => Ok(Some(key)),
+            _ => Err(keyid),
+        }
+    }
+}
+
+/// Wait until the next broadcast is due, forever when we do not broadcast
+async fn next_broadcast(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn accept_request(
+    result: Result<(usize, SocketAddr, Option<NtpTimestamp>), std::io::Error>,
+    buf: &[u8],
+) -> Option<(&[u8], SocketAddr, NtpTimestamp)> {
+    match result {
+        Ok((size, peer_addr, Some(recv_timestamp))) => {
+            // Messages of fewer than 48 bytes are skipped entirely
+            if size < 48 {
+                debug!(
+                    expected = 48,
+                    actual = size,
+                    ?peer_addr,
+                    "received request is too small"
+                );
+
+                None
+            } else {
+                Some((&buf[..size], peer_addr, recv_timestamp))
+            }
+        }
+        Ok((size, peer_addr, None)) => {
+            warn!(?size, ?peer_addr, "received a request without a timestamp");
+
+            None
+        }
+        Err(receive_error) => {
+            warn!(?receive_error, "could not receive request");
+
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_os_clock::UnixNtpClock;
+    use ntp_proto::{NtpDuration, NtpLeapIndicator, PollInterval, ReferenceId};
+    use tokio::sync::{mpsc, watch};
+
+    use crate::{
+        peer::NtpSource,
+        resolver::SystemResolver,
+        source::{self, MsgForSystem, PeerChannels, PeerId, ResetEpoch},
+    };
+
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    struct TestClock {}
+
+    const TEST_TIME: NtpTimestamp = NtpTimestamp::from_seconds_nanos_since_ntp_era(1000, 0);
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> std::result::Result<NtpTimestamp, Self::Error> {
+            Ok(TEST_TIME)
+        }
+
+        fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn update_clock(
+            &self,
+            _offset: NtpDuration,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+            _poll_interval: PollInterval,
+            _leap_status: NtpLeapIndicator,
+        ) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn set_tai(&self, _tai_offset: i32) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_responds() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let system = SystemSnapshot {
+            stratum: 2,
+            reference_id: ReferenceId::from_ip("127.0.0.3".parse().unwrap()),
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            ..Default::default()
+        };
+        let system_snapshots = Arc::new(RwLock::new(system));
+
+        let handle = ServerTask::spawn(
+            ServerConfig {
+                addr: "127.0.0.1:9000".parse().unwrap(),
+                require_nts: false,
+                broadcast: None,
+            },
+            system_snapshots,
+            Default::default(),
+            None,
+            HashMap::new(),
+            None,
+            TestClock {},
+        )
+        .await
+        .unwrap();
+
+        let socket = UdpSocket::new("127.0.0.1:9001", "127.0.0.1:9000")
+            .await
+            .unwrap();
+
+        // requests that are not in client mode are ignored
+        let mut request = NtpHeader::new();
+        request.mode = NtpAssociationMode::Server;
+        request.transmit_timestamp = NtpTimestamp::from_seconds_nanos_since_ntp_era(1, 0);
+        socket.send(&request.serialize()).await.unwrap();
+
+        let mut request = NtpHeader::new();
+        request.transmit_timestamp = NtpTimestamp::from_seconds_nanos_since_ntp_era(2, 0);
+        socket.send(&request.serialize()).await.unwrap();
+
+        let mut buf = [0; 48];
+        let (size, _) = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+
+        let response = NtpHeader::deserialize(&buf);
+        assert_eq!(response.mode, NtpAssociationMode::Server);
+        assert_eq!(response.stratum, system.stratum);
+        assert_eq!(response.reference_id, system.reference_id);
+        assert_eq!(response.origin_timestamp, request.transmit_timestamp);
+        assert_eq!(response.transmit_timestamp, TEST_TIME);
+
+        // symmetric peers are answered in passive mode
+        request.mode = NtpAssociationMode::SymmetricActive;
+        request.transmit_timestamp = NtpTimestamp::from_seconds_nanos_since_ntp_era(3, 0);
+        socket.send(&request.serialize()).await.unwrap();
+
+        let (size, _) = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+
+        let response = NtpHeader::deserialize(&buf);
+        assert_eq!(response.mode, NtpAssociationMode::SymmetricPassive);
+        assert_eq!(response.origin_timestamp, request.transmit_timestamp);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_broadcasts() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let system = SystemSnapshot {
+            stratum: 2,
+            reference_id: ReferenceId::from_ip("127.0.0.3".parse().unwrap()),
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            ..Default::default()
+        };
+        let system_snapshots = Arc::new(RwLock::new(system));
+        let key = SymmetricKey::new(1, ntp_proto::MacAlgorithm::Sha1, vec![0x42; 20]).unwrap();
+
+        let handle = ServerTask::spawn(
+            ServerConfig {
+                addr: "127.0.0.1:9040".parse().unwrap(),
+                require_nts: false,
+                broadcast: Some(BroadcastConfig {
+                    addr: "127.0.0.1:9041".parse().unwrap(),
+                    interval: 1,
+                    ttl: 1,
+                    key: Some(1),
+                }),
+            },
+            system_snapshots,
+            Default::default(),
+            None,
+            HashMap::new(),
+            Some(key.clone()),
+            TestClock {},
+        )
+        .await
+        .unwrap();
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:9041").await.unwrap();
+
+        for _ in 0..2 {
+            let mut buf = [0; 1024];
+            let (size, addr) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(addr, "127.0.0.1:9040".parse().unwrap());
+
+            let packet = NtpPacket::deserialize(&buf[..size]).unwrap();
+            assert!(key.verify(&packet));
+            assert_eq!(packet.header.mode, NtpAssociationMode::Broadcast);
+            assert_eq!(packet.header.stratum, system.stratum);
+            assert_eq!(packet.header.poll, PollInterval::MIN.as_log());
+            assert_eq!(packet.header.transmit_timestamp, TEST_TIME);
+        }
+
+        handle.abort();
+    }
+    #[tokio::test]
+    async fn test_server_keys() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let key = SymmetricKey::new(1, ntp_proto::MacAlgorithm::Sha1, vec![0x42; 20]).unwrap();
+        let unknown_key =
+            SymmetricKey::new(2, ntp_proto::MacAlgorithm::Sha1, vec![0x43; 20]).unwrap();
+
+        let handle = ServerTask::spawn(
+            ServerConfig {
+                addr: "127.0.0.1:9086".parse().unwrap(),
+                require_nts: false,
+                broadcast: None,
+            },
+            system_snapshots,
+            Default::default(),
+            None,
+            HashMap::from([(1, key.clone())]),
+            None,
+            TestClock {},
+        )
+        .await
+        .unwrap();
+
+        let socket = UdpSocket::new("127.0.0.1:9087", "127.0.0.1:9086")
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+
+        // requests with a MAC we cannot verify are ignored
+        let mut request = NtpPacket::new(NtpHeader::new());
+        request.header.transmit_timestamp = NtpTimestamp::from_seconds_nanos_since_ntp_era(1, 0);
+        unknown_key.sign(&mut request);
+        socket.send(&request.serialize()).await.unwrap();
+
+        let response =
+            tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buf)).await;
+        assert!(response.is_err());
+
+        // and other requests are answered with the same key
+        request.header.mode = NtpAssociationMode::SymmetricActive;
+        key.sign(&mut request);
+        socket.send(&request.serialize()).await.unwrap();
+
+        let (size, _) = socket.recv(&mut buf).await.unwrap();
+        let response = NtpPacket::deserialize(&buf[..size]).unwrap();
+        assert_eq!(response.header.mode, NtpAssociationMode::SymmetricPassive);
+        assert_eq!(
+            response.header.origin_timestamp,
+            request.header.transmit_timestamp
+        );
+        assert!(key.verify(&response));
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_symmetric_peers_with_key() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let system = SystemSnapshot {
+            stratum: 2,
+            reference_id: ReferenceId::from_ip("127.0.0.3".parse().unwrap()),
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            ..Default::default()
+        };
+        let key = SymmetricKey::new(1, ntp_proto::MacAlgorithm::Sha1, vec![0x42; 20]).unwrap();
+
+        let server = ServerTask::spawn(
+            ServerConfig {
+                addr: "127.0.0.1:9088".parse().unwrap(),
+                require_nts: false,
+                broadcast: None,
+            },
+            Arc::new(RwLock::new(system)),
+            Default::default(),
+            None,
+            HashMap::from([(1, key.clone())]),
+            None,
+            UnixNtpClock::new(),
+        )
+        .await
+        .unwrap();
+
+        let (msg_for_system_sender, mut msg_for_system_receiver) = mpsc::channel(1);
+        let (_reset_send, reset) = watch::channel(ResetEpoch::default());
+        let peer = source::spawn(
+            PeerId(0),
+            NtpSource::symmetric(
+                "127.0.0.1:9088".into(),
+                Some(key),
+                Arc::new(SystemResolver),
+                UnixNtpClock::new(),
+            ),
+            PeerChannels {
+                msg_for_system_sender,
+                system_snapshots: Default::default(),
+                system_config: Default::default(),
+                reset,
+            },
+        );
+
+        // the peer accepts the authenticated passive response of the server
+        loop {
+            match msg_for_system_receiver.recv().await.unwrap() {
+                MsgForSystem::NewMeasurement(_, _, snapshot, _) => {
+                    assert_eq!(snapshot.stratum, system.stratum);
+                    break;
+                }
+                MsgForSystem::UpdatedSnapshot(_, _, _) => continue,
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        }
+
+        peer.abort();
+        server.abort();
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use ntp_proto::{PeerSnapshot, SystemConfig, SystemSnapshot};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::Instrument;
+
+/// Only messages from the current reset epoch are valid. The system's reset epoch is incremented
+/// (with wrapping addition) on every reset. Only after a reset does the peer update its reset
+/// epoch, thereby indicating to the system that the reset was successful and the peer's messages
+/// are valid measurements again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ResetEpoch(u64);
+
+impl ResetEpoch {
+    #[must_use]
+    pub const fn inc(mut self) -> Self {
+        self.0 = self.0.wrapping_add(1);
+
+        self
+    }
+}
+
+/// Identifies a peer for as long as the daemon runs. Ids are never reused, so that messages from a
+/// peer that was removed cannot be mistaken for those of a peer that was added later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct PeerId(pub(crate) u64);
+
+/// What the system may use the measurements of a source for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    /// Selecting and combining them with the measurements of other sources to steer the clock
+    Steer,
+    /// Only bounding the offset of our clock, because the source is too coarse to steer the clock
+    /// with, like a Roughtime server
+    CrossCheck,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MsgForSystem {
+    /// Received a Kiss-o'-Death and must demobilize
+    MustDemobilize(PeerId),
+    /// Received an acceptable packet and made a new peer snapshot, to be used as `Usage` says.
+    /// A new measurement should try to trigger a clock select
+    NewMeasurement(PeerId, ResetEpoch, PeerSnapshot, Usage),
+    /// A snapshot may have been updated, but this should not
+    /// trigger a clock select in System
+    UpdatedSnapshot(PeerId, ResetEpoch, PeerSnapshot),
+    /// The total number of received packets that could not be parsed
+    ParseFailures(PeerId, u64),
+}
+
+#[derive(Clone)]
+pub(crate) struct PeerChannels {
+    pub(crate) msg_for_system_sender: tokio::sync::mpsc::Sender<MsgForSystem>,
+    pub(crate) system_snapshots: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+    pub(crate) system_config: Arc<tokio::sync::RwLock<SystemConfig>>,
+    pub(crate) reset: watch::Receiver<ResetEpoch>,
+}
+
+/// Something that measures the offset of our clock, like an NTP server or a reference clock.
+///
+/// The system selects and combines the measurements of all sources, without knowing what kind of
+/// source they come from. A new kind of source only needs to implement this trait, and be spawned
+/// with [`spawn`].
+pub(crate) trait TimeSource: Send + 'static {
+    /// Produce measurements until the source demobilizes. The task running the source is aborted
+    /// when the source is removed, so the future must not rely on running to completion.
+    fn run(self, channel: SourceChannel) -> BoxFuture<'static, ()>;
+
+    /// What the system may use the measurements of this source for
+    fn usage(&self) -> Usage {
+        Usage::Steer
+    }
+}
+
+/// Run `source` in a task of its own, reporting to the system as the peer `id`
+pub(crate) fn spawn<S: TimeSource>(
+    id: PeerId,
+    source: S,
+    channels: PeerChannels,
+) -> JoinHandle<()> {
+    let channel = SourceChannel::new(id, source.usage(), channels);
+
+    tokio::spawn(
+        source
+            .run(channel)
+            .instrument(tracing::info_span!("source", ?id)),
+    )
+}
+
+/// The connection of a source to the system. It keeps track of the reset epoch, so sources only
+/// have to forget their measurements when [`SourceChannel::reset`] completes.
+pub(crate) struct SourceChannel {
+    id: PeerId,
+    usage: Usage,
+    channels: PeerChannels,
+
+    /// Number of resets that this source has performed
+    reset_epoch: ResetEpoch,
+}
+
+impl SourceChannel {
+    pub(crate) fn new(id: PeerId, usage: Usage, mut channels: PeerChannels) -> Self {
+        // Even though we currently always have reset_epoch start at
+        // the default value, we shouldn't rely on that.
+        let reset_epoch = *channels.reset.borrow_and_update();
+
+        SourceChannel {
+            id,
+            usage,
+            channels,
+            reset_epoch,
+        }
+    }
+
+    async fn send(&self, msg: MsgForSystem) {
+        self.channels.msg_for_system_sender.send(msg).await.ok();
+    }
+
+    /// Pass a new measurement to the system, which then uses it as the usage of the source says
+    pub(crate) async fn measurement(&self, snapshot: PeerSnapshot) {
+        self.send(MsgForSystem::NewMeasurement(
+            self.id,
+            self.reset_epoch,
+            snapshot,
+            self.usage,
+        ))
+        .await
+    }
+
+    /// Let the system know the state of the source without a new measurement, e.g. when the source
+    /// did not respond to a poll
+    pub(crate) async fn snapshot(&self, snapshot: PeerSnapshot) {
+        self.send(MsgForSystem::UpdatedSnapshot(
+            self.id,
+            self.reset_epoch,
+            snapshot,
+        ))
+        .await
+    }
+
+    /// Let the system know how many received packets could not be parsed so far
+    pub(crate) async fn parse_failures(&self, count: u64) {
+        self.send(MsgForSystem::ParseFailures(self.id, count)).await
+    }
+
+    /// Tell the system that this source must not be used anymore
+    pub(crate) async fn demobilize(&self) {
+        self.send(MsgForSystem::MustDemobilize(self.id)).await
+    }
+
+    pub(crate) async fn system_snapshot(&self) -> SystemSnapshot {
+        *self.channels.system_snapshots.read().await
+    }
+
+    pub(crate) async fn system_config(&self) -> SystemConfig {
+        *self.channels.system_config.read().await
+    }
+
+    /// Completes when the system reset, e.g. because it stepped the clock. The source must then
+    /// forget its measurements, its next measurements have the new reset epoch.
+    ///
+    /// This is cancel safe, and never completes once the system is gone.
+    pub(crate) async fn reset(&mut self) {
+        match self.channels.reset.changed().await {
+            Ok(()) => self.reset_epoch = *self.channels.reset.borrow_and_update(),
+            Err(_) => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{NtpInstant, Peer, ReferenceId};
+    use tokio::sync::{mpsc, RwLock};
+
+    use super::*;
+
+    /// Reports a snapshot whenever the system reset
+    struct TestSource {
+        snapshot: PeerSnapshot,
+    }
+
+    impl TimeSource for TestSource {
+        fn run(self, mut channel: SourceChannel) -> BoxFuture<'static, ()> {
+            Box::pin(async move {
+                channel.measurement(self.snapshot).await;
+
+                loop {
+                    channel.reset().await;
+                    channel.snapshot(self.snapshot).await;
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_source_channel() {
+        let epoch = ResetEpoch::default().inc();
+        let (msg_for_system_sender, mut msg_for_system_receiver) = mpsc::channel(1);
+        let (reset_send, reset) = watch::channel(epoch);
+
+        let peer = Peer::new(ReferenceId::NONE, ReferenceId::NONE, NtpInstant::now());
+        let snapshot = PeerSnapshot::from_peer(&peer);
+        let handle = spawn(
+            PeerId(4),
+            TestSource { snapshot },
+            PeerChannels {
+                msg_for_system_sender,
+                system_snapshots: Arc::new(RwLock::new(SystemSnapshot::default())),
+                system_config: Arc::new(RwLock::new(SystemConfig::default())),
+                reset,
+            },
+        );
+
+        // the source starts in the epoch of the system
+        match msg_for_system_receiver.recv().await.unwrap() {
+            MsgForSystem::NewMeasurement(PeerId(4), peer_epoch, _, Usage::Steer) => {
+                assert_eq!(peer_epoch, epoch)
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        reset_send.send(epoch.inc()).unwrap();
+        match msg_for_system_receiver.recv().await.unwrap() {
+            MsgForSystem::UpdatedSnapshot(PeerId(4), peer_epoch, _) => {
+                assert_eq!(peer_epoch, epoch.inc())
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // without a system, the source stays quiet instead of spinning
+        drop(reset_send);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert!(msg_for_system_receiver.try_recv().is_err());
+
+        handle.abort();
+    }
+}
@@ -1,25 +1,66 @@
 use crate::{
-    config::PeerConfig,
-    peer::{MsgForSystem, PeerChannels, PeerIndex, PeerTask, ResetEpoch},
+    broadcast::{self, BroadcastSource},
+    config::{Config, PeerConfig, PeerHostMode, RefclockConfig, RoughtimeConfig},
+    drift, keyexchange, keyset, manycast,
+    peer::NtpSource,
+    refclock::{Refclock, RefclockSource},
+    resolver::{Resolver, SystemResolver},
+    roughtime::{CrossCheck, RoughtimeSource},
+    server::ServerTask,
+    source::{self, MsgForSystem, PeerChannels, PeerId, ResetEpoch, TimeSource, Usage},
 };
+use futures::{stream::FuturesUnordered, StreamExt};
 use ntp_os_clock::UnixNtpClock;
 use ntp_proto::{
-    ClockController, ClockUpdateResult, FilterAndCombine, FrequencyTolerance, NtpClock,
-    NtpDuration, NtpInstant, PeerSnapshot, PeerStatistics, PollInterval, Reach, ReferenceId,
-    SystemConfig, SystemSnapshot,
+    ClockController, ClockUpdateResult, FilterAndCombine, FrequencyTolerance, LeapSecondsList,
+    NtpClock, NtpDuration, NtpInstant, NtpLeapIndicator, NtpTimestamp, PeerSnapshot,
+    PeerStatistics, PollInterval, Reach, ReferenceId, SymmetricKey, SystemConfig, SystemSnapshot,
 };
 use serde::{Deserialize, Serialize};
-use tracing::info;
-
-use std::sync::Arc;
-use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, warn};
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::Instant,
+};
 
-/// Spawn the NTP daemon
+/// Consecutive polls without any response after which a pool server is replaced
+const POOL_MAX_UNREACHABLE_POLLS: u32 = 8;
+/// Consecutive clock selections in which a pool server is a falseticker before it is replaced
+const POOL_MAX_FALSETICKER_COUNT: u32 = 3;
+/// Time between DNS lookups for pools that have fewer servers than configured
+const POOL_LOOKUP_INTERVAL: Duration = Duration::from_secs(60);
+/// Time after which a rejected pool server may be used again
+const POOL_REJECT_DURATION: Duration = Duration::from_secs(24 * 3600);
+/// Time between writes of the drift file
+const DRIFT_FILE_INTERVAL: Duration = Duration::from_secs(3600);
+/// Time without a usable peer after which we fall back to orphan mode or the local clock
+const FALLBACK_WAIT: Duration = Duration::from_secs(300);
+/// Interval at which the local clock counts as updated again while we serve its time as a
+/// fallback, so that our clients keep seeing it as synchronized
+const FALLBACK_REFRESH: Duration = Duration::from_secs(64);
+/// Time before the expiration of the leap seconds file at which we warn that it must be updated
+const LEAP_SECONDS_EXPIRY_WARNING: Duration = Duration::from_secs(14 * 24 * 3600);
+
+/// Spawn the NTP daemon for `config`, of which the system part is shared as `system_config`, so
+/// that it can be changed at runtime. Peers are added and removed at runtime with
+/// `peer_commands`. The daemon stops after saving its state when `shutdown` is notified.
 pub async fn spawn(
-    config: Arc<tokio::sync::RwLock<SystemConfig>>,
-    peer_configs: &[PeerConfig],
+    config: &Config,
+    system_config: Arc<tokio::sync::RwLock<SystemConfig>>,
+    leap_seconds: watch::Receiver<Option<LeapSecondsList>>,
     peers_rwlock: Arc<tokio::sync::RwLock<Peers>>,
+    peer_commands: mpsc::Receiver<PeerCommand>,
     system_rwlock: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+    shutdown: watch::Receiver<()>,
 ) -> std::io::Result<()> {
     // send the reset signal to all peers
     let reset_epoch: ResetEpoch = ResetEpoch::default();
@@ -28,62 +69,917 @@ pub async fn spawn(
     // receive peer snapshots from all peers
     let (msg_for_system_tx, msg_for_system_rx) = mpsc::channel::<MsgForSystem>(32);
 
-    for (index, peer_config) in peer_configs.iter().enumerate() {
-        let channels = PeerChannels {
-            msg_for_system_sender: msg_for_system_tx.clone(),
-            system_snapshots: system_rwlock.clone(),
-            reset: reset_rx.clone(),
-            system_config: config.clone(),
+    *peers_rwlock.write().await = Peers::default();
+
+    let channels = PeerChannels {
+        msg_for_system_sender: msg_for_system_tx,
+        system_snapshots: system_rwlock.clone(),
+        reset: reset_rx,
+        system_config: system_config.clone(),
+    };
+    let mut spawner = PeerSpawner::new(
+        channels,
+        config.keys.clone(),
+        Arc::new(SystemResolver),
+        peers_rwlock.clone(),
+        peer_commands,
+    );
+
+    for peer_config in &config.peers {
+        spawner.spawn(peer_config).await?;
+    }
+
+    for refclock_config in &config.refclocks {
+        spawner.spawn_refclock(refclock_config).await?;
+    }
+
+    for roughtime_config in &config.roughtime {
+        spawner.spawn_roughtime(roughtime_config).await;
+    }
+
+    // tasks that must keep running for as long as the daemon does
+    let mut services = Vec::new();
+
+    let keyset = match &config.nts_ke {
+        Some(nts_ke_config) => {
+            let (keyset, rotation) = keyset::spawn(
+                nts_ke_config.key_storage.clone(),
+                nts_ke_config.key_rotation_interval,
+            )
+            .await?;
+            services.push(rotation);
+            services.push(keyexchange::spawn_server(nts_ke_config.clone(), keyset.clone()).await?);
+            Some(keyset)
+        }
+        None => None,
+    };
+
+    for server_config in &config.servers {
+        let broadcast_key = match server_config
+            .broadcast
+            .as_ref()
+            .and_then(|broadcast| broadcast.key)
+        {
+            Some(id) => match config.keys.get(&id) {
+                Some(key) => Some(key.clone()),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("key {} is not defined", id),
+                    ))
+                }
+            },
+            None => None,
         };
 
-        PeerTask::spawn(
-            PeerIndex { index },
-            &peer_config.addr,
+        let server = ServerTask::spawn(
+            server_config.clone(),
+            system_rwlock.clone(),
+            system_config.clone(),
+            keyset.clone(),
+            config.keys.clone(),
+            broadcast_key,
             UnixNtpClock::new(),
-            channels,
         )
         .await?;
+        services.push(server);
     }
 
-    let peers = Peers::new(peer_configs.len());
-
-    {
-        let mut writer = peers_rwlock.write().await;
-        *writer = peers;
-    }
+    let inputs = SystemInputs {
+        msg_for_system: msg_for_system_rx,
+        services,
+        leap_seconds,
+        shutdown,
+    };
 
     run(
-        config,
-        reset_epoch,
-        system_rwlock,
-        msg_for_system_rx,
+        spawner,
+        inputs,
         reset_tx,
-        peers_rwlock,
+        config.drift_file.clone(),
         UnixNtpClock::new(),
     )
     .await
 }
 
+/// Everything the clock steering task listens to, apart from the commands for its spawner
+struct SystemInputs {
+    /// The measurements and other news of the peers
+    msg_for_system: mpsc::Receiver<MsgForSystem>,
+    /// Tasks such as the servers, which must keep running for as long as the daemon does
+    services: Vec<JoinHandle<()>>,
+    /// The contents of the leap seconds file, whenever it is read again
+    leap_seconds: watch::Receiver<Option<LeapSecondsList>>,
+    /// Notified when the daemon must stop
+    shutdown: watch::Receiver<()>,
+}
+
+/// Changes to the set of peers, requested over the configuration socket
+#[derive(Debug)]
+pub enum PeerCommand {
+    Add(PeerConfig),
+    Remove(PeerId),
+    /// Answered once all earlier commands are handled
+    Sync(oneshot::Sender<()>),
+    /// The servers that a manycast discovery found, sent by the daemon to itself
+    Discovered(Discovery),
+}
+
+/// The result of looking for the servers of a manycast peer, which runs in a task of its own
+#[derive(Debug)]
+pub struct Discovery {
+    pool: PeerId,
+    addrs: std::io::Result<Vec<SocketAddr>>,
+}
+
+/// A pool, together with the servers from it that we no longer want to use. The servers of a
+/// manycast peer are discovered in its multicast group, and are otherwise handled like a pool.
+struct Pool {
+    config: PeerConfig,
+    /// Servers that were demobilized, because they were bad or asked us to go away, with the
+    /// moment until which they are not used again
+    rejected: HashMap<SocketAddr, Instant>,
+    last_lookup: Option<Instant>,
+    /// Whether a manycast discovery is running
+    discovering: bool,
+}
+
+/// A peer task that uses a server from a pool
+struct PoolMember {
+    pool: PeerId,
+    addr: SocketAddr,
+    unreachable_polls: u32,
+    falseticker_count: u32,
+}
+
+/// Starts the peer tasks, adds and removes peers on request, and keeps replacing the servers of
+/// pools that stop working
+struct PeerSpawner {
+    channels: PeerChannels,
+    keys: HashMap<u32, SymmetricKey>,
+    resolver: Arc<dyn Resolver>,
+    peers: Arc<tokio::sync::RwLock<Peers>>,
+    commands: mpsc::Receiver<PeerCommand>,
+    /// Commands from our own tasks, such as the results of manycast discoveries
+    own_commands: mpsc::Receiver<PeerCommand>,
+    own_commands_tx: mpsc::Sender<PeerCommand>,
+    tasks: HashMap<PeerId, JoinHandle<()>>,
+    /// The pools by their own id, which is listed and removed like the id of a peer
+    pools: HashMap<PeerId, Pool>,
+    members: HashMap<PeerId, PoolMember>,
+}
+
+impl PeerSpawner {
+    fn new(
+        channels: PeerChannels,
+        keys: HashMap<u32, SymmetricKey>,
+        resolver: Arc<dyn Resolver>,
+        peers: Arc<tokio::sync::RwLock<Peers>>,
+        commands: mpsc::Receiver<PeerCommand>,
+    ) -> Self {
+        let (own_commands_tx, own_commands) = mpsc::channel(8);
+
+        PeerSpawner {
+            channels,
+            keys,
+            resolver,
+            peers,
+            commands,
+            own_commands,
+            own_commands_tx,
+            tasks: HashMap::new(),
+            pools: HashMap::new(),
+            members: HashMap::new(),
+        }
+    }
+
+    fn key(&self, peer_config: &PeerConfig) -> std::io::Result<Option<SymmetricKey>> {
+        match peer_config.key {
+            Some(id) => match self.keys.get(&id) {
+                Some(key) => Ok(Some(key.clone())),
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("key {} is not defined", id),
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Start the task of a source, which is listed as `name`
+    async fn spawn_source<S: TimeSource>(&mut self, name: String, source: S) -> PeerId {
+        let id = self.peers.write().await.add_source(name, source.usage());
+        self.start(id, source);
+
+        id
+    }
+
+    /// Start the task of a source that already has the id `id`
+    fn start<S: TimeSource>(&mut self, id: PeerId, source: S) {
+        let handle = source::spawn(id, source, self.channels.clone());
+        self.tasks.insert(id, handle);
+    }
+
+    async fn spawn(&mut self, peer_config: &PeerConfig) -> std::io::Result<()> {
+        let addr = peer_config.addr.clone();
+
+        match peer_config.mode {
+            PeerHostMode::Server => {
+                let key = self.key(peer_config)?;
+                let source = NtpSource::client(
+                    addr.clone(),
+                    key,
+                    self.resolver.clone(),
+                    UnixNtpClock::new(),
+                );
+                self.spawn_source(addr, source).await;
+            }
+            PeerHostMode::Symmetric => {
+                let key = self.key(peer_config)?;
+                let source = NtpSource::symmetric(
+                    addr.clone(),
+                    key,
+                    self.resolver.clone(),
+                    UnixNtpClock::new(),
+                );
+                self.spawn_source(addr, source).await;
+            }
+            PeerHostMode::Nts => {
+                let tls_config =
+                    keyexchange::client_config(peer_config.certificate_authority.as_deref())?;
+                let source = NtpSource::nts(
+                    addr.clone(),
+                    tls_config,
+                    self.resolver.clone(),
+                    UnixNtpClock::new(),
+                );
+                self.spawn_source(addr, source).await;
+            }
+            PeerHostMode::Pool | PeerHostMode::Manycast => {
+                // fail early on configuration errors, rather than on every lookup
+                self.key(peer_config)?;
+
+                let id = self.peers.write().await.add_pool(addr);
+                self.pools.insert(
+                    id,
+                    Pool {
+                        config: peer_config.clone(),
+                        rejected: HashMap::new(),
+                        last_lookup: None,
+                        discovering: false,
+                    },
+                );
+                self.fill_pool(id).await;
+            }
+            PeerHostMode::Broadcast => {
+                let key = self.key(peer_config)?;
+                let addr = peer_config.addr.parse().map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "broadcast peers must listen on an ip address",
+                    )
+                })?;
+                let socket = broadcast::listen(addr).await?;
+
+                let source = BroadcastSource::new(socket, key, UnixNtpClock::new());
+                self.spawn_source(peer_config.addr.clone(), source).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn spawn_refclock(&mut self, refclock_config: &RefclockConfig) -> std::io::Result<()> {
+        let source = RefclockSource::open(refclock_config)?;
+
+        let refclock = Refclock::new(refclock_config, source, UnixNtpClock::new());
+        self.spawn_source(refclock_config.to_string(), refclock)
+            .await;
+
+        Ok(())
+    }
+
+    async fn spawn_roughtime(&mut self, roughtime_config: &RoughtimeConfig) {
+        let source =
+            RoughtimeSource::new(roughtime_config, self.resolver.clone(), UnixNtpClock::new());
+        self.spawn_source(roughtime_config.to_string(), source)
+            .await;
+    }
+
+    /// Stop the task of a peer, which also closes its socket, and forget about the peer.
+    /// Returns whether the peer existed.
+    async fn remove(&mut self, id: PeerId) -> bool {
+        self.members.remove(&id);
+
+        if let Some(handle) = self.tasks.remove(&id) {
+            handle.abort();
+        }
+
+        self.peers.write().await.remove(id)
+    }
+
+    /// Stop using a pool, together with all of its servers
+    async fn remove_pool(&mut self, id: PeerId) {
+        self.pools.remove(&id);
+
+        let members: Vec<_> = self
+            .members
+            .iter()
+            .filter(|(_, member)| member.pool == id)
+            .map(|(member_id, _)| *member_id)
+            .collect();
+        for member_id in members {
+            self.remove(member_id).await;
+        }
+
+        self.peers.write().await.remove(id);
+    }
+
+    async fn handle_command(&mut self, command: PeerCommand) {
+        match command {
+            PeerCommand::Add(peer_config) => match self.spawn(&peer_config).await {
+                Ok(()) => info!(addr = ?peer_config.addr, "added peer"),
+                Err(error) => warn!(?error, addr = ?peer_config.addr, "could not add peer"),
+            },
+            PeerCommand::Remove(id) if self.pools.contains_key(&id) => {
+                self.remove_pool(id).await;
+                info!(?id, "removed pool and its servers");
+            }
+            // a server of a pool is not rejected, only the operator changed their mind about it.
+            // The pool may pick it again on its next lookup.
+            PeerCommand::Remove(id) => {
+                if self.remove(id).await {
+                    info!(?id, "removed peer");
+                } else {
+                    warn!(?id, "cannot remove a peer that does not exist");
+                }
+            }
+            PeerCommand::Sync(done) => {
+                done.send(()).ok();
+            }
+            PeerCommand::Discovered(discovery) => {
+                // the pool may have been removed while the discovery ran
+                if let Some(pool) = self.pools.get_mut(&discovery.pool) {
+                    pool.discovering = false;
+                    self.use_servers(discovery.pool, discovery.addrs).await;
+                }
+            }
+        }
+    }
+
+    fn member_count(&self, pool: PeerId) -> usize {
+        self.members.values().filter(|m| m.pool == pool).count()
+    }
+
+    /// The addresses of the servers of a pool that we use
+    fn in_use(&self, pool: PeerId) -> Vec<SocketAddr> {
+        self.members
+            .values()
+            .filter(|m| m.pool == pool)
+            .map(|m| m.addr)
+            .collect()
+    }
+
+    /// Look up the servers of a pool, and spawn peers until it has as many as configured. The
+    /// servers of a manycast peer are used once the discovery that this starts completes.
+    async fn fill_pool(&mut self, pool_id: PeerId) {
+        let pool = match self.pools.get_mut(&pool_id) {
+            Some(pool) => pool,
+            None => return,
+        };
+        let now = Instant::now();
+        pool.last_lookup = Some(now);
+
+        // servers may have been fixed or have been replaced by other machines in the meantime
+        pool.rejected.retain(|_, until| *until > now);
+
+        let config = pool.config.clone();
+        let in_use = self.in_use(pool_id);
+
+        if in_use.len() >= config.count {
+            return;
+        }
+
+        if config.mode == PeerHostMode::Manycast {
+            self.discover(pool_id, &in_use);
+        } else {
+            let addrs = self.resolver.resolve(&config.addr).await;
+            self.use_servers(pool_id, addrs).await;
+        }
+    }
+
+    /// Spawn peers for the servers at `addrs` that are not in use or rejected yet, until the
+    /// pool has as many as configured
+    async fn use_servers(&mut self, pool_id: PeerId, addrs: std::io::Result<Vec<SocketAddr>>) {
+        let config = self.pools[&pool_id].config.clone();
+        let mut in_use = self.in_use(pool_id);
+
+        let addrs = match addrs {
+            Ok(addrs) => addrs,
+            Err(error) => {
+                warn!(?error, pool = ?config.addr, "could not resolve pool");
+                return;
+            }
+        };
+
+        let key = match self.key(&config) {
+            Ok(key) => key,
+            Err(error) => {
+                warn!(?error, pool = ?config.addr, "could not spawn pool peers");
+                return;
+            }
+        };
+
+        for addr in addrs {
+            if in_use.len() >= config.count {
+                break;
+            }
+
+            if in_use.contains(&addr) || self.pools[&pool_id].rejected.contains_key(&addr) {
+                continue;
+            }
+
+            let source = NtpSource::client(
+                addr.to_string(),
+                key.clone(),
+                self.resolver.clone(),
+                UnixNtpClock::new(),
+            );
+            let id = self
+                .peers
+                .write()
+                .await
+                .add_pool_server(addr.to_string(), pool_id);
+            self.start(id, source);
+
+            info!(pool = ?config.addr, ?addr, "using server from pool");
+            in_use.push(addr);
+            self.members.insert(
+                id,
+                PoolMember {
+                    pool: pool_id,
+                    addr,
+                    unreachable_polls: 0,
+                    falseticker_count: 0,
+                },
+            );
+        }
+
+        if in_use.len() < config.count {
+            info!(
+                pool = ?config.addr,
+                servers = in_use.len(),
+                wanted = config.count,
+                "pool does not have enough usable servers yet"
+            );
+        }
+    }
+
+    /// Start looking for the servers of a manycast peer that are not in use or rejected yet. The
+    /// discovery takes a few seconds, so it runs in a task of its own that sends the servers it
+    /// found back to us as a [`PeerCommand::Discovered`].
+    fn discover(&mut self, pool_id: PeerId, in_use: &[SocketAddr]) {
+        let pool = &self.pools[&pool_id];
+        if pool.discovering {
+            return;
+        }
+
+        let config = &pool.config;
+        let group = match config.addr.parse() {
+            Ok(group) => group,
+            Err(_) => {
+                warn!(pool = ?config.addr, "manycast peers must use a multicast group as address");
+                return;
+            }
+        };
+
+        let key = match self.key(config) {
+            Ok(key) => key,
+            Err(error) => {
+                warn!(?error, pool = ?config.addr, "could not spawn pool peers");
+                return;
+            }
+        };
+
+        let mut exclude: HashSet<_> = pool.rejected.keys().copied().collect();
+        exclude.extend(in_use);
+        let wanted = config.count - in_use.len();
+
+        let commands = self.own_commands_tx.clone();
+        tokio::spawn(async move {
+            let addrs =
+                manycast::discover(group, wanted, &exclude, key.as_ref(), &UnixNtpClock::new())
+                    .await;
+
+            let discovery = Discovery {
+                pool: pool_id,
+                addrs,
+            };
+            commands.send(PeerCommand::Discovered(discovery)).await.ok();
+        });
+
+        if let Some(pool) = self.pools.get_mut(&pool_id) {
+            pool.discovering = true;
+        }
+    }
+
+    /// Retry the lookups of pools that are short of servers
+    async fn fill_pools(&mut self) {
+        let now = Instant::now();
+
+        let pool_ids: Vec<_> = self.pools.keys().copied().collect();
+        for pool_id in pool_ids {
+            let pool = &self.pools[&pool_id];
+            let due = match pool.last_lookup {
+                Some(last) => now >= last + POOL_LOOKUP_INTERVAL,
+                None => true,
+            };
+
+            if due && self.member_count(pool_id) < pool.config.count {
+                self.fill_pool(pool_id).await;
+            }
+        }
+    }
+
+    /// The moment a pool that is short of servers is due for another lookup, if any
+    fn next_lookup(&self) -> Option<Instant> {
+        self.pools
+            .iter()
+            .filter(|(id, pool)| self.member_count(**id) < pool.config.count)
+            .map(|(_, pool)| {
+                pool.last_lookup
+                    .map_or_else(Instant::now, |last| last + POOL_LOOKUP_INTERVAL)
+            })
+            .min()
+    }
+
+    /// Stop using a pool server, and look for a replacement
+    async fn replace(&mut self, id: PeerId) {
+        if let Some(member) = self.members.remove(&id) {
+            self.remove(id).await;
+
+            if let Some(pool) = self.pools.get_mut(&member.pool) {
+                let until = Instant::now() + POOL_REJECT_DURATION;
+                pool.rejected.insert(member.addr, until);
+            }
+            self.fill_pool(member.pool).await;
+        }
+    }
+
+    /// Keep track of the health of pool servers
+    async fn handle_message(&mut self, msg: &MsgForSystem) {
+        let (id, reachable) = match msg {
+            MsgForSystem::MustDemobilize(id) => {
+                if let Some(member) = self.members.get(id) {
+                    warn!(addr = ?member.addr, "pool server demobilized us, replacing it");
+                    self.replace(*id).await;
+                }
+                return;
+            }
+            MsgForSystem::NewMeasurement(id, _, _, _) => (id, true),
+            MsgForSystem::UpdatedSnapshot(id, _, snapshot) => (id, snapshot.reach.is_reachable()),
+            MsgForSystem::ParseFailures(_, _) => return,
+        };
+
+        let member = match self.members.get_mut(id) {
+            Some(member) => member,
+            None => return,
+        };
+
+        if reachable {
+            member.unreachable_polls = 0;
+        } else {
+            member.unreachable_polls += 1;
+
+            if member.unreachable_polls >= POOL_MAX_UNREACHABLE_POLLS {
+                warn!(addr = ?member.addr, "pool server is unreachable, replacing it");
+                self.replace(*id).await;
+            }
+        }
+    }
+
+    /// Replace pool servers that keep disagreeing with the majority of our peers. `ids` are the
+    /// ids of the peers that took part in the clock selection.
+    async fn handle_falsetickers(&mut self, ids: &[PeerId], falsetickers: &[usize]) {
+        let mut replace = Vec::new();
+
+        for (position, id) in ids.iter().enumerate() {
+            if let Some(member) = self.members.get_mut(id) {
+                if falsetickers.contains(&position) {
+                    member.falseticker_count += 1;
+
+                    if member.falseticker_count >= POOL_MAX_FALSETICKER_COUNT {
+                        warn!(addr = ?member.addr, "pool server is a falseticker, replacing it");
+                        replace.push(*id);
+                    }
+                } else {
+                    member.falseticker_count = 0;
+                }
+            }
+        }
+
+        for id in replace {
+            self.replace(id).await;
+        }
+    }
+}
+
+/// Completes at `deadline`, or never when there is none
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Decides which leap second to announce. While the leap seconds file is current it is
+/// authoritative, otherwise we follow the majority of the peers that survived clock selection.
+struct LeapSeconds {
+    list: Option<LeapSecondsList>,
+    expires_soon: bool,
+    expired: bool,
+    tai_offset: Option<i32>,
+}
+
+impl LeapSeconds {
+    fn new(list: Option<LeapSecondsList>) -> Self {
+        LeapSeconds {
+            list,
+            expires_soon: false,
+            expired: false,
+            tai_offset: None,
+        }
+    }
+
+    /// Use a list read again from an updated leap seconds file
+    fn replace(&mut self, list: Option<LeapSecondsList>) {
+        *self = LeapSeconds {
+            tai_offset: self.tai_offset,
+            ..LeapSeconds::new(list)
+        };
+    }
+
+    fn leap_indicator<C: NtpClock>(
+        &mut self,
+        controller: &ClockController<C>,
+        vote: NtpLeapIndicator,
+    ) -> NtpLeapIndicator {
+        let list = match &self.list {
+            Some(list) => list,
+            None => return vote,
+        };
+
+        let now = match controller.now() {
+            Ok(now) => now,
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "could not read the clock to check the leap seconds file"
+                );
+                return vote;
+            }
+        };
+
+        if list.is_expired(now) {
+            if !self.expired {
+                warn!("the leap seconds file has expired, following the leap indicator of the peers instead");
+                self.expired = true;
+            }
+            return vote;
+        }
+
+        let remaining = list.expires() - now;
+        if !self.expires_soon
+            && remaining < NtpDuration::from_system_duration(LEAP_SECONDS_EXPIRY_WARNING)
+        {
+            warn!(
+                expires_in_days = (remaining.to_seconds() / 86400.0) as u64,
+                "the leap seconds file expires soon, it should be updated"
+            );
+            self.expires_soon = true;
+        }
+
+        if let Some(tai_offset) = list.tai_offset(now) {
+            if self.tai_offset != Some(tai_offset) {
+                // only try once, so a clock without TAI support does not flood the log
+                self.tai_offset = Some(tai_offset);
+                match controller.set_tai(tai_offset) {
+                    Ok(()) => info!(tai_offset, "set the TAI offset"),
+                    Err(error) => warn!(?error, "could not set the TAI offset"),
+                }
+            }
+        }
+
+        let leap_indicator = list.leap_indicator(now);
+        if leap_indicator != vote {
+            debug!(
+                ?leap_indicator,
+                ?vote,
+                "the peers disagree with the leap seconds file, using the file"
+            );
+        }
+
+        leap_indicator
+    }
+}
+
+/// Write the frequency of the clock to the drift file. Returns false when the frequency has not
+/// been measured yet.
+async fn store_frequency<C: NtpClock>(controller: &ClockController<C>, path: &Path) -> bool {
+    match controller.frequency() {
+        Ok(Some(frequency)) => {
+            if let Err(error) = drift::store(path, frequency).await {
+                warn!(?error, ?path, "could not write the drift file");
+            }
+            true
+        }
+        Ok(None) => false,
+        Err(error) => {
+            warn!(?error, "could not read the clock frequency");
+            false
+        }
+    }
+}
+
+/// Whether a peer may be used for synchronization in orphan mode. Peers below the orphan stratum
+/// have access to an upstream source and are always preferred. Without any such peer, the daemons
+/// in orphan mode follow the one with the lowest reference id, so that they all agree on the time.
+fn orphan_usable(snapshot: &PeerSnapshot, orphan_stratum: u8, upstream: bool) -> bool {
+    match snapshot.stratum.cmp(&orphan_stratum) {
+        std::cmp::Ordering::Less => true,
+        std::cmp::Ordering::Equal => !upstream && snapshot.peer_id < snapshot.our_id,
+        // such peers are (indirectly) synchronized to an orphan, following them could form a loop
+        std::cmp::Ordering::Greater => false,
+    }
+}
+
+/// Tracks when we were last synchronized, to serve our own time once no peer has been usable for
+/// a while and orphan mode or the local clock is configured.
+struct Fallback {
+    last_sync: Instant,
+    active: bool,
+    last_refresh: Instant,
+}
+
+impl Fallback {
+    fn new() -> Self {
+        Fallback {
+            last_sync: Instant::now(),
+            active: false,
+            last_refresh: Instant::now(),
+        }
+    }
+
+    /// When to activate the fallback, or to refresh it when it is active already
+    fn deadline(&self, config: &SystemConfig) -> Option<Instant> {
+        let configured = config.orphan_stratum.is_some() || config.local_stratum.is_some();
+
+        if !configured {
+            None
+        } else if self.active {
+            Some(self.last_refresh + FALLBACK_REFRESH)
+        } else {
+            Some(self.last_sync + FALLBACK_WAIT)
+        }
+    }
+
+    fn synchronized(&mut self) {
+        if self.active {
+            info!("synchronized to a peer again");
+        }
+
+        self.last_sync = Instant::now();
+        self.active = false;
+    }
+
+    /// Serve the time of our own clock, which is our reference at `now`
+    fn activate(&mut self, config: &SystemConfig, global: &mut SystemSnapshot, now: NtpTimestamp) {
+        let (stratum, reference_id) = match (config.orphan_stratum, config.local_stratum) {
+            (Some(stratum), _) => {
+                if !self.active {
+                    info!(stratum, "no usable peers, entering orphan mode");
+                }
+                (stratum, ReferenceId::from_ip(Ipv4Addr::LOCALHOST.into()))
+            }
+            (None, Some(stratum)) => {
+                if !self.active {
+                    info!(
+                        stratum,
+                        "no usable peers, serving the time of the local clock"
+                    );
+                }
+                (stratum, ReferenceId::LOCAL)
+            }
+            (None, None) => return,
+        };
+
+        self.active = true;
+        self.last_refresh = Instant::now();
+        global.reference_timestamp = now;
+
+        global.stratum = stratum;
+        global.reference_id = reference_id;
+        global.leap_indicator = NtpLeapIndicator::NoWarning;
+        global.root_delay = NtpDuration::ZERO;
+        global.root_dispersion = NtpDuration::ZERO;
+    }
+}
+
+/// Steer the clock with the measurements of the peers of `spawner`, until the daemon stops. The
+/// peers are told to forget their measurements with `reset_tx` whenever the clock steps.
 async fn run<C: NtpClock>(
-    config: Arc<tokio::sync::RwLock<SystemConfig>>,
-    mut reset_epoch: ResetEpoch,
-    global_system_snapshot: Arc<tokio::sync::RwLock<SystemSnapshot>>,
-    mut msg_for_system_rx: mpsc::Receiver<MsgForSystem>,
+    mut spawner: PeerSpawner,
+    inputs: SystemInputs,
     reset_tx: watch::Sender<ResetEpoch>,
-    peers_rwlock: Arc<tokio::sync::RwLock<Peers>>,
+    drift_file: Option<PathBuf>,
     clock: C,
 ) -> std::io::Result<()> {
-    let mut controller = ClockController::new(clock);
+    let SystemInputs {
+        msg_for_system: mut msg_for_system_rx,
+        services,
+        leap_seconds: mut leap_seconds_rx,
+        mut shutdown,
+    } = inputs;
+    let config = spawner.channels.system_config.clone();
+    let global_system_snapshot = spawner.channels.system_snapshots.clone();
+    let mut reset_epoch = *reset_tx.borrow();
+    let peers_rwlock = spawner.peers.clone();
+
+    let frequency = match &drift_file {
+        Some(path) => drift::load(path).await,
+        None => None,
+    };
+    let mut controller = match frequency {
+        Some(frequency) => {
+            info!(frequency, "using the frequency from the drift file");
+            ClockController::with_frequency(clock, frequency)
+        }
+        None => ClockController::new(clock),
+    };
+    let mut last_drift_store: Option<Instant> = None;
+
+    let mut leap_seconds = LeapSeconds::new(leap_seconds_rx.borrow_and_update().clone());
     let mut snapshots = Vec::with_capacity(peers_rwlock.read().await.len());
+    let mut snapshot_ids = Vec::with_capacity(snapshots.capacity());
+    let mut fallback = Fallback::new();
+    let mut services: FuturesUnordered<_> = services.into_iter().collect();
+
+    loop {
+        let next_lookup = spawner.next_lookup();
+        let fallback_deadline = fallback.deadline(&*config.read().await);
+
+        let msg_for_system = tokio::select! {
+            msg_for_system = msg_for_system_rx.recv() => match msg_for_system {
+                Some(msg_for_system) => msg_for_system,
+                None => break,
+            },
+            Some(command) = spawner.commands.recv() => {
+                spawner.handle_command(command).await;
+                continue;
+            }
+            Some(command) = spawner.own_commands.recv() => {
+                spawner.handle_command(command).await;
+                continue;
+            }
+            () = sleep_until(next_lookup) => {
+                spawner.fill_pools().await;
+                continue;
+            }
+            Ok(()) = leap_seconds_rx.changed() => {
+                leap_seconds.replace(leap_seconds_rx.borrow_and_update().clone());
+                continue;
+            }
+            () = sleep_until(fallback_deadline) => {
+                let config = *config.read().await;
+                let now = match controller.now() {
+                    Ok(now) => now,
+                    Err(error) => panic!("`clock.now()` reported an error: {:?}", error),
+                };
+                fallback.activate(&config, &mut *global_system_snapshot.write().await, now);
+                continue;
+            }
+            Some(result) = services.next() => {
+                // the server tasks and key rotation never stop, unless they panicked
+                return Err(std::io::Error::other(format!(
+                    "a task of the daemon stopped unexpectedly: {:?}",
+                    result
+                )));
+            }
+            Ok(()) = shutdown.changed() => {
+                if let Some(path) = &drift_file {
+                    store_frequency(&controller, path).await;
+                }
+                break;
+            }
+        };
 
-    while let Some(msg_for_system) = msg_for_system_rx.recv().await {
         let ntp_instant = NtpInstant::now();
         let system_poll = global_system_snapshot.read().await.poll_interval;
 
         // ensure the config is not updated in the middle of clock selection
         let config = *config.read().await;
 
-        let new = peers_rwlock.write().await.receive_update(
+        let usage = peers_rwlock.write().await.receive_update(
             msg_for_system,
             reset_epoch,
             ntp_instant,
@@ -92,15 +988,78 @@ async fn run<C: NtpClock>(
             system_poll,
         );
 
-        if let NewMeasurement::No = new {
-            continue;
+        spawner.handle_message(&msg_for_system).await;
+
+        match usage {
+            Some(Usage::Steer) => {}
+            None => continue,
+            Some(Usage::CrossCheck) => {
+                let peers = peers_rwlock.read().await;
+                let bounds = peers.step_bounds(ntp_instant, config.frequency_tolerance);
+                let quorum = peers.cross_check_quorum();
+                drop(peers);
+
+                match bounds {
+                    Some((lowest, highest)) if lowest > highest => {
+                        warn!("cross-checking peers disagree, refusing to step the clock");
+                    }
+                    Some((lowest, highest))
+                        if lowest > NtpDuration::ZERO || highest < NtpDuration::ZERO =>
+                    {
+                        if !quorum {
+                            info!("waiting for more cross-checking peers before seeding the clock");
+                            continue;
+                        }
+
+                        // until our peers steer the clock, the signed time is the best we have
+                        match controller.seed(&config, (lowest + highest) / 2i64) {
+                            ClockUpdateResult::Panic => {
+                                panic!(
+                                    r"Unusually large clock step suggested,
+                            please manually verify system clock and reference clock
+                                 state and restart if appropriate."
+                                )
+                            }
+                            ClockUpdateResult::Step => {
+                                peers_rwlock.write().await.reset_all();
+
+                                reset_epoch = reset_epoch.inc();
+                                reset_tx.send_replace(reset_epoch);
+                            }
+                            _ => {}
+                        }
+                    }
+                    Some(_) | None => {}
+                }
+
+                continue;
+            }
         }
 
         // remove snapshots from previous iteration
         snapshots.clear();
+        snapshot_ids.clear();
 
         // add all valid measurements to our list of snapshots
-        snapshots.extend(peers_rwlock.read().await.valid_snapshots());
+        let peers = peers_rwlock.read().await;
+        let upstream = match config.orphan_stratum {
+            Some(orphan_stratum) => peers
+                .valid_snapshots()
+                .any(|(_, snapshot)| snapshot.stratum < orphan_stratum),
+            None => false,
+        };
+        for (id, snapshot) in peers.valid_snapshots() {
+            let usable = match config.orphan_stratum {
+                Some(orphan_stratum) => orphan_usable(&snapshot, orphan_stratum, upstream),
+                None => true,
+            };
+
+            if usable {
+                snapshot_ids.push(id);
+                snapshots.push(snapshot);
+            }
+        }
+        drop(peers);
 
         let result = FilterAndCombine::run(&config, &snapshots, ntp_instant, system_poll);
 
@@ -112,17 +1071,33 @@ async fn run<C: NtpClock>(
             }
         };
 
+        fallback.synchronized();
+
+        spawner
+            .handle_falsetickers(&snapshot_ids, &clock_select.falsetickers)
+            .await;
+
         let offset_ms = clock_select.system_offset.to_seconds() * 1000.0;
         let jitter_ms = clock_select.system_jitter.to_seconds() * 1000.0;
         info!(offset_ms, jitter_ms, "system offset and jitter");
 
+        let leap_indicator = leap_seconds.leap_indicator(&controller, clock_select.leap_indicator);
+
+        // the peers that cross-check the time, like Roughtime servers, veto implausible steps
+        controller.set_step_bounds(
+            peers_rwlock
+                .read()
+                .await
+                .step_bounds(ntp_instant, config.frequency_tolerance),
+        );
+
         let adjust_type = controller.update(
             &config,
             clock_select.system_offset,
             clock_select.system_jitter,
             clock_select.system_root_delay,
             clock_select.system_root_dispersion,
-            clock_select.system_peer_snapshot.leap_indicator,
+            leap_indicator,
             clock_select.system_peer_snapshot.time,
         );
 
@@ -135,7 +1110,7 @@ async fn run<C: NtpClock>(
                                  state and restart if appropriate."
                 )
             }
-            ClockUpdateResult::Step => {
+            ClockUpdateResult::Step | ClockUpdateResult::Leap => {
                 peers_rwlock.write().await.reset_all();
 
                 reset_epoch = reset_epoch.inc();
@@ -144,25 +1119,51 @@ async fn run<C: NtpClock>(
             _ => {}
         }
 
+        if let Some(path) = &drift_file {
+            let due = match last_drift_store {
+                Some(last) => last.elapsed() >= DRIFT_FILE_INTERVAL,
+                None => true,
+            };
+
+            if due && store_frequency(&controller, path).await {
+                last_drift_store = Some(Instant::now());
+            }
+        }
+
         // Handle updating system snapshot
         if let ClockUpdateResult::Ignore = adjust_type {
             // ignore this update
         } else {
             let mut global = global_system_snapshot.write().await;
             global.poll_interval = controller.preferred_poll_interval();
-            global.leap_indicator = clock_select.system_peer_snapshot.leap_indicator;
+            global.leap_smear = controller.leap_smear_status();
+            // clients of a smearing server must not apply the leap second themselves
+            global.leap_indicator = match config.leap_smear {
+                Some(_) => NtpLeapIndicator::NoWarning,
+                None => leap_indicator,
+            };
+
+            // these are the values we advertise when serving time to others
+            global.stratum = clock_select.system_peer_snapshot.stratum.saturating_add(1);
+            global.reference_id = clock_select.system_peer_snapshot.peer_id;
+            global.root_delay = clock_select.system_root_delay;
+            global.root_dispersion = clock_select.system_root_dispersion;
+            match controller.now() {
+                Ok(now) => global.reference_timestamp = now,
+                Err(error) => warn!(?error, "could not read the clock after updating it"),
+            }
         }
     }
 
-    // the channel closed and has no more messages in it
+    // the channel closed and has no more messages in it, or we were asked to shut down
     Ok(())
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum PeerStatus {
     /// This peer is demobilized, meaning we will not send further packets to it.
-    /// Demobilized peers are kept because our logic is built around using indices,
-    /// and removing a peer would mess up the indexing.
+    /// Demobilized peers are kept, so that they remain listed until they are removed over the
+    /// configuration socket.
     Demobilized,
     /// We are waiting for the first snapshot from this peer _in the current reset epoch_.
     /// This state is the initial state for all peers (when the system is spawned), and also
@@ -185,40 +1186,145 @@ pub enum ObservablePeerState {
         uptime: std::time::Duration,
         poll_interval: std::time::Duration,
         peer_id: ReferenceId,
+        parse_failures: u64,
     },
 }
 
-#[derive(Debug, Default)]
-pub struct Peers {
-    peers: Box<[PeerStatus]>,
+/// A peer as listed over the configuration socket
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerListing {
+    pub id: PeerId,
+    pub addr: String,
+    /// For a server of a pool, the id of that pool
+    pub pool: Option<PeerId>,
+    pub demobilized: bool,
+}
+
+#[derive(Debug)]
+struct PeerState {
+    addr: String,
+    /// The pool that this peer uses a server of
+    pool: Option<PeerId>,
+    status: PeerStatus,
+    parse_failures: u64,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum NewMeasurement {
-    Yes,
-    No,
+#[derive(Debug, Default)]
+pub struct Peers {
+    /// Ordered by id, which is the order in which the peers were added
+    peers: BTreeMap<PeerId, PeerState>,
+    /// The addresses of the pools, whose ids are taken from the same sequence as those of peers
+    pools: BTreeMap<PeerId, String>,
+    next_id: u64,
+    /// The peers that only cross-check the time of the others, like Roughtime servers
+    cross_check: CrossCheck,
 }
 
 impl Peers {
+    #[cfg(test)]
     fn new(length: usize) -> Self {
-        Self {
-            peers: vec![PeerStatus::NoMeasurement; length].into(),
-        }
+        Self::from_statuslist(&vec![PeerStatus::NoMeasurement; length])
     }
 
     #[cfg(test)]
     pub(crate) fn from_statuslist(data: &[PeerStatus]) -> Self {
-        Self {
-            peers: data.to_owned().into(),
+        let mut peers = Self::default();
+
+        for status in data {
+            let id = peers.add(String::new());
+            peers.peers.get_mut(&id).unwrap().status = *status;
         }
+
+        peers
     }
 
     fn len(&self) -> usize {
         self.peers.len()
     }
 
+    fn next_id(&mut self) -> PeerId {
+        let id = PeerId(self.next_id);
+        self.next_id += 1;
+
+        id
+    }
+
+    /// Add a new peer for the server at `addr`, returning its id
+    fn add(&mut self, addr: String) -> PeerId {
+        self.add_peer(addr, None)
+    }
+
+    /// Add a new peer for a source at `addr` whose measurements are used as `usage` says,
+    /// returning its id
+    fn add_source(&mut self, addr: String, usage: Usage) -> PeerId {
+        let id = self.add(addr);
+
+        // the clock is only seeded once enough of these peers measured
+        if usage == Usage::CrossCheck {
+            self.cross_check.add(id);
+        }
+
+        id
+    }
+
+    /// Add a new peer for the server at `addr`, which is one of the servers of `pool`
+    fn add_pool_server(&mut self, addr: String, pool: PeerId) -> PeerId {
+        self.add_peer(addr, Some(pool))
+    }
+
+    fn add_peer(&mut self, addr: String, pool: Option<PeerId>) -> PeerId {
+        let id = self.next_id();
+
+        let status = PeerStatus::NoMeasurement;
+        self.peers.insert(
+            id,
+            PeerState {
+                addr,
+                pool,
+                status,
+                parse_failures: 0,
+            },
+        );
+
+        id
+    }
+
+    /// Add a pool at `addr`, whose servers are added as peers of their own, returning its id
+    fn add_pool(&mut self, addr: String) -> PeerId {
+        let id = self.next_id();
+        self.pools.insert(id, addr);
+
+        id
+    }
+
+    /// Forget about a peer or pool, returning whether it existed
+    fn remove(&mut self, id: PeerId) -> bool {
+        self.cross_check.remove(id);
+        self.peers.remove(&id).is_some() || self.pools.remove(&id).is_some()
+    }
+
+    /// The peers and pools, in the order in which they were added
+    pub fn list(&self) -> Vec<PeerListing> {
+        let peers = self.peers.iter().map(|(id, state)| PeerListing {
+            id: *id,
+            addr: state.addr.clone(),
+            pool: state.pool,
+            demobilized: matches!(state.status, PeerStatus::Demobilized),
+        });
+        let pools = self.pools.iter().map(|(id, addr)| PeerListing {
+            id: *id,
+            addr: addr.clone(),
+            pool: None,
+            demobilized: false,
+        });
+
+        let mut listing: Vec<_> = peers.chain(pools).collect();
+        listing.sort_by_key(|peer| peer.id);
+        listing
+    }
+
     pub fn observe(&self) -> impl Iterator<Item = ObservablePeerState> + '_ {
-        self.peers.iter().map(|status| match status {
+        self.peers.values().map(|state| match state.status {
             PeerStatus::Demobilized => ObservablePeerState::Nothing,
             PeerStatus::NoMeasurement => ObservablePeerState::Nothing,
             PeerStatus::Measurement(snapshot) => ObservablePeerState::Observable {
@@ -227,19 +1333,24 @@ impl Peers {
                 uptime: snapshot.time.elapsed(),
                 poll_interval: snapshot.poll_interval.as_system_duration(),
                 peer_id: snapshot.peer_id,
+                parse_failures: state.parse_failures,
             },
         })
     }
 
-    fn valid_snapshots(&self) -> impl Iterator<Item = PeerSnapshot> + '_ {
+    /// The snapshots of the peers that may be selected to steer the clock
+    fn valid_snapshots(&self) -> impl Iterator<Item = (PeerId, PeerSnapshot)> + '_ {
         self.peers
             .iter()
-            .filter_map(|peer_status| match peer_status {
+            .filter(|(id, _)| !self.cross_check.contains(**id))
+            .filter_map(|(id, state)| match state.status {
                 PeerStatus::Demobilized | PeerStatus::NoMeasurement => None,
-                PeerStatus::Measurement(snapshot) => Some(*snapshot),
+                PeerStatus::Measurement(snapshot) => Some((*id, snapshot)),
             })
     }
 
+    /// Keep track of the state of a peer. Returns how its new measurement can be used, if `msg`
+    /// brings one that can be used at all.
     fn receive_update(
         &mut self,
         msg: MsgForSystem,
@@ -249,45 +1360,90 @@ impl Peers {
         frequency_tolerance: FrequencyTolerance,
         distance_threshold: NtpDuration,
         system_poll: PollInterval,
-    ) -> NewMeasurement {
+    ) -> Option<Usage> {
+        // a peer may have sent messages just before it was demobilized or removed
+        let id = match msg {
+            MsgForSystem::MustDemobilize(id)
+            | MsgForSystem::NewMeasurement(id, _, _, _)
+            | MsgForSystem::UpdatedSnapshot(id, _, _)
+            | MsgForSystem::ParseFailures(id, _) => id,
+        };
+        let state = match self.peers.get_mut(&id) {
+            None => return None,
+            Some(PeerState {
+                status: PeerStatus::Demobilized,
+                ..
+            }) => return None,
+            Some(state) => state,
+        };
+
         match msg {
-            MsgForSystem::MustDemobilize(index) => {
-                self.peers[index.index] = PeerStatus::Demobilized;
+            MsgForSystem::MustDemobilize(_) => {
+                state.status = PeerStatus::Demobilized;
             }
-            MsgForSystem::NewMeasurement(index, msg_reset_epoch, snapshot) => {
+            MsgForSystem::NewMeasurement(_, msg_reset_epoch, snapshot, usage) => {
                 if current_reset_epoch == msg_reset_epoch {
-                    self.peers[index.index] = PeerStatus::Measurement(snapshot);
-
-                    let accept = snapshot.accept_synchronization(
-                        local_clock_time,
-                        frequency_tolerance,
-                        distance_threshold,
-                        system_poll,
-                    );
-
-                    if accept.is_ok() {
-                        return NewMeasurement::Yes;
-                    } else {
-                        // the snapshot is updated (useful for observability)
-                        // but we will not trigger a clock select based on this measurement
+                    state.status = PeerStatus::Measurement(snapshot);
+
+                    match usage {
+                        Usage::Steer => {
+                            let accept = snapshot.accept_synchronization(
+                                local_clock_time,
+                                frequency_tolerance,
+                                distance_threshold,
+                                system_poll,
+                            );
+
+                            if accept.is_ok() {
+                                return Some(Usage::Steer);
+                            } else {
+                                // the snapshot is updated (useful for observability)
+                                // but we will not trigger a clock select based on this measurement
+                            }
+                        }
+                        Usage::CrossCheck => {
+                            self.cross_check.update(id, snapshot);
+                            return Some(Usage::CrossCheck);
+                        }
                     }
                 }
             }
-            MsgForSystem::UpdatedSnapshot(index, msg_reset_epoch, snapshot) => {
+            MsgForSystem::UpdatedSnapshot(_, msg_reset_epoch, snapshot) => {
                 if current_reset_epoch == msg_reset_epoch {
-                    self.peers[index.index] = PeerStatus::Measurement(snapshot);
+                    state.status = PeerStatus::Measurement(snapshot);
                 }
             }
+            MsgForSystem::ParseFailures(_, count) => {
+                state.parse_failures = count;
+            }
         }
 
-        NewMeasurement::No
+        None
+    }
+
+    /// The offsets that the peers that cross-check the time allow our clock to be stepped by, see
+    /// [`CrossCheck::bounds`]
+    fn step_bounds(
+        &self,
+        now: NtpInstant,
+        frequency_tolerance: FrequencyTolerance,
+    ) -> Option<(NtpDuration, NtpDuration)> {
+        self.cross_check.bounds(now, frequency_tolerance)
+    }
+
+    /// Whether enough peers that cross-check the time measured to seed the clock with their
+    /// bounds, see [`CrossCheck::quorum`]
+    fn cross_check_quorum(&self) -> bool {
+        self.cross_check.quorum()
     }
 
     fn reset_all(&mut self) {
-        for peer_status in self.peers.iter_mut() {
+        self.cross_check.clear();
+
+        for state in self.peers.values_mut() {
             use PeerStatus::*;
 
-            *peer_status = match peer_status {
+            state.status = match state.status {
                 Demobilized => Demobilized,
                 Measurement(_) | NoMeasurement => NoMeasurement,
             };
@@ -297,7 +1453,7 @@ impl Peers {
 
 #[cfg(test)]
 mod tests {
-    use ntp_proto::{peer_snapshot, NtpLeapIndicator, NtpTimestamp};
+    use ntp_proto::{peer_snapshot, test_peer_snapshot, NtpLeapIndicator};
 
     use super::*;
 
@@ -315,6 +1471,10 @@ mod tests {
             Ok(())
         }
 
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            Ok(0.0)
+        }
+
         fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
             Ok(())
         }
@@ -329,6 +1489,10 @@ mod tests {
         ) -> Result<(), Self::Error> {
             Ok(())
         }
+
+        fn set_tai(&self, _tai_offset: i32) -> Result<(), Self::Error> {
+            Ok(())
+        }
     }
 
     #[test]
@@ -341,7 +1505,7 @@ mod tests {
 
         let new = peers.receive_update(
             MsgForSystem::NewMeasurement(
-                PeerIndex { index: 0 },
+                PeerId(0),
                 prev_epoch,
                 peer_snapshot(
                     PeerStatistics {
@@ -354,6 +1518,7 @@ mod tests {
                     NtpDuration::from_seconds(0.1),
                     NtpDuration::from_seconds(0.05),
                 ),
+                Usage::Steer,
             ),
             epoch,
             base,
@@ -361,12 +1526,12 @@ mod tests {
             NtpDuration::from_seconds(1.),
             PollInterval::MIN,
         );
-        assert_eq!(new, NewMeasurement::No);
+        assert_eq!(new, None);
         assert_eq!(peers.valid_snapshots().collect::<Vec<_>>().len(), 0);
 
         let new = peers.receive_update(
             MsgForSystem::NewMeasurement(
-                PeerIndex { index: 0 },
+                PeerId(0),
                 epoch,
                 peer_snapshot(
                     PeerStatistics {
@@ -379,6 +1544,7 @@ mod tests {
                     NtpDuration::from_seconds(1.0),
                     NtpDuration::from_seconds(2.0),
                 ),
+                Usage::Steer,
             ),
             epoch,
             base,
@@ -386,12 +1552,12 @@ mod tests {
             NtpDuration::from_seconds(1.),
             PollInterval::MIN,
         );
-        assert_eq!(new, NewMeasurement::No);
+        assert_eq!(new, None);
         assert_eq!(peers.valid_snapshots().collect::<Vec<_>>().len(), 1);
 
         let new = peers.receive_update(
             MsgForSystem::NewMeasurement(
-                PeerIndex { index: 0 },
+                PeerId(0),
                 epoch,
                 peer_snapshot(
                     PeerStatistics {
@@ -404,6 +1570,7 @@ mod tests {
                     NtpDuration::from_seconds(0.1),
                     NtpDuration::from_seconds(0.05),
                 ),
+                Usage::Steer,
             ),
             epoch,
             base,
@@ -411,12 +1578,12 @@ mod tests {
             NtpDuration::from_seconds(1.),
             PollInterval::MIN,
         );
-        assert_eq!(new, NewMeasurement::Yes);
+        assert_eq!(new, Some(Usage::Steer));
         assert_eq!(peers.valid_snapshots().collect::<Vec<_>>().len(), 1);
 
         let new = peers.receive_update(
             MsgForSystem::UpdatedSnapshot(
-                PeerIndex { index: 1 },
+                PeerId(1),
                 epoch,
                 peer_snapshot(
                     PeerStatistics {
@@ -436,24 +1603,164 @@ mod tests {
             NtpDuration::from_seconds(1.),
             PollInterval::MIN,
         );
-        assert_eq!(new, NewMeasurement::No);
+        assert_eq!(new, None);
         assert_eq!(peers.valid_snapshots().collect::<Vec<_>>().len(), 2);
 
         let new = peers.receive_update(
-            MsgForSystem::MustDemobilize(PeerIndex { index: 1 }),
+            MsgForSystem::ParseFailures(PeerId(1), 3),
+            epoch,
+            base,
+            FrequencyTolerance::ppm(15),
+            NtpDuration::from_seconds(1.),
+            PollInterval::MIN,
+        );
+        assert_eq!(new, None);
+        assert!(matches!(
+            peers.observe().nth(1),
+            Some(ObservablePeerState::Observable {
+                parse_failures: 3,
+                ..
+            })
+        ));
+
+        let new = peers.receive_update(
+            MsgForSystem::MustDemobilize(PeerId(1)),
             epoch,
             base,
             FrequencyTolerance::ppm(15),
             NtpDuration::from_seconds(1.),
             PollInterval::MIN,
         );
-        assert_eq!(new, NewMeasurement::No);
+        assert_eq!(new, None);
         assert_eq!(peers.valid_snapshots().collect::<Vec<_>>().len(), 1);
 
+        // messages of a peer that was removed are ignored
+        assert!(peers.remove(PeerId(0)));
+        let new = peers.receive_update(
+            MsgForSystem::UpdatedSnapshot(PeerId(0), epoch, test_peer_snapshot(base)),
+            epoch,
+            base,
+            FrequencyTolerance::ppm(15),
+            NtpDuration::from_seconds(1.),
+            PollInterval::MIN,
+        );
+        assert_eq!(new, None);
+        assert_eq!(peers.len(), 3);
+        assert_eq!(peers.valid_snapshots().collect::<Vec<_>>().len(), 0);
+
         peers.reset_all();
         assert_eq!(peers.valid_snapshots().collect::<Vec<_>>().len(), 0);
     }
 
+    #[test]
+    fn test_cross_check_peers() {
+        let base = NtpInstant::now();
+        let prev_epoch = ResetEpoch::default();
+        let epoch = prev_epoch.inc();
+        let tolerance = FrequencyTolerance::ppm(15);
+        let mut peers = Peers::new(2);
+
+        let receive = |peers: &mut Peers, msg| {
+            peers.receive_update(
+                msg,
+                epoch,
+                base,
+                tolerance,
+                NtpDuration::from_seconds(1.),
+                PollInterval::MIN,
+            )
+        };
+
+        let new = receive(
+            &mut peers,
+            MsgForSystem::NewMeasurement(PeerId(0), epoch, test_peer_snapshot(base), Usage::Steer),
+        );
+        assert_eq!(new, Some(Usage::Steer));
+
+        // measurements from before a reset are ignored
+        let new = receive(
+            &mut peers,
+            MsgForSystem::NewMeasurement(
+                PeerId(1),
+                prev_epoch,
+                test_peer_snapshot(base),
+                Usage::CrossCheck,
+            ),
+        );
+        assert_eq!(new, None);
+        assert_eq!(peers.step_bounds(base, tolerance), None);
+
+        let new = receive(
+            &mut peers,
+            MsgForSystem::NewMeasurement(
+                PeerId(1),
+                epoch,
+                test_peer_snapshot(base),
+                Usage::CrossCheck,
+            ),
+        );
+        assert_eq!(new, Some(Usage::CrossCheck));
+        assert!(peers.step_bounds(base, tolerance).is_some());
+        assert!(matches!(
+            peers.observe().nth(1),
+            Some(ObservablePeerState::Observable { .. })
+        ));
+
+        // a peer that cross-checks the time is never selected, even without a new measurement
+        let new = receive(
+            &mut peers,
+            MsgForSystem::UpdatedSnapshot(PeerId(1), epoch, test_peer_snapshot(base)),
+        );
+        assert_eq!(new, None);
+        let ids: Vec<_> = peers.valid_snapshots().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![PeerId(0)]);
+
+        peers.reset_all();
+        assert_eq!(peers.step_bounds(base, tolerance), None);
+
+        let new = receive(
+            &mut peers,
+            MsgForSystem::NewMeasurement(
+                PeerId(1),
+                epoch,
+                test_peer_snapshot(base),
+                Usage::CrossCheck,
+            ),
+        );
+        assert_eq!(new, Some(Usage::CrossCheck));
+        assert!(peers.remove(PeerId(1)));
+        assert_eq!(peers.step_bounds(base, tolerance), None);
+
+        // configured servers are expected to measure before their bounds seed the clock
+        let first = peers.add_source("first".into(), Usage::CrossCheck);
+        let second = peers.add_source("second".into(), Usage::CrossCheck);
+        assert!(!peers.cross_check_quorum());
+
+        for (id, quorum) in [(first, false), (second, true)] {
+            let new = receive(
+                &mut peers,
+                MsgForSystem::NewMeasurement(
+                    id,
+                    epoch,
+                    test_peer_snapshot(base),
+                    Usage::CrossCheck,
+                ),
+            );
+            assert_eq!(new, Some(Usage::CrossCheck));
+            assert_eq!(peers.cross_check_quorum(), quorum);
+        }
+    }
+
+    /// Inputs of a system without services, leap seconds file or shutdown
+    fn test_inputs(msg_for_system: mpsc::Receiver<MsgForSystem>) -> SystemInputs {
+        SystemInputs {
+            msg_for_system,
+            services: Vec::new(),
+            leap_seconds: watch::channel(None).1,
+            shutdown: watch::channel(()).1,
+        }
+    }
+
     #[tokio::test]
     async fn test_system_reset() {
         let config = Arc::new(tokio::sync::RwLock::new(SystemConfig::default()));
@@ -464,14 +1771,27 @@ mod tests {
         let peers_rwlock = Arc::new(tokio::sync::RwLock::new(Peers::new(4)));
         let peers_copy = peers_rwlock.clone();
 
+        let channels = PeerChannels {
+            msg_for_system_sender: msg_for_system_tx.clone(),
+            system_snapshots: global_system_snapshot.clone(),
+            system_config: config.clone(),
+            reset: reset_rx.clone(),
+        };
+        let (_, commands) = mpsc::channel(1);
+        let spawner = PeerSpawner::new(
+            channels,
+            HashMap::new(),
+            Arc::new(SystemResolver),
+            peers_rwlock.clone(),
+            commands,
+        );
+
         let handle = tokio::spawn(async move {
             run(
-                config,
-                reset_epoch,
-                global_system_snapshot,
-                msg_for_system_rx,
+                spawner,
+                test_inputs(msg_for_system_rx),
                 reset_tx,
-                peers_rwlock,
+                None,
                 TestClock {},
             )
             .await
@@ -482,7 +1802,7 @@ mod tests {
 
         msg_for_system_tx
             .send(MsgForSystem::NewMeasurement(
-                PeerIndex { index: 0 },
+                PeerId(0),
                 prev_epoch,
                 peer_snapshot(
                     PeerStatistics {
@@ -495,6 +1815,7 @@ mod tests {
                     NtpDuration::from_seconds(0.1),
                     NtpDuration::from_seconds(0.05),
                 ),
+                Usage::Steer,
             ))
             .await
             .unwrap();
@@ -514,7 +1835,7 @@ mod tests {
 
         msg_for_system_tx
             .send(MsgForSystem::NewMeasurement(
-                PeerIndex { index: 0 },
+                PeerId(0),
                 prev_epoch,
                 peer_snapshot(
                     PeerStatistics {
@@ -527,6 +1848,7 @@ mod tests {
                     NtpDuration::from_seconds(0.1),
                     NtpDuration::from_seconds(0.05),
                 ),
+                Usage::Steer,
             ))
             .await
             .unwrap();
@@ -547,4 +1869,344 @@ mod tests {
 
         handle.abort();
     }
+
+    #[tokio::test]
+    async fn test_manycast_discovery() {
+        let (msg_for_system_tx, _msg_for_system_rx) = mpsc::channel::<MsgForSystem>(32);
+        let (_reset_tx, reset_rx) = watch::channel(ResetEpoch::default());
+        let channels = PeerChannels {
+            msg_for_system_sender: msg_for_system_tx,
+            system_snapshots: Arc::new(tokio::sync::RwLock::new(SystemSnapshot::default())),
+            system_config: Arc::new(tokio::sync::RwLock::new(SystemConfig::default())),
+            reset: reset_rx,
+        };
+        let peers = Arc::new(tokio::sync::RwLock::new(Peers::default()));
+        let (_, commands) = mpsc::channel(1);
+        let mut spawner = PeerSpawner::new(
+            channels,
+            HashMap::new(),
+            Arc::new(SystemResolver),
+            peers.clone(),
+            commands,
+        );
+
+        // Note: Ports must be unique among tests to deal with parallelism
+        let manycast = PeerConfig {
+            addr: "127.0.0.1:9089".into(),
+            mode: PeerHostMode::Manycast,
+            certificate_authority: None,
+            key: None,
+            count: 2,
+        };
+        spawner.spawn(&manycast).await.unwrap();
+        let pool = PeerId(0);
+
+        // the discovery runs in the background, and is not started twice
+        assert!(spawner.pools[&pool].discovering);
+        assert_eq!(spawner.member_count(pool), 0);
+        spawner.pools.get_mut(&pool).unwrap().last_lookup = None;
+        spawner.fill_pools().await;
+        assert!(spawner.pools[&pool].discovering);
+
+        // the servers it finds are used once it completes
+        let discovery = Discovery {
+            pool,
+            addrs: Ok(vec!["127.0.0.1:9090".parse().unwrap()]),
+        };
+        spawner
+            .handle_command(PeerCommand::Discovered(discovery))
+            .await;
+        assert!(!spawner.pools[&pool].discovering);
+        assert_eq!(spawner.member_count(pool), 1);
+        assert_eq!(peers.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pool_replacement() {
+        let (msg_for_system_tx, _msg_for_system_rx) = mpsc::channel::<MsgForSystem>(32);
+        let (_reset_tx, reset_rx) = watch::channel(ResetEpoch::default());
+        let channels = PeerChannels {
+            msg_for_system_sender: msg_for_system_tx,
+            system_snapshots: Arc::new(tokio::sync::RwLock::new(SystemSnapshot::default())),
+            system_config: Arc::new(tokio::sync::RwLock::new(SystemConfig::default())),
+            reset: reset_rx,
+        };
+        let peers = Arc::new(tokio::sync::RwLock::new(Peers::default()));
+        let (_, commands) = mpsc::channel(1);
+        let mut spawner = PeerSpawner::new(
+            channels,
+            HashMap::new(),
+            Arc::new(SystemResolver),
+            peers.clone(),
+            commands,
+        );
+
+        // Note: Ports must be unique among tests to deal with parallelism
+        let pool_config = PeerConfig {
+            addr: "127.0.0.1:9020".into(),
+            mode: PeerHostMode::Pool,
+            certificate_authority: None,
+            key: None,
+            count: 2,
+        };
+        spawner.spawn(&pool_config).await.unwrap();
+        let pool = PeerId(0);
+
+        // the pool resolves to just one server
+        assert_eq!(spawner.member_count(pool), 1);
+        assert_eq!(peers.read().await.len(), 1);
+
+        let epoch = ResetEpoch::default();
+        let mut snapshot = test_peer_snapshot(NtpInstant::now());
+        snapshot.reach = Reach::default();
+
+        // a server that stays unreachable is replaced
+        let id = PeerId(1);
+        for _ in 1..POOL_MAX_UNREACHABLE_POLLS {
+            spawner
+                .handle_message(&MsgForSystem::UpdatedSnapshot(id, epoch, snapshot))
+                .await;
+        }
+        assert_eq!(spawner.member_count(pool), 1);
+        spawner
+            .handle_message(&MsgForSystem::UpdatedSnapshot(id, epoch, snapshot))
+            .await;
+        assert_eq!(spawner.member_count(pool), 0);
+        assert_eq!(peers.read().await.len(), 0);
+
+        // but it is not used again, even when the pool is short of servers
+        spawner.pools.get_mut(&pool).unwrap().last_lookup = None;
+        spawner.fill_pools().await;
+        assert_eq!(spawner.member_count(pool), 0);
+
+        // until the rejection expires
+        for until in spawner.pools.get_mut(&pool).unwrap().rejected.values_mut() {
+            *until = Instant::now();
+        }
+        spawner.pools.get_mut(&pool).unwrap().last_lookup = None;
+        spawner.fill_pools().await;
+        assert_eq!(spawner.member_count(pool), 1);
+        assert!(spawner.pools[&pool].rejected.is_empty());
+
+        // servers that are falsetickers are replaced too
+        let id = PeerId(2);
+        assert!(spawner.members.contains_key(&id));
+        for _ in 0..POOL_MAX_FALSETICKER_COUNT {
+            spawner.handle_falsetickers(&[id], &[0]).await;
+        }
+        assert_eq!(spawner.member_count(pool), 0);
+
+        // and servers that send a kiss-o'-death
+        spawner.pools.get_mut(&pool).unwrap().rejected.clear();
+        spawner.fill_pool(pool).await;
+        let id = PeerId(3);
+        assert!(spawner.members.contains_key(&id));
+        spawner
+            .handle_message(&MsgForSystem::MustDemobilize(id))
+            .await;
+        assert_eq!(spawner.member_count(pool), 0);
+        assert!(spawner.pools[&pool]
+            .rejected
+            .contains_key(&"127.0.0.1:9020".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_remove_pool() {
+        let (msg_for_system_tx, _msg_for_system_rx) = mpsc::channel::<MsgForSystem>(32);
+        let (_reset_tx, reset_rx) = watch::channel(ResetEpoch::default());
+        let channels = PeerChannels {
+            msg_for_system_sender: msg_for_system_tx,
+            system_snapshots: Arc::new(tokio::sync::RwLock::new(SystemSnapshot::default())),
+            system_config: Arc::new(tokio::sync::RwLock::new(SystemConfig::default())),
+            reset: reset_rx,
+        };
+        let peers = Arc::new(tokio::sync::RwLock::new(Peers::default()));
+        let (_, commands) = mpsc::channel(1);
+        let mut spawner = PeerSpawner::new(
+            channels,
+            HashMap::new(),
+            Arc::new(SystemResolver),
+            peers.clone(),
+            commands,
+        );
+
+        // Note: Ports must be unique among tests to deal with parallelism
+        let pool_config = PeerConfig {
+            addr: "127.0.0.1:9091".into(),
+            mode: PeerHostMode::Pool,
+            certificate_authority: None,
+            key: None,
+            count: 2,
+        };
+        spawner.spawn(&pool_config).await.unwrap();
+
+        // the pool is listed with an id of its own, next to its server
+        let pool = PeerId(0);
+        let server = PeerId(1);
+        let listing = peers.read().await.list();
+        assert_eq!(listing.len(), 2);
+        assert_eq!(listing[0].id, pool);
+        assert_eq!(listing[0].pool, None);
+        assert_eq!(listing[1].id, server);
+        assert_eq!(listing[1].pool, Some(pool));
+
+        // removing a server of the pool by hand does not reject it
+        spawner.handle_command(PeerCommand::Remove(server)).await;
+        assert_eq!(spawner.member_count(pool), 0);
+        assert!(spawner.pools[&pool].rejected.is_empty());
+        assert_eq!(peers.read().await.list().len(), 1);
+
+        spawner.fill_pool(pool).await;
+        assert_eq!(spawner.member_count(pool), 1);
+
+        // removing the pool removes its servers too
+        spawner.handle_command(PeerCommand::Remove(pool)).await;
+        assert!(spawner.pools.is_empty());
+        assert!(spawner.members.is_empty());
+        assert!(spawner.tasks.is_empty());
+        assert!(peers.read().await.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_add_remove_peers() {
+        let config = Arc::new(tokio::sync::RwLock::new(SystemConfig::default()));
+        let (reset_tx, reset_rx) = watch::channel(ResetEpoch::default());
+        let (msg_for_system_tx, msg_for_system_rx) = mpsc::channel::<MsgForSystem>(32);
+        let global_system_snapshot = Arc::new(tokio::sync::RwLock::new(SystemSnapshot::default()));
+        let peers = Arc::new(tokio::sync::RwLock::new(Peers::default()));
+
+        let channels = PeerChannels {
+            msg_for_system_sender: msg_for_system_tx.clone(),
+            system_snapshots: global_system_snapshot.clone(),
+            system_config: config.clone(),
+            reset: reset_rx,
+        };
+        let (commands_tx, commands_rx) = mpsc::channel(1);
+        let spawner = PeerSpawner::new(
+            channels,
+            HashMap::new(),
+            Arc::new(SystemResolver),
+            peers.clone(),
+            commands_rx,
+        );
+
+        let handle = tokio::spawn(run(
+            spawner,
+            test_inputs(msg_for_system_rx),
+            reset_tx,
+            None,
+            TestClock {},
+        ));
+
+        let sync = || async {
+            let (done_tx, done_rx) = oneshot::channel();
+            commands_tx.send(PeerCommand::Sync(done_tx)).await.unwrap();
+            done_rx.await.unwrap();
+        };
+
+        // Note: Ports must be unique among tests to deal with parallelism
+        for addr in ["127.0.0.1:9021", "127.0.0.1:9022"] {
+            let peer_config = PeerConfig::try_from(addr).unwrap();
+            commands_tx
+                .send(PeerCommand::Add(peer_config))
+                .await
+                .unwrap();
+        }
+        sync().await;
+
+        let listing = peers.read().await.list();
+        assert_eq!(listing.len(), 2);
+        assert_eq!(listing[0].addr, "127.0.0.1:9021");
+        assert_eq!(listing[1].addr, "127.0.0.1:9022");
+
+        let removed = listing[0].id;
+        commands_tx
+            .send(PeerCommand::Remove(removed))
+            .await
+            .unwrap();
+        sync().await;
+
+        let listing = peers.read().await.list();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].addr, "127.0.0.1:9022");
+
+        // a new peer never reuses its id
+        let peer_config = PeerConfig::try_from("127.0.0.1:9021").unwrap();
+        commands_tx
+            .send(PeerCommand::Add(peer_config))
+            .await
+            .unwrap();
+        sync().await;
+
+        let listing = peers.read().await.list();
+        assert_eq!(listing.len(), 2);
+        assert!(listing.iter().all(|peer| peer.id != removed));
+
+        handle.abort();
+    }
+
+    #[test]
+    fn test_orphan_usable() {
+        let mut snapshot = test_peer_snapshot(NtpInstant::now());
+        snapshot.our_id = ReferenceId::from_ip("10.0.0.2".parse().unwrap());
+
+        snapshot.stratum = 3;
+        assert!(orphan_usable(&snapshot, 10, true));
+
+        // the orphan with the lowest reference id is followed, unless a peer has an upstream
+        snapshot.stratum = 10;
+        snapshot.peer_id = ReferenceId::from_ip("10.0.0.1".parse().unwrap());
+        assert!(orphan_usable(&snapshot, 10, false));
+        assert!(!orphan_usable(&snapshot, 10, true));
+
+        snapshot.peer_id = ReferenceId::from_ip("10.0.0.3".parse().unwrap());
+        assert!(!orphan_usable(&snapshot, 10, false));
+
+        snapshot.stratum = 11;
+        snapshot.peer_id = ReferenceId::from_ip("10.0.0.1".parse().unwrap());
+        assert!(!orphan_usable(&snapshot, 10, false));
+    }
+
+    #[test]
+    fn test_fallback() {
+        let mut fallback = Fallback::new();
+        let mut global = SystemSnapshot::default();
+
+        let mut config = SystemConfig::default();
+        assert_eq!(fallback.deadline(&config), None);
+
+        config.local_stratum = Some(12);
+        assert_eq!(
+            fallback.deadline(&config),
+            Some(fallback.last_sync + FALLBACK_WAIT)
+        );
+
+        let now = NtpTimestamp::from_unix(1_000_000, 0);
+        fallback.activate(&config, &mut global, now);
+        assert_eq!(global.stratum, 12);
+        assert_eq!(global.reference_id, ReferenceId::LOCAL);
+        assert_eq!(global.leap_indicator, NtpLeapIndicator::NoWarning);
+        assert_eq!(global.reference_timestamp, now);
+
+        // the local clock keeps counting as recently updated
+        assert_eq!(
+            fallback.deadline(&config),
+            Some(fallback.last_refresh + FALLBACK_REFRESH)
+        );
+        let later = NtpTimestamp::from_unix(1_000_064, 0);
+        fallback.activate(&config, &mut global, later);
+        assert_eq!(global.reference_timestamp, later);
+
+        // orphan mode takes precedence over the local clock
+        config.orphan_stratum = Some(10);
+        fallback.activate(&config, &mut global, later);
+        assert_eq!(global.stratum, 10);
+        assert_eq!(
+            global.reference_id,
+            ReferenceId::from_ip("127.0.0.1".parse().unwrap())
+        );
+
+        fallback.synchronized();
+        assert!(fallback.deadline(&config).is_some());
+    }
 }
@@ -0,0 +1,68 @@
+use ntp_proto::{NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval};
+
+/// A clock for tests that can only tell the time, either the system time or a
+/// fixed time. Any attempt to adjust it panics.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TestClock {
+    fixed: Option<NtpTimestamp>,
+}
+
+impl TestClock {
+    /// A clock that tells the current system time
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// A clock that always tells the given number of seconds since the unix epoch
+    pub(crate) fn fixed(unix_seconds: u32) -> Self {
+        Self {
+            fixed: Some(NtpTimestamp::from_unix(unix_seconds as i64, 0)),
+        }
+    }
+}
+
+impl NtpClock for TestClock {
+    type Error = std::io::Error;
+
+    fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+        if let Some(fixed) = self.fixed {
+            return Ok(fixed);
+        }
+
+        let cur = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .map_err(std::io::Error::other)?;
+
+        Ok(NtpTimestamp::from_unix(
+            cur.as_secs() as i64,
+            cur.subsec_nanos(),
+        ))
+    }
+
+    fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+        panic!("Shouldn't be called by a test that only reads the clock");
+    }
+
+    fn get_freq(&self) -> Result<f64, Self::Error> {
+        panic!("Shouldn't be called by a test that only reads the clock");
+    }
+
+    fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+        panic!("Shouldn't be called by a test that only reads the clock");
+    }
+
+    fn update_clock(
+        &self,
+        _offset: NtpDuration,
+        _est_error: NtpDuration,
+        _max_error: NtpDuration,
+        _poll_interval: PollInterval,
+        _leap_status: NtpLeapIndicator,
+    ) -> Result<(), Self::Error> {
+        panic!("Shouldn't be called by a test that only reads the clock");
+    }
+
+    fn set_tai(&self, _tai_offset: i32) -> Result<(), Self::Error> {
+        panic!("Shouldn't be called by a test that only reads the clock");
+    }
+}
@@ -8,6 +8,12 @@
 use ntp_proto::{NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval};
 use thiserror::Error as ThisError;
 
+mod serial;
+mod shm;
+
+pub use serial::open_serial;
+pub use shm::ShmSegment;
+
 #[derive(Debug, Copy, Clone, ThisError)]
 pub enum Error {
     #[error("Insufficient permissions to interact with the clock.")]
@@ -20,11 +26,6 @@ pub enum Error {
     NotSupported,
 }
 
-// Unix uses an epoch located at 1/1/1970-00:00h (UTC) and NTP uses 1/1/1900-00:00h.
-// This leads to an offset equivalent to 70 years in seconds
-// there are 17 leap years between the two dates so the offset is
-const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
-
 // Libc has no good other way of obtaining this, so let's at least make our functions
 // more readable.
 const EMPTY_TIMEX: libc::timex = libc::timex {
@@ -109,10 +110,8 @@ impl NtpClock for UnixNtpClock {
             return Err(convert_errno());
         }
 
-        // Negative eras are completely valid, so any wrapping is
-        // perfectly reasonable here.
-        Ok(NtpTimestamp::from_seconds_nanos_since_ntp_era(
-            (ntp_kapi_timex.time.tv_sec as u32).wrapping_add(EPOCH_OFFSET),
+        Ok(NtpTimestamp::from_unix(
+            ntp_kapi_timex.time.tv_sec,
             if ntp_kapi_timex.status & libc::STA_NANO != 0 {
                 // We have nanosecond precision. use it
                 ntp_kapi_timex.time.tv_usec as u32
@@ -137,6 +136,17 @@ impl NtpClock for UnixNtpClock {
         }
     }
 
+    fn get_freq(&self) -> Result<f64, Self::Error> {
+        let mut ntp_kapi_timex = EMPTY_TIMEX;
+
+        if unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) } == -1 {
+            return Err(convert_errno());
+        }
+
+        // Convert back from units of 2^-16 ppm to seconds drift per second
+        Ok(ntp_kapi_timex.freq as f64 / 65536e6)
+    }
+
     fn step_clock(&self, offset: ntp_proto::NtpDuration) -> Result<(), Self::Error> {
         let mut tp = libc::timespec {
             tv_sec: 0,
@@ -202,6 +212,21 @@ impl NtpClock for UnixNtpClock {
             Err(convert_errno())
         }
     }
+
+    fn set_tai(&self, tai_offset: i32) -> Result<(), Self::Error> {
+        let mut ntp_kapi_timex = EMPTY_TIMEX;
+        ntp_kapi_timex.modes = libc::MOD_TAI;
+        // The kernel takes the new TAI offset from the time constant field
+        ntp_kapi_timex.constant = tai_offset as libc::c_long;
+
+        if unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) } != -1 {
+            // We don't care here about the time status, so the non-error
+            // information in the return value of ntp_adjtime can be ignored
+            Ok(())
+        } else {
+            Err(convert_errno())
+        }
+    }
 }
 
 #[cfg(test)]
@@ -216,4 +241,11 @@ mod tests {
             NtpTimestamp::from_seconds_nanos_since_ntp_era(0, 0)
         );
     }
+
+    #[test]
+    fn test_get_freq_does_not_crash() {
+        let clock = UnixNtpClock::new();
+        // the kernel accepts at most 500 ppm
+        assert!(clock.get_freq().unwrap().abs() <= 500e-6);
+    }
 }
@@ -0,0 +1,100 @@
+// Note on unsafe usage.
+//
+// The termios functions only read and write the `termios` struct they are given, and the file
+// descriptor they use stays open for as long as the `File` that owns it exists.
+
+use std::{
+    fs::File,
+    io,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::Path,
+};
+
+/// The speed setting of a baud rate that GPS receivers commonly use
+fn speed(baud: u32) -> Option<libc::speed_t> {
+    match baud {
+        4800 => Some(libc::B4800),
+        9600 => Some(libc::B9600),
+        19200 => Some(libc::B19200),
+        38400 => Some(libc::B38400),
+        57600 => Some(libc::B57600),
+        115200 => Some(libc::B115200),
+        230400 => Some(libc::B230400),
+        _ => None,
+    }
+}
+
+/// Open the serial device at `path` for reading at `baud`, e.g. to read the NMEA sentences of a
+/// GPS receiver. The device is put in raw mode, so every byte is passed on unchanged.
+pub fn open_serial(path: &Path, baud: u32) -> io::Result<File> {
+    let speed = speed(baud)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unsupported baud rate"))?;
+
+    // the device must not become our controlling terminal
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+
+    // Safety: termios only consists of integers, for which all zeroes is valid
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe { libc::cfmakeraw(&mut termios) };
+    // GPS receivers usually do not drive the modem control lines
+    termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+
+    if unsafe { libc::cfsetspeed(&mut termios, speed) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CStr,
+        io::{BufRead, BufReader, Write},
+        os::unix::io::FromRawFd,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_open_serial() {
+        // a pseudo terminal stands in for the serial device
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        assert_ne!(master, -1);
+        let mut name = [0 as libc::c_char; 64];
+        unsafe {
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+            assert_eq!(libc::ptsname_r(master, name.as_mut_ptr(), name.len()), 0);
+        }
+        let mut master = unsafe { File::from_raw_fd(master) };
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) }.to_str().unwrap();
+
+        let serial = open_serial(Path::new(name), 9600).unwrap();
+
+        // raw mode leaves the line ending alone
+        let sentence = "$GPZDA,201530.00,04,07,2002,00,00*60\r\n";
+        master.write_all(sentence.as_bytes()).unwrap();
+        let mut line = String::new();
+        BufReader::new(serial).read_line(&mut line).unwrap();
+        assert_eq!(line, sentence);
+
+        let error = open_serial(Path::new(name), 1234).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        // regular files are not serial devices
+        assert!(open_serial(Path::new("/dev/null"), 9600).is_err());
+    }
+}
@@ -0,0 +1,276 @@
+// Note on unsafe usage.
+//
+// The shared memory segment is written by another process at any moment, so it is only accessed
+// through volatile reads and writes of its fields. The segment stays attached for as long as the
+// `ShmSegment` exists, which keeps the pointer to it valid.
+
+use std::{
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{fence, Ordering},
+    time::Duration,
+};
+
+use ntp_proto::{NtpLeapIndicator, NtpTimestamp, RefclockSample};
+
+/// Key of the segment of unit 0 ("NTP0"), the segments of the other units follow it
+const SHM_KEY_BASE: libc::key_t = 0x4e545030;
+
+/// Layout of the segment, as defined by the shared memory driver of the NTP reference
+/// implementation
+#[repr(C)]
+struct ShmTime {
+    /// 0 when the writer does not use `count`, 1 when it increments `count` before and after
+    /// writing a sample
+    mode: libc::c_int,
+    count: libc::c_int,
+    clock_timestamp_sec: libc::time_t,
+    clock_timestamp_usec: libc::c_int,
+    receive_timestamp_sec: libc::time_t,
+    receive_timestamp_usec: libc::c_int,
+    leap: libc::c_int,
+    precision: libc::c_int,
+    nsamples: libc::c_int,
+    valid: libc::c_int,
+    clock_timestamp_nsec: libc::c_uint,
+    receive_timestamp_nsec: libc::c_uint,
+    dummy: [libc::c_int; 8],
+}
+
+/// The shared memory segment through which a program like gpsd passes the time of a reference
+/// clock, using the protocol of the shared memory driver of the NTP reference implementation
+#[derive(Debug)]
+pub struct ShmSegment {
+    id: libc::c_int,
+    time: *mut ShmTime,
+}
+
+// The segment is shared with other processes anyway, moving it to another thread is no different
+unsafe impl Send for ShmSegment {}
+
+impl ShmSegment {
+    /// Attach to the segment of `unit`, creating it when it does not exist yet. Like in the NTP
+    /// reference implementation, the segments of units 0 and 1 are only accessible by root, and
+    /// those of other units by anyone.
+    pub fn attach(unit: u32) -> std::io::Result<Self> {
+        let key = SHM_KEY_BASE.wrapping_add(unit as libc::key_t);
+        let permissions = if unit < 2 { 0o600 } else { 0o666 };
+
+        let id = unsafe {
+            libc::shmget(
+                key,
+                std::mem::size_of::<ShmTime>(),
+                libc::IPC_CREAT | permissions,
+            )
+        };
+        if id == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let time = unsafe { libc::shmat(id, std::ptr::null(), 0) };
+        if time as isize == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(ShmSegment {
+            id,
+            time: time as *mut ShmTime,
+        })
+    }
+
+    /// Take the sample from the segment, when the writer put a new one in it. A sample that the
+    /// writer changed while we were reading it is dropped.
+    pub fn read(&self) -> Option<RefclockSample> {
+        let time = self.time;
+
+        unsafe {
+            if std::ptr::read_volatile(addr_of!((*time).valid)) == 0 {
+                return None;
+            }
+
+            let mode = std::ptr::read_volatile(addr_of!((*time).mode));
+            let count = std::ptr::read_volatile(addr_of!((*time).count));
+            fence(Ordering::SeqCst);
+
+            let clock_timestamp = timestamp(
+                std::ptr::read_volatile(addr_of!((*time).clock_timestamp_sec)),
+                std::ptr::read_volatile(addr_of!((*time).clock_timestamp_usec)),
+                std::ptr::read_volatile(addr_of!((*time).clock_timestamp_nsec)),
+            );
+            let receive_timestamp = timestamp(
+                std::ptr::read_volatile(addr_of!((*time).receive_timestamp_sec)),
+                std::ptr::read_volatile(addr_of!((*time).receive_timestamp_usec)),
+                std::ptr::read_volatile(addr_of!((*time).receive_timestamp_nsec)),
+            );
+            let leap = std::ptr::read_volatile(addr_of!((*time).leap));
+
+            fence(Ordering::SeqCst);
+            let unchanged = std::ptr::read_volatile(addr_of!((*time).count)) == count;
+
+            // the sample has been used, wait for the writer to put a new one in
+            std::ptr::write_volatile(addr_of_mut!((*time).valid), 0);
+
+            match mode {
+                0 => {}
+                1 if unchanged => {}
+                _ => return None,
+            }
+
+            Some(RefclockSample {
+                reference_time: clock_timestamp,
+                local_time: receive_timestamp,
+                leap: match leap {
+                    0 => NtpLeapIndicator::NoWarning,
+                    1 => NtpLeapIndicator::Leap61,
+                    2 => NtpLeapIndicator::Leap59,
+                    _ => NtpLeapIndicator::Unknown,
+                },
+            })
+        }
+    }
+
+    /// Put a sample in the segment in mode 1, like gpsd does. The times are given since the unix
+    /// epoch: `clock_time` is the time of the reference clock and `receive_time` the time of the
+    /// system clock at that same moment.
+    pub fn write(&self, clock_time: Duration, receive_time: Duration, leap: NtpLeapIndicator) {
+        let time = self.time;
+
+        unsafe {
+            std::ptr::write_volatile(addr_of_mut!((*time).valid), 0);
+            let count = std::ptr::read_volatile(addr_of!((*time).count));
+            std::ptr::write_volatile(addr_of_mut!((*time).count), count.wrapping_add(1));
+            fence(Ordering::SeqCst);
+
+            std::ptr::write_volatile(addr_of_mut!((*time).mode), 1);
+            std::ptr::write_volatile(
+                addr_of_mut!((*time).clock_timestamp_sec),
+                clock_time.as_secs() as libc::time_t,
+            );
+            std::ptr::write_volatile(
+                addr_of_mut!((*time).clock_timestamp_usec),
+                clock_time.subsec_micros() as libc::c_int,
+            );
+            std::ptr::write_volatile(
+                addr_of_mut!((*time).clock_timestamp_nsec),
+                clock_time.subsec_nanos(),
+            );
+            std::ptr::write_volatile(
+                addr_of_mut!((*time).receive_timestamp_sec),
+                receive_time.as_secs() as libc::time_t,
+            );
+            std::ptr::write_volatile(
+                addr_of_mut!((*time).receive_timestamp_usec),
+                receive_time.subsec_micros() as libc::c_int,
+            );
+            std::ptr::write_volatile(
+                addr_of_mut!((*time).receive_timestamp_nsec),
+                receive_time.subsec_nanos(),
+            );
+            std::ptr::write_volatile(
+                addr_of_mut!((*time).leap),
+                match leap {
+                    NtpLeapIndicator::NoWarning => 0,
+                    NtpLeapIndicator::Leap61 => 1,
+                    NtpLeapIndicator::Leap59 => 2,
+                    NtpLeapIndicator::Unknown => 3,
+                },
+            );
+
+            fence(Ordering::SeqCst);
+            std::ptr::write_volatile(addr_of_mut!((*time).count), count.wrapping_add(2));
+            std::ptr::write_volatile(addr_of_mut!((*time).valid), 1);
+        }
+    }
+
+    /// Remove the segment from the system once every process has detached from it
+    pub fn remove(&self) -> std::io::Result<()> {
+        if unsafe { libc::shmctl(self.id, libc::IPC_RMID, std::ptr::null_mut()) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        // Nothing sensible can be done when detaching fails
+        unsafe { libc::shmdt(self.time as *const libc::c_void) };
+    }
+}
+
+/// Convert a time in the segment to an NTP timestamp. The nanoseconds were added to the segment
+/// later, so they are only used when a writer filled them in consistently with the microseconds.
+fn timestamp(seconds: libc::time_t, micros: libc::c_int, nanos: libc::c_uint) -> NtpTimestamp {
+    let nanos = if nanos < 1_000_000_000 && (nanos / 1000) as libc::c_int == micros {
+        nanos
+    } else {
+        (micros as u32).wrapping_mul(1000)
+    };
+
+    NtpTimestamp::from_unix(seconds, nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shm_segment() {
+        // Note: units must be unique among tests to deal with parallelism
+        let segment = ShmSegment::attach(1000).unwrap();
+        let _ = segment.read();
+        assert_eq!(segment.read(), None);
+
+        segment.write(
+            Duration::new(1_000_000, 500_000_000),
+            Duration::new(1_000_001, 250_000_000),
+            NtpLeapIndicator::NoWarning,
+        );
+
+        let sample = segment.read().unwrap();
+        assert_eq!(
+            sample.reference_time,
+            NtpTimestamp::from_unix(1_000_000, 500_000_000)
+        );
+        assert_eq!(
+            sample.local_time,
+            NtpTimestamp::from_unix(1_000_001, 250_000_000)
+        );
+        assert_eq!(sample.leap, NtpLeapIndicator::NoWarning);
+
+        // a sample is only used once
+        assert_eq!(segment.read(), None);
+
+        // writers in mode 0 do not use the count
+        segment.write(
+            Duration::new(1_000_002, 0),
+            Duration::new(1_000_002, 0),
+            NtpLeapIndicator::Leap61,
+        );
+        unsafe { (*segment.time).mode = 0 };
+        assert_eq!(segment.read().unwrap().leap, NtpLeapIndicator::Leap61);
+
+        segment.write(
+            Duration::new(1_000_003, 0),
+            Duration::new(1_000_003, 0),
+            NtpLeapIndicator::NoWarning,
+        );
+        unsafe { (*segment.time).mode = 2 };
+        assert_eq!(segment.read(), None);
+
+        segment.remove().unwrap();
+    }
+
+    #[test]
+    fn test_timestamp() {
+        // writers that do not fill in the nanoseconds
+        assert_eq!(
+            timestamp(0, 250_000, 0),
+            NtpTimestamp::from_unix(0, 250_000_000)
+        );
+        assert_eq!(
+            timestamp(0, 250_000, 250_000_123),
+            NtpTimestamp::from_unix(0, 250_000_123)
+        );
+    }
+}
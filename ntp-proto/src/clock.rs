@@ -1,8 +1,11 @@
 use crate::{
-    packet::NtpLeapIndicator, time_types::PollInterval, NtpDuration, NtpInstant, NtpTimestamp,
-    SystemConfig,
+    config::LeapSmearConfig,
+    leap_smear::{LeapSmear, LeapSmearStatus},
+    packet::NtpLeapIndicator,
+    time_types::PollInterval,
+    NtpDuration, NtpInstant, NtpTimestamp, SystemConfig,
 };
-use tracing::{debug, error, info, instrument, trace};
+use tracing::{debug, error, info, instrument, trace, warn};
 
 /// Interface for a clock settable by the ntp implementation.
 /// This needs to be a trait as a single system can have multiple clocks
@@ -13,6 +16,8 @@ pub trait NtpClock {
     fn now(&self) -> Result<NtpTimestamp, Self::Error>;
 
     fn set_freq(&self, freq: f64) -> Result<(), Self::Error>;
+    /// The current frequency correction, as set by `set_freq` and adjusted by `update_clock`
+    fn get_freq(&self) -> Result<f64, Self::Error>;
     fn step_clock(&self, offset: NtpDuration) -> Result<(), Self::Error>;
     fn update_clock(
         &self,
@@ -22,13 +27,14 @@ pub trait NtpClock {
         poll_interval: PollInterval,
         leap_status: NtpLeapIndicator,
     ) -> Result<(), Self::Error>;
+    /// Set the difference between TAI and UTC in seconds
+    fn set_tai(&self, tai_offset: i32) -> Result<(), Self::Error>;
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum ClockState {
     StartupBlank,
-    // Needed when implementing frequency backups
-    #[allow(dead_code)]
+    /// Started with a frequency that was measured before, so no measurement is needed
     StartupFreq,
     MeasureFreq,
     Spike,
@@ -47,6 +53,11 @@ pub struct ClockController<C: NtpClock> {
     preferred_poll_interval: PollInterval,
     poll_interval_counter: i32,
     offset: NtpDuration,
+    leap_smear: Option<LeapSmear>,
+    /// The offset from UTC that the leap smear wanted at the last update
+    leap_smear_offset: NtpDuration,
+    /// Lowest and highest offset that we may step the clock by, as set by `set_step_bounds`
+    step_bounds: Option<(NtpDuration, NtpDuration)>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -55,6 +66,9 @@ pub enum ClockUpdateResult {
     Step,
     Slew,
     Panic,
+    /// UTC passed a smeared leap second. The clock is not changed, but measurements from before
+    /// the leap second are a second off.
+    Leap,
 }
 
 impl<C: NtpClock> ClockController<C> {
@@ -69,6 +83,23 @@ impl<C: NtpClock> ClockController<C> {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::ZERO,
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
+        }
+    }
+
+    /// Start from a frequency measured in an earlier run, skipping the initial frequency
+    /// measurement
+    pub fn with_frequency(clock: C, frequency: f64) -> Self {
+        let controller = Self::new(clock);
+        controller
+            .clock
+            .set_freq(frequency)
+            .expect("Unable to set clock frequency");
+        Self {
+            state: ClockState::StartupFreq,
+            ..controller
         }
     }
 
@@ -90,12 +121,36 @@ impl<C: NtpClock> ClockController<C> {
         leap_status: NtpLeapIndicator,
         last_peer_update: NtpInstant,
     ) -> ClockUpdateResult {
+        // When smearing, we steer towards the smeared time instead of UTC, and the clock itself is
+        // never told about leap seconds
+        let (offset, leap_status) = match &config.leap_smear {
+            Some(leap_smear_config) => match self.update_leap_smear(leap_smear_config, leap_status)
+            {
+                Some(smear_offset) => (offset - smear_offset, NtpLeapIndicator::NoWarning),
+                None => return ClockUpdateResult::Leap,
+            },
+            None => (offset, leap_status),
+        };
+
         // Check that we have a somewhat reasonable result
         if self.offset_too_large(config, offset) {
             error!("Detected overly large offset");
             return ClockUpdateResult::Panic;
         }
 
+        // Only the steps below can move the clock by more than a little, so those are the ones
+        // that a trusted source must agree with
+        let step =
+            offset.abs() > NtpDuration::STEP_THRESHOLD || self.state == ClockState::StartupBlank;
+        if step && !self.step_allowed(offset) {
+            warn!(
+                offset = debug(offset),
+                bounds = debug(self.step_bounds),
+                "Refusing to step the clock outside the bounds of a trusted source"
+            );
+            return ClockUpdateResult::Ignore;
+        }
+
         // Main decision making
         //
         // Combined, this code is responsible for:
@@ -236,10 +291,125 @@ impl<C: NtpClock> ClockController<C> {
         ClockUpdateResult::Slew
     }
 
+    /// Start, continue or end the smear of a leap second, and return the offset from UTC that
+    /// the clock should have now. Returns `None` on the first update after the leap second.
+    fn update_leap_smear(
+        &mut self,
+        config: &LeapSmearConfig,
+        leap_status: NtpLeapIndicator,
+    ) -> Option<NtpDuration> {
+        let utc = match self.clock.now() {
+            Ok(now) => now + self.leap_smear_offset,
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "could not read the clock, not smearing leap seconds"
+                );
+                return Some(NtpDuration::ZERO);
+            }
+        };
+
+        if self.leap_smear.is_none() {
+            self.leap_smear = LeapSmear::start(config, leap_status, utc);
+            if self.leap_smear.is_some() {
+                info!(?leap_status, "started smearing leap second");
+            }
+        }
+
+        let leap_smear = match self.leap_smear.as_mut() {
+            Some(leap_smear) => leap_smear,
+            None => return Some(NtpDuration::ZERO),
+        };
+        if leap_smear.pass_leap(utc) {
+            self.leap_smear_offset = leap_smear.offset(utc);
+            return None;
+        }
+
+        let finished = if leap_smear.has_passed_leap() {
+            leap_smear.is_finished(utc)
+        } else {
+            // the servers no longer announce the leap second, so it is not going to happen
+            leap_status != leap_smear.leap_indicator()
+        };
+
+        if finished {
+            info!("stopped smearing leap second");
+            self.leap_smear = None;
+            self.leap_smear_offset = NtpDuration::ZERO;
+        } else {
+            self.leap_smear_offset = leap_smear.offset(utc);
+        }
+
+        Some(self.leap_smear_offset)
+    }
+
+    /// Progress of the current leap smear, if any
+    pub fn leap_smear_status(&self) -> Option<LeapSmearStatus> {
+        let leap_smear = self.leap_smear.as_ref()?;
+        let now = self.clock.now().ok()?;
+        Some(leap_smear.status(now + self.leap_smear_offset))
+    }
+
+    /// The frequency correction of the clock, once it has been measured
+    pub fn frequency(&self) -> Result<Option<f64>, C::Error> {
+        match self.state {
+            ClockState::Sync | ClockState::Spike => self.clock.get_freq().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn now(&self) -> Result<NtpTimestamp, C::Error> {
+        self.clock.now()
+    }
+
+    /// Pass the difference between TAI and UTC on to the clock. Unlike the other clock
+    /// adjustments, failing to do so is not fatal: the system time itself is not affected.
+    pub fn set_tai(&self, tai_offset: i32) -> Result<(), C::Error> {
+        self.clock.set_tai(tai_offset)
+    }
+
     pub fn preferred_poll_interval(&self) -> PollInterval {
         self.preferred_poll_interval
     }
 
+    /// Only step the clock by offsets between the lowest and highest offset in `bounds`, e.g.
+    /// because a coarse but trusted source of time puts our clock there. A step outside of the
+    /// bounds is more likely caused by broken or malicious servers than by our clock being that
+    /// far off, so the update is ignored instead. Bounds with the lowest offset above the highest,
+    /// e.g. from trusted sources that disagree, allow no step at all.
+    pub fn set_step_bounds(&mut self, bounds: Option<(NtpDuration, NtpDuration)>) {
+        self.step_bounds = bounds;
+    }
+
+    fn step_allowed(&self, offset: NtpDuration) -> bool {
+        match self.step_bounds {
+            Some((lowest, highest)) => lowest <= offset && offset <= highest,
+            None => true,
+        }
+    }
+
+    /// Step the clock by `offset` before the time of any peer was used, e.g. because our clock is
+    /// too far off to validate the certificates of NTS servers. Does nothing once the clock has
+    /// been stepped or slewed with the time of peers. Like the first update, the step is limited
+    /// by the startup panic threshold of `config`.
+    pub fn seed(&mut self, config: &SystemConfig, offset: NtpDuration) -> ClockUpdateResult {
+        match self.state {
+            ClockState::StartupBlank | ClockState::StartupFreq => {
+                if self.offset_too_large(config, offset) {
+                    error!("Detected overly large offset");
+                    return ClockUpdateResult::Panic;
+                }
+
+                info!(offset = debug(offset), "Seeding clock");
+                // It is reasonable to panic here, as there is very little we can
+                // be expected to do if the clock is not amenable to change
+                self.clock.step_clock(offset).expect("Unable to step clock");
+                ClockUpdateResult::Step
+            }
+            _ => ClockUpdateResult::Ignore,
+        }
+    }
+
     fn offset_too_large(&self, config: &SystemConfig, offset: NtpDuration) -> bool {
         let threshold = match self.state {
             // The system might be wildly off on startup
@@ -302,13 +472,16 @@ mod tests {
         last_max_error: RefCell<Option<NtpDuration>>,
         last_poll_interval: RefCell<Option<PollInterval>>,
         last_leap_status: RefCell<Option<NtpLeapIndicator>>,
+        now: RefCell<Option<NtpTimestamp>>,
     }
 
     impl NtpClock for TestClock {
         type Error = std::io::Error;
 
         fn now(&self) -> std::result::Result<NtpTimestamp, Self::Error> {
-            Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+            self.now
+                .borrow()
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::Unsupported))
         }
 
         fn set_freq(&self, freq: f64) -> Result<(), Self::Error> {
@@ -316,6 +489,10 @@ mod tests {
             Ok(())
         }
 
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            Ok(self.last_freq.borrow().unwrap_or_default())
+        }
+
         fn step_clock(&self, offset: NtpDuration) -> Result<(), Self::Error> {
             *self.last_offset.borrow_mut() = Some(offset);
             Ok(())
@@ -336,6 +513,10 @@ mod tests {
             *self.last_leap_status.borrow_mut() = Some(leap_status);
             Ok(())
         }
+
+        fn set_tai(&self, _tai_offset: i32) -> Result<(), Self::Error> {
+            Ok(())
+        }
     }
 
     #[test]
@@ -351,6 +532,9 @@ mod tests {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
         };
 
         let ref_interval = controller.preferred_poll_interval;
@@ -471,6 +655,9 @@ mod tests {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
         };
 
         controller.update(
@@ -490,6 +677,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_with_frequency() {
+        let base = NtpInstant::now();
+        let config = SystemConfig::default();
+
+        let mut controller = ClockController::with_frequency(TestClock::default(), 1e-5);
+        assert_eq!(*controller.clock.last_freq.borrow(), Some(1e-5));
+        assert_eq!(controller.frequency().unwrap(), None);
+
+        // no frequency measurement is needed
+        controller.update(
+            &config,
+            NtpDuration::from_seconds(0.001),
+            NtpDuration::from_seconds(0.01),
+            NtpDuration::from_seconds(0.02),
+            NtpDuration::from_seconds(0.03),
+            NtpLeapIndicator::NoWarning,
+            base + Duration::from_secs(1),
+        );
+
+        assert_eq!(controller.state, ClockState::Sync);
+        assert_eq!(controller.frequency().unwrap(), Some(1e-5));
+    }
+
     #[test]
     fn test_spike_rejection() {
         let base = NtpInstant::now();
@@ -502,6 +713,9 @@ mod tests {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
         };
 
         controller.update(
@@ -546,6 +760,9 @@ mod tests {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
         };
 
         controller.update(
@@ -590,6 +807,9 @@ mod tests {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
         };
 
         assert_eq!(
@@ -612,6 +832,9 @@ mod tests {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
         };
 
         assert_eq!(
@@ -634,6 +857,9 @@ mod tests {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
         };
 
         assert_eq!(
@@ -656,6 +882,9 @@ mod tests {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
         };
 
         assert_eq!(
@@ -678,6 +907,9 @@ mod tests {
             preferred_poll_interval: PollInterval::MIN,
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
         };
 
         assert_eq!(
@@ -693,4 +925,201 @@ mod tests {
             ClockUpdateResult::Step
         );
     }
+
+    #[test]
+    fn test_step_bounds() {
+        let mut controller = ClockController::new(TestClock::default());
+        let config = SystemConfig::default();
+        let base = controller.last_update_time;
+
+        // a trusted source puts our clock 9 to 11 seconds behind
+        controller.set_step_bounds(Some((
+            NtpDuration::from_seconds(9.0),
+            NtpDuration::from_seconds(11.0),
+        )));
+
+        assert_eq!(
+            controller.update(
+                &config,
+                NtpDuration::from_seconds(-20.0),
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(1),
+            ),
+            ClockUpdateResult::Ignore
+        );
+        assert_eq!(*controller.clock.last_offset.borrow(), None);
+
+        assert_eq!(
+            controller.update(
+                &config,
+                NtpDuration::from_seconds(10.0),
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(2),
+            ),
+            ClockUpdateResult::Step
+        );
+        assert_eq!(
+            *controller.clock.last_offset.borrow(),
+            Some(NtpDuration::from_seconds(10.0))
+        );
+
+        // slews are not restricted
+        controller.state = ClockState::Sync;
+        assert_eq!(
+            controller.update(
+                &config,
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(3),
+            ),
+            ClockUpdateResult::Slew
+        );
+
+        // trusted sources that disagree allow no step at all
+        controller.set_step_bounds(Some((
+            NtpDuration::from_seconds(11.0),
+            NtpDuration::from_seconds(9.0),
+        )));
+        assert_eq!(
+            controller.update(
+                &config,
+                NtpDuration::from_seconds(10.0),
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(4),
+            ),
+            ClockUpdateResult::Ignore
+        );
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut controller = ClockController::new(TestClock::default());
+        let config = SystemConfig::default();
+        let base = controller.last_update_time;
+
+        // a step beyond the startup panic threshold is refused
+        let strict = SystemConfig {
+            startup_panic_threshold: Some(NtpDuration::from_seconds(1800.0)),
+            ..config
+        };
+        assert_eq!(
+            controller.seed(&strict, NtpDuration::from_seconds(3600.0)),
+            ClockUpdateResult::Panic
+        );
+        assert_eq!(*controller.clock.last_offset.borrow(), None);
+
+        assert_eq!(
+            controller.seed(&config, NtpDuration::from_seconds(3600.0)),
+            ClockUpdateResult::Step
+        );
+        assert_eq!(
+            *controller.clock.last_offset.borrow(),
+            Some(NtpDuration::from_seconds(3600.0))
+        );
+
+        // the first measurement of a peer still steps the clock
+        assert_eq!(
+            controller.update(
+                &config,
+                NtpDuration::from_seconds(0.5),
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(1),
+            ),
+            ClockUpdateResult::Step
+        );
+
+        // after which seeding would only make things worse
+        assert_eq!(
+            controller.seed(&config, NtpDuration::from_seconds(3600.0)),
+            ClockUpdateResult::Ignore
+        );
+        assert_eq!(
+            *controller.clock.last_offset.borrow(),
+            Some(NtpDuration::from_seconds(0.5))
+        );
+    }
+
+    #[test]
+    fn test_leap_smear() {
+        let base = NtpInstant::now();
+
+        let config = SystemConfig {
+            leap_smear: Some(LeapSmearConfig {
+                mode: crate::LeapSmearMode::Linear,
+                window: NtpDuration::from_seconds(1000.),
+            }),
+            ..Default::default()
+        };
+
+        let mut controller = ClockController {
+            clock: TestClock::default(),
+            state: ClockState::Sync,
+            last_update_time: base,
+            preferred_poll_interval: PollInterval::MIN,
+            poll_interval_counter: 0,
+            offset: NtpDuration::from_fixed_int(0),
+            leap_smear: None,
+            leap_smear_offset: NtpDuration::ZERO,
+            step_bounds: None,
+        };
+
+        // 1 January 2017
+        let leap = 3692217600;
+        let set_now = |controller: &ClockController<TestClock>, seconds: u32| {
+            *controller.clock.now.borrow_mut() =
+                Some(NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0));
+        };
+        let update = |controller: &mut ClockController<TestClock>, offset: f64| {
+            controller.update(
+                &config,
+                NtpDuration::from_seconds(offset),
+                NtpDuration::from_seconds(0.001),
+                NtpDuration::from_seconds(0.001),
+                NtpDuration::from_seconds(0.001),
+                NtpLeapIndicator::Leap61,
+                base,
+            )
+        };
+
+        // the smear starts 100 seconds late, from our current time
+        set_now(&controller, leap - 400);
+        assert_eq!(update(&mut controller, 0.0), ClockUpdateResult::Slew);
+        assert_eq!(
+            *controller.clock.last_leap_status.borrow(),
+            Some(NtpLeapIndicator::NoWarning)
+        );
+        assert!(controller.leap_smear_status().is_some());
+
+        // a third into the smear, we steer towards a third of a second behind UTC
+        set_now(&controller, leap - 100);
+        assert_eq!(update(&mut controller, 0.3), ClockUpdateResult::Slew);
+        let offset = controller.clock.last_offset.borrow().unwrap().to_seconds();
+        assert!((offset + 0.3 / 9.0).abs() < 1e-3);
+
+        set_now(&controller, leap);
+        assert_eq!(update(&mut controller, 0.3), ClockUpdateResult::Leap);
+
+        // after the leap second our clock is ahead of UTC until the smear is done
+        let status = controller.leap_smear_status().unwrap();
+        assert!(status.offset < NtpDuration::ZERO);
+
+        set_now(&controller, leap + 600);
+        assert_eq!(update(&mut controller, 0.0), ClockUpdateResult::Slew);
+        assert!(controller.leap_smear_status().is_none());
+    }
 }
@@ -1,3 +1,4 @@
+use crate::packet::NtpLeapIndicator;
 use crate::peer::PeerSnapshot;
 use crate::time_types::{FrequencyTolerance, NtpInstant};
 use crate::{NtpDuration, PollInterval, SystemConfig};
@@ -10,6 +11,11 @@ pub struct FilterAndCombine {
     pub system_root_delay: NtpDuration,
     pub system_root_dispersion: NtpDuration,
     pub system_peer_snapshot: PeerSnapshot,
+    /// The leap second announced by a majority of the survivors, if any
+    pub leap_indicator: NtpLeapIndicator,
+    /// Indices (into the given peers) of the peers whose offset lies outside of the interval
+    /// that the majority of peers agrees on
+    pub falsetickers: Vec<usize>,
 }
 
 impl FilterAndCombine {
@@ -57,6 +63,8 @@ impl FilterAndCombine {
             system_root_delay: root_delay,
             system_root_dispersion: root_dispersion,
             system_peer_snapshot,
+            leap_indicator: leap_vote(&selection.survivors),
+            falsetickers: selection.falsetickers,
         })
     }
 
@@ -113,9 +121,26 @@ impl FilterAndCombine {
     }
 }
 
+/// Only announce a leap second when more than half of the survivors announce it, so a single
+/// misbehaving server cannot make us insert or delete a second.
+fn leap_vote(survivors: &[SurvivorTuple]) -> NtpLeapIndicator {
+    let votes = |leap_indicator| {
+        survivors
+            .iter()
+            .filter(|survivor| survivor.peer.leap_indicator == leap_indicator)
+            .count()
+    };
+
+    [NtpLeapIndicator::Leap61, NtpLeapIndicator::Leap59]
+        .into_iter()
+        .find(|leap_indicator| 2 * votes(*leap_indicator) > survivors.len())
+        .unwrap_or(NtpLeapIndicator::NoWarning)
+}
+
 struct ClockSelect<'a> {
     survivors: Vec<SurvivorTuple<'a>>,
     system_selection_jitter: NtpDuration,
+    falsetickers: Vec<usize>,
 }
 
 #[instrument(skip(config, local_clock_time, system_poll))]
@@ -145,12 +170,29 @@ fn clock_select<'a>(
         return None;
     }
 
+    // must be determined before clustering, which also removes truechimers
+    let falsetickers = candidates
+        .iter()
+        .filter(|candidate| candidate.endpoint_type == EndpointType::Middle)
+        .filter(|candidate| {
+            !survivors
+                .iter()
+                .any(|survivor| std::ptr::eq(survivor.peer, candidate.peer))
+        })
+        .filter_map(|candidate| {
+            peers
+                .iter()
+                .position(|peer| std::ptr::eq(peer, candidate.peer))
+        })
+        .collect();
+
     let system_selection_jitter =
         NtpDuration::from_seconds(cluster_algorithm(config, &mut survivors));
 
     Some(ClockSelect {
         survivors,
         system_selection_jitter,
+        falsetickers,
     })
 }
 
@@ -1320,6 +1362,8 @@ mod test {
                 NtpDuration::ZERO,
                 NtpDuration::ZERO,
             ),
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            falsetickers: vec![],
         };
 
         let frequency_tolerance = FrequencyTolerance::ppm(15);
@@ -1439,4 +1483,69 @@ mod test {
         assert!(result.system_root_dispersion > NtpDuration::from_seconds(0.001));
         assert!(result.system_root_delay > baseline_result.system_root_delay);
     }
+
+    #[test]
+    fn test_falsetickers() {
+        let base = NtpInstant::now();
+        let config = SystemConfig::default();
+
+        let peer = |offset| {
+            peer_snapshot(
+                PeerStatistics {
+                    offset: NtpDuration::from_seconds(offset),
+                    delay: NtpDuration::from_seconds(0.01),
+                    dispersion: NtpDuration::from_seconds(0.01),
+                    jitter: 0.0,
+                },
+                base,
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.01),
+            )
+        };
+
+        let peers = [peer(0.0), peer(5.0), peer(0.001), peer(0.002)];
+        let result = FilterAndCombine::run(&config, &peers, base, PollInterval::MIN).unwrap();
+        assert_eq!(result.falsetickers, vec![1]);
+
+        let result = FilterAndCombine::run(&config, &peers[2..], base, PollInterval::MIN).unwrap();
+        assert!(result.falsetickers.is_empty());
+    }
+
+    #[test]
+    fn test_leap_vote() {
+        let base = NtpInstant::now();
+        let config = SystemConfig::default();
+
+        let peer = |leap_indicator| PeerSnapshot {
+            leap_indicator,
+            ..peer_snapshot(
+                PeerStatistics {
+                    offset: NtpDuration::from_seconds(0.0),
+                    delay: NtpDuration::from_seconds(0.01),
+                    dispersion: NtpDuration::from_seconds(0.01),
+                    jitter: 0.0,
+                },
+                base,
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.01),
+            )
+        };
+
+        // a single server cannot schedule a leap second
+        let peers = [
+            peer(NtpLeapIndicator::Leap61),
+            peer(NtpLeapIndicator::NoWarning),
+            peer(NtpLeapIndicator::NoWarning),
+        ];
+        let result = FilterAndCombine::run(&config, &peers, base, PollInterval::MIN).unwrap();
+        assert_eq!(result.leap_indicator, NtpLeapIndicator::NoWarning);
+
+        let peers = [
+            peer(NtpLeapIndicator::Leap59),
+            peer(NtpLeapIndicator::Leap59),
+            peer(NtpLeapIndicator::NoWarning),
+        ];
+        let result = FilterAndCombine::run(&config, &peers, base, PollInterval::MIN).unwrap();
+        assert_eq!(result.leap_indicator, NtpLeapIndicator::Leap59);
+    }
 }
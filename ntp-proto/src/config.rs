@@ -1,4 +1,4 @@
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::{time_types::FrequencyTolerance, NtpDuration};
 
@@ -14,6 +14,20 @@ where
     })
 }
 
+fn deserialize_option_stratum<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let stratum: u8 = Deserialize::deserialize(deserializer)?;
+    if (1..16).contains(&stratum) {
+        Ok(Some(stratum))
+    } else {
+        Err(serde::de::Error::custom(
+            "stratum must be between 1 and 15 (inclusive)",
+        ))
+    }
+}
+
 #[derive(Deserialize, Debug, Clone, Copy)]
 #[serde(rename_all = "kebab-case")]
 pub struct SystemConfig {
@@ -78,6 +92,42 @@ pub struct SystemConfig {
     /// is known to be reasonable on startup
     #[serde(deserialize_with = "deserialize_option_threshold", default)]
     pub startup_panic_threshold: Option<NtpDuration>,
+
+    /// Spread leap seconds out over a window of time by slewing the clock, instead of letting
+    /// the kernel insert or delete a second. While smearing, the clock (and so the time we
+    /// serve) is intentionally off from UTC by up to a second.
+    #[serde(default)]
+    pub leap_smear: Option<LeapSmearConfig>,
+
+    /// Stratum at which we serve our own time when none of our peers can be used. Daemons in
+    /// orphan mode elect a leader among each other, so that they agree on the time even
+    /// without access to any upstream server.
+    #[serde(deserialize_with = "deserialize_option_stratum", default)]
+    pub orphan_stratum: Option<u8>,
+
+    /// Stratum at which we serve the time of our own clock right away when none of our peers can
+    /// be used, without electing a leader. Ignored when `orphan_stratum` is set.
+    #[serde(deserialize_with = "deserialize_option_stratum", default)]
+    pub local_stratum: Option<u8>,
+}
+
+/// How the smeared clock moves from the old to the new UTC time
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LeapSmearMode {
+    /// At a constant rate
+    Linear,
+    /// Slowly at the start and end of the window, fastest around the leap second itself
+    Cosine,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub struct LeapSmearConfig {
+    pub mode: LeapSmearMode,
+    /// Length of the smear, centered on the leap second
+    #[serde(default = "default_leap_smear_window")]
+    pub window: NtpDuration,
 }
 
 impl Default for SystemConfig {
@@ -93,6 +143,9 @@ impl Default for SystemConfig {
             spike_threshold: default_spike_threshold(),
             panic_threshold: default_panic_threshold(),
             startup_panic_threshold: None,
+            leap_smear: None,
+            orphan_stratum: None,
+            local_stratum: None,
         }
     }
 }
@@ -124,3 +177,7 @@ fn default_spike_threshold() -> NtpDuration {
 fn default_panic_threshold() -> Option<NtpDuration> {
     Some(NtpDuration::from_seconds(1000.))
 }
+
+fn default_leap_smear_window() -> NtpDuration {
+    NtpDuration::from_seconds(86400.)
+}
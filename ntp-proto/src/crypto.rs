@@ -0,0 +1,321 @@
+use aes_siv::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128SivAead, Key, Nonce,
+};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+/// Identifier of AEAD_AES_SIV_CMAC_256 in the IANA AEAD algorithm registry.
+/// This is the only algorithm that we support for NTS.
+pub const AEAD_AES_SIV_CMAC_256: u16 = 15;
+
+/// The TLS exporter label defined in rfc8915, section 5.1
+const NTS_EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecryptError;
+
+impl std::fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("could not decrypt or authenticate data")
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// The AEAD_AES_SIV_CMAC_256 algorithm from rfc5297
+#[derive(Clone)]
+pub struct AesSivCmac256 {
+    key: Key<Aes128SivAead>,
+}
+
+// Never print the key material
+impl std::fmt::Debug for AesSivCmac256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AesSivCmac256").finish_non_exhaustive()
+    }
+}
+
+impl AesSivCmac256 {
+    pub const KEY_SIZE: usize = 32;
+    pub const NONCE_SIZE: usize = 16;
+
+    pub fn new(key: [u8; Self::KEY_SIZE]) -> Self {
+        AesSivCmac256 { key: key.into() }
+    }
+
+    pub fn key_bytes(&self) -> [u8; Self::KEY_SIZE] {
+        self.key.into()
+    }
+
+    /// Encrypt `plaintext` with a fresh random nonce. Returns the nonce and the ciphertext.
+    pub fn encrypt(&self, plaintext: &[u8], associated_data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let nonce: [u8; Self::NONCE_SIZE] = thread_rng().gen();
+
+        let cipher = Aes128SivAead::new(&self.key);
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: associated_data,
+                },
+            )
+            // encryption can only fail on absurdly large inputs
+            .expect("encryption failed");
+
+        (nonce.to_vec(), ciphertext)
+    }
+
+    pub fn decrypt(
+        &self,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, DecryptError> {
+        if nonce.len() != Self::NONCE_SIZE {
+            return Err(DecryptError);
+        }
+
+        let cipher = Aes128SivAead::new(&self.key);
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| DecryptError)
+    }
+}
+
+/// The keys used to protect NTP packets of a single NTS association
+#[derive(Debug, Clone)]
+pub struct NtsKeys {
+    /// Protects messages from the client to the server
+    pub c2s: AesSivCmac256,
+    /// Protects messages from the server to the client
+    pub s2c: AesSivCmac256,
+}
+
+impl NtsKeys {
+    /// Derive the keys from the TLS session of an NTS key exchange.
+    ///
+    /// The `export` function must implement the TLS exporter (rfc5705): fill the output buffer
+    /// with keying material for the given label and context.
+    pub fn extract<E>(
+        mut export: impl FnMut(&mut [u8], &[u8], &[u8]) -> Result<(), E>,
+    ) -> Result<Self, E> {
+        // context: protocol id (0 for NTPv4), AEAD algorithm id, and then 0 for the
+        // client-to-server and 1 for the server-to-client key.
+        let [alg_hi, alg_lo] = AEAD_AES_SIV_CMAC_256.to_be_bytes();
+
+        let mut c2s = [0; AesSivCmac256::KEY_SIZE];
+        export(&mut c2s, NTS_EXPORTER_LABEL, &[0, 0, alg_hi, alg_lo, 0])?;
+
+        let mut s2c = [0; AesSivCmac256::KEY_SIZE];
+        export(&mut s2c, NTS_EXPORTER_LABEL, &[0, 0, alg_hi, alg_lo, 1])?;
+
+        Ok(NtsKeys {
+            c2s: AesSivCmac256::new(c2s),
+            s2c: AesSivCmac256::new(s2c),
+        })
+    }
+}
+
+/// Number of cookie keys kept by a server, including the one used for new cookies
+const KEY_HISTORY: usize = 3;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CookieKey {
+    id: u32,
+    key: [u8; AesSivCmac256::KEY_SIZE],
+}
+
+/// The keys a server uses to encrypt its NTS cookies.
+///
+/// New cookies are always encrypted with the newest key. After a rotation, the previous keys are
+/// kept for a while, so that clients can still use the cookies they were given earlier.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeySet {
+    // Invariant: never empty, ordered from oldest to newest
+    keys: Vec<CookieKey>,
+}
+
+// Never print the key material
+impl std::fmt::Debug for KeySet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeySet")
+            .field("ids", &self.keys.iter().map(|k| k.id).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl KeySet {
+    pub fn new() -> Self {
+        KeySet {
+            keys: vec![CookieKey {
+                id: thread_rng().gen(),
+                key: thread_rng().gen(),
+            }],
+        }
+    }
+
+    /// Start encrypting new cookies with a fresh key, forgetting the oldest key if needed
+    pub fn rotate(&mut self) {
+        let id = self.newest().id.wrapping_add(1);
+        self.keys.push(CookieKey {
+            id,
+            key: thread_rng().gen(),
+        });
+
+        if self.keys.len() > KEY_HISTORY {
+            self.keys.remove(0);
+        }
+    }
+
+    fn newest(&self) -> &CookieKey {
+        self.keys.last().expect("key set is never empty")
+    }
+
+    /// Encrypt the keys of an association into a cookie for the client to store
+    pub fn encode_cookie(&self, keys: &NtsKeys) -> Vec<u8> {
+        let cookie_key = self.newest();
+        let id = cookie_key.id.to_be_bytes();
+
+        let mut plaintext = Vec::with_capacity(2 * AesSivCmac256::KEY_SIZE);
+        plaintext.extend_from_slice(&keys.c2s.key_bytes());
+        plaintext.extend_from_slice(&keys.s2c.key_bytes());
+
+        let (nonce, ciphertext) = AesSivCmac256::new(cookie_key.key).encrypt(&plaintext, &id);
+
+        let mut cookie = id.to_vec();
+        cookie.extend_from_slice(&nonce);
+        cookie.extend_from_slice(&ciphertext);
+        cookie
+    }
+
+    /// Recover the keys of an association from a cookie made by [`KeySet::encode_cookie`]
+    pub fn decode_cookie(&self, cookie: &[u8]) -> Result<NtsKeys, DecryptError> {
+        if cookie.len() < 4 + AesSivCmac256::NONCE_SIZE {
+            return Err(DecryptError);
+        }
+
+        let (id, rest) = cookie.split_at(4);
+        let (nonce, ciphertext) = rest.split_at(AesSivCmac256::NONCE_SIZE);
+
+        let cookie_key = self
+            .keys
+            .iter()
+            .find(|k| k.id.to_be_bytes() == id)
+            .ok_or(DecryptError)?;
+
+        let plaintext = AesSivCmac256::new(cookie_key.key).decrypt(nonce, ciphertext, id)?;
+        if plaintext.len() != 2 * AesSivCmac256::KEY_SIZE {
+            return Err(DecryptError);
+        }
+
+        let (c2s, s2c) = plaintext.split_at(AesSivCmac256::KEY_SIZE);
+        Ok(NtsKeys {
+            c2s: AesSivCmac256::new(c2s.try_into().unwrap()),
+            s2c: AesSivCmac256::new(s2c.try_into().unwrap()),
+        })
+    }
+}
+
+impl Default for KeySet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt_roundtrip() {
+        let cipher = AesSivCmac256::new([7; 32]);
+
+        let (nonce, ciphertext) = cipher.encrypt(b"some plaintext", b"header");
+        assert_eq!(nonce.len(), AesSivCmac256::NONCE_SIZE);
+        // the synthetic iv is prepended to the ciphertext
+        assert_eq!(ciphertext.len(), 14 + 16);
+
+        let plaintext = cipher.decrypt(&nonce, &ciphertext, b"header").unwrap();
+        assert_eq!(plaintext, b"some plaintext");
+
+        // tampering with any input must be detected
+        assert!(cipher.decrypt(&nonce, &ciphertext, b"Header").is_err());
+        assert!(cipher.decrypt(&nonce[1..], &ciphertext, b"header").is_err());
+        let mut tampered = ciphertext.clone();
+        tampered[20] ^= 1;
+        assert!(cipher.decrypt(&nonce, &tampered, b"header").is_err());
+
+        let other = AesSivCmac256::new([8; 32]);
+        assert!(other.decrypt(&nonce, &ciphertext, b"header").is_err());
+    }
+
+    #[test]
+    fn test_key_extraction_context() {
+        let keys = NtsKeys::extract(|out: &mut [u8], label: &[u8], context: &[u8]| {
+            assert_eq!(label, b"EXPORTER-network-time-security");
+            assert_eq!(&context[..4], &[0, 0, 0, 15]);
+            out.fill(context[4] + 1);
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+
+        assert_eq!(keys.c2s.key_bytes(), [1; 32]);
+        assert_eq!(keys.s2c.key_bytes(), [2; 32]);
+    }
+
+    #[test]
+    fn test_cookie_roundtrip() {
+        let keys = NtsKeys {
+            c2s: AesSivCmac256::new([1; 32]),
+            s2c: AesSivCmac256::new([2; 32]),
+        };
+
+        let mut keyset = KeySet::new();
+        let cookie = keyset.encode_cookie(&keys);
+        // key id, nonce, two keys and the synthetic iv
+        assert_eq!(cookie.len(), 4 + 16 + 64 + 16);
+
+        let decoded = keyset.decode_cookie(&cookie).unwrap();
+        assert_eq!(decoded.c2s.key_bytes(), [1; 32]);
+        assert_eq!(decoded.s2c.key_bytes(), [2; 32]);
+
+        let mut tampered = cookie.clone();
+        tampered[30] ^= 1;
+        assert!(keyset.decode_cookie(&tampered).is_err());
+        assert!(keyset.decode_cookie(&cookie[..10]).is_err());
+
+        // cookies stay valid for a few rotations
+        for _ in 1..KEY_HISTORY {
+            keyset.rotate();
+            assert!(keyset.decode_cookie(&cookie).is_ok());
+            assert_ne!(keyset.encode_cookie(&keys)[..4], cookie[..4]);
+        }
+
+        keyset.rotate();
+        assert!(keyset.decode_cookie(&cookie).is_err());
+    }
+
+    #[test]
+    fn test_keyset_serialization() {
+        let keys = NtsKeys {
+            c2s: AesSivCmac256::new([1; 32]),
+            s2c: AesSivCmac256::new([2; 32]),
+        };
+
+        let mut keyset = KeySet::new();
+        keyset.rotate();
+        let cookie = keyset.encode_cookie(&keys);
+
+        let serialized = serde_json::to_string(&keyset).unwrap();
+        let restored: KeySet = serde_json::from_str(&serialized).unwrap();
+        assert!(restored.decode_cookie(&cookie).is_ok());
+    }
+}
@@ -92,6 +92,49 @@ impl FilterTuple {
             time: local_clock_time,
         }
     }
+
+    /// The logic for a broadcast association. Broadcast packets only carry the time at which the
+    /// server sent them, so their `delay` must be known from earlier client/server exchanges
+    /// with the same server.
+    pub(crate) fn from_packet_broadcast(
+        packet: &NtpHeader,
+        system_precision: NtpDuration,
+        local_clock_time: NtpInstant,
+        delay: NtpDuration,
+        destination_timestamp: NtpTimestamp,
+    ) -> Self {
+        debug_assert_eq!(packet.mode, NtpAssociationMode::Broadcast);
+
+        let packet_precision = NtpDuration::from_exponent(packet.precision);
+
+        // the packet spent half the round trip delay on its way to us
+        let offset = packet.transmit_timestamp - destination_timestamp + delay / 2i64;
+        let dispersion = packet_precision + system_precision;
+
+        Self {
+            offset,
+            delay,
+            dispersion,
+            time: local_clock_time,
+        }
+    }
+
+    /// The logic for a reference clock, which tells us its time at a moment that we know the
+    /// time of our own clock of as well. There is no network in between, so there is no delay.
+    pub(crate) fn from_refclock(
+        precision: i8,
+        system_precision: NtpDuration,
+        local_clock_time: NtpInstant,
+        reference_time: NtpTimestamp,
+        local_time: NtpTimestamp,
+    ) -> Self {
+        Self {
+            offset: reference_time - local_time,
+            delay: NtpDuration::ZERO,
+            dispersion: NtpDuration::from_exponent(precision) + system_precision,
+            time: local_clock_time,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -520,4 +563,25 @@ mod test {
         assert_eq!(result.delay, NtpDuration::from_fixed_int(1));
         assert!(result.dispersion >= NtpDuration::from_fixed_int(0));
     }
+
+    #[test]
+    fn test_tuple_from_packet_broadcast() {
+        let instant = NtpInstant::now();
+
+        let mut packet = NtpHeader::new();
+        packet.mode = NtpAssociationMode::Broadcast;
+        packet.transmit_timestamp = NtpTimestamp::from_fixed_int(10);
+        packet.precision = -32;
+
+        let result = FilterTuple::from_packet_broadcast(
+            &packet,
+            NtpDuration::from_exponent(-32),
+            instant,
+            NtpDuration::from_fixed_int(4),
+            NtpTimestamp::from_fixed_int(13),
+        );
+        assert_eq!(result.offset, NtpDuration::from_fixed_int(-1));
+        assert_eq!(result.delay, NtpDuration::from_fixed_int(4));
+        assert!(result.dispersion >= NtpDuration::from_fixed_int(0));
+    }
 }
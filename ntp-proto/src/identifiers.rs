@@ -3,7 +3,7 @@ use std::net::IpAddr;
 use md5::{Digest, Md5};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct ReferenceId(u32);
 
 impl ReferenceId {
@@ -11,6 +11,20 @@ impl ReferenceId {
     pub const KISS_DENY: ReferenceId = ReferenceId(u32::from_be_bytes(*b"DENY"));
     pub const KISS_RATE: ReferenceId = ReferenceId(u32::from_be_bytes(*b"RATE"));
     pub const KISS_RSTR: ReferenceId = ReferenceId(u32::from_be_bytes(*b"RSTR"));
+    // Note: defined in rfc8915, sent when the server cannot decrypt our NTS cookie
+    pub const KISS_NTSN: ReferenceId = ReferenceId(u32::from_be_bytes(*b"NTSN"));
+
+    /// Reference id used before we have a system peer
+    pub const NONE: ReferenceId = ReferenceId(0);
+    /// Reference id used while we serve the time of our own, undisciplined clock
+    pub const LOCAL: ReferenceId = ReferenceId(u32::from_be_bytes(*b"LOCL"));
+    // Note: the reference id of the shared memory driver of the NTP reference implementation
+    pub const SHM: ReferenceId = ReferenceId(u32::from_be_bytes(*b"SHM\0"));
+    // Note: the reference id of the socket driver of chrony
+    pub const SOCK: ReferenceId = ReferenceId(u32::from_be_bytes(*b"SOCK"));
+    // Note: the reference ids of the NMEA and gpsd drivers of the NTP reference implementation
+    pub const NMEA: ReferenceId = ReferenceId(u32::from_be_bytes(*b"GPS\0"));
+    pub const GPSD: ReferenceId = ReferenceId(u32::from_be_bytes(*b"GPSD"));
 
     pub fn from_ip(addr: IpAddr) -> ReferenceId {
         match addr {
@@ -37,6 +51,15 @@ impl ReferenceId {
         *self == Self::KISS_RSTR
     }
 
+    pub(crate) fn is_ntsn(&self) -> bool {
+        *self == Self::KISS_NTSN
+    }
+
+    /// Kiss codes are four printable ascii characters
+    pub(crate) fn is_kiss_code(&self) -> bool {
+        self.to_bytes().iter().all(u8::is_ascii_graphic)
+    }
+
     pub(crate) fn to_bytes(self) -> [u8; 4] {
         self.0.to_be_bytes()
     }
@@ -73,6 +96,10 @@ mod tests {
         let a = [b'D', b'E', b'N', b'Y'];
         let b = ReferenceId::from_bytes(a);
         assert!(b.is_deny());
+
+        let a = [b'N', b'T', b'S', b'N'];
+        let b = ReferenceId::from_bytes(a);
+        assert!(b.is_ntsn());
     }
 
     #[test]
@@ -0,0 +1,310 @@
+use sha1::{Digest, Sha1};
+
+use crate::{NtpDuration, NtpLeapIndicator, NtpTimestamp};
+
+/// Leap seconds are announced during the last day before they take effect, which is how long the
+/// kernel keeps an announced leap second pending before inserting it at midnight UTC.
+const ANNOUNCE_SECONDS: i64 = 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeapSecondsError {
+    /// A line that is neither a comment nor a `<time> <tai offset>` entry
+    Syntax { line: usize },
+    /// The file does not state when it expires
+    MissingExpiry,
+    /// The hash line does not contain five hexadecimal words
+    InvalidHash { line: usize },
+    /// The contents of the file do not match its hash
+    HashMismatch,
+}
+
+impl std::fmt::Display for LeapSecondsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeapSecondsError::Syntax { line } => {
+                write!(
+                    f,
+                    "leap seconds file line {line}: expected `<time> <tai offset>`"
+                )
+            }
+            LeapSecondsError::MissingExpiry => {
+                f.write_str("leap seconds file does not have an expiration date")
+            }
+            LeapSecondsError::InvalidHash { line } => {
+                write!(f, "leap seconds file line {line}: invalid hash")
+            }
+            LeapSecondsError::HashMismatch => {
+                f.write_str("leap seconds file does not match its hash")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LeapSecondsError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LeapSecond {
+    /// The moment from which `tai_offset` applies
+    time: NtpTimestamp,
+    /// The difference between TAI and UTC in seconds
+    tai_offset: i32,
+}
+
+/// The leap seconds as published by the IETF and NIST in the `leap-seconds.list` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeapSecondsList {
+    updated: Option<NtpTimestamp>,
+    expires: NtpTimestamp,
+    leap_seconds: Vec<LeapSecond>,
+    hashed: bool,
+}
+
+/// Feed the digits of `data` up to the first comment into the hash, as the reference
+/// implementation does. All other characters are ignored.
+fn hash_digits(hasher: &mut Sha1, data: &str) {
+    let content = data.split('#').next().unwrap_or_default();
+    for digit in content.bytes().filter(u8::is_ascii_digit) {
+        hasher.update([digit]);
+    }
+}
+
+fn parse_timestamp(value: &str) -> Option<NtpTimestamp> {
+    let seconds = value.trim().parse().ok()?;
+    Some(NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0))
+}
+
+impl LeapSecondsList {
+    /// Parse the contents of a `leap-seconds.list` file. Times in this file are given in seconds
+    /// since the start of the NTP epoch. Besides the leap seconds themselves, it contains the time
+    /// of its last update (`#$`), its expiration date (`#@`) and a SHA-1 hash of its contents
+    /// (`#h`). The hash is verified when present.
+    pub fn parse(contents: &str) -> Result<Self, LeapSecondsError> {
+        let mut hasher = Sha1::new();
+        let mut updated = None;
+        let mut expires = None;
+        let mut hash = None;
+        let mut leap_seconds = Vec::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line_number = index + 1;
+            let syntax_error = LeapSecondsError::Syntax { line: line_number };
+
+            if let Some(value) = line.strip_prefix("#$") {
+                hash_digits(&mut hasher, value);
+                updated = Some(parse_timestamp(value).ok_or(syntax_error)?);
+            } else if let Some(value) = line.strip_prefix("#@") {
+                hash_digits(&mut hasher, value);
+                expires = Some(parse_timestamp(value).ok_or(syntax_error)?);
+            } else if let Some(value) = line.strip_prefix("#h") {
+                let words = value
+                    .split_whitespace()
+                    .map(|word| u32::from_str_radix(word, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| LeapSecondsError::InvalidHash { line: line_number })?;
+
+                if words.len() != 5 {
+                    return Err(LeapSecondsError::InvalidHash { line: line_number });
+                }
+
+                hash = Some(words);
+            } else if !line.starts_with('#') {
+                let content = line.split('#').next().unwrap_or_default();
+                let (time, tai_offset) = match content.split_whitespace().collect::<Vec<_>>()[..] {
+                    [] => continue,
+                    [time, tai_offset] => (time, tai_offset),
+                    _ => return Err(syntax_error),
+                };
+
+                hash_digits(&mut hasher, content);
+                leap_seconds.push(LeapSecond {
+                    time: parse_timestamp(time).ok_or(syntax_error)?,
+                    tai_offset: tai_offset.parse().map_err(|_| syntax_error)?,
+                });
+            }
+        }
+
+        let expires = expires.ok_or(LeapSecondsError::MissingExpiry)?;
+
+        let hashed = match hash {
+            Some(words) => {
+                let digest = hasher.finalize();
+                let expected = words.iter().flat_map(|word| word.to_be_bytes());
+                if !digest.iter().copied().eq(expected) {
+                    return Err(LeapSecondsError::HashMismatch);
+                }
+                true
+            }
+            None => false,
+        };
+
+        Ok(LeapSecondsList {
+            updated,
+            expires,
+            leap_seconds,
+            hashed,
+        })
+    }
+
+    /// Whether the file contained a hash. Files without one are accepted, but could be corrupted.
+    pub fn is_hashed(&self) -> bool {
+        self.hashed
+    }
+
+    pub fn updated(&self) -> Option<NtpTimestamp> {
+        self.updated
+    }
+
+    pub fn expires(&self) -> NtpTimestamp {
+        self.expires
+    }
+
+    /// An expired list may miss leap seconds that were announced after it was published
+    pub fn is_expired(&self, now: NtpTimestamp) -> bool {
+        self.expires - now <= NtpDuration::ZERO
+    }
+
+    /// The difference between TAI and UTC in seconds at `now`, if the list goes back that far
+    pub fn tai_offset(&self, now: NtpTimestamp) -> Option<i32> {
+        self.leap_seconds
+            .iter()
+            .rev()
+            .find(|leap_second| leap_second.time - now <= NtpDuration::ZERO)
+            .map(|leap_second| leap_second.tai_offset)
+    }
+
+    /// The leap indicator to use at `now`: a leap second is announced during the last day
+    /// before it takes effect.
+    pub fn leap_indicator(&self, now: NtpTimestamp) -> NtpLeapIndicator {
+        let announce = NtpDuration::ONE * ANNOUNCE_SECONDS;
+
+        for pair in self.leap_seconds.windows(2) {
+            let (previous, next) = (pair[0], pair[1]);
+            let remaining = next.time - now;
+
+            if remaining > NtpDuration::ZERO && remaining <= announce {
+                return match next.tai_offset.cmp(&previous.tai_offset) {
+                    std::cmp::Ordering::Greater => NtpLeapIndicator::Leap61,
+                    std::cmp::Ordering::Less => NtpLeapIndicator::Leap59,
+                    std::cmp::Ordering::Equal => NtpLeapIndicator::NoWarning,
+                };
+            }
+        }
+
+        NtpLeapIndicator::NoWarning
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The digits of the leap-seconds.list published on 7 July 2025. Comments do not count
+    // towards the hash, so all explanatory text is left out.
+    const LEAP_SECONDS_LIST: &str = "
+#$\t3960835200
+#@\t3991593600
+2272060800\t10\t# 1 Jan 1972
+2287785600\t11\t# 1 Jul 1972
+2303683200\t12\t# 1 Jan 1973
+2335219200\t13\t# 1 Jan 1974
+2366755200\t14\t# 1 Jan 1975
+2398291200\t15\t# 1 Jan 1976
+2429913600\t16\t# 1 Jan 1977
+2461449600\t17\t# 1 Jan 1978
+2492985600\t18\t# 1 Jan 1979
+2524521600\t19\t# 1 Jan 1980
+2571782400\t20\t# 1 Jul 1981
+2603318400\t21\t# 1 Jul 1982
+2634854400\t22\t# 1 Jul 1983
+2698012800\t23\t# 1 Jul 1985
+2776982400\t24\t# 1 Jan 1988
+2840140800\t25\t# 1 Jan 1990
+2871676800\t26\t# 1 Jan 1991
+2918937600\t27\t# 1 Jul 1992
+2950473600\t28\t# 1 Jul 1993
+2982009600\t29\t# 1 Jul 1994
+3029443200\t30\t# 1 Jan 1996
+3076704000\t31\t# 1 Jul 1997
+3124137600\t32\t# 1 Jan 1999
+3345062400\t33\t# 1 Jan 2006
+3439756800\t34\t# 1 Jan 2009
+3550089600\t35\t# 1 Jul 2012
+3644697600\t36\t# 1 Jul 2015
+3692217600\t37\t# 1 Jan 2017
+#h\t49db2447 571e5e1b 2f002a53 9c8da8e4 39b8e49e
+";
+
+    fn timestamp(seconds: u32) -> NtpTimestamp {
+        NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0)
+    }
+
+    #[test]
+    fn test_parse() {
+        let list = LeapSecondsList::parse(LEAP_SECONDS_LIST).unwrap();
+
+        assert!(list.is_hashed());
+        assert_eq!(list.updated(), Some(timestamp(3960835200)));
+        assert_eq!(list.expires(), timestamp(3991593600));
+        assert!(!list.is_expired(timestamp(3991593599)));
+        assert!(list.is_expired(timestamp(3991593600)));
+
+        assert_eq!(list.tai_offset(timestamp(3692217599)), Some(36));
+        assert_eq!(list.tai_offset(timestamp(3692217600)), Some(37));
+        assert_eq!(list.tai_offset(timestamp(3960835200)), Some(37));
+    }
+
+    #[test]
+    fn test_leap_indicator() {
+        let list = LeapSecondsList::parse(LEAP_SECONDS_LIST).unwrap();
+
+        // the last day of 2016 ended with a leap second
+        let leap = 3692217600;
+        assert_eq!(
+            list.leap_indicator(timestamp(leap - 86401)),
+            NtpLeapIndicator::NoWarning
+        );
+        assert_eq!(
+            list.leap_indicator(timestamp(leap - 86400)),
+            NtpLeapIndicator::Leap61
+        );
+        assert_eq!(
+            list.leap_indicator(timestamp(leap - 1)),
+            NtpLeapIndicator::Leap61
+        );
+        assert_eq!(
+            list.leap_indicator(timestamp(leap)),
+            NtpLeapIndicator::NoWarning
+        );
+
+        let list = LeapSecondsList::parse("#@ 3991593600\n3644697600 36\n3692217600 35").unwrap();
+        assert!(!list.is_hashed());
+        assert_eq!(
+            list.leap_indicator(timestamp(leap - 1)),
+            NtpLeapIndicator::Leap59
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let modified = LEAP_SECONDS_LIST.replace("3692217600\t37", "3692217600\t38");
+        assert_eq!(
+            LeapSecondsList::parse(&modified),
+            Err(LeapSecondsError::HashMismatch)
+        );
+
+        let modified = LEAP_SECONDS_LIST.replace("39b8e49e", "");
+        assert_eq!(
+            LeapSecondsList::parse(&modified),
+            Err(LeapSecondsError::InvalidHash { line: 32 })
+        );
+
+        assert_eq!(
+            LeapSecondsList::parse("3692217600 37"),
+            Err(LeapSecondsError::MissingExpiry)
+        );
+        assert_eq!(
+            LeapSecondsList::parse("#@ 3991593600\n3692217600"),
+            Err(LeapSecondsError::Syntax { line: 2 })
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{config::LeapSmearConfig, LeapSmearMode, NtpDuration, NtpLeapIndicator, NtpTimestamp};
+
+const SECONDS_PER_DAY: u64 = 86400;
+/// Days from 0000-03-01 (the start of the proleptic gregorian calendar as used below) to
+/// 1900-01-01, the start of the first NTP era
+const NTP_EPOCH_DAYS: u64 = 693901;
+
+/// Convert days since 0000-03-01 to a (year, month) pair, following
+/// <http://howardhinnant.github.io/date_algorithms.html>
+fn year_month_from_days(days: u64) -> (u64, u64) {
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    (year, month)
+}
+
+/// Inverse of `year_month_from_days`, for the first day of the month
+fn days_from_year_month(year: u64, month: u64) -> u64 {
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era
+}
+
+/// The end of the month that `time` falls in, which is when leap seconds take effect (RFC 5905)
+fn end_of_month(time: NtpTimestamp) -> NtpTimestamp {
+    let seconds = u64::from_be_bytes(time.to_bits()) >> 32;
+    let (year, month) = year_month_from_days(NTP_EPOCH_DAYS + seconds / SECONDS_PER_DAY);
+    let (year, month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    let days = days_from_year_month(year, month) - NTP_EPOCH_DAYS;
+
+    // timestamps wrap around at the end of an NTP era
+    NtpTimestamp::from_seconds_nanos_since_ntp_era((days * SECONDS_PER_DAY) as u32, 0)
+}
+
+/// Progress of a leap smear, for operators to see that the clock deliberately differs from UTC
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LeapSmearStatus {
+    pub mode: LeapSmearMode,
+    /// The leap second that is smeared, either `Leap61` or `Leap59`
+    pub leap_indicator: NtpLeapIndicator,
+    /// Fraction of the smear that is done, from 0 to 1
+    pub progress: f64,
+    /// How far the clock is meant to be behind UTC, negative when it is ahead
+    pub offset: NtpDuration,
+}
+
+/// A leap second that is spread out over a window around it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LeapSmear {
+    mode: LeapSmearMode,
+    leap_indicator: NtpLeapIndicator,
+    leap: NtpTimestamp,
+    start: NtpTimestamp,
+    end: NtpTimestamp,
+    passed_leap: bool,
+}
+
+impl LeapSmear {
+    /// Start smearing the leap second announced by `leap_indicator`, if `utc` lies in the window
+    /// before it. When the leap second is learned of late, the smear starts right away.
+    pub(crate) fn start(
+        config: &LeapSmearConfig,
+        leap_indicator: NtpLeapIndicator,
+        utc: NtpTimestamp,
+    ) -> Option<Self> {
+        if !matches!(
+            leap_indicator,
+            NtpLeapIndicator::Leap61 | NtpLeapIndicator::Leap59
+        ) {
+            return None;
+        }
+
+        let leap = end_of_month(utc);
+        let start = leap - config.window / 2i64;
+        if utc - start < NtpDuration::ZERO {
+            return None;
+        }
+
+        Some(LeapSmear {
+            mode: config.mode,
+            leap_indicator,
+            leap,
+            start: utc,
+            end: leap + config.window / 2i64,
+            passed_leap: false,
+        })
+    }
+
+    pub(crate) fn leap_indicator(&self) -> NtpLeapIndicator {
+        self.leap_indicator
+    }
+
+    /// Returns true exactly once: for the first time after the leap second
+    pub(crate) fn pass_leap(&mut self, utc: NtpTimestamp) -> bool {
+        if self.passed_leap || utc - self.leap < NtpDuration::ZERO {
+            return false;
+        }
+
+        self.passed_leap = true;
+        true
+    }
+
+    pub(crate) fn has_passed_leap(&self) -> bool {
+        self.passed_leap
+    }
+
+    pub(crate) fn is_finished(&self, utc: NtpTimestamp) -> bool {
+        utc - self.end >= NtpDuration::ZERO
+    }
+
+    fn progress(&self, utc: NtpTimestamp) -> f64 {
+        let elapsed = (utc - self.start).to_seconds();
+        let total = (self.end - self.start).to_seconds();
+        (elapsed / total).clamp(0.0, 1.0)
+    }
+
+    /// The offset from UTC (as measured against our peers) that the clock should have at `utc`
+    pub(crate) fn offset(&self, utc: NtpTimestamp) -> NtpDuration {
+        let progress = self.progress(utc);
+        let smeared = match self.mode {
+            LeapSmearMode::Linear => progress,
+            LeapSmearMode::Cosine => (1.0 - (std::f64::consts::PI * progress).cos()) / 2.0,
+        };
+
+        // Before an inserted leap second our clock runs behind UTC, afterwards UTC has repeated
+        // a second and our clock is ahead of it. A deleted leap second is the mirror image.
+        let seconds = if self.passed_leap {
+            smeared - 1.0
+        } else {
+            smeared
+        };
+
+        match self.leap_indicator {
+            NtpLeapIndicator::Leap59 => NtpDuration::from_seconds(-seconds),
+            _ => NtpDuration::from_seconds(seconds),
+        }
+    }
+
+    pub(crate) fn status(&self, utc: NtpTimestamp) -> LeapSmearStatus {
+        LeapSmearStatus {
+            mode: self.mode,
+            leap_indicator: self.leap_indicator,
+            progress: self.progress(utc),
+            offset: self.offset(utc),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(seconds: u32) -> NtpTimestamp {
+        NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0)
+    }
+
+    // 1 January 2017, the last time a leap second was inserted
+    const LEAP: u32 = 3692217600;
+
+    #[test]
+    fn test_end_of_month() {
+        assert_eq!(end_of_month(timestamp(LEAP - 43200)), timestamp(LEAP));
+        assert_eq!(end_of_month(timestamp(LEAP - 86400 * 30)), timestamp(LEAP));
+        assert_eq!(end_of_month(timestamp(LEAP)), timestamp(LEAP + 86400 * 31));
+        // 1 July 2015
+        assert_eq!(
+            end_of_month(timestamp(3644697600 - 1)),
+            timestamp(3644697600)
+        );
+        // 1 March 2016, after a leap day
+        assert_eq!(
+            end_of_month(timestamp(3665779200 - 1)),
+            timestamp(3665779200)
+        );
+    }
+
+    #[test]
+    fn test_linear_smear() {
+        let config = LeapSmearConfig {
+            mode: LeapSmearMode::Linear,
+            window: NtpDuration::from_seconds(86400.),
+        };
+
+        assert!(
+            LeapSmear::start(&config, NtpLeapIndicator::NoWarning, timestamp(LEAP - 100)).is_none()
+        );
+        // too early
+        assert!(
+            LeapSmear::start(&config, NtpLeapIndicator::Leap61, timestamp(LEAP - 43201)).is_none()
+        );
+
+        let mut smear =
+            LeapSmear::start(&config, NtpLeapIndicator::Leap61, timestamp(LEAP - 43200)).unwrap();
+        assert_eq!(smear.offset(timestamp(LEAP - 43200)), NtpDuration::ZERO);
+
+        let offset = smear.offset(timestamp(LEAP - 1)).to_seconds();
+        assert!((offset - 0.5).abs() < 1e-4);
+
+        assert!(!smear.pass_leap(timestamp(LEAP - 1)));
+        assert!(smear.pass_leap(timestamp(LEAP)));
+        assert!(!smear.pass_leap(timestamp(LEAP + 1)));
+
+        let offset = smear.offset(timestamp(LEAP)).to_seconds();
+        assert!((offset + 0.5).abs() < 1e-4);
+
+        assert!(!smear.is_finished(timestamp(LEAP + 43199)));
+        assert!(smear.is_finished(timestamp(LEAP + 43200)));
+        assert_eq!(smear.offset(timestamp(LEAP + 43200)), NtpDuration::ZERO);
+    }
+
+    #[test]
+    fn test_cosine_smear() {
+        let config = LeapSmearConfig {
+            mode: LeapSmearMode::Cosine,
+            window: NtpDuration::from_seconds(1000.),
+        };
+
+        let smear =
+            LeapSmear::start(&config, NtpLeapIndicator::Leap59, timestamp(LEAP - 500)).unwrap();
+
+        // a deleted leap second puts the clock ahead of UTC, slowly at first
+        let offset = smear.offset(timestamp(LEAP - 450)).to_seconds();
+        assert!(offset < 0.0 && offset > -0.05);
+        let offset = smear.offset(timestamp(LEAP)).to_seconds();
+        assert!((offset + 0.5).abs() < 1e-4);
+
+        let status = smear.status(timestamp(LEAP - 250));
+        assert_eq!(status.leap_indicator, NtpLeapIndicator::Leap59);
+        assert!((status.progress - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_late_smear() {
+        let config = LeapSmearConfig {
+            mode: LeapSmearMode::Linear,
+            window: NtpDuration::from_seconds(86400.),
+        };
+
+        // the smear starts from where the clock is, and catches up after the leap second
+        let smear =
+            LeapSmear::start(&config, NtpLeapIndicator::Leap61, timestamp(LEAP - 100)).unwrap();
+        assert_eq!(smear.offset(timestamp(LEAP - 100)), NtpDuration::ZERO);
+        assert!(smear.offset(timestamp(LEAP - 1)).to_seconds() < 0.01);
+    }
+}
@@ -3,10 +3,17 @@
 mod clock;
 mod clock_select;
 mod config;
+mod crypto;
 mod filter;
 mod identifiers;
+mod leap_seconds;
+mod leap_smear;
+mod mac;
+mod nts;
+mod nts_record;
 mod packet;
 mod peer;
+mod refclock;
 mod time_types;
 
 pub use clock::{ClockController, ClockUpdateResult, NtpClock};
@@ -15,16 +22,28 @@ pub use clock_select::fuzz_find_interval;
 pub use clock_select::FilterAndCombine;
 #[cfg(feature = "ext-test")]
 pub use clock_select::{peer_snapshot, test_peer_snapshot};
-pub use config::SystemConfig;
+pub use config::{LeapSmearConfig, LeapSmearMode, SystemConfig};
+pub use crypto::{AesSivCmac256, DecryptError, KeySet, NtsKeys, AEAD_AES_SIV_CMAC_256};
 #[cfg(feature = "fuzz")]
 pub use filter::fuzz_tuple_from_packet_default;
 pub use identifiers::ReferenceId;
+pub use leap_seconds::{LeapSecondsError, LeapSecondsList};
+pub use leap_smear::LeapSmearStatus;
+pub use mac::{KeyError, MacAlgorithm, SymmetricKey};
+pub use nts::{AuthenticatedRequest, NtsError, NtsServerRequest, PeerNtsData, RejectedRequest};
+pub use nts_record::{
+    KeyExchangeRequest, KeyExchangeResponse, NtsRecord, NtsRecordError, NTS_KE_ALPN,
+};
 
-pub use packet::{NtpAssociationMode, NtpHeader, NtpLeapIndicator};
+pub use packet::{
+    ExtensionField, ExtensionFieldTooLarge, Mac, NtpAssociationMode, NtpHeader, NtpLeapIndicator,
+    NtpPacket, PacketParseError,
+};
 pub use peer::{
     AcceptSynchronizationError, IgnoreReason, Peer, PeerSnapshot, PeerStatistics, Reach,
     SystemSnapshot,
 };
+pub use refclock::{Refclock, RefclockError, RefclockFilter, RefclockSample};
 #[cfg(feature = "fuzz")]
 pub use time_types::fuzz_duration_from_seconds;
 pub use time_types::{FrequencyTolerance, NtpDuration, NtpInstant, NtpTimestamp, PollInterval};
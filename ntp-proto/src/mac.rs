@@ -0,0 +1,226 @@
+use aes::Aes128;
+use cmac::{Cmac, Mac as _};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+use crate::{packet::Mac, NtpPacket};
+
+/// The algorithms for symmetric key authentication of rfc5905 and rfc8573
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacAlgorithm {
+    Md5,
+    Sha1,
+    AesCmac128,
+}
+
+impl std::str::FromStr for MacAlgorithm {
+    type Err = KeyError;
+
+    /// Parse the name of an algorithm as used in key files
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "MD5" => Ok(MacAlgorithm::Md5),
+            "SHA1" => Ok(MacAlgorithm::Sha1),
+            "AES128CMAC" => Ok(MacAlgorithm::AesCmac128),
+            _ => Err(KeyError::UnknownAlgorithm),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyError {
+    UnknownAlgorithm,
+    /// The key is empty, or does not have the size the algorithm requires
+    InvalidKeyLength,
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::UnknownAlgorithm => f.write_str("unknown MAC algorithm"),
+            KeyError::InvalidKeyLength => f.write_str("invalid key length for MAC algorithm"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// A key shared with a server, used to authenticate packets with a MAC
+#[derive(Clone)]
+pub struct SymmetricKey {
+    id: u32,
+    algorithm: MacAlgorithm,
+    key: Vec<u8>,
+}
+
+// Never print the key material
+impl std::fmt::Debug for SymmetricKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SymmetricKey")
+            .field("id", &self.id)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SymmetricKey {
+    pub fn new(id: u32, algorithm: MacAlgorithm, key: Vec<u8>) -> Result<Self, KeyError> {
+        let valid = match algorithm {
+            MacAlgorithm::Md5 | MacAlgorithm::Sha1 => !key.is_empty(),
+            MacAlgorithm::AesCmac128 => key.len() == 16,
+        };
+
+        if !valid {
+            return Err(KeyError::InvalidKeyLength);
+        }
+
+        Ok(SymmetricKey { id, algorithm, key })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn algorithm(&self) -> MacAlgorithm {
+        self.algorithm
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            // rfc5905 defines the digest as the hash of the key followed by the packet
+            MacAlgorithm::Md5 => {
+                let mut hasher = Md5::new();
+                hasher.update(&self.key);
+                hasher.update(data);
+                hasher.finalize().to_vec()
+            }
+            MacAlgorithm::Sha1 => {
+                let mut hasher = Sha1::new();
+                hasher.update(&self.key);
+                hasher.update(data);
+                hasher.finalize().to_vec()
+            }
+            MacAlgorithm::AesCmac128 => {
+                let mut mac = Cmac::<Aes128>::new_from_slice(&self.key)
+                    .expect("key length is checked on creation");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Add a MAC made with this key to `packet`, replacing any existing MAC
+    pub fn sign(&self, packet: &mut NtpPacket) {
+        packet.mac = None;
+        let digest = self.digest(&packet.serialize());
+
+        packet.mac = Some(Mac {
+            keyid: self.id,
+            digest,
+        });
+    }
+
+    /// Check that `packet` carries a valid MAC made with this key
+    pub fn verify(&self, packet: &NtpPacket) -> bool {
+        let mac = match &packet.mac {
+            Some(mac) if mac.keyid == self.id => mac,
+            _ => return false,
+        };
+
+        let mut unsigned = packet.clone();
+        unsigned.mac = None;
+        let expected = self.digest(&unsigned.serialize());
+
+        expected.ct_eq(&mac.digest).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ExtensionField, NtpHeader, NtpTimestamp};
+
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_digests() {
+        // the digests of "abc" from rfc1321, rfc3174 and rfc4493 (the latter for the empty message)
+        let key = SymmetricKey::new(1, MacAlgorithm::Md5, b"a".to_vec()).unwrap();
+        assert_eq!(hex(&key.digest(b"bc")), "900150983cd24fb0d6963f7d28e17f72");
+
+        let key = SymmetricKey::new(1, MacAlgorithm::Sha1, b"ab".to_vec()).unwrap();
+        assert_eq!(
+            hex(&key.digest(b"c")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+
+        let key = SymmetricKey::new(
+            1,
+            MacAlgorithm::AesCmac128,
+            vec![
+                0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+                0x4f, 0x3c,
+            ],
+        )
+        .unwrap();
+        assert_eq!(hex(&key.digest(b"")), "bb1d6929e95937287fa37d129b756746");
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        assert_eq!(
+            SymmetricKey::new(1, MacAlgorithm::Md5, vec![]).unwrap_err(),
+            KeyError::InvalidKeyLength
+        );
+        assert_eq!(
+            SymmetricKey::new(1, MacAlgorithm::AesCmac128, vec![0; 20]).unwrap_err(),
+            KeyError::InvalidKeyLength
+        );
+        assert_eq!(
+            "SHA256".parse::<MacAlgorithm>().unwrap_err(),
+            KeyError::UnknownAlgorithm
+        );
+        assert_eq!("md5".parse::<MacAlgorithm>(), Ok(MacAlgorithm::Md5));
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let mut header = NtpHeader::new();
+        header.transmit_timestamp = NtpTimestamp::from_fixed_int(0x1234);
+
+        for algorithm in [
+            MacAlgorithm::Md5,
+            MacAlgorithm::Sha1,
+            MacAlgorithm::AesCmac128,
+        ] {
+            let key = SymmetricKey::new(7, algorithm, vec![9; 16]).unwrap();
+            let other = SymmetricKey::new(8, algorithm, vec![9; 16]).unwrap();
+            let wrong = SymmetricKey::new(7, algorithm, vec![10; 16]).unwrap();
+
+            let mut packet = NtpPacket::new(header);
+            packet
+                .extension_fields
+                .push(ExtensionField::new(0x2005, vec![1; 12]).unwrap());
+            assert!(!key.verify(&packet));
+
+            key.sign(&mut packet);
+            // the MAC survives a trip over the network
+            let packet = NtpPacket::deserialize(&packet.serialize()).unwrap();
+            assert!(key.verify(&packet));
+            assert!(!other.verify(&packet));
+            assert!(!wrong.verify(&packet));
+
+            let mut tampered = packet.clone();
+            tampered.header.stratum = 1;
+            assert!(!key.verify(&tampered));
+
+            let mut tampered = packet;
+            tampered.extension_fields.clear();
+            assert!(!key.verify(&tampered));
+        }
+    }
+}
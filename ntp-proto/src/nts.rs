@@ -0,0 +1,664 @@
+use std::collections::VecDeque;
+
+use rand::{thread_rng, Rng};
+
+use crate::{
+    crypto::{AesSivCmac256, KeySet, NtsKeys},
+    packet::padded_len,
+    ExtensionField, NtpHeader, NtpPacket, PacketParseError, ReferenceId,
+};
+
+// Extension field types defined in rfc8915, section 5
+const UNIQUE_IDENTIFIER: u16 = 0x0104;
+const NTS_COOKIE: u16 = 0x0204;
+const NTS_COOKIE_PLACEHOLDER: u16 = 0x0304;
+const NTS_AUTHENTICATOR: u16 = 0x0404;
+
+/// Number of cookies we try to keep in stock, one for each of the next polls
+const MAX_COOKIES: usize = 8;
+const UNIQUE_IDENTIFIER_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NtsError {
+    /// The packet is shorter than an NTP header
+    TooShort,
+    /// The packet uses a version of NTP that we cannot interpret
+    UnsupportedVersion(u8),
+    /// The extension fields of the packet do not have valid lengths
+    MalformedExtensionField,
+    /// The packet does not echo the unique identifier of our last request
+    UnexpectedUniqueIdentifier,
+    /// The request has no unique identifier
+    MissingUniqueIdentifier,
+    /// The packet has no authenticator extension field
+    MissingAuthenticator,
+    /// The authenticator did not validate
+    AuthenticationFailed,
+    /// The packet contains extension fields that are not covered by the authenticator
+    UnauthenticatedExtensionField,
+    /// The server could not decrypt our cookie, we need to do a new key exchange
+    Nak,
+}
+
+impl std::fmt::Display for NtsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NtsError::TooShort => f.write_str("packet too short"),
+            NtsError::UnsupportedVersion(version) => {
+                write!(f, "unsupported NTP version {}", version)
+            }
+            NtsError::MalformedExtensionField => f.write_str("malformed extension field"),
+            NtsError::UnexpectedUniqueIdentifier => f.write_str("unexpected unique identifier"),
+            NtsError::MissingUniqueIdentifier => f.write_str("missing unique identifier"),
+            NtsError::MissingAuthenticator => f.write_str("missing NTS authenticator"),
+            NtsError::AuthenticationFailed => f.write_str("NTS authentication failed"),
+            NtsError::UnauthenticatedExtensionField => {
+                f.write_str("extension field after the NTS authenticator")
+            }
+            NtsError::Nak => f.write_str("server sent an NTS negative-acknowledgment"),
+        }
+    }
+}
+
+impl std::error::Error for NtsError {}
+
+impl From<PacketParseError> for NtsError {
+    fn from(error: PacketParseError) -> Self {
+        match error {
+            PacketParseError::TooShort => NtsError::TooShort,
+            PacketParseError::UnsupportedVersion(version) => NtsError::UnsupportedVersion(version),
+            PacketParseError::MalformedExtensionField => NtsError::MalformedExtensionField,
+        }
+    }
+}
+
+/// An extension field with a value that is small by construction, like an identifier or one of
+/// our own cookies
+fn small_field(field_type: u16, value: Vec<u8>) -> ExtensionField {
+    ExtensionField::new(field_type, value).expect("value fits in an extension field")
+}
+
+fn write_authenticator(packet: &mut NtpPacket, cipher: &AesSivCmac256, plaintext: &[u8]) {
+    // everything that precedes the authenticator is authenticated
+    let (nonce, ciphertext) = cipher.encrypt(plaintext, &packet.serialize());
+
+    let mut value = Vec::new();
+    value.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+    value.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+    value.extend_from_slice(&nonce);
+    value.resize(4 + padded_len(nonce.len()), 0);
+    value.extend_from_slice(&ciphertext);
+
+    packet
+        .extension_fields
+        .push(small_field(NTS_AUTHENTICATOR, value));
+}
+
+/// Verify the authenticator, the extension field at `index`, and return the decrypted content
+fn read_authenticator(
+    packet: &NtpPacket,
+    index: usize,
+    cipher: &AesSivCmac256,
+) -> Result<Vec<u8>, NtsError> {
+    let value = &packet.extension_fields[index].value;
+    if value.len() < 4 {
+        return Err(NtsError::MalformedExtensionField);
+    }
+
+    let nonce_len = u16::from_be_bytes([value[0], value[1]]) as usize;
+    let ciphertext_len = u16::from_be_bytes([value[2], value[3]]) as usize;
+
+    let ciphertext_start = 4 + padded_len(nonce_len);
+    let nonce = value
+        .get(4..4 + nonce_len)
+        .ok_or(NtsError::MalformedExtensionField)?;
+    let ciphertext = value
+        .get(ciphertext_start..ciphertext_start + ciphertext_len)
+        .ok_or(NtsError::MalformedExtensionField)?;
+
+    // fields read from a packet serialize to exactly the bytes they were read from
+    let mut associated_data = packet.header.serialize().to_vec();
+    for field in &packet.extension_fields[..index] {
+        field.serialize(&mut associated_data);
+    }
+
+    cipher
+        .decrypt(nonce, ciphertext, &associated_data)
+        .map_err(|_| NtsError::AuthenticationFailed)
+}
+
+/// Find the authenticator, which must be the last extension field
+fn authenticator_index(packet: &NtpPacket) -> Result<Option<usize>, NtsError> {
+    let fields = &packet.extension_fields;
+    match fields
+        .iter()
+        .position(|field| field.field_type == NTS_AUTHENTICATOR)
+    {
+        Some(index) if index + 1 == fields.len() && packet.mac.is_none() => Ok(Some(index)),
+        Some(_) => Err(NtsError::UnauthenticatedExtensionField),
+        None => Ok(None),
+    }
+}
+
+/// The NTS state of a client association: the keys and cookies obtained through key exchange
+#[derive(Debug, Clone)]
+pub struct PeerNtsData {
+    cookies: VecDeque<Vec<u8>>,
+    keys: NtsKeys,
+    // The unique identifier we expect the response to our last request to contain
+    expected_identifier: Option<[u8; UNIQUE_IDENTIFIER_SIZE]>,
+}
+
+impl PeerNtsData {
+    pub fn new(cookies: Vec<Vec<u8>>, keys: NtsKeys) -> Self {
+        PeerNtsData {
+            cookies: cookies.into_iter().take(MAX_COOKIES).collect(),
+            keys,
+            expected_identifier: None,
+        }
+    }
+
+    /// Number of cookies left. When we run out, a new key exchange is needed
+    pub fn cookie_count(&self) -> usize {
+        self.cookies.len()
+    }
+
+    #[cfg(feature = "ext-test")]
+    pub fn keys(&self) -> &NtsKeys {
+        &self.keys
+    }
+
+    /// Add the NTS extension fields to a poll message and serialize it.
+    ///
+    /// Every request uses up one cookie, so this returns `None` when we have no cookies left.
+    pub fn protect_request(&mut self, header: &NtpHeader) -> Option<Vec<u8>> {
+        let cookie = self.cookies.pop_front()?;
+        let placeholder = vec![0; cookie.len()];
+        // a cookie from the key exchange that does not fit in a packet is of no use
+        let cookie = ExtensionField::new(NTS_COOKIE, cookie).ok()?;
+
+        let identifier: [u8; UNIQUE_IDENTIFIER_SIZE] = thread_rng().gen();
+        self.expected_identifier = Some(identifier);
+
+        let mut packet = NtpPacket::new(*header);
+        packet
+            .extension_fields
+            .push(small_field(UNIQUE_IDENTIFIER, identifier.to_vec()));
+        packet.extension_fields.push(cookie);
+
+        // the server sends a new cookie for the one we use, and one more for every
+        // placeholder, so we can refill our stock of cookies.
+        let missing = MAX_COOKIES - 1 - self.cookies.len();
+        for _ in 0..missing {
+            packet
+                .extension_fields
+                .push(small_field(NTS_COOKIE_PLACEHOLDER, placeholder.clone()));
+        }
+
+        write_authenticator(&mut packet, &self.keys.c2s, &[]);
+
+        Some(packet.serialize())
+    }
+
+    /// Check the NTS extension fields of a response to our last request, storing the new cookies
+    /// it contains.
+    pub fn verify_response(&mut self, data: &[u8]) -> Result<NtpHeader, NtsError> {
+        let packet = NtpPacket::deserialize(data)?;
+
+        // A response must echo our identifier. Checking this first ensures that spoofed
+        // packets do not change our state.
+        let identifier_matches = packet.extension_fields.iter().any(|field| {
+            field.field_type == UNIQUE_IDENTIFIER
+                && Some(&field.value[..]) == self.expected_identifier.as_ref().map(|id| &id[..])
+        });
+        if !identifier_matches {
+            return Err(NtsError::UnexpectedUniqueIdentifier);
+        }
+
+        let index = match authenticator_index(&packet)? {
+            Some(index) => index,
+            // a NAK is not authenticated, the identifier is its only protection
+            None if packet.header.is_kiss_ntsn() => {
+                self.expected_identifier = None;
+                self.cookies.clear();
+                return Err(NtsError::Nak);
+            }
+            None => return Err(NtsError::MissingAuthenticator),
+        };
+
+        let plaintext = read_authenticator(&packet, index, &self.keys.s2c)?;
+        self.expected_identifier = None;
+
+        for field in ExtensionField::deserialize_all(&plaintext)? {
+            if field.field_type == NTS_COOKIE && self.cookies.len() < MAX_COOKIES {
+                self.cookies.push_back(field.value);
+            }
+        }
+
+        Ok(packet.header)
+    }
+}
+
+/// The number of new cookies, up to `wanted`, for which the response is no larger than the
+/// request of `request_size` bytes, so that we cannot be used to amplify traffic
+fn cookies_that_fit(
+    request_size: usize,
+    identifier_len: usize,
+    cookie_len: usize,
+    wanted: usize,
+) -> usize {
+    let cookie_size = ExtensionField::size_for(cookie_len);
+    let identifier_size = ExtensionField::size_for(identifier_len);
+
+    let response_size = |cookies: usize| {
+        // the ciphertext has a 16 byte SIV tag in front of the encrypted cookies
+        let ciphertext_len = cookies * cookie_size + 16;
+        let authenticator_len = 4 + padded_len(AesSivCmac256::NONCE_SIZE) + ciphertext_len;
+        let authenticator_size =
+            Ord::max(ExtensionField::MIN_SIZE, padded_len(4 + authenticator_len));
+
+        48 + identifier_size + authenticator_size
+    };
+
+    (0..=Ord::min(wanted, MAX_COOKIES))
+        .rev()
+        .find(|cookies| response_size(*cookies) <= request_size)
+        .unwrap_or(0)
+}
+
+/// A request received by a server, classified by its use of NTS
+#[derive(Debug)]
+pub enum NtsServerRequest {
+    /// The request does not use NTS
+    Plain(NtpHeader),
+    /// The request is authenticated with NTS
+    Authenticated(NtpHeader, AuthenticatedRequest),
+    /// The request uses NTS, but we could not decrypt its cookie or verify its authenticator
+    Rejected(NtpHeader, RejectedRequest),
+}
+
+/// What a server needs to answer an NTS-authenticated request
+#[derive(Debug)]
+pub struct AuthenticatedRequest {
+    identifier: Vec<u8>,
+    keys: NtsKeys,
+    /// Number of new cookies that fit in the response
+    cookies: usize,
+}
+
+/// What a server needs to tell a client that it should redo the key exchange
+#[derive(Debug)]
+pub struct RejectedRequest {
+    identifier: Vec<u8>,
+}
+
+impl NtsServerRequest {
+    /// Decode a request to a server, using `keyset` to decrypt its NTS cookie
+    pub fn decode(data: &[u8], keyset: &KeySet) -> Result<Self, NtsError> {
+        let packet = NtpPacket::deserialize(data)?;
+        let header = packet.header;
+        let fields = &packet.extension_fields;
+
+        let cookie = match fields.iter().find(|field| field.field_type == NTS_COOKIE) {
+            Some(cookie) => cookie,
+            None => return Ok(NtsServerRequest::Plain(header)),
+        };
+
+        let identifier = fields
+            .iter()
+            .find(|field| field.field_type == UNIQUE_IDENTIFIER)
+            .ok_or(NtsError::MissingUniqueIdentifier)?
+            .value
+            .clone();
+
+        // rfc8915, section 5.7: a placeholder must be as large as the cookie, so that the new
+        // cookie that it asks for takes up the same space in the response
+        let placeholders = fields
+            .iter()
+            .filter(|field| {
+                field.field_type == NTS_COOKIE_PLACEHOLDER
+                    && field.value.len() == cookie.value.len()
+            })
+            .count();
+
+        let authenticated = match authenticator_index(&packet)? {
+            Some(index) => keyset.decode_cookie(&cookie.value).ok().and_then(|keys| {
+                read_authenticator(&packet, index, &keys.c2s)
+                    .ok()
+                    .map(|_| keys)
+            }),
+            None => None,
+        };
+
+        match authenticated {
+            Some(keys) => {
+                let cookies = cookies_that_fit(
+                    data.len(),
+                    identifier.len(),
+                    cookie.value.len(),
+                    1 + placeholders,
+                );
+
+                Ok(NtsServerRequest::Authenticated(
+                    header,
+                    AuthenticatedRequest {
+                        identifier,
+                        keys,
+                        cookies,
+                    },
+                ))
+            }
+            None => Ok(NtsServerRequest::Rejected(
+                header,
+                RejectedRequest { identifier },
+            )),
+        }
+    }
+}
+
+impl AuthenticatedRequest {
+    /// Serialize the response `header`, adding new cookies and the NTS authenticator
+    pub fn encode_response(&self, header: &NtpHeader, keyset: &KeySet) -> Vec<u8> {
+        let mut packet = NtpPacket::new(*header);
+        packet
+            .extension_fields
+            .push(small_field(UNIQUE_IDENTIFIER, self.identifier.clone()));
+
+        // the new cookies are only readable by the client
+        let mut plaintext = Vec::new();
+        for _ in 0..self.cookies {
+            small_field(NTS_COOKIE, keyset.encode_cookie(&self.keys)).serialize(&mut plaintext);
+        }
+        write_authenticator(&mut packet, &self.keys.s2c, &plaintext);
+
+        packet.serialize()
+    }
+}
+
+impl RejectedRequest {
+    /// Serialize an NTS negative-acknowledgment, based on the response `header`
+    pub fn encode_nak(&self, header: &NtpHeader) -> Vec<u8> {
+        let mut header = *header;
+        header.stratum = 0;
+        header.reference_id = ReferenceId::KISS_NTSN;
+
+        let mut packet = NtpPacket::new(header);
+        packet
+            .extension_fields
+            .push(small_field(UNIQUE_IDENTIFIER, self.identifier.clone()));
+
+        packet.serialize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::NtpAssociationMode;
+
+    use super::*;
+
+    fn test_keys() -> NtsKeys {
+        NtsKeys {
+            c2s: AesSivCmac256::new([1; 32]),
+            s2c: AesSivCmac256::new([2; 32]),
+        }
+    }
+
+    /// What a server would answer, with an optional NAK instead of a proper response
+    fn respond(request: &[u8], new_cookies: usize, nak: bool) -> Vec<u8> {
+        let keys = test_keys();
+
+        let request = NtpPacket::deserialize(request).unwrap();
+        let index = authenticator_index(&request).unwrap().unwrap();
+        read_authenticator(&request, index, &keys.c2s).unwrap();
+
+        let identifier = request
+            .extension_fields
+            .iter()
+            .find(|field| field.field_type == UNIQUE_IDENTIFIER)
+            .unwrap();
+
+        let mut header = NtpHeader::new();
+        header.mode = NtpAssociationMode::Server;
+        if nak {
+            header.stratum = 0;
+            header.reference_id = crate::ReferenceId::KISS_NTSN;
+        }
+
+        let mut packet = NtpPacket::new(header);
+        packet.extension_fields.push(identifier.clone());
+
+        if !nak {
+            let mut plaintext = Vec::new();
+            for i in 0..new_cookies {
+                ExtensionField::new(NTS_COOKIE, vec![i as u8; 20])
+                    .unwrap()
+                    .serialize(&mut plaintext);
+            }
+            write_authenticator(&mut packet, &keys.s2c, &plaintext);
+        }
+
+        packet.serialize()
+    }
+
+    #[test]
+    fn test_request_response_roundtrip() {
+        let mut nts = PeerNtsData::new(vec![vec![0xAA; 20]; 3], test_keys());
+
+        let request = nts.protect_request(&NtpHeader::new()).unwrap();
+        assert_eq!(nts.cookie_count(), 2);
+
+        let placeholders = NtpPacket::deserialize(&request)
+            .unwrap()
+            .extension_fields
+            .iter()
+            .filter(|field| field.field_type == NTS_COOKIE_PLACEHOLDER)
+            .count();
+        assert_eq!(placeholders, 5);
+
+        let response = respond(&request, 6, false);
+        let header = nts.verify_response(&response).unwrap();
+        assert_eq!(header.mode, NtpAssociationMode::Server);
+        assert_eq!(nts.cookie_count(), 8);
+
+        // the response must not be accepted twice
+        assert_eq!(
+            nts.verify_response(&response).unwrap_err(),
+            NtsError::UnexpectedUniqueIdentifier
+        );
+    }
+
+    #[test]
+    fn test_reject_tampered_response() {
+        let mut nts = PeerNtsData::new(vec![vec![0xAA; 20]], test_keys());
+        let request = nts.protect_request(&NtpHeader::new()).unwrap();
+        let response = respond(&request, 1, false);
+
+        let mut tampered = response.clone();
+        tampered[45] ^= 1;
+        assert_eq!(
+            nts.verify_response(&tampered).unwrap_err(),
+            NtsError::AuthenticationFailed
+        );
+
+        let mut unauthenticated = response[..48 + 36].to_vec();
+        assert_eq!(
+            nts.verify_response(&unauthenticated).unwrap_err(),
+            NtsError::MissingAuthenticator
+        );
+
+        unauthenticated.extend_from_slice(&response[48 + 36..]);
+        ExtensionField::new(NTS_COOKIE, vec![0; 20])
+            .unwrap()
+            .serialize(&mut unauthenticated);
+        assert_eq!(
+            nts.verify_response(&unauthenticated).unwrap_err(),
+            NtsError::UnauthenticatedExtensionField
+        );
+
+        assert_eq!(
+            nts.verify_response(&response[..40]).unwrap_err(),
+            NtsError::TooShort
+        );
+
+        // the original response is still accepted
+        assert!(nts.verify_response(&response).is_ok());
+    }
+
+    #[test]
+    fn test_nak_drops_cookies() {
+        let mut nts = PeerNtsData::new(vec![vec![0xAA; 20]; 4], test_keys());
+
+        let request = nts.protect_request(&NtpHeader::new()).unwrap();
+        let response = respond(&request, 0, true);
+
+        assert_eq!(nts.verify_response(&response).unwrap_err(), NtsError::Nak);
+        assert_eq!(nts.cookie_count(), 0);
+        assert!(nts.protect_request(&NtpHeader::new()).is_none());
+    }
+
+    /// A client with cookies from a server using `keyset`
+    fn nts_client(keyset: &KeySet) -> PeerNtsData {
+        let keys = NtsKeys {
+            c2s: AesSivCmac256::new([3; 32]),
+            s2c: AesSivCmac256::new([4; 32]),
+        };
+        let cookies = (0..2).map(|_| keyset.encode_cookie(&keys)).collect();
+
+        PeerNtsData::new(cookies, keys)
+    }
+
+    #[test]
+    fn test_server_roundtrip() {
+        let keyset = KeySet::new();
+        let mut client = nts_client(&keyset);
+
+        let request = client.protect_request(&NtpHeader::new()).unwrap();
+
+        let answer = match NtsServerRequest::decode(&request, &keyset).unwrap() {
+            NtsServerRequest::Authenticated(header, authenticated) => {
+                assert_eq!(header.mode, NtpAssociationMode::Client);
+                // one for the used cookie, and one for each of the 6 placeholders
+                assert_eq!(authenticated.cookies, 7);
+
+                let mut response = NtpHeader::new();
+                response.mode = NtpAssociationMode::Server;
+                authenticated.encode_response(&response, &keyset)
+            }
+            other => panic!("unexpected request {:?}", other),
+        };
+
+        // responses must never be larger than requests
+        assert!(answer.len() <= request.len());
+
+        let header = client.verify_response(&answer).unwrap();
+        assert_eq!(header.mode, NtpAssociationMode::Server);
+        assert_eq!(client.cookie_count(), 8);
+    }
+
+    #[test]
+    fn test_server_limits_cookies() {
+        let keyset = KeySet::new();
+        let keys = NtsKeys {
+            c2s: AesSivCmac256::new([3; 32]),
+            s2c: AesSivCmac256::new([4; 32]),
+        };
+        let cookie = keyset.encode_cookie(&keys);
+
+        let request = |placeholder_len: usize, placeholders: usize| {
+            let mut packet = NtpPacket::new(NtpHeader::new());
+            packet
+                .extension_fields
+                .push(ExtensionField::new(UNIQUE_IDENTIFIER, vec![1; 32]).unwrap());
+            packet
+                .extension_fields
+                .push(ExtensionField::new(NTS_COOKIE, cookie.clone()).unwrap());
+            for _ in 0..placeholders {
+                packet.extension_fields.push(
+                    ExtensionField::new(NTS_COOKIE_PLACEHOLDER, vec![0; placeholder_len]).unwrap(),
+                );
+            }
+            write_authenticator(&mut packet, &keys.c2s, &[]);
+            packet.serialize()
+        };
+
+        let new_cookies = |request: &[u8]| match NtsServerRequest::decode(request, &keyset) {
+            Ok(NtsServerRequest::Authenticated(_, authenticated)) => {
+                let mut response = NtpHeader::new();
+                response.mode = NtpAssociationMode::Server;
+                let response = authenticated.encode_response(&response, &keyset);
+                assert!(response.len() <= request.len());
+
+                authenticated.cookies
+            }
+            other => panic!("unexpected request {:?}", other),
+        };
+
+        assert_eq!(new_cookies(&request(cookie.len(), 0)), 1);
+        assert_eq!(new_cookies(&request(cookie.len(), 7)), 8);
+        assert_eq!(new_cookies(&request(cookie.len(), 12)), MAX_COOKIES);
+
+        // small placeholders do not earn full-size cookies
+        assert_eq!(new_cookies(&request(12, 7)), 1);
+
+        // a request that is too small for even one cookie gets none
+        let size = request(cookie.len(), 0).len();
+        assert_eq!(cookies_that_fit(size, 32, cookie.len(), 1), 1);
+        assert_eq!(cookies_that_fit(size - 4, 32, cookie.len(), 1), 0);
+    }
+
+    #[test]
+    fn test_server_rejects_unknown_cookie() {
+        let mut keyset = KeySet::new();
+        let mut client = nts_client(&keyset);
+        for _ in 0..3 {
+            keyset.rotate();
+        }
+
+        let request = client.protect_request(&NtpHeader::new()).unwrap();
+        let nak = match NtsServerRequest::decode(&request, &keyset).unwrap() {
+            NtsServerRequest::Rejected(_, rejected) => rejected.encode_nak(&NtpHeader::new()),
+            other => panic!("unexpected request {:?}", other),
+        };
+
+        assert_eq!(client.verify_response(&nak).unwrap_err(), NtsError::Nak);
+    }
+
+    #[test]
+    fn test_server_rejects_tampered_request() {
+        let keyset = KeySet::new();
+        let mut client = nts_client(&keyset);
+
+        let mut request = client.protect_request(&NtpHeader::new()).unwrap();
+        request[40] ^= 1;
+
+        assert!(matches!(
+            NtsServerRequest::decode(&request, &keyset).unwrap(),
+            NtsServerRequest::Rejected(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_server_plain_request() {
+        let keyset = KeySet::new();
+
+        let request = NtpHeader::new().serialize();
+        assert!(matches!(
+            NtsServerRequest::decode(&request, &keyset).unwrap(),
+            NtsServerRequest::Plain(_)
+        ));
+
+        // a legacy MAC is not an extension field
+        let mut request = NtpHeader::new().serialize().to_vec();
+        request.extend_from_slice(&[1; 20]);
+        assert!(matches!(
+            NtsServerRequest::decode(&request, &keyset).unwrap(),
+            NtsServerRequest::Plain(_)
+        ));
+
+        assert_eq!(
+            NtsServerRequest::decode(&request[..47], &keyset).unwrap_err(),
+            NtsError::TooShort
+        );
+    }
+}
@@ -0,0 +1,734 @@
+use crate::crypto::AEAD_AES_SIV_CMAC_256;
+
+/// The ALPN protocol id of NTS key establishment
+pub const NTS_KE_ALPN: &[u8] = b"ntske/1";
+
+/// The NTS next protocol id of NTPv4
+const NTP_PROTOCOL_ID: u16 = 0;
+
+const CRITICAL_BIT: u16 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NtsRecord {
+    EndOfMessage,
+    NextProtocol {
+        protocol_ids: Vec<u16>,
+    },
+    Error {
+        errorcode: u16,
+    },
+    Warning {
+        warningcode: u16,
+    },
+    AeadAlgorithm {
+        critical: bool,
+        algorithm_ids: Vec<u16>,
+    },
+    NewCookie {
+        cookie_data: Vec<u8>,
+    },
+    Server {
+        critical: bool,
+        name: String,
+    },
+    Port {
+        critical: bool,
+        port: u16,
+    },
+    Unknown {
+        record_type: u16,
+        critical: bool,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NtsRecordError {
+    /// The body of a record does not match its type, or the record is not allowed here
+    InvalidRecord(u16),
+    /// A record that must be present was not sent
+    MissingRecord(u16),
+    /// The server sent a critical record that we do not understand
+    UnrecognizedCriticalRecord(u16),
+    /// The server sent an error record with the given error code
+    ServerError(u16),
+    /// The server does not support NTPv4 as the next protocol
+    NoValidProtocol,
+    /// The server does not support any AEAD algorithm that we support
+    NoValidAlgorithm,
+    /// The server did not give us any cookies
+    NoCookies,
+}
+
+impl std::fmt::Display for NtsRecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidRecord(record_type) => write!(f, "invalid record of type {record_type}"),
+            Self::MissingRecord(record_type) => write!(f, "missing record of type {record_type}"),
+            Self::UnrecognizedCriticalRecord(record_type) => {
+                write!(f, "unrecognized critical record of type {record_type}")
+            }
+            Self::ServerError(0) => f.write_str("server did not recognize a critical record"),
+            Self::ServerError(1) => f.write_str("server rejected our request as malformed"),
+            Self::ServerError(2) => f.write_str("server reported an internal error"),
+            Self::ServerError(code) => write!(f, "server reported error code {code}"),
+            Self::NoValidProtocol => f.write_str("server does not support NTPv4"),
+            Self::NoValidAlgorithm => f.write_str("server does not support AEAD_AES_SIV_CMAC_256"),
+            Self::NoCookies => f.write_str("server did not provide any cookies"),
+        }
+    }
+}
+
+impl std::error::Error for NtsRecordError {}
+
+impl NtsRecordError {
+    /// The records a server sends to a client whose request caused this error
+    pub fn server_response_records(&self) -> [NtsRecord; 2] {
+        let errorcode = match self {
+            NtsRecordError::UnrecognizedCriticalRecord(_) => 0,
+            // bad request
+            _ => 1,
+        };
+
+        [NtsRecord::Error { errorcode }, NtsRecord::EndOfMessage]
+    }
+}
+
+fn decode_u16_list(record_type: u16, body: &[u8]) -> Result<Vec<u16>, NtsRecordError> {
+    let chunks = body.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return Err(NtsRecordError::InvalidRecord(record_type));
+    }
+
+    Ok(chunks
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+        .collect())
+}
+
+fn decode_u16(record_type: u16, body: &[u8]) -> Result<u16, NtsRecordError> {
+    match body {
+        [hi, lo] => Ok(u16::from_be_bytes([*hi, *lo])),
+        _ => Err(NtsRecordError::InvalidRecord(record_type)),
+    }
+}
+
+impl NtsRecord {
+    /// The records a client sends to start a key exchange
+    pub fn client_key_exchange_records() -> [NtsRecord; 3] {
+        [
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![NTP_PROTOCOL_ID],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![AEAD_AES_SIV_CMAC_256],
+            },
+            NtsRecord::EndOfMessage,
+        ]
+    }
+
+    fn record_type(&self) -> u16 {
+        match self {
+            NtsRecord::EndOfMessage => 0,
+            NtsRecord::NextProtocol { .. } => 1,
+            NtsRecord::Error { .. } => 2,
+            NtsRecord::Warning { .. } => 3,
+            NtsRecord::AeadAlgorithm { .. } => 4,
+            NtsRecord::NewCookie { .. } => 5,
+            NtsRecord::Server { .. } => 6,
+            NtsRecord::Port { .. } => 7,
+            NtsRecord::Unknown { record_type, .. } => *record_type,
+        }
+    }
+
+    fn is_critical(&self) -> bool {
+        match self {
+            // these records must always be sent with the critical bit set
+            NtsRecord::EndOfMessage
+            | NtsRecord::NextProtocol { .. }
+            | NtsRecord::Error { .. }
+            | NtsRecord::Warning { .. } => true,
+            NtsRecord::NewCookie { .. } => false,
+            NtsRecord::AeadAlgorithm { critical, .. }
+            | NtsRecord::Server { critical, .. }
+            | NtsRecord::Port { critical, .. }
+            | NtsRecord::Unknown { critical, .. } => *critical,
+        }
+    }
+
+    /// Append the wire representation of this record to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        match self {
+            NtsRecord::EndOfMessage => {}
+            NtsRecord::NextProtocol { protocol_ids: ids }
+            | NtsRecord::AeadAlgorithm {
+                algorithm_ids: ids, ..
+            } => {
+                for id in ids {
+                    body.extend_from_slice(&id.to_be_bytes());
+                }
+            }
+            NtsRecord::Error { errorcode: code } | NtsRecord::Warning { warningcode: code } => {
+                body.extend_from_slice(&code.to_be_bytes());
+            }
+            NtsRecord::NewCookie { cookie_data: data } | NtsRecord::Unknown { data, .. } => {
+                body.extend_from_slice(data);
+            }
+            NtsRecord::Server { name, .. } => body.extend_from_slice(name.as_bytes()),
+            NtsRecord::Port { port, .. } => body.extend_from_slice(&port.to_be_bytes()),
+        }
+
+        let mut record_type = self.record_type();
+        if self.is_critical() {
+            record_type |= CRITICAL_BIT;
+        }
+
+        // Record bodies are tiny in practice, anything bigger is a bug on our side
+        let body_length = u16::try_from(body.len()).expect("NTS record body too long");
+
+        buf.extend_from_slice(&record_type.to_be_bytes());
+        buf.extend_from_slice(&body_length.to_be_bytes());
+        buf.extend_from_slice(&body);
+    }
+
+    /// Decode the record at the start of `data`, returning it together with the number of bytes
+    /// it occupies. Returns `None` when `data` does not yet contain the full record.
+    pub fn decode(data: &[u8]) -> Result<Option<(NtsRecord, usize)>, NtsRecordError> {
+        let (header, rest) = match data {
+            [a, b, c, d, rest @ ..] => ([*a, *b, *c, *d], rest),
+            _ => return Ok(None),
+        };
+
+        let raw_type = u16::from_be_bytes([header[0], header[1]]);
+        let critical = raw_type & CRITICAL_BIT != 0;
+        let record_type = raw_type & !CRITICAL_BIT;
+        let body_length = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+        let body = match rest.get(..body_length) {
+            Some(body) => body,
+            None => return Ok(None),
+        };
+
+        let record = match record_type {
+            0 if body.is_empty() => NtsRecord::EndOfMessage,
+            1 => NtsRecord::NextProtocol {
+                protocol_ids: decode_u16_list(record_type, body)?,
+            },
+            2 => NtsRecord::Error {
+                errorcode: decode_u16(record_type, body)?,
+            },
+            3 => NtsRecord::Warning {
+                warningcode: decode_u16(record_type, body)?,
+            },
+            4 => NtsRecord::AeadAlgorithm {
+                critical,
+                algorithm_ids: decode_u16_list(record_type, body)?,
+            },
+            5 => NtsRecord::NewCookie {
+                cookie_data: body.to_vec(),
+            },
+            6 => NtsRecord::Server {
+                critical,
+                name: String::from_utf8(body.to_vec())
+                    .map_err(|_| NtsRecordError::InvalidRecord(record_type))?,
+            },
+            7 => NtsRecord::Port {
+                critical,
+                port: decode_u16(record_type, body)?,
+            },
+            0 => return Err(NtsRecordError::InvalidRecord(record_type)),
+            _ => NtsRecord::Unknown {
+                record_type,
+                critical,
+                data: body.to_vec(),
+            },
+        };
+
+        Ok(Some((record, 4 + body_length)))
+    }
+}
+
+/// The information a client obtains from a successful NTS key exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyExchangeResponse {
+    /// The NTP server to use, if it is not the key exchange server itself
+    pub remote: Option<String>,
+    /// The port of the NTP server, if it is not the default port
+    pub port: Option<u16>,
+    pub cookies: Vec<Vec<u8>>,
+}
+
+impl KeyExchangeResponse {
+    /// Interpret the records sent by a key exchange server. Returns `None` when `data` does not
+    /// contain the end of message record yet.
+    pub fn decode(data: &[u8]) -> Result<Option<Self>, NtsRecordError> {
+        let mut remote = None;
+        let mut port = None;
+        let mut cookies = Vec::new();
+        let mut protocol_ok = false;
+        let mut algorithm_ok = false;
+
+        let mut offset = 0;
+        loop {
+            let (record, size) = match NtsRecord::decode(&data[offset..])? {
+                Some(decoded) => decoded,
+                None => return Ok(None),
+            };
+            offset += size;
+
+            match record {
+                NtsRecord::EndOfMessage => break,
+                NtsRecord::NextProtocol { protocol_ids } => {
+                    protocol_ok = protocol_ids.contains(&NTP_PROTOCOL_ID);
+                }
+                NtsRecord::Error { errorcode } => {
+                    return Err(NtsRecordError::ServerError(errorcode));
+                }
+                NtsRecord::Warning { warningcode } => {
+                    tracing::warn!(warningcode, "NTS key exchange server sent a warning");
+                }
+                NtsRecord::AeadAlgorithm { algorithm_ids, .. } => {
+                    algorithm_ok = algorithm_ids.contains(&AEAD_AES_SIV_CMAC_256);
+                }
+                NtsRecord::NewCookie { cookie_data } => cookies.push(cookie_data),
+                NtsRecord::Server { name, .. } => remote = Some(name),
+                NtsRecord::Port { port: p, .. } => port = Some(p),
+                NtsRecord::Unknown {
+                    record_type,
+                    critical,
+                    ..
+                } => {
+                    if critical {
+                        return Err(NtsRecordError::UnrecognizedCriticalRecord(record_type));
+                    }
+                }
+            }
+        }
+
+        if !protocol_ok {
+            Err(NtsRecordError::NoValidProtocol)
+        } else if !algorithm_ok {
+            Err(NtsRecordError::NoValidAlgorithm)
+        } else if cookies.is_empty() {
+            Err(NtsRecordError::NoCookies)
+        } else {
+            Ok(Some(KeyExchangeResponse {
+                remote,
+                port,
+                cookies,
+            }))
+        }
+    }
+}
+
+/// A key exchange request of a client, as seen by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyExchangeRequest {
+    /// The client can use NTPv4 as the next protocol
+    protocol_supported: bool,
+    /// The client can use an AEAD algorithm that we support
+    algorithm_supported: bool,
+}
+
+impl KeyExchangeRequest {
+    /// Interpret the records sent by a key exchange client. Returns `None` when `data` does not
+    /// contain the end of message record yet.
+    pub fn decode(data: &[u8]) -> Result<Option<Self>, NtsRecordError> {
+        let mut protocol_supported = None;
+        let mut algorithm_supported = None;
+
+        let mut offset = 0;
+        loop {
+            let (record, size) = match NtsRecord::decode(&data[offset..])? {
+                Some(decoded) => decoded,
+                None => return Ok(None),
+            };
+            offset += size;
+
+            match record {
+                NtsRecord::EndOfMessage => break,
+                NtsRecord::NextProtocol { protocol_ids } => {
+                    if protocol_supported.is_some() {
+                        return Err(NtsRecordError::InvalidRecord(1));
+                    }
+                    protocol_supported = Some(protocol_ids.contains(&NTP_PROTOCOL_ID));
+                }
+                NtsRecord::AeadAlgorithm { algorithm_ids, .. } => {
+                    if algorithm_supported.is_some() {
+                        return Err(NtsRecordError::InvalidRecord(4));
+                    }
+                    algorithm_supported = Some(algorithm_ids.contains(&AEAD_AES_SIV_CMAC_256));
+                }
+                // only servers send these
+                NtsRecord::Error { .. } => return Err(NtsRecordError::InvalidRecord(2)),
+                NtsRecord::Warning { .. } => return Err(NtsRecordError::InvalidRecord(3)),
+                NtsRecord::NewCookie { .. } => return Err(NtsRecordError::InvalidRecord(5)),
+                // we always tell the client which server to use, ignore its preferences
+                NtsRecord::Server { .. } | NtsRecord::Port { .. } => {}
+                NtsRecord::Unknown {
+                    record_type,
+                    critical,
+                    ..
+                } => {
+                    if critical {
+                        return Err(NtsRecordError::UnrecognizedCriticalRecord(record_type));
+                    }
+                }
+            }
+        }
+
+        Ok(Some(KeyExchangeRequest {
+            protocol_supported: protocol_supported.ok_or(NtsRecordError::MissingRecord(1))?,
+            algorithm_supported: algorithm_supported.ok_or(NtsRecordError::MissingRecord(4))?,
+        }))
+    }
+
+    /// The records a server sends in response to this request. The `cookies` are only sent when
+    /// the client supports NTPv4 and our AEAD algorithm.
+    pub fn response_records(
+        &self,
+        cookies: Vec<Vec<u8>>,
+        server: Option<String>,
+        port: Option<u16>,
+    ) -> Vec<NtsRecord> {
+        // an empty list tells the client that we support none of its options
+        if !self.protocol_supported {
+            return vec![
+                NtsRecord::NextProtocol {
+                    protocol_ids: vec![],
+                },
+                NtsRecord::EndOfMessage,
+            ];
+        }
+
+        if !self.algorithm_supported {
+            return vec![
+                NtsRecord::NextProtocol {
+                    protocol_ids: vec![NTP_PROTOCOL_ID],
+                },
+                NtsRecord::AeadAlgorithm {
+                    critical: false,
+                    algorithm_ids: vec![],
+                },
+                NtsRecord::EndOfMessage,
+            ];
+        }
+
+        let mut records = vec![
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![NTP_PROTOCOL_ID],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![AEAD_AES_SIV_CMAC_256],
+            },
+        ];
+        records.extend(
+            cookies
+                .into_iter()
+                .map(|cookie_data| NtsRecord::NewCookie { cookie_data }),
+        );
+        if let Some(name) = server {
+            records.push(NtsRecord::Server {
+                critical: false,
+                name,
+            });
+        }
+        if let Some(port) = port {
+            records.push(NtsRecord::Port {
+                critical: false,
+                port,
+            });
+        }
+        records.push(NtsRecord::EndOfMessage);
+
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_all(records: &[NtsRecord]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for record in records {
+            record.encode(&mut buf);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_client_request_bytes() {
+        let buf = encode_all(&NtsRecord::client_key_exchange_records());
+
+        assert_eq!(buf, [0x80, 1, 0, 2, 0, 0, 0, 4, 0, 2, 0, 15, 0x80, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let records = [
+            NtsRecord::EndOfMessage,
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![0, 1],
+            },
+            NtsRecord::Error { errorcode: 1 },
+            NtsRecord::Warning { warningcode: 7 },
+            NtsRecord::AeadAlgorithm {
+                critical: true,
+                algorithm_ids: vec![15],
+            },
+            NtsRecord::NewCookie {
+                cookie_data: vec![1, 2, 3],
+            },
+            NtsRecord::Server {
+                critical: false,
+                name: "time.example.com".into(),
+            },
+            NtsRecord::Port {
+                critical: true,
+                port: 1234,
+            },
+            NtsRecord::Unknown {
+                record_type: 100,
+                critical: false,
+                data: vec![4, 5],
+            },
+        ];
+
+        for record in records {
+            let mut buf = Vec::new();
+            record.encode(&mut buf);
+
+            let (decoded, size) = NtsRecord::decode(&buf).unwrap().unwrap();
+            assert_eq!(decoded, record);
+            assert_eq!(size, buf.len());
+
+            // incomplete records need more data
+            assert_eq!(NtsRecord::decode(&buf[..buf.len() - 1]), Ok(None));
+        }
+    }
+
+    #[test]
+    fn test_invalid_records() {
+        // odd number of bytes in a list of protocol ids
+        assert_eq!(
+            NtsRecord::decode(&[0x80, 1, 0, 1, 0]),
+            Err(NtsRecordError::InvalidRecord(1))
+        );
+        // error record with the wrong size
+        assert_eq!(
+            NtsRecord::decode(&[0x80, 2, 0, 3, 0, 0, 0]),
+            Err(NtsRecordError::InvalidRecord(2))
+        );
+        // end of message with a body
+        assert_eq!(
+            NtsRecord::decode(&[0x80, 0, 0, 1, 0]),
+            Err(NtsRecordError::InvalidRecord(0))
+        );
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let mut records = vec![
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![0],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![15],
+            },
+            NtsRecord::NewCookie {
+                cookie_data: vec![1; 10],
+            },
+            NtsRecord::NewCookie {
+                cookie_data: vec![2; 10],
+            },
+            NtsRecord::Port {
+                critical: false,
+                port: 124,
+            },
+            NtsRecord::Unknown {
+                record_type: 1000,
+                critical: false,
+                data: vec![],
+            },
+            NtsRecord::EndOfMessage,
+        ];
+        let buf = encode_all(&records);
+
+        // wait for the end of message
+        assert_eq!(KeyExchangeResponse::decode(&buf[..buf.len() - 4]), Ok(None));
+
+        let response = KeyExchangeResponse::decode(&buf).unwrap().unwrap();
+        assert_eq!(response.remote, None);
+        assert_eq!(response.port, Some(124));
+        assert_eq!(response.cookies, vec![vec![1; 10], vec![2; 10]]);
+
+        records.insert(
+            0,
+            NtsRecord::Unknown {
+                record_type: 1000,
+                critical: true,
+                data: vec![],
+            },
+        );
+        assert_eq!(
+            KeyExchangeResponse::decode(&encode_all(&records)),
+            Err(NtsRecordError::UnrecognizedCriticalRecord(1000))
+        );
+    }
+
+    #[test]
+    fn test_decode_response_errors() {
+        let buf = encode_all(&[NtsRecord::Error { errorcode: 1 }, NtsRecord::EndOfMessage]);
+        assert_eq!(
+            KeyExchangeResponse::decode(&buf),
+            Err(NtsRecordError::ServerError(1))
+        );
+
+        let buf = encode_all(&[
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![15],
+            },
+            NtsRecord::NewCookie {
+                cookie_data: vec![1; 10],
+            },
+            NtsRecord::EndOfMessage,
+        ]);
+        assert_eq!(
+            KeyExchangeResponse::decode(&buf),
+            Err(NtsRecordError::NoValidProtocol)
+        );
+
+        let buf = encode_all(&[
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![0],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![1],
+            },
+            NtsRecord::NewCookie {
+                cookie_data: vec![1; 10],
+            },
+            NtsRecord::EndOfMessage,
+        ]);
+        assert_eq!(
+            KeyExchangeResponse::decode(&buf),
+            Err(NtsRecordError::NoValidAlgorithm)
+        );
+
+        let buf = encode_all(&[
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![0],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![15],
+            },
+            NtsRecord::EndOfMessage,
+        ]);
+        assert_eq!(
+            KeyExchangeResponse::decode(&buf),
+            Err(NtsRecordError::NoCookies)
+        );
+    }
+
+    #[test]
+    fn test_server_handles_client_request() {
+        let buf = encode_all(&NtsRecord::client_key_exchange_records());
+
+        // wait for the end of message
+        assert_eq!(KeyExchangeRequest::decode(&buf[..buf.len() - 4]), Ok(None));
+
+        let request = KeyExchangeRequest::decode(&buf).unwrap().unwrap();
+        let records = request.response_records(
+            vec![vec![1; 10], vec![2; 10]],
+            Some("time.example.com".into()),
+            Some(1234),
+        );
+
+        // the client understands the response
+        let response = KeyExchangeResponse::decode(&encode_all(&records))
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.cookies, vec![vec![1; 10], vec![2; 10]]);
+        assert_eq!(response.remote.as_deref(), Some("time.example.com"));
+        assert_eq!(response.port, Some(1234));
+    }
+
+    #[test]
+    fn test_server_handles_unsupported_request() {
+        let buf = encode_all(&[
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![0],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![1, 2],
+            },
+            NtsRecord::EndOfMessage,
+        ]);
+        let request = KeyExchangeRequest::decode(&buf).unwrap().unwrap();
+        let records = request.response_records(vec![vec![1; 10]], None, None);
+        assert_eq!(
+            KeyExchangeResponse::decode(&encode_all(&records)),
+            Err(NtsRecordError::NoValidAlgorithm)
+        );
+
+        let buf = encode_all(&[
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![0x8000],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![15],
+            },
+            NtsRecord::EndOfMessage,
+        ]);
+        let request = KeyExchangeRequest::decode(&buf).unwrap().unwrap();
+        let records = request.response_records(vec![vec![1; 10]], None, None);
+        assert_eq!(
+            KeyExchangeResponse::decode(&encode_all(&records)),
+            Err(NtsRecordError::NoValidProtocol)
+        );
+    }
+
+    #[test]
+    fn test_server_rejects_invalid_request() {
+        let buf = encode_all(&[
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![15],
+            },
+            NtsRecord::EndOfMessage,
+        ]);
+        let error = KeyExchangeRequest::decode(&buf).unwrap_err();
+        assert_eq!(error, NtsRecordError::MissingRecord(1));
+        assert_eq!(
+            error.server_response_records()[0],
+            NtsRecord::Error { errorcode: 1 }
+        );
+
+        let buf = encode_all(&[
+            NtsRecord::Unknown {
+                record_type: 1000,
+                critical: true,
+                data: vec![],
+            },
+            NtsRecord::EndOfMessage,
+        ]);
+        let error = KeyExchangeRequest::decode(&buf).unwrap_err();
+        assert_eq!(error, NtsRecordError::UnrecognizedCriticalRecord(1000));
+        assert_eq!(
+            error.server_response_records()[0],
+            NtsRecord::Error { errorcode: 0 }
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{NtpDuration, NtpTimestamp, ReferenceId};
+use crate::{NtpClock, NtpDuration, NtpTimestamp, PollInterval, ReferenceId, SystemSnapshot};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NtpLeapIndicator {
@@ -105,6 +105,11 @@ pub struct NtpHeader {
 }
 
 impl NtpHeader {
+    /// The oldest version we can understand. NTPv3 (rfc1305) uses the same header format,
+    /// with minor differences in the interpretation of some fields.
+    const MIN_VERSION: u8 = 3;
+    const MAX_VERSION: u8 = 4;
+
     /// A new, empty NtpHeader
     pub fn new() -> Self {
         Self {
@@ -128,6 +133,67 @@ impl NtpHeader {
         self.version
     }
 
+    /// Build the response of a server to the client (or symmetric active) request
+    /// `input`, with the state of the server given by `system`.
+    ///
+    /// The `recv_timestamp` should be the time at which `input` was received.
+    /// The transmit timestamp is read from `clock` as the last step, so the
+    /// response should be sent out as soon as possible after this returns.
+    pub fn timestamp_response<C: NtpClock>(
+        system: &SystemSnapshot,
+        input: Self,
+        recv_timestamp: NtpTimestamp,
+        clock: &C,
+    ) -> Result<Self, C::Error> {
+        Ok(Self {
+            leap: system.leap_indicator,
+            // reply with the version of the request, so older clients understand us
+            version: input.version,
+            // a request from a symmetric active peer is answered in passive mode
+            mode: match input.mode {
+                NtpAssociationMode::SymmetricActive => NtpAssociationMode::SymmetricPassive,
+                _ => NtpAssociationMode::Server,
+            },
+            stratum: system.stratum,
+            poll: input.poll,
+            precision: system.precision.log2(),
+            root_delay: system.root_delay,
+            root_dispersion: system.root_dispersion,
+            reference_id: system.reference_id,
+            reference_timestamp: system.reference_timestamp,
+            origin_timestamp: input.transmit_timestamp,
+            receive_timestamp: recv_timestamp,
+            transmit_timestamp: clock.now()?,
+        })
+    }
+
+    /// Build a broadcast of the time of a server with the state given by `system`, that is
+    /// sent every `interval`.
+    ///
+    /// The transmit timestamp is read from `clock` as the last step, so the broadcast should be
+    /// sent out as soon as possible after this returns.
+    pub fn broadcast<C: NtpClock>(
+        system: &SystemSnapshot,
+        interval: PollInterval,
+        clock: &C,
+    ) -> Result<Self, C::Error> {
+        Ok(Self {
+            leap: system.leap_indicator,
+            version: 4,
+            mode: NtpAssociationMode::Broadcast,
+            stratum: system.stratum,
+            poll: interval.as_log(),
+            precision: system.precision.log2(),
+            root_delay: system.root_delay,
+            root_dispersion: system.root_dispersion,
+            reference_id: system.reference_id,
+            reference_timestamp: system.reference_timestamp,
+            origin_timestamp: NtpTimestamp::default(),
+            receive_timestamp: NtpTimestamp::default(),
+            transmit_timestamp: clock.now()?,
+        })
+    }
+
     pub fn deserialize(data: &[u8; 48]) -> NtpHeader {
         NtpHeader {
             leap: NtpLeapIndicator::from_bits((data[0] & 0xC0) >> 6),
@@ -146,6 +212,32 @@ impl NtpHeader {
         }
     }
 
+    /// Read the header at the start of `data`, checking that we understand its version
+    pub fn try_deserialize(data: &[u8]) -> Result<NtpHeader, PacketParseError> {
+        let header_bytes: &[u8; 48] = data
+            .get(..48)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(PacketParseError::TooShort)?;
+        let mut header = NtpHeader::deserialize(header_bytes);
+
+        if !(Self::MIN_VERSION..=Self::MAX_VERSION).contains(&header.version) {
+            return Err(PacketParseError::UnsupportedVersion(header.version));
+        }
+
+        // In NTPv3, the root delay and dispersion are signed. Negative values carry no
+        // meaning for us, and would otherwise be read as enormous durations.
+        if header.version == 3 {
+            if header_bytes[4] & 0x80 != 0 {
+                header.root_delay = NtpDuration::ZERO;
+            }
+            if header_bytes[8] & 0x80 != 0 {
+                header.root_dispersion = NtpDuration::ZERO;
+            }
+        }
+
+        Ok(header)
+    }
+
     pub fn serialize(&self) -> [u8; 48] {
         // Version should only ever be set internally in this module, so
         // violations of this should never happen.
@@ -215,16 +307,26 @@ impl NtpHeader {
         self.stratum == 0
     }
 
+    /// Kiss codes were introduced in NTPv4. In NTPv3, stratum 0 just means that the server
+    /// is unsynchronized, whatever its reference id.
+    pub fn has_kiss_code(&self) -> bool {
+        self.is_kiss() && self.version >= 4 && self.reference_id.is_kiss_code()
+    }
+
     pub fn is_kiss_deny(&self) -> bool {
-        self.is_kiss() && self.reference_id.is_deny()
+        self.has_kiss_code() && self.reference_id.is_deny()
     }
 
     pub fn is_kiss_rate(&self) -> bool {
-        self.is_kiss() && self.reference_id.is_rate()
+        self.has_kiss_code() && self.reference_id.is_rate()
     }
 
     pub fn is_kiss_rstr(&self) -> bool {
-        self.is_kiss() && self.reference_id.is_rstr()
+        self.has_kiss_code() && self.reference_id.is_rstr()
+    }
+
+    pub fn is_kiss_ntsn(&self) -> bool {
+        self.has_kiss_code() && self.reference_id.is_ntsn()
     }
 }
 
@@ -234,6 +336,232 @@ impl Default for NtpHeader {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketParseError {
+    /// The packet is shorter than an NTP header
+    TooShort,
+    /// The packet uses a version of NTP that we cannot interpret
+    UnsupportedVersion(u8),
+    /// The data after the header is not a valid list of extension fields, optionally
+    /// followed by a MAC
+    MalformedExtensionField,
+}
+
+impl std::fmt::Display for PacketParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketParseError::TooShort => f.write_str("packet too short"),
+            PacketParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported NTP version {}", version)
+            }
+            PacketParseError::MalformedExtensionField => f.write_str("malformed extension field"),
+        }
+    }
+}
+
+impl std::error::Error for PacketParseError {}
+
+/// The value of an extension field is too large for the 16 bit length of the field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtensionFieldTooLarge;
+
+impl std::fmt::Display for ExtensionFieldTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("extension field value too large")
+    }
+}
+
+impl std::error::Error for ExtensionFieldTooLarge {}
+
+/// An extension field as defined in rfc7822
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionField {
+    pub field_type: u16,
+    /// The contents of the field. Fields read from a packet include their padding. Only
+    /// [`ExtensionField::new`] makes fields, so that every value fits in a field.
+    pub(crate) value: Vec<u8>,
+}
+
+impl ExtensionField {
+    /// Extension fields are at least 16 bytes, including the 4 byte type and length
+    pub const MIN_SIZE: usize = 16;
+
+    /// The largest value, for which the size of the field is still a multiple of 4 that fits in
+    /// 16 bits
+    pub const MAX_VALUE_SIZE: usize = 0xfffc - 4;
+
+    pub fn new(field_type: u16, value: Vec<u8>) -> Result<Self, ExtensionFieldTooLarge> {
+        if value.len() > Self::MAX_VALUE_SIZE {
+            return Err(ExtensionFieldTooLarge);
+        }
+
+        Ok(ExtensionField { field_type, value })
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Size of the field when serialized
+    pub fn size(&self) -> usize {
+        Self::size_for(self.value.len())
+    }
+
+    /// Size of a field with a value of `len` bytes when serialized
+    pub(crate) fn size_for(len: usize) -> usize {
+        Ord::max(Self::MIN_SIZE, padded_len(4 + len))
+    }
+
+    pub fn serialize(&self, buf: &mut Vec<u8>) {
+        let size = self.size();
+
+        buf.extend_from_slice(&self.field_type.to_be_bytes());
+        buf.extend_from_slice(&(size as u16).to_be_bytes());
+        buf.extend_from_slice(&self.value);
+        buf.resize(buf.len() + size - 4 - self.value.len(), 0);
+    }
+
+    /// Read a single extension field from the start of `data`, returning it together with
+    /// its size
+    fn deserialize(data: &[u8]) -> Result<(Self, usize), PacketParseError> {
+        let header = data
+            .get(..4)
+            .ok_or(PacketParseError::MalformedExtensionField)?;
+        let field_type = u16::from_be_bytes([header[0], header[1]]);
+        let size = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+        if size < Self::MIN_SIZE || padded_len(size) != size || size > data.len() {
+            return Err(PacketParseError::MalformedExtensionField);
+        }
+
+        let field = ExtensionField {
+            field_type,
+            value: data[4..size].to_vec(),
+        };
+
+        Ok((field, size))
+    }
+
+    /// Read a sequence of extension fields that spans all of `data`
+    pub fn deserialize_all(mut data: &[u8]) -> Result<Vec<Self>, PacketParseError> {
+        let mut fields = Vec::new();
+
+        while !data.is_empty() {
+            let (field, size) = Self::deserialize(data)?;
+            fields.push(field);
+            data = &data[size..];
+        }
+
+        Ok(fields)
+    }
+}
+
+pub(crate) fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A message authentication code using a pre-shared key, as defined in rfc5905
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mac {
+    pub keyid: u32,
+    pub digest: Vec<u8>,
+}
+
+impl Mac {
+    /// Digests of MD5 and AES-CMAC are 16 bytes, those of SHA1 20 bytes
+    const SIZES: [usize; 2] = [4 + 16, 4 + 20];
+    const MAX_SIZE: usize = 4 + 20;
+
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.keyid.to_be_bytes());
+        buf.extend_from_slice(&self.digest);
+    }
+
+    fn deserialize(data: &[u8]) -> Self {
+        Mac {
+            keyid: u32::from_be_bytes(data[..4].try_into().unwrap()),
+            digest: data[4..].to_vec(),
+        }
+    }
+}
+
+/// A complete NTP packet: the header, followed by any extension fields and an optional MAC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtpPacket {
+    pub header: NtpHeader,
+    pub extension_fields: Vec<ExtensionField>,
+    pub mac: Option<Mac>,
+}
+
+impl NtpPacket {
+    pub fn new(header: NtpHeader) -> Self {
+        NtpPacket {
+            header,
+            extension_fields: Vec::new(),
+            mac: None,
+        }
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, PacketParseError> {
+        let header = NtpHeader::try_deserialize(data)?;
+        let mut rest = &data[48..];
+
+        // NTPv3 has no extension fields, only an optional MAC
+        if header.version == 3 {
+            let mac = match rest.len() {
+                0 => None,
+                len if Mac::SIZES.contains(&len) => Some(Mac::deserialize(rest)),
+                _ => return Err(PacketParseError::MalformedExtensionField),
+            };
+
+            return Ok(NtpPacket {
+                header,
+                extension_fields: Vec::new(),
+                mac,
+            });
+        }
+
+        // As described in rfc7822, section 7.5, extension fields and a MAC are told apart by
+        // their length: anything longer than a MAC must start with an extension field.
+        let mut extension_fields = Vec::new();
+        while rest.len() > Mac::MAX_SIZE {
+            let (field, size) = ExtensionField::deserialize(rest)?;
+            extension_fields.push(field);
+            rest = &rest[size..];
+        }
+
+        let mac = if rest.is_empty() {
+            None
+        } else if Mac::SIZES.contains(&rest.len()) {
+            Some(Mac::deserialize(rest))
+        } else {
+            // only a minimal extension field is shorter than a MAC
+            extension_fields.append(&mut ExtensionField::deserialize_all(rest)?);
+            None
+        };
+
+        Ok(NtpPacket {
+            header,
+            extension_fields,
+            mac,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = self.header.serialize().to_vec();
+
+        for field in &self.extension_fields {
+            field.serialize(&mut buf);
+        }
+
+        if let Some(mac) = &self.mac {
+            mac.serialize(&mut buf);
+        }
+
+        buf
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -352,4 +680,333 @@ mod tests {
             assert_eq!(packet, b);
         }
     }
+
+    #[test]
+    fn test_extension_field_padding() {
+        let mut buf = Vec::new();
+        ExtensionField::new(0x0104, vec![1, 2, 3])
+            .unwrap()
+            .serialize(&mut buf);
+        assert_eq!(buf, [1, 4, 0, 16, 1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut buf = Vec::new();
+        ExtensionField::new(0x0204, vec![7; 17])
+            .unwrap()
+            .serialize(&mut buf);
+        assert_eq!(buf.len(), 24);
+        assert_eq!(buf[..4], [2, 4, 0, 24]);
+        assert_eq!(buf[4..21], [7; 17]);
+        assert_eq!(buf[21..], [0, 0, 0]);
+
+        let fields = ExtensionField::deserialize_all(&buf).unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].field_type, 0x0204);
+        assert_eq!(fields[0].value[..17], [7; 17]);
+        assert_eq!(fields[0].value.len(), 20);
+    }
+
+    #[test]
+    fn test_extension_field_too_large() {
+        // the largest field still round trips
+        let field = ExtensionField::new(0x0104, vec![5; ExtensionField::MAX_VALUE_SIZE]).unwrap();
+        let mut buf = Vec::new();
+        field.serialize(&mut buf);
+        assert_eq!(buf.len(), 0xfffc);
+        assert_eq!(ExtensionField::deserialize_all(&buf).unwrap(), [field]);
+
+        // anything larger would have a truncated length
+        for size in [ExtensionField::MAX_VALUE_SIZE + 1, 0xfffc, 0x10000] {
+            assert_eq!(
+                ExtensionField::new(0x0104, vec![5; size]),
+                Err(ExtensionFieldTooLarge)
+            );
+        }
+    }
+
+    #[test]
+    fn test_extension_field_invalid_length() {
+        let mut field = Vec::new();
+        ExtensionField::new(0x0104, vec![1; 12])
+            .unwrap()
+            .serialize(&mut field);
+
+        // shorter than the minimum size
+        let mut data = field.clone();
+        data[3] = 12;
+        assert!(ExtensionField::deserialize_all(&data[..12]).is_err());
+
+        // not a multiple of 4
+        let mut data = field.clone();
+        data[3] = 17;
+        data.push(0);
+        assert!(ExtensionField::deserialize_all(&data).is_err());
+
+        // longer than the data
+        let mut data = field.clone();
+        data[3] = 20;
+        assert!(ExtensionField::deserialize_all(&data).is_err());
+
+        // trailing data
+        let mut data = field;
+        data.extend_from_slice(&[0, 0]);
+        assert!(ExtensionField::deserialize_all(&data).is_err());
+    }
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let mut header = NtpHeader::new();
+        header.transmit_timestamp = NtpTimestamp::from_fixed_int(0x1234);
+
+        let packets = [
+            NtpPacket::new(header),
+            NtpPacket {
+                header,
+                extension_fields: vec![
+                    ExtensionField::new(0x0104, vec![1; 32]).unwrap(),
+                    ExtensionField::new(0x0204, vec![2; 100]).unwrap(),
+                ],
+                mac: None,
+            },
+            NtpPacket {
+                header,
+                extension_fields: vec![],
+                mac: Some(Mac {
+                    keyid: 42,
+                    digest: vec![3; 16],
+                }),
+            },
+            NtpPacket {
+                header,
+                extension_fields: vec![
+                    ExtensionField::new(0x0104, vec![1; 12]).unwrap(),
+                    ExtensionField::new(0x2005, vec![4; 12]).unwrap(),
+                ],
+                mac: Some(Mac {
+                    keyid: 7,
+                    digest: vec![5; 20],
+                }),
+            },
+            // a minimal extension field at the end is not mistaken for a MAC
+            NtpPacket {
+                header,
+                extension_fields: vec![ExtensionField::new(0x0104, vec![1; 12]).unwrap()],
+                mac: None,
+            },
+        ];
+
+        for packet in packets {
+            let data = packet.serialize();
+            assert_eq!(NtpPacket::deserialize(&data).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_packet_invalid() {
+        let header = NtpHeader::new().serialize();
+        assert_eq!(
+            NtpPacket::deserialize(&header[..47]),
+            Err(PacketParseError::TooShort)
+        );
+
+        // trailing data that is neither an extension field nor a MAC
+        let mut data = header.to_vec();
+        data.extend_from_slice(&[0; 8]);
+        assert_eq!(
+            NtpPacket::deserialize(&data),
+            Err(PacketParseError::MalformedExtensionField)
+        );
+
+        // an extension field claiming to be longer than the packet
+        let mut data = header.to_vec();
+        ExtensionField::new(0x0104, vec![1; 32])
+            .unwrap()
+            .serialize(&mut data);
+        data.truncate(data.len() - 4);
+        assert_eq!(
+            NtpPacket::deserialize(&data),
+            Err(PacketParseError::MalformedExtensionField)
+        );
+    }
+
+    #[test]
+    fn test_try_deserialize_version() {
+        let mut header = NtpHeader::new();
+
+        for version in [0, 1, 2, 5, 6, 7] {
+            header.version = version;
+            assert_eq!(
+                NtpHeader::try_deserialize(&header.serialize()),
+                Err(PacketParseError::UnsupportedVersion(version))
+            );
+        }
+
+        for version in [3, 4] {
+            header.version = version;
+            assert_eq!(NtpHeader::try_deserialize(&header.serialize()), Ok(header));
+        }
+
+        assert_eq!(
+            NtpHeader::try_deserialize(&header.serialize()[..40]),
+            Err(PacketParseError::TooShort)
+        );
+    }
+
+    #[test]
+    fn test_ntpv3_compatibility() {
+        let mut header = NtpHeader::new();
+        header.version = 3;
+        header.stratum = 0;
+        header.reference_id = ReferenceId::KISS_DENY;
+        header.root_delay = NtpDuration::from_fixed_int(1 << 28);
+
+        let mut data = header.serialize();
+        // a negative root dispersion
+        data[8..12].copy_from_slice(&(-256_i32).to_be_bytes());
+
+        let packet = NtpPacket::deserialize(&data).unwrap();
+        assert_eq!(packet.header.root_delay, header.root_delay);
+        assert_eq!(packet.header.root_dispersion, NtpDuration::ZERO);
+        // there are no kiss codes in NTPv3
+        assert!(packet.header.is_kiss());
+        assert!(!packet.header.is_kiss_deny());
+
+        // a MAC may follow the header, but there are no extension fields
+        let mut signed = data.to_vec();
+        signed.extend_from_slice(&[0; 20]);
+        assert!(NtpPacket::deserialize(&signed).unwrap().mac.is_some());
+
+        let mut extended = data.to_vec();
+        ExtensionField::new(0x0104, vec![1; 12])
+            .unwrap()
+            .serialize(&mut extended);
+        assert_eq!(
+            NtpPacket::deserialize(&extended),
+            Err(PacketParseError::MalformedExtensionField)
+        );
+    }
+
+    struct FixedClock(NtpTimestamp);
+
+    impl NtpClock for FixedClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(self.0)
+        }
+
+        fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn update_clock(
+            &self,
+            _offset: NtpDuration,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+            _poll_interval: crate::PollInterval,
+            _leap_status: NtpLeapIndicator,
+        ) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn set_tai(&self, _tai_offset: i32) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+    }
+
+    #[test]
+    fn test_timestamp_response() {
+        let system = SystemSnapshot {
+            leap_indicator: NtpLeapIndicator::Leap61,
+            stratum: 3,
+            reference_id: ReferenceId::from_int(0x7f000001),
+            root_delay: NtpDuration::from_fixed_int(1 << 28),
+            root_dispersion: NtpDuration::from_fixed_int(1 << 27),
+            reference_timestamp: NtpTimestamp::from_fixed_int(0x1000),
+            ..Default::default()
+        };
+
+        let mut request = NtpHeader::new();
+        request.version = 3;
+        request.poll = 6;
+        request.transmit_timestamp = NtpTimestamp::from_fixed_int(0x1234);
+
+        let response = NtpHeader::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(0x5678),
+            &FixedClock(NtpTimestamp::from_fixed_int(0x9abc)),
+        )
+        .unwrap();
+
+        assert_eq!(response.mode, NtpAssociationMode::Server);
+        assert_eq!(response.version, 3);
+        assert_eq!(response.poll, 6);
+        assert_eq!(response.leap, NtpLeapIndicator::Leap61);
+        assert_eq!(response.stratum, 3);
+        assert_eq!(response.precision, -18);
+        assert_eq!(response.reference_id, system.reference_id);
+        assert_eq!(response.root_delay, system.root_delay);
+        assert_eq!(response.root_dispersion, system.root_dispersion);
+        assert_eq!(response.reference_timestamp, system.reference_timestamp);
+        assert_eq!(response.origin_timestamp, request.transmit_timestamp);
+        assert_eq!(
+            response.receive_timestamp,
+            NtpTimestamp::from_fixed_int(0x5678)
+        );
+        assert_eq!(
+            response.transmit_timestamp,
+            NtpTimestamp::from_fixed_int(0x9abc)
+        );
+
+        request.mode = NtpAssociationMode::SymmetricActive;
+        let response = NtpHeader::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(0x5678),
+            &FixedClock(NtpTimestamp::from_fixed_int(0x9abc)),
+        )
+        .unwrap();
+        assert_eq!(response.mode, NtpAssociationMode::SymmetricPassive);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let system = SystemSnapshot {
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            stratum: 2,
+            reference_id: ReferenceId::from_int(0x7f000001),
+            root_delay: NtpDuration::from_fixed_int(1 << 28),
+            reference_timestamp: NtpTimestamp::from_fixed_int(0x1000),
+            ..Default::default()
+        };
+
+        let broadcast = NtpHeader::broadcast(
+            &system,
+            PollInterval::at_least(std::time::Duration::from_secs(64)),
+            &FixedClock(NtpTimestamp::from_fixed_int(0x9abc)),
+        )
+        .unwrap();
+
+        assert_eq!(broadcast.mode, NtpAssociationMode::Broadcast);
+        assert_eq!(broadcast.version, 4);
+        assert_eq!(broadcast.poll, 6);
+        assert_eq!(broadcast.stratum, 2);
+        assert_eq!(broadcast.reference_id, system.reference_id);
+        assert_eq!(broadcast.root_delay, system.root_delay);
+        assert_eq!(broadcast.reference_timestamp, system.reference_timestamp);
+        assert_eq!(broadcast.origin_timestamp, NtpTimestamp::default());
+        assert_eq!(
+            broadcast.transmit_timestamp,
+            NtpTimestamp::from_fixed_int(0x9abc)
+        );
+    }
 }
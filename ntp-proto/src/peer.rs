@@ -1,8 +1,9 @@
 use crate::{
     filter::{FilterTuple, LastMeasurements},
+    leap_smear::LeapSmearStatus,
     packet::{NtpAssociationMode, NtpLeapIndicator},
     time_types::{FrequencyTolerance, NtpInstant},
-    NtpDuration, NtpHeader, NtpTimestamp, PollInterval, ReferenceId,
+    NtpDuration, NtpHeader, NtpPacket, NtpTimestamp, PollInterval, ReferenceId, SymmetricKey,
 };
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
@@ -10,6 +11,10 @@ use tracing::{debug, info, instrument, trace, warn};
 
 const MAX_STRATUM: u8 = 16;
 
+/// Seconds after the last update of our clock after which we no longer serve our time as
+/// synchronized, because by then it may have drifted arbitrarily far from that of our peers
+const MAX_SYNC_AGE: f64 = 86400.0;
+
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub struct PeerStatistics {
     pub offset: NtpDuration,
@@ -19,6 +24,18 @@ pub struct PeerStatistics {
     pub jitter: f64,
 }
 
+/// The timestamps of our last completed exchange with the server. An interleaved response tells
+/// us when the server actually sent its response to that exchange.
+#[derive(Debug, Clone, Copy)]
+struct PreviousExchange {
+    /// When we sent our request (T1)
+    send_time: NtpTimestamp,
+    /// When the server received our request (T2)
+    remote_receive: NtpTimestamp,
+    /// When we received the response (T4)
+    recv_time: NtpTimestamp,
+}
+
 #[derive(Debug, Clone)]
 pub struct Peer {
     // Poll interval dictated by unreachability backoff
@@ -34,6 +51,12 @@ pub struct Peer {
     // This is used as validation that the packet we get is the correct response to the one we sent
     // (guards against e.g. replay and packet reordering)
     next_expected_origin: Option<NtpTimestamp>,
+    // An interleaved response instead has the receive timestamp of our request as its origin
+    next_expected_interleaved_origin: Option<NtpTimestamp>,
+    previous_exchange: Option<PreviousExchange>,
+
+    /// The delay measured in client/server mode, which broadcast packets are assumed to have
+    calibrated_delay: Option<NtpDuration>,
 
     statistics: PeerStatistics,
     last_measurements: LastMeasurements,
@@ -42,6 +65,11 @@ pub struct Peer {
     peer_id: ReferenceId,
     our_id: ReferenceId,
     reach: Reach,
+
+    /// When set, our polls carry a MAC made with this key, and responses must carry one too
+    key: Option<SymmetricKey>,
+    /// Whether this is a symmetric active association (rfc5905, section 3) instead of a client
+    symmetric: bool,
 }
 
 /// Used to determine whether the server is reachable and the data are fresh
@@ -70,18 +98,18 @@ impl std::fmt::Debug for Reach {
 }
 
 impl Reach {
-    fn is_reachable(&self) -> bool {
+    pub fn is_reachable(&self) -> bool {
         self.0 != 0
     }
 
     /// We have just received a packet, so the peer is definitely reachable
-    pub(crate) fn received_packet(&mut self) {
+    pub fn received_packet(&mut self) {
         self.0 |= 1;
     }
 
     /// A packet received some number of poll intervals ago is decreasingly relevant for
     /// determining that a peer is still reachable. We discount the packets received so far.
-    fn poll(&mut self) {
+    pub fn poll(&mut self) {
         self.0 <<= 1
     }
 }
@@ -94,6 +122,18 @@ pub struct SystemSnapshot {
     pub precision: NtpDuration,
     /// May be updated by clock_update
     pub leap_indicator: NtpLeapIndicator,
+    /// Stratum of the system peer plus one, or MAX_STRATUM when not synchronized
+    pub stratum: u8,
+    /// Reference id of the current system peer
+    pub reference_id: ReferenceId,
+    /// Total round-trip delay to the primary reference source
+    pub root_delay: NtpDuration,
+    /// Total dispersion to the primary reference source
+    pub root_dispersion: NtpDuration,
+    /// Time at which our clock was last updated with the time of our peers
+    pub reference_timestamp: NtpTimestamp,
+    /// Progress of the leap smear, while our clock deliberately differs from UTC
+    pub leap_smear: Option<LeapSmearStatus>,
 }
 
 impl Default for SystemSnapshot {
@@ -102,6 +142,39 @@ impl Default for SystemSnapshot {
             poll_interval: PollInterval::default(),
             precision: NtpDuration::from_exponent(-18),
             leap_indicator: NtpLeapIndicator::Unknown,
+            stratum: MAX_STRATUM,
+            reference_id: ReferenceId::NONE,
+            root_delay: NtpDuration::ZERO,
+            root_dispersion: NtpDuration::ZERO,
+            reference_timestamp: NtpTimestamp::default(),
+            leap_smear: None,
+        }
+    }
+}
+
+impl SystemSnapshot {
+    /// The state of the system as it is served to clients at `now`. Our clock may have drifted by
+    /// up to `frequency_tolerance` since it was last updated, which adds to the root dispersion.
+    /// Once that update is more than [`MAX_SYNC_AGE`] seconds old, we are not synchronized at all.
+    pub fn at(&self, now: NtpTimestamp, frequency_tolerance: FrequencyTolerance) -> Self {
+        if self.stratum >= MAX_STRATUM {
+            return *self;
+        }
+
+        // after our clock was stepped back, the last update can seem to be in the future
+        let age = Ord::max(now - self.reference_timestamp, NtpDuration::ZERO);
+
+        if age.to_seconds() > MAX_SYNC_AGE {
+            SystemSnapshot {
+                leap_indicator: NtpLeapIndicator::Unknown,
+                stratum: MAX_STRATUM,
+                ..*self
+            }
+        } else {
+            SystemSnapshot {
+                root_dispersion: self.root_dispersion + age * frequency_tolerance,
+                ..*self
+            }
         }
     }
 }
@@ -118,6 +191,22 @@ pub enum IgnoreReason {
     KissDemobilize,
     /// The best packet is older than the peer's current time
     TooOld,
+    /// The packet does not carry a valid MAC made with the key of this peer
+    AuthenticationFailed,
+    /// The packet has stratum 0, but no kiss code
+    InvalidStratum,
+    /// The server did not fill in the transmit timestamp
+    InvalidTransmitTimestamp,
+    /// The transmit timestamp is the same as in the previous packet of this peer
+    DuplicatePacket,
+    /// The server is not synchronized, but sent a regular reply
+    Unsynchronized,
+    /// The root delay or root dispersion of the server exceeds the maximum dispersion
+    InvalidRootDistance,
+    /// The server claims to have been synchronized after it sent this packet
+    InvalidReferenceTimestamp,
+    /// A broadcast packet arrived before the delay to the server was measured
+    Uncalibrated,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -253,6 +342,10 @@ impl Peer {
             remote_min_poll_interval: PollInterval::MIN,
 
             next_expected_origin: None,
+            next_expected_interleaved_origin: None,
+            previous_exchange: None,
+
+            calibrated_delay: None,
 
             statistics: Default::default(),
             last_measurements: LastMeasurements::new(time),
@@ -261,9 +354,43 @@ impl Peer {
             our_id,
             peer_id,
             reach: Default::default(),
+            key: None,
+            symmetric: false,
         }
     }
 
+    /// A peer that authenticates its packets with a symmetric key
+    pub fn new_with_key(
+        our_id: ReferenceId,
+        peer_id: ReferenceId,
+        local_clock_time: NtpInstant,
+        key: SymmetricKey,
+    ) -> Self {
+        Self {
+            key: Some(key),
+            ..Self::new(our_id, peer_id, local_clock_time)
+        }
+    }
+
+    /// A symmetric active association with a peer that can both give us time and take time from
+    /// us. Its packets are authenticated with `key` when given.
+    pub fn new_symmetric(
+        our_id: ReferenceId,
+        peer_id: ReferenceId,
+        local_clock_time: NtpInstant,
+        key: Option<SymmetricKey>,
+    ) -> Self {
+        Self {
+            key,
+            symmetric: true,
+            ..Self::new(our_id, peer_id, local_clock_time)
+        }
+    }
+
+    pub fn is_symmetric(&self) -> bool {
+        self.symmetric
+    }
+
     pub fn current_poll_interval(&self, system: SystemSnapshot) -> PollInterval {
         system
             .poll_interval
@@ -271,7 +398,56 @@ impl Peer {
             .max(self.remote_min_poll_interval)
     }
 
-    pub fn generate_poll_message(&mut self, system: SystemSnapshot) -> NtpHeader {
+    pub fn generate_poll_message(&mut self, system: SystemSnapshot) -> NtpPacket {
+        // In order to increase the entropy of the transmit timestamp
+        // it is just a randomly generated timestamp.
+        // We then expect to get it back identically from the remote
+        // in the origin field.
+        let transmit_timestamp = thread_rng().gen();
+        let mut packet = self.generate_message(system, transmit_timestamp);
+
+        // Ask for an interleaved response, which tells us when the server actually sent its
+        // previous response, by sending back the receive timestamp of that response. Servers
+        // that do not support this ignore it, and respond in basic mode. The receive timestamp is
+        // random for the same reasons as the transmit timestamp.
+        self.next_expected_interleaved_origin = match self.previous_exchange {
+            Some(previous) => {
+                let receive_timestamp = thread_rng().gen();
+                packet.origin_timestamp = previous.remote_receive;
+                packet.receive_timestamp = receive_timestamp;
+                Some(receive_timestamp)
+            }
+            None => None,
+        };
+
+        self.sign(packet)
+    }
+
+    /// The poll message of a symmetric association. Unlike in client mode, the peer measures
+    /// its offset to us with the timestamps of our messages, so `transmit_timestamp` must be the
+    /// time at which the message is sent. Following rfc5905, the origin and receive timestamps
+    /// are the transmit timestamp of the last packet of the peer, and the time we received it.
+    pub fn generate_symmetric_message(
+        &mut self,
+        system: SystemSnapshot,
+        transmit_timestamp: NtpTimestamp,
+    ) -> NtpPacket {
+        let mut packet = self.generate_message(system, transmit_timestamp);
+        packet.mode = NtpAssociationMode::SymmetricActive;
+
+        if let Some(previous) = self.previous_exchange {
+            packet.origin_timestamp = self.last_packet.transmit_timestamp;
+            packet.receive_timestamp = previous.recv_time;
+        }
+
+        self.sign(packet)
+    }
+
+    fn generate_message(
+        &mut self,
+        system: SystemSnapshot,
+        transmit_timestamp: NtpTimestamp,
+    ) -> NtpHeader {
         self.reach.poll();
 
         let mut packet = NtpHeader::new();
@@ -282,28 +458,36 @@ impl Peer {
         // Ensure we don't spam the remote with polls if it is not reachable
         self.backoff_interval = poll_interval.inc();
 
-        // In order to increase the entropy of the transmit timestamp
-        // it is just a randomly generated timestamp.
-        // We then expect to get it back identically from the remote
-        // in the origin field.
-        let transmit_timestamp = thread_rng().gen();
         self.next_expected_origin = Some(transmit_timestamp);
+        self.next_expected_interleaved_origin = None;
         packet.transmit_timestamp = transmit_timestamp;
 
         packet
     }
 
+    fn sign(&self, packet: NtpHeader) -> NtpPacket {
+        let mut packet = NtpPacket::new(packet);
+        if let Some(key) = &self.key {
+            key.sign(&mut packet);
+        }
+
+        packet
+    }
+
     #[instrument(skip(self, system, frequency_tolerance), fields(peer = debug(self.peer_id)))]
     pub fn handle_incoming(
         &mut self,
         system: SystemSnapshot,
-        message: NtpHeader,
+        packet: NtpPacket,
         local_clock_time: NtpInstant,
         frequency_tolerance: FrequencyTolerance,
         send_time: NtpTimestamp,
         recv_time: NtpTimestamp,
     ) -> Result<PeerSnapshot, IgnoreReason> {
-        if Some(message.origin_timestamp) != self.next_expected_origin {
+        let message = packet.header;
+        let interleaved = Some(message.origin_timestamp) == self.next_expected_interleaved_origin;
+
+        if !interleaved && Some(message.origin_timestamp) != self.next_expected_origin {
             // Packets should be a response to a previous request from us,
             // if not just ignore. Note that this might also happen when
             // we reset between sending the request and receiving the response.
@@ -312,6 +496,10 @@ impl Peer {
             // to denial of service attacks.
             debug!("Received old/unexpected packet from peer");
             Err(IgnoreReason::InvalidPacketTime)
+        } else if matches!(&self.key, Some(key) if !key.verify(&packet)) {
+            // Checked before anything else, so forged kiss codes cannot affect us either
+            warn!("Received packet that failed authentication");
+            Err(IgnoreReason::AuthenticationFailed)
         } else if message.is_kiss_rate() {
             // KISS packets may not have correct timestamps at all, handle them anyway
             self.remote_min_poll_interval =
@@ -322,14 +510,18 @@ impl Peer {
             warn!("Peer denied service");
             // KISS packets may not have correct timestamps at all, handle them anyway
             Err(IgnoreReason::KissDemobilize)
-        } else if message.is_kiss() {
+        } else if message.has_kiss_code() {
             warn!("Unrecognized KISS Message from peer");
             // Ignore unrecognized control messages
             Err(IgnoreReason::KissIgnore)
-        } else if message.mode != NtpAssociationMode::Server {
-            // we currently only support a client <-> server association
+        } else if message.is_kiss() {
+            debug!("Received packet with stratum 0 but no kiss code");
+            Err(IgnoreReason::InvalidStratum)
+        } else if !self.accepts_mode(message.mode) {
             warn!("Received packet with invalid mode");
             Err(IgnoreReason::InvalidMode)
+        } else if let Err(ignore_reason) = self.check_contents(&message) {
+            Err(ignore_reason)
         } else {
             trace!("Packet accepted for processing");
             // For reachability, mark that we have had a response
@@ -340,9 +532,31 @@ impl Peer {
 
             // we received this packet, and don't want to accept future ones with this next_expected_origin
             self.next_expected_origin = None;
+            self.next_expected_interleaved_origin = None;
+
+            self.last_packet = message;
+
+            let current_exchange = PreviousExchange {
+                send_time,
+                remote_receive: message.receive_timestamp,
+                recv_time,
+            };
+
+            // An interleaved response carries the transmit timestamp of the previous response,
+            // so the measurement is made with the other timestamps of the previous exchange
+            let (header, send_time, recv_time) =
+                match self.previous_exchange.replace(current_exchange) {
+                    Some(previous) if interleaved => {
+                        trace!("Interleaved response");
+                        let mut header = message;
+                        header.receive_timestamp = previous.remote_receive;
+                        (header, previous.send_time, previous.recv_time)
+                    }
+                    _ => (message, send_time, recv_time),
+                };
 
             let filter_input = FilterTuple::from_packet_default(
-                &message,
+                &header,
                 system.precision,
                 local_clock_time,
                 frequency_tolerance,
@@ -350,6 +564,120 @@ impl Peer {
                 recv_time,
             );
 
+            let result = self.message_for_system(
+                filter_input,
+                system.leap_indicator,
+                system.precision,
+                frequency_tolerance,
+            );
+
+            if result.is_ok() {
+                self.calibrated_delay = Some(self.statistics.delay);
+            }
+
+            result
+        }
+    }
+
+    /// A server answers a client, a symmetric peer answers in passive mode, or in active mode
+    /// when it has an association with us as well
+    fn accepts_mode(&self, mode: NtpAssociationMode) -> bool {
+        if self.symmetric {
+            matches!(
+                mode,
+                NtpAssociationMode::SymmetricPassive | NtpAssociationMode::SymmetricActive
+            )
+        } else {
+            mode == NtpAssociationMode::Server
+        }
+    }
+
+    /// Checks on the timestamps and synchronization state that a packet from the server reports
+    fn check_contents(&self, message: &NtpHeader) -> Result<(), IgnoreReason> {
+        if message.transmit_timestamp == NtpTimestamp::default() {
+            warn!("Received packet without transmit timestamp");
+            Err(IgnoreReason::InvalidTransmitTimestamp)
+        } else if message.transmit_timestamp == self.last_packet.transmit_timestamp {
+            // Replays are ruled out before this check, so this is a server that sent the same
+            // timestamp in two different packets, i.e. a stuck clock
+            warn!("Received packet with the transmit timestamp of the previous packet");
+            Err(IgnoreReason::DuplicatePacket)
+        } else if !message.leap.is_synchronized() || message.stratum >= MAX_STRATUM {
+            debug!("Received regular reply from an unsynchronized peer");
+            Err(IgnoreReason::Unsynchronized)
+        } else if message.root_delay > NtpDuration::MAX_DISPERSION
+            || message.root_dispersion > NtpDuration::MAX_DISPERSION
+        {
+            debug!(
+                root_delay = debug(message.root_delay),
+                root_dispersion = debug(message.root_dispersion),
+                "Received packet with excessive root distance"
+            );
+            Err(IgnoreReason::InvalidRootDistance)
+        } else if message.reference_timestamp != NtpTimestamp::default()
+            && message.reference_timestamp - message.transmit_timestamp > NtpDuration::ZERO
+        {
+            // A reference timestamp of 0 just means the server does not tell us
+            warn!("Received packet with reference timestamp after its transmit timestamp");
+            Err(IgnoreReason::InvalidReferenceTimestamp)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Process a broadcast packet of the server. Broadcast packets are only used once the delay
+    /// to the server is calibrated by a client/server exchange through `handle_incoming`.
+    #[instrument(skip(self, system, frequency_tolerance), fields(peer = debug(self.peer_id)))]
+    pub fn handle_broadcast(
+        &mut self,
+        system: SystemSnapshot,
+        packet: NtpPacket,
+        local_clock_time: NtpInstant,
+        frequency_tolerance: FrequencyTolerance,
+        recv_time: NtpTimestamp,
+    ) -> Result<PeerSnapshot, IgnoreReason> {
+        let message = packet.header;
+
+        if matches!(&self.key, Some(key) if !key.verify(&packet)) {
+            warn!("Received broadcast packet that failed authentication");
+            Err(IgnoreReason::AuthenticationFailed)
+        } else if message.mode != NtpAssociationMode::Broadcast {
+            warn!("Received broadcast packet with invalid mode");
+            Err(IgnoreReason::InvalidMode)
+        } else if message.is_kiss() {
+            // kiss codes are only meaningful as a response to our own requests
+            debug!("Received broadcast packet with stratum 0");
+            Err(IgnoreReason::InvalidStratum)
+        } else if self.last_packet.transmit_timestamp != NtpTimestamp::default()
+            && message.transmit_timestamp - self.last_packet.transmit_timestamp < NtpDuration::ZERO
+        {
+            // Broadcasts cannot be matched to a request, so a replayed packet is recognized by
+            // being older than the last packet we accepted
+            debug!("Received old broadcast packet");
+            Err(IgnoreReason::InvalidPacketTime)
+        } else if let Err(ignore_reason) = self.check_contents(&message) {
+            Err(ignore_reason)
+        } else {
+            let delay = match self.calibrated_delay {
+                Some(delay) => delay,
+                None => {
+                    debug!("Received broadcast packet before calibration");
+                    return Err(IgnoreReason::Uncalibrated);
+                }
+            };
+
+            trace!("Broadcast packet accepted for processing");
+            self.reach.received_packet();
+            self.last_packet = message;
+
+            let filter_input = FilterTuple::from_packet_broadcast(
+                &message,
+                system.precision,
+                local_clock_time,
+                delay,
+                recv_time,
+            );
+
             self.message_for_system(
                 filter_input,
                 system.leap_indicator,
@@ -359,6 +687,16 @@ impl Peer {
         }
     }
 
+    /// A broadcast interval passed, in which we expected a broadcast packet from the server
+    pub fn expect_broadcast(&mut self) {
+        self.reach.poll();
+    }
+
+    /// The interval at which the server says it sends broadcast packets
+    pub fn broadcast_interval(&self) -> PollInterval {
+        PollInterval::from_log(self.last_packet.poll)
+    }
+
     /// Data from a peer that is needed for the (global) clock filter and combine process
     fn message_for_system(
         &mut self,
@@ -417,10 +755,29 @@ impl Peer {
 
         // make sure in-flight messages are ignored
         self.next_expected_origin = None;
+        self.next_expected_interleaved_origin = None;
+        self.previous_exchange = None;
 
         info!(our_id = ?self.our_id, peer_id = ?self.peer_id, "Peer reset");
     }
 
+    /// Start over as a new association, because the server moved to a different address. The
+    /// key (if any) and the association mode are kept.
+    pub fn reset_address(
+        &mut self,
+        our_id: ReferenceId,
+        peer_id: ReferenceId,
+        local_clock_time: NtpInstant,
+    ) {
+        let key = self.key.take();
+
+        *self = Self {
+            key,
+            symmetric: self.symmetric,
+            ..Self::new(our_id, peer_id, local_clock_time)
+        };
+    }
+
     #[cfg(any(test, feature = "fuzz"))]
     pub(crate) fn test_peer(instant: NtpInstant) -> Self {
         Peer {
@@ -429,6 +786,10 @@ impl Peer {
             remote_min_poll_interval: PollInterval::default(),
 
             next_expected_origin: None,
+            next_expected_interleaved_origin: None,
+            previous_exchange: None,
+
+            calibrated_delay: None,
 
             statistics: Default::default(),
             last_measurements: LastMeasurements::new(instant),
@@ -437,6 +798,8 @@ impl Peer {
             peer_id: ReferenceId::from_int(0),
             our_id: ReferenceId::from_int(0),
             reach: Reach::default(),
+            key: None,
+            symmetric: false,
         }
     }
 }
@@ -585,6 +948,41 @@ mod test {
         assert!(reach.is_reachable());
     }
 
+    #[test]
+    fn test_system_snapshot_at() {
+        let updated = NtpTimestamp::from_unix(1_000_000, 0);
+        let system = SystemSnapshot {
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            stratum: 2,
+            root_dispersion: NtpDuration::from_seconds(0.01),
+            reference_timestamp: updated,
+            ..Default::default()
+        };
+        let tolerance = FrequencyTolerance::ppm(15);
+
+        let served = system.at(updated, tolerance);
+        assert_eq!(served.root_dispersion, system.root_dispersion);
+
+        // the dispersion grows by 15 ppm of the 1000 seconds since the update
+        let served = system.at(updated + NtpDuration::from_seconds(1000.0), tolerance);
+        assert!((served.root_dispersion.to_seconds() - 0.025).abs() < 1e-6);
+        assert_eq!(served.stratum, 2);
+        assert_eq!(served.leap_indicator, NtpLeapIndicator::NoWarning);
+
+        let served = system.at(updated - NtpDuration::from_seconds(10.0), tolerance);
+        assert_eq!(served.root_dispersion, system.root_dispersion);
+
+        // a day after the last update we no longer claim to be synchronized
+        let served = system.at(updated + NtpDuration::from_seconds(86401.0), tolerance);
+        assert_eq!(served.stratum, MAX_STRATUM);
+        assert_eq!(served.leap_indicator, NtpLeapIndicator::Unknown);
+
+        // nor do we claim to be when we never were
+        let served = SystemSnapshot::default().at(updated, tolerance);
+        assert_eq!(served.stratum, MAX_STRATUM);
+        assert_eq!(served.root_dispersion, NtpDuration::ZERO);
+    }
+
     #[test]
     fn test_accept_synchronization() {
         use AcceptSynchronizationError::*;
@@ -655,11 +1053,12 @@ mod test {
         let mut response = NtpHeader::new();
         response.mode = NtpAssociationMode::Server;
         response.stratum = 1;
-        response.origin_timestamp = packet.transmit_timestamp;
+        response.origin_timestamp = packet.header.transmit_timestamp;
+        response.transmit_timestamp = NtpTimestamp::from_fixed_int(200);
         assert!(peer
             .handle_incoming(
                 system,
-                response,
+                NtpPacket::new(response),
                 base,
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::default(),
@@ -674,12 +1073,12 @@ mod test {
         let mut response = NtpHeader::new();
         response.mode = NtpAssociationMode::Server;
         response.stratum = 0;
-        response.origin_timestamp = packet.transmit_timestamp;
+        response.origin_timestamp = packet.header.transmit_timestamp;
         response.reference_id = ReferenceId::KISS_RATE;
         assert!(peer
             .handle_incoming(
                 system,
-                response,
+                NtpPacket::new(response),
                 base,
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::default(),
@@ -701,14 +1100,14 @@ mod test {
         let system = SystemSnapshot::default();
         packet.stratum = 1;
         packet.mode = NtpAssociationMode::Server;
-        packet.origin_timestamp = outgoing.transmit_timestamp;
+        packet.origin_timestamp = outgoing.header.transmit_timestamp;
         packet.receive_timestamp = NtpTimestamp::from_fixed_int(100);
         packet.transmit_timestamp = NtpTimestamp::from_fixed_int(200);
 
         assert!(peer
             .handle_incoming(
                 system,
-                packet,
+                NtpPacket::new(packet),
                 base + Duration::from_secs(1),
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::from_fixed_int(0),
@@ -718,7 +1117,7 @@ mod test {
         assert!(peer
             .handle_incoming(
                 system,
-                packet,
+                NtpPacket::new(packet),
                 base + Duration::from_secs(1),
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::from_fixed_int(0),
@@ -739,7 +1138,7 @@ mod test {
         assert!(!matches!(
             peer.handle_incoming(
                 system,
-                packet,
+                NtpPacket::new(packet),
                 base + Duration::from_secs(1),
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::from_fixed_int(0),
@@ -752,12 +1151,12 @@ mod test {
         let system = SystemSnapshot::default();
         let outgoing = peer.generate_poll_message(system);
         packet.reference_id = ReferenceId::KISS_RSTR;
-        packet.origin_timestamp = outgoing.transmit_timestamp;
+        packet.origin_timestamp = outgoing.header.transmit_timestamp;
         packet.mode = NtpAssociationMode::Server;
         assert!(matches!(
             peer.handle_incoming(
                 system,
-                packet,
+                NtpPacket::new(packet),
                 base + Duration::from_secs(1),
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::from_fixed_int(0),
@@ -773,7 +1172,7 @@ mod test {
         assert!(!matches!(
             peer.handle_incoming(
                 system,
-                packet,
+                NtpPacket::new(packet),
                 base + Duration::from_secs(1),
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::from_fixed_int(0),
@@ -786,12 +1185,12 @@ mod test {
         let system = SystemSnapshot::default();
         let outgoing = peer.generate_poll_message(system);
         packet.reference_id = ReferenceId::KISS_DENY;
-        packet.origin_timestamp = outgoing.transmit_timestamp;
+        packet.origin_timestamp = outgoing.header.transmit_timestamp;
         packet.mode = NtpAssociationMode::Server;
         assert!(matches!(
             peer.handle_incoming(
                 system,
-                packet,
+                NtpPacket::new(packet),
                 base + Duration::from_secs(1),
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::from_fixed_int(0),
@@ -809,7 +1208,7 @@ mod test {
         assert!(peer
             .handle_incoming(
                 system,
-                packet,
+                NtpPacket::new(packet),
                 base + Duration::from_secs(1),
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::from_fixed_int(0),
@@ -825,12 +1224,12 @@ mod test {
         let system = SystemSnapshot::default();
         let outgoing = peer.generate_poll_message(system);
         packet.reference_id = ReferenceId::KISS_RATE;
-        packet.origin_timestamp = outgoing.transmit_timestamp;
+        packet.origin_timestamp = outgoing.header.transmit_timestamp;
         packet.mode = NtpAssociationMode::Server;
         assert!(peer
             .handle_incoming(
                 system,
-                packet,
+                NtpPacket::new(packet),
                 base + Duration::from_secs(1),
                 FrequencyTolerance::ppm(15),
                 NtpTimestamp::from_fixed_int(0),
@@ -840,4 +1239,390 @@ mod test {
         assert!(peer.remote_min_poll_interval > old_poll_interval);
         assert!(peer.remote_min_poll_interval >= old_remote_interval);
     }
+
+    #[test]
+    fn test_packet_sanity() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let mut peer = Peer::test_peer(base);
+
+        let mut valid = NtpHeader::new();
+        valid.stratum = 2;
+        valid.mode = NtpAssociationMode::Server;
+        valid.root_delay = NtpDuration::from_seconds(0.01);
+        valid.root_dispersion = NtpDuration::from_seconds(0.01);
+        valid.reference_timestamp = NtpTimestamp::from_fixed_int(50);
+        valid.receive_timestamp = NtpTimestamp::from_fixed_int(100);
+        valid.transmit_timestamp = NtpTimestamp::from_fixed_int(200);
+
+        let respond = |peer: &mut Peer, mut header: NtpHeader| {
+            let outgoing = peer.generate_poll_message(system);
+            header.origin_timestamp = outgoing.header.transmit_timestamp;
+            peer.handle_incoming(
+                system,
+                NtpPacket::new(header),
+                base + Duration::from_secs(1),
+                FrequencyTolerance::ppm(15),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            )
+        };
+
+        let mut packet = valid;
+        packet.stratum = 0;
+        assert!(matches!(
+            respond(&mut peer, packet),
+            Err(IgnoreReason::InvalidStratum)
+        ));
+
+        let mut packet = valid;
+        packet.transmit_timestamp = NtpTimestamp::default();
+        assert!(matches!(
+            respond(&mut peer, packet),
+            Err(IgnoreReason::InvalidTransmitTimestamp)
+        ));
+
+        let mut packet = valid;
+        packet.leap = NtpLeapIndicator::Unknown;
+        assert!(matches!(
+            respond(&mut peer, packet),
+            Err(IgnoreReason::Unsynchronized)
+        ));
+
+        let mut packet = valid;
+        packet.stratum = MAX_STRATUM;
+        assert!(matches!(
+            respond(&mut peer, packet),
+            Err(IgnoreReason::Unsynchronized)
+        ));
+
+        let mut packet = valid;
+        packet.root_delay = NtpDuration::from_seconds(17.0);
+        assert!(matches!(
+            respond(&mut peer, packet),
+            Err(IgnoreReason::InvalidRootDistance)
+        ));
+
+        let mut packet = valid;
+        packet.root_dispersion = NtpDuration::from_seconds(17.0);
+        assert!(matches!(
+            respond(&mut peer, packet),
+            Err(IgnoreReason::InvalidRootDistance)
+        ));
+
+        let mut packet = valid;
+        packet.reference_timestamp = NtpTimestamp::from_fixed_int(300);
+        assert!(matches!(
+            respond(&mut peer, packet),
+            Err(IgnoreReason::InvalidReferenceTimestamp)
+        ));
+
+        // none of the rejected packets reached the measurements
+        assert_eq!(peer.last_packet, NtpHeader::default());
+        assert!(respond(&mut peer, valid).is_ok());
+        assert_eq!(
+            peer.last_packet.transmit_timestamp,
+            valid.transmit_timestamp
+        );
+
+        // a server that is stuck at the same transmit timestamp
+        assert!(matches!(
+            respond(&mut peer, valid),
+            Err(IgnoreReason::DuplicatePacket)
+        ));
+    }
+
+    #[test]
+    fn test_handle_authenticated() {
+        let base = NtpInstant::now();
+        let key = SymmetricKey::new(1, crate::MacAlgorithm::Sha1, vec![7; 20]).unwrap();
+        let mut peer = Peer::test_peer(base);
+        peer.key = Some(key.clone());
+
+        let system = SystemSnapshot::default();
+        let outgoing = peer.generate_poll_message(system);
+        assert!(key.verify(&outgoing));
+
+        let mut header = NtpHeader::new();
+        header.stratum = 1;
+        header.mode = NtpAssociationMode::Server;
+        header.origin_timestamp = outgoing.header.transmit_timestamp;
+        header.receive_timestamp = NtpTimestamp::from_fixed_int(100);
+        header.transmit_timestamp = NtpTimestamp::from_fixed_int(200);
+
+        // a forged kiss code must not demobilize us
+        let mut kiss = header;
+        kiss.stratum = 0;
+        kiss.reference_id = ReferenceId::KISS_DENY;
+        assert!(matches!(
+            peer.handle_incoming(
+                system,
+                NtpPacket::new(kiss),
+                base + Duration::from_secs(1),
+                FrequencyTolerance::ppm(15),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400)
+            ),
+            Err(IgnoreReason::AuthenticationFailed)
+        ));
+
+        let other_key = SymmetricKey::new(1, crate::MacAlgorithm::Sha1, vec![8; 20]).unwrap();
+        let mut packet = NtpPacket::new(header);
+        other_key.sign(&mut packet);
+        assert!(matches!(
+            peer.handle_incoming(
+                system,
+                packet,
+                base + Duration::from_secs(1),
+                FrequencyTolerance::ppm(15),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400)
+            ),
+            Err(IgnoreReason::AuthenticationFailed)
+        ));
+
+        // the failed attempts did not use up the expected origin timestamp
+        let mut packet = NtpPacket::new(header);
+        key.sign(&mut packet);
+        assert!(peer
+            .handle_incoming(
+                system,
+                packet,
+                base + Duration::from_secs(1),
+                FrequencyTolerance::ppm(15),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400)
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_handle_interleaved() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer(base);
+        let system = SystemSnapshot::default();
+
+        let seconds = |seconds: u32| NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0);
+
+        // the first exchange is always in basic mode
+        let outgoing = peer.generate_poll_message(system);
+        assert_eq!(outgoing.header.origin_timestamp, NtpTimestamp::default());
+
+        let mut header = NtpHeader::new();
+        header.stratum = 1;
+        header.mode = NtpAssociationMode::Server;
+        header.origin_timestamp = outgoing.header.transmit_timestamp;
+        header.receive_timestamp = seconds(12);
+        header.transmit_timestamp = seconds(13);
+
+        let snapshot = peer
+            .handle_incoming(
+                system,
+                NtpPacket::new(header),
+                base + Duration::from_secs(1),
+                FrequencyTolerance::ppm(15),
+                seconds(10),
+                seconds(18),
+            )
+            .unwrap();
+        assert_eq!(snapshot.statistics.delay, NtpDuration::from_seconds(7.0));
+
+        // the server tells us it actually sent its response at 15 instead of 13
+        let outgoing = peer.generate_poll_message(system);
+        assert_eq!(outgoing.header.origin_timestamp, seconds(12));
+
+        header.origin_timestamp = outgoing.header.receive_timestamp;
+        header.receive_timestamp = seconds(30);
+        header.transmit_timestamp = seconds(15);
+
+        let snapshot = peer
+            .handle_incoming(
+                system,
+                NtpPacket::new(header),
+                base + Duration::from_secs(2),
+                FrequencyTolerance::ppm(15),
+                seconds(25),
+                seconds(35),
+            )
+            .unwrap();
+        assert_eq!(snapshot.statistics.delay, NtpDuration::from_seconds(5.0));
+        assert!((snapshot.statistics.offset.to_seconds() + 0.5).abs() < 1e-6);
+
+        // servers that do not support interleaved mode respond in basic mode
+        let outgoing = peer.generate_poll_message(system);
+        assert_eq!(outgoing.header.origin_timestamp, seconds(30));
+
+        header.origin_timestamp = outgoing.header.transmit_timestamp;
+        header.receive_timestamp = seconds(50);
+        header.transmit_timestamp = seconds(51);
+
+        assert!(peer
+            .handle_incoming(
+                system,
+                NtpPacket::new(header),
+                base + Duration::from_secs(3),
+                FrequencyTolerance::ppm(15),
+                seconds(45),
+                seconds(55),
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_handle_symmetric() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::new_symmetric(
+            ReferenceId::from_int(0),
+            ReferenceId::from_int(1),
+            base,
+            None,
+        );
+        let system = SystemSnapshot::default();
+
+        let seconds = |seconds: u32| NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0);
+
+        let outgoing = peer.generate_symmetric_message(system, seconds(10));
+        assert_eq!(outgoing.header.mode, NtpAssociationMode::SymmetricActive);
+        assert_eq!(outgoing.header.transmit_timestamp, seconds(10));
+        assert_eq!(outgoing.header.origin_timestamp, NtpTimestamp::default());
+
+        // a server response is not accepted from a symmetric peer
+        let mut header = NtpHeader::new();
+        header.stratum = 1;
+        header.mode = NtpAssociationMode::Server;
+        header.origin_timestamp = seconds(10);
+        header.receive_timestamp = seconds(12);
+        header.transmit_timestamp = seconds(13);
+
+        assert!(matches!(
+            peer.handle_incoming(
+                system,
+                NtpPacket::new(header),
+                base + Duration::from_secs(1),
+                FrequencyTolerance::ppm(15),
+                seconds(10),
+                seconds(15),
+            ),
+            Err(IgnoreReason::InvalidMode)
+        ));
+
+        header.mode = NtpAssociationMode::SymmetricPassive;
+        let snapshot = peer
+            .handle_incoming(
+                system,
+                NtpPacket::new(header),
+                base + Duration::from_secs(1),
+                FrequencyTolerance::ppm(15),
+                seconds(10),
+                seconds(15),
+            )
+            .unwrap();
+        assert_eq!(snapshot.statistics.delay, NtpDuration::from_seconds(4.0));
+
+        // the next message tells the peer when we received its packet
+        let outgoing = peer.generate_symmetric_message(system, seconds(20));
+        assert_eq!(outgoing.header.origin_timestamp, seconds(13));
+        assert_eq!(outgoing.header.receive_timestamp, seconds(15));
+        assert_eq!(outgoing.header.transmit_timestamp, seconds(20));
+
+        // and both passive and active peers may answer it
+        header.mode = NtpAssociationMode::SymmetricActive;
+        header.origin_timestamp = seconds(20);
+        header.receive_timestamp = seconds(22);
+        header.transmit_timestamp = seconds(23);
+        assert!(peer
+            .handle_incoming(
+                system,
+                NtpPacket::new(header),
+                base + Duration::from_secs(2),
+                FrequencyTolerance::ppm(15),
+                seconds(20),
+                seconds(25),
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_handle_broadcast() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer(base);
+        let system = SystemSnapshot::default();
+
+        let seconds = |seconds: u32| NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0);
+
+        let mut broadcast = NtpHeader::new();
+        broadcast.stratum = 1;
+        broadcast.mode = NtpAssociationMode::Broadcast;
+        broadcast.poll = 6;
+        broadcast.transmit_timestamp = seconds(20);
+
+        assert!(matches!(
+            peer.handle_broadcast(
+                system,
+                NtpPacket::new(broadcast),
+                base + Duration::from_secs(1),
+                FrequencyTolerance::ppm(15),
+                seconds(21),
+            ),
+            Err(IgnoreReason::Uncalibrated)
+        ));
+
+        // calibrate with a round trip delay of 2 seconds
+        let outgoing = peer.generate_poll_message(system);
+        let mut response = NtpHeader::new();
+        response.stratum = 1;
+        response.mode = NtpAssociationMode::Server;
+        response.origin_timestamp = outgoing.header.transmit_timestamp;
+        response.receive_timestamp = seconds(31);
+        response.transmit_timestamp = seconds(31);
+        assert!(peer
+            .handle_incoming(
+                system,
+                NtpPacket::new(response),
+                base + Duration::from_secs(2),
+                FrequencyTolerance::ppm(15),
+                seconds(30),
+                seconds(32),
+            )
+            .is_ok());
+
+        // the broadcast took half the round trip delay to arrive
+        broadcast.transmit_timestamp = seconds(40);
+        assert!(peer
+            .handle_broadcast(
+                system,
+                NtpPacket::new(broadcast),
+                base + Duration::from_secs(3),
+                FrequencyTolerance::ppm(15),
+                seconds(41),
+            )
+            .is_ok());
+        assert_eq!(peer.broadcast_interval(), PollInterval::from_log(6));
+
+        // replays are older than the last packet we accepted
+        broadcast.transmit_timestamp = seconds(35);
+        assert!(matches!(
+            peer.handle_broadcast(
+                system,
+                NtpPacket::new(broadcast),
+                base + Duration::from_secs(4),
+                FrequencyTolerance::ppm(15),
+                seconds(36),
+            ),
+            Err(IgnoreReason::InvalidPacketTime)
+        ));
+
+        broadcast.transmit_timestamp = seconds(50);
+        broadcast.mode = NtpAssociationMode::Server;
+        assert!(matches!(
+            peer.handle_broadcast(
+                system,
+                NtpPacket::new(broadcast),
+                base + Duration::from_secs(5),
+                FrequencyTolerance::ppm(15),
+                seconds(51),
+            ),
+            Err(IgnoreReason::InvalidMode)
+        ));
+    }
 }
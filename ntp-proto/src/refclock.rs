@@ -0,0 +1,376 @@
+use std::collections::VecDeque;
+
+use tracing::{debug, info, instrument, trace};
+
+use crate::{
+    filter::{FilterTuple, LastMeasurements},
+    peer::{PeerSnapshot, PeerStatistics, Reach, SystemSnapshot},
+    time_types::{FrequencyTolerance, NtpInstant},
+    NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval, ReferenceId,
+};
+
+/// Why a sample of a reference clock was not used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefclockError {
+    /// The reference clock is not synchronized, e.g. a GPS receiver without a fix
+    Unsynchronized,
+    /// The sample was used before
+    DuplicateSample,
+    /// The clock filter still prefers an older sample, so there is no new measurement
+    TooOld,
+}
+
+impl std::fmt::Display for RefclockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefclockError::Unsynchronized => f.write_str("reference clock is not synchronized"),
+            RefclockError::DuplicateSample => f.write_str("sample was used before"),
+            RefclockError::TooOld => f.write_str("clock filter prefers an older sample"),
+        }
+    }
+}
+
+impl std::error::Error for RefclockError {}
+
+/// A sample read from a reference clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefclockSample {
+    /// The time according to the reference clock
+    pub reference_time: NtpTimestamp,
+    /// The time of our clock at the moment the reference clock had `reference_time`
+    pub local_time: NtpTimestamp,
+    /// Leap second announced by the reference clock, `Unknown` when it is not synchronized
+    pub leap: NtpLeapIndicator,
+}
+
+impl RefclockSample {
+    /// Offset of our clock to the reference clock
+    pub fn offset(&self) -> NtpDuration {
+        self.reference_time - self.local_time
+    }
+}
+
+/// Collects the samples that a reference clock provides between two polls, to use only the
+/// median one. Samples of reference clocks usually arrive much more often than the clock filter of
+/// a peer takes measurements, and the median removes outliers, e.g. samples that were timestamped
+/// late because of interrupt latency.
+#[derive(Debug, Clone)]
+pub struct RefclockFilter {
+    samples: VecDeque<RefclockSample>,
+    capacity: usize,
+}
+
+impl RefclockFilter {
+    /// A filter that keeps at most the `capacity` most recent samples
+    pub fn new(capacity: usize) -> Self {
+        RefclockFilter {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn add(&mut self, sample: RefclockSample) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(sample);
+    }
+
+    /// The sample with the median offset of the samples added since the previous call, if any
+    pub fn take(&mut self) -> Option<RefclockSample> {
+        let mut samples: Vec<_> = self.samples.drain(..).collect();
+        samples.sort_by_key(RefclockSample::offset);
+
+        samples.get(samples.len() / 2).copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+/// A reference clock, which is read every poll interval. Its samples go through the clock filter
+/// like the measurements of a peer, and are described to the clock selection as if they came from
+/// a server at the configured stratum, without any delay or dispersion of its own.
+#[derive(Debug, Clone)]
+pub struct Refclock {
+    reference_id: ReferenceId,
+    stratum: u8,
+    /// As log2 of seconds
+    precision: i8,
+    poll_interval: PollInterval,
+
+    statistics: PeerStatistics,
+    last_measurements: LastMeasurements,
+    time: NtpInstant,
+    reach: Reach,
+    /// Leap second announced by the last sample, `Unknown` while the reference clock is not
+    /// synchronized
+    leap: NtpLeapIndicator,
+    /// The time of the reference clock in the last sample that was used
+    last_reference_time: Option<NtpTimestamp>,
+}
+
+impl Refclock {
+    /// A reference clock identified by `reference_id`, which is read every `poll_interval`, at
+    /// `stratum` and with `precision` (as log2 of seconds)
+    pub fn new(
+        reference_id: ReferenceId,
+        stratum: u8,
+        precision: i8,
+        poll_interval: PollInterval,
+        local_clock_time: NtpInstant,
+    ) -> Self {
+        Refclock {
+            reference_id,
+            stratum,
+            precision,
+            poll_interval,
+
+            statistics: Default::default(),
+            last_measurements: LastMeasurements::new(local_clock_time),
+            time: local_clock_time,
+            reach: Default::default(),
+            leap: NtpLeapIndicator::Unknown,
+            last_reference_time: None,
+        }
+    }
+
+    /// Process a sample of the reference clock, returning the new snapshot of the reference
+    /// clock when it results in a new measurement
+    #[instrument(skip(self, system, frequency_tolerance), fields(refclock = debug(self.reference_id)))]
+    pub fn handle_sample(
+        &mut self,
+        system: SystemSnapshot,
+        sample: RefclockSample,
+        local_clock_time: NtpInstant,
+        frequency_tolerance: FrequencyTolerance,
+    ) -> Result<PeerSnapshot, RefclockError> {
+        if !sample.leap.is_synchronized() {
+            debug!("Received sample of an unsynchronized reference clock");
+            self.unsynchronized();
+            return Err(RefclockError::Unsynchronized);
+        }
+
+        if Some(sample.reference_time) == self.last_reference_time {
+            debug!("Received the previous sample of the reference clock again");
+            return Err(RefclockError::DuplicateSample);
+        }
+
+        trace!("Reference clock sample accepted for processing");
+        self.reach.received_packet();
+        self.leap = sample.leap;
+        self.last_reference_time = Some(sample.reference_time);
+
+        let filter_input = FilterTuple::from_refclock(
+            self.precision,
+            system.precision,
+            local_clock_time,
+            sample.reference_time,
+            sample.local_time,
+        );
+
+        let updated = self.last_measurements.step(
+            filter_input,
+            self.time,
+            system.leap_indicator,
+            system.precision,
+            frequency_tolerance,
+        );
+
+        match updated {
+            None => Err(RefclockError::TooOld),
+            Some((statistics, smallest_delay_time)) => {
+                self.statistics = statistics;
+                self.time = smallest_delay_time;
+
+                Ok(self.snapshot())
+            }
+        }
+    }
+
+    /// A poll interval passed, in which we expected a new sample
+    pub fn expect_sample(&mut self) {
+        self.reach.poll();
+    }
+
+    /// The reference clock reported that it is not synchronized, e.g. a GPS receiver that lost
+    /// its fix. Its snapshots are not accepted for synchronization until it provides a
+    /// synchronized sample again.
+    pub fn unsynchronized(&mut self) {
+        self.leap = NtpLeapIndicator::Unknown;
+    }
+
+    /// The state of the reference clock, as used by the clock selection
+    pub fn snapshot(&self) -> PeerSnapshot {
+        PeerSnapshot {
+            root_distance_without_time: self.root_distance_without_time(),
+            statistics: self.statistics,
+            time: self.time,
+            stratum: self.stratum,
+            peer_id: self.reference_id,
+            poll_interval: self.poll_interval,
+            reference_id: self.reference_id,
+            our_id: ReferenceId::NONE,
+            reach: self.reach,
+            leap_indicator: self.leap,
+            root_delay: NtpDuration::ZERO,
+            root_dispersion: NtpDuration::ZERO,
+        }
+    }
+
+    /// Root distance without the `(local_clock_time - self.time) * PHI` term. A reference clock
+    /// is a root of its own, so only our measurements of it count.
+    fn root_distance_without_time(&self) -> NtpDuration {
+        NtpDuration::MIN_DISPERSION.max(self.statistics.delay) / 2i64
+            + self.statistics.dispersion
+            + NtpDuration::from_seconds(self.statistics.jitter)
+    }
+
+    /// Forget the measurements, because our clock jumped. The reference clock counts as
+    /// unsynchronized until its next sample.
+    #[instrument(level="trace", skip(self), fields(refclock = debug(self.reference_id)))]
+    pub fn reset_measurements(&mut self) {
+        self.statistics = Default::default();
+        self.last_measurements = LastMeasurements::new(self.time);
+        self.leap = NtpLeapIndicator::Unknown;
+        self.last_reference_time = None;
+
+        info!(refclock = ?self.reference_id, "Reference clock reset");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn sample(local_seconds: u32, offset_seconds: u32) -> RefclockSample {
+        RefclockSample {
+            reference_time: NtpTimestamp::from_seconds_nanos_since_ntp_era(
+                local_seconds + offset_seconds,
+                0,
+            ),
+            local_time: NtpTimestamp::from_seconds_nanos_since_ntp_era(local_seconds, 0),
+            leap: NtpLeapIndicator::NoWarning,
+        }
+    }
+
+    #[test]
+    fn test_refclock_filter() {
+        let mut filter = RefclockFilter::new(4);
+        assert_eq!(filter.take(), None);
+
+        filter.add(sample(10, 1));
+        filter.add(sample(11, 9));
+        filter.add(sample(12, 2));
+        assert_eq!(filter.take(), Some(sample(12, 2)));
+
+        // the samples are used once
+        assert_eq!(filter.take(), None);
+
+        // only the most recent samples are kept
+        for (local, offset) in [(20, 100), (21, 100), (22, 3), (23, 4), (24, 5), (25, 6)] {
+            filter.add(sample(local, offset));
+        }
+        assert_eq!(filter.take(), Some(sample(24, 5)));
+
+        filter.add(sample(30, 1));
+        filter.clear();
+        assert_eq!(filter.take(), None);
+    }
+
+    #[test]
+    fn test_refclock_sample() {
+        let base = NtpInstant::now();
+        let mut refclock = Refclock::new(ReferenceId::SHM, 0, -20, PollInterval::from_log(4), base);
+        let system = SystemSnapshot::default();
+
+        // nothing is known before the first sample
+        assert_eq!(
+            refclock.snapshot().leap_indicator,
+            NtpLeapIndicator::Unknown
+        );
+        assert!(!refclock.snapshot().reach.is_reachable());
+
+        let mut sample = sample(10, 2);
+        let snapshot = refclock
+            .handle_sample(
+                system,
+                sample,
+                base + Duration::from_secs(1),
+                FrequencyTolerance::ppm(15),
+            )
+            .unwrap();
+        assert_eq!(snapshot.stratum, 0);
+        assert_eq!(snapshot.peer_id, ReferenceId::SHM);
+        assert_eq!(snapshot.reference_id, ReferenceId::SHM);
+        assert_eq!(snapshot.leap_indicator, NtpLeapIndicator::NoWarning);
+        assert_eq!(snapshot.statistics.offset, NtpDuration::from_seconds(2.0));
+        assert_eq!(snapshot.statistics.delay, NtpDuration::ZERO);
+        assert_eq!(snapshot.root_delay, NtpDuration::ZERO);
+        assert!(snapshot.reach.is_reachable());
+
+        // the sample was read before
+        assert_eq!(
+            refclock
+                .handle_sample(
+                    system,
+                    sample,
+                    base + Duration::from_secs(2),
+                    FrequencyTolerance::ppm(15),
+                )
+                .unwrap_err(),
+            RefclockError::DuplicateSample
+        );
+
+        sample.reference_time = NtpTimestamp::from_seconds_nanos_since_ntp_era(22, 0);
+        sample.local_time = NtpTimestamp::from_seconds_nanos_since_ntp_era(20, 0);
+        sample.leap = NtpLeapIndicator::Unknown;
+        assert_eq!(
+            refclock
+                .handle_sample(
+                    system,
+                    sample,
+                    base + Duration::from_secs(3),
+                    FrequencyTolerance::ppm(15),
+                )
+                .unwrap_err(),
+            RefclockError::Unsynchronized
+        );
+
+        // an unsynchronized reference clock is not used until it is synchronized again
+        let snapshot = refclock.snapshot();
+        assert_eq!(snapshot.leap_indicator, NtpLeapIndicator::Unknown);
+        assert!(snapshot
+            .accept_synchronization(
+                base + Duration::from_secs(3),
+                FrequencyTolerance::ppm(15),
+                NtpDuration::from_seconds(1.0),
+                PollInterval::from_log(4),
+            )
+            .is_err());
+
+        // the kind of reference clock survives a reset
+        refclock.reset_measurements();
+        sample.leap = NtpLeapIndicator::NoWarning;
+        let snapshot = refclock
+            .handle_sample(
+                system,
+                sample,
+                base + Duration::from_secs(4),
+                FrequencyTolerance::ppm(15),
+            )
+            .unwrap();
+        assert_eq!(snapshot.reference_id, ReferenceId::SHM);
+        assert_eq!(snapshot.statistics.offset, NtpDuration::from_seconds(2.0));
+
+        // a reference clock that stops sending samples becomes unreachable
+        for _ in 0..8 {
+            refclock.expect_sample();
+        }
+        assert!(!refclock.snapshot().reach.is_reachable());
+    }
+}
@@ -54,7 +54,7 @@ impl Add<Duration> for NtpInstant {
 }
 
 /// NtpTimestamp represents an ntp timestamp without the era number.
-#[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Default)]
+#[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Default, Serialize, Deserialize)]
 pub struct NtpTimestamp {
     timestamp: u64,
 }
@@ -96,6 +96,13 @@ impl NtpTimestamp {
         NtpTimestamp::from_bits(timestamp.to_be_bytes())
     }
 
+    /// Create an NTP timestamp from the number of seconds and nanoseconds that have passed since
+    /// the unix epoch.
+    pub const fn from_unix(seconds: i64, nanos: u32) -> Self {
+        // Negative eras are completely valid, so any wrapping is perfectly reasonable here.
+        Self::from_seconds_nanos_since_ntp_era((seconds as u32).wrapping_add(EPOCH_OFFSET), nanos)
+    }
+
     #[cfg(any(test, feature = "fuzz"))]
     pub(crate) const fn from_fixed_int(timestamp: u64) -> NtpTimestamp {
         NtpTimestamp { timestamp }
@@ -176,6 +183,10 @@ impl SubAssign<NtpDuration> for NtpTimestamp {
     }
 }
 
+/// Seconds from the start of the NTP era (1900) to the Unix epoch (1970): 70 years, of which 17
+/// are leap years
+const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
+
 /// NtpDuration is used to represent signed intervals between NtpTimestamps.
 /// A negative duration interval is interpreted to mean that the first
 /// timestamp used to define the interval represents a point in time after
@@ -291,6 +302,16 @@ impl NtpDuration {
         }
     }
 
+    /// Interpret the duration as `2^k` seconds and return `k`, rounded down.
+    /// Non-positive durations map to the smallest exponent that can be represented.
+    pub fn log2(self) -> i8 {
+        if self.duration <= 0 {
+            return i8::MIN;
+        }
+
+        31 - (self.duration.leading_zeros() as i8)
+    }
+
     pub fn from_system_duration(duration: Duration) -> Self {
         let seconds = duration.as_secs();
         let nanos = duration.subsec_nanos();
@@ -497,6 +518,18 @@ impl PollInterval {
         Self(self.0 - 1).max(Self::MIN)
     }
 
+    /// The poll interval of 2^`log` seconds, clamped to the allowed range
+    pub(crate) fn from_log(log: i8) -> Self {
+        Self(log).max(Self::MIN).min(Self::MAX)
+    }
+
+    /// The shortest poll interval that lasts at least `duration`, clamped to the allowed range
+    pub fn at_least(duration: Duration) -> Self {
+        let seconds = duration.as_secs().max(1);
+        let log = 64 - (seconds - 1).leading_zeros();
+        Self::from_log(log.min(i8::MAX as u32) as i8)
+    }
+
     pub const fn as_log(self) -> i8 {
         self.0
     }
@@ -600,6 +633,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_timestamp_from_unix() {
+        assert_eq!(
+            NtpTimestamp::from_unix(0, 500_000_000),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(2_208_988_800, 500_000_000)
+        );
+        // 2036-02-07T06:28:16Z starts the next era
+        assert_eq!(
+            NtpTimestamp::from_unix(2_085_978_496, 0),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(0, 0)
+        );
+        assert_eq!(
+            NtpTimestamp::from_unix(-1, 0),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(2_208_988_799, 0)
+        );
+    }
+
     #[test]
     fn test_timestamp_duration_math() {
         let mut a = NtpTimestamp::from_fixed_int(5);
@@ -624,6 +674,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_poll_interval_at_least() {
+        assert_eq!(PollInterval::at_least(Duration::from_secs(64)).as_log(), 6);
+        assert_eq!(PollInterval::at_least(Duration::from_secs(65)).as_log(), 7);
+        assert_eq!(
+            PollInterval::at_least(Duration::from_secs(1)),
+            PollInterval::MIN
+        );
+        assert_eq!(
+            PollInterval::at_least(Duration::from_secs(1 << 20)),
+            PollInterval::MAX
+        );
+    }
+
     #[test]
     fn test_duration_math() {
         let mut a = NtpDuration::from_fixed_int(5);
@@ -712,6 +776,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn duration_log2_roundtrip() {
+        for i in -32..=30 {
+            assert_eq!(NtpDuration::from_exponent(i).log2(), i);
+        }
+
+        assert_eq!(NtpDuration::from_seconds(3.0).log2(), 1);
+        assert_eq!(NtpDuration::ZERO.log2(), i8::MIN);
+    }
+
     #[test]
     fn duration_from_float_seconds_saturates() {
         assert_eq!(
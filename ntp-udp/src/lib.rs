@@ -1,20 +1,75 @@
 use std::{
+    ffi::CStr,
     io,
     io::{ErrorKind, IoSliceMut},
-    os::unix::prelude::AsRawFd,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::unix::prelude::{AsRawFd, RawFd},
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
 };
 
 use ntp_proto::NtpTimestamp;
 use tokio::{io::unix::AsyncFd, net::ToSocketAddrs};
 use tracing::{debug, instrument, trace, warn};
 
-// Unix uses an epoch located at 1/1/1970-00:00h (UTC) and NTP uses 1/1/1900-00:00h.
-// This leads to an offset equivalent to 70 years in seconds
-// there are 17 leap years between the two dates so the offset is
-const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
+// Timestamping constants from the linux headers that libc does not provide
+const SOF_TIMESTAMPING_OPT_ID: libc::c_uint = 1 << 7;
+const SOF_TIMESTAMPING_OPT_TSONLY: libc::c_uint = 1 << 11;
+const SIOCGHWTSTAMP: libc::c_ulong = 0x89b1;
+const HWTSTAMP_TX_ON: libc::c_int = 1;
+const HWTSTAMP_FILTER_NONE: libc::c_int = 0;
+
+/// Send timestamps are only looked for in the error queue, they never carry a copy of the packet.
+/// The id lets us match them to the packet they belong to.
+const SEND_TIMESTAMP_FLAGS: libc::c_uint = SOF_TIMESTAMPING_OPT_ID | SOF_TIMESTAMPING_OPT_TSONLY;
+
+const HARDWARE_FLAGS: libc::c_uint = libc::SOF_TIMESTAMPING_RX_HARDWARE
+    | libc::SOF_TIMESTAMPING_TX_HARDWARE
+    | libc::SOF_TIMESTAMPING_RAW_HARDWARE
+    | SEND_TIMESTAMP_FLAGS;
+
+const SOFTWARE_FLAGS: libc::c_uint = libc::SOF_TIMESTAMPING_RX_SOFTWARE
+    | libc::SOF_TIMESTAMPING_TX_SOFTWARE
+    | libc::SOF_TIMESTAMPING_SOFTWARE
+    | SEND_TIMESTAMP_FLAGS;
+
+/// How long to wait for the kernel to report when a packet was sent. Software timestamps are
+/// available almost immediately, hardware timestamps take a little longer.
+const SEND_TIMESTAMP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How a socket timestamps the packets that it sends and receives, from most to least accurate.
+/// A socket uses the first of these that is available to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampingMode {
+    /// The network card timestamps packets. Only used when the card with the local address
+    /// already has hardware timestamping enabled, because the timestamps come from the clock of
+    /// the card, which must be kept in sync with the system clock (e.g. by `phc2sys`).
+    Hardware,
+    /// The kernel timestamps packets right before they are handed to the network card, and
+    /// right after they are received from it.
+    Software,
+    /// The kernel timestamps received packets, sent packets are not timestamped
+    ReceiveOnly,
+    /// Packets are not timestamped at all
+    None,
+}
+
+impl TimestampingMode {
+    fn timestamps_sends(self) -> bool {
+        matches!(
+            self,
+            TimestampingMode::Hardware | TimestampingMode::Software
+        )
+    }
+}
 
 pub struct UdpSocket {
     io: AsyncFd<std::net::UdpSocket>,
+    timestamping: TimestampingMode,
+    /// Readable when the kernel has placed send timestamps in the error queue of `io`
+    error_queue: Option<AsyncFd<ErrorQueue>>,
+    /// Id of the next packet that we send, as counted by the kernel
+    send_counter: AtomicU32,
 }
 
 impl UdpSocket {
@@ -35,23 +90,136 @@ impl UdpSocket {
             peer_addr = debug(socket.peer_addr().unwrap()),
             "socket connected"
         );
+        Self::from_tokio(socket)
+    }
+
+    /// Create a socket that is not connected to a specific peer, for use when
+    /// serving time to any client that sends us a request.
+    #[instrument(level = "debug")]
+    pub async fn server<A>(listen_addr: A) -> io::Result<UdpSocket>
+    where
+        A: ToSocketAddrs + std::fmt::Debug,
+    {
+        let socket = tokio::net::UdpSocket::bind(listen_addr).await?;
+        debug!(
+            local_addr = debug(socket.local_addr().unwrap()),
+            "server socket bound"
+        );
+        Self::from_tokio(socket)
+    }
+
+    fn from_tokio(socket: tokio::net::UdpSocket) -> io::Result<UdpSocket> {
         let socket = socket.into_std()?;
-        init_socket(&socket)?;
+        let timestamping = init_socket(&socket)?;
+        debug!(?timestamping, "timestamping enabled");
+
+        let error_queue = if timestamping.timestamps_sends() {
+            Some(AsyncFd::new(ErrorQueue::new(&socket)?)?)
+        } else {
+            None
+        };
+
         Ok(UdpSocket {
             io: AsyncFd::new(socket)?,
+            timestamping,
+            error_queue,
+            send_counter: AtomicU32::new(0),
         })
     }
 
+    /// How the packets of this socket are timestamped
+    pub fn timestamping_mode(&self) -> TimestampingMode {
+        self.timestamping
+    }
+
+    /// Allow sending to broadcast addresses, and limit the number of hops that multicast packets
+    /// sent from this socket can make to `ttl`
+    pub fn enable_broadcast(&self, ttl: u32) -> io::Result<()> {
+        let socket = self.as_ref();
+        socket.set_broadcast(true)?;
+
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => socket.set_multicast_ttl_v4(ttl),
+            SocketAddr::V6(_) => {
+                let hops = ttl as libc::c_int;
+                let result = unsafe {
+                    libc::setsockopt(
+                        socket.as_raw_fd(),
+                        libc::IPPROTO_IPV6,
+                        libc::IPV6_MULTICAST_HOPS,
+                        &hops as *const _ as *const libc::c_void,
+                        std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                    )
+                };
+
+                if result == -1 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Send `buf` to the connected peer. Also returns the time at which the packet was actually
+    /// sent, when the socket timestamps sent packets.
     #[instrument(level = "trace", skip(self, buf), fields(
         local_addr = debug(self.as_ref().local_addr().unwrap()),
         peer_addr = debug(self.as_ref().peer_addr()),
         buf_size = buf.len(),
     ))]
-    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+    pub async fn send(&self, buf: &[u8]) -> io::Result<(usize, Option<NtpTimestamp>)> {
         trace!(size = buf.len(), "sending bytes");
         loop {
             let mut guard = self.io.writable().await?;
             match guard.try_io(|inner| inner.get_ref().send(buf)) {
+                Ok(result) => {
+                    match &result {
+                        Ok(size) => trace!(sent = size, "sent bytes"),
+                        Err(e) => debug!(error = debug(e), "error sending data"),
+                    }
+                    let size = result?;
+                    return Ok((size, self.fetch_send_timestamp().await));
+                }
+                Err(_would_block) => {
+                    trace!("blocked after becoming writable, retrying");
+                    continue;
+                }
+            }
+        }
+    }
+
+    /// Send `buf` to `addr`, like [`UdpSocket::send`]
+    #[instrument(level = "trace", skip(self, buf), fields(
+        local_addr = debug(self.as_ref().local_addr().unwrap()),
+        buf_size = buf.len(),
+    ))]
+    pub async fn send_to(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> io::Result<(usize, Option<NtpTimestamp>)> {
+        let size = self.send_to_inner(buf, addr).await?;
+        Ok((size, self.fetch_send_timestamp().await))
+    }
+
+    /// Send `buf` to `addr` without waiting for the time at which the packet was sent, for when
+    /// that time is not needed, like for the responses of a server
+    #[instrument(level = "trace", skip(self, buf), fields(
+        local_addr = debug(self.as_ref().local_addr().unwrap()),
+        buf_size = buf.len(),
+    ))]
+    pub async fn send_to_untimestamped(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let size = self.send_to_inner(buf, addr).await?;
+        self.discard_send_timestamps();
+        Ok(size)
+    }
+
+    async fn send_to_inner(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        trace!(size = buf.len(), ?addr, "sending bytes");
+        loop {
+            let mut guard = self.io.writable().await?;
+            match guard.try_io(|inner| inner.get_ref().send_to(buf, addr)) {
                 Ok(result) => {
                     match &result {
                         Ok(size) => trace!(sent = size, "sent bytes"),
@@ -73,6 +241,31 @@ impl UdpSocket {
         buf_size = buf.len(),
     ))]
     pub async fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, Option<NtpTimestamp>)> {
+        let (size, _addr, timestamp) = self.recv_with_addr(buf).await?;
+        Ok((size, timestamp))
+    }
+
+    #[instrument(level = "trace", skip(self, buf), fields(
+        local_addr = debug(self.as_ref().local_addr().unwrap()),
+        buf_size = buf.len(),
+    ))]
+    pub async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<NtpTimestamp>)> {
+        match self.recv_with_addr(buf).await? {
+            (size, Some(addr), timestamp) => Ok((size, addr, timestamp)),
+            (_, None, _) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "received message from unsupported address family",
+            )),
+        }
+    }
+
+    async fn recv_with_addr(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, Option<SocketAddr>, Option<NtpTimestamp>)> {
         loop {
             trace!("waiting for socket to become readable");
             let mut guard = self.io.readable().await?;
@@ -84,12 +277,68 @@ impl UdpSocket {
                 Ok(result) => result,
             };
             match &result {
-                Ok((size, ts)) => trace!(size, ts = debug(ts), "received message"),
+                Ok((size, addr, ts)) => {
+                    trace!(size, addr = debug(addr), ts = debug(ts), "received message")
+                }
                 Err(e) => debug!(error = debug(e), "error receiving data"),
             }
             return result;
         }
     }
+
+    /// Skip the send timestamp of the packet that was just sent. The kernel still timestamps it,
+    /// so we remove the timestamps that have arrived so far, as they take up space in the receive
+    /// buffer of the socket.
+    fn discard_send_timestamps(&self) {
+        if self.error_queue.is_none() {
+            return;
+        }
+
+        // the timestamp of this packet must not be mistaken for that of a later packet
+        self.send_counter.fetch_add(1, Ordering::Relaxed);
+
+        while recv_send_timestamp(self.as_ref()).is_ok() {}
+    }
+
+    /// Wait for the kernel to report when the packet that was just sent actually left
+    async fn fetch_send_timestamp(&self) -> Option<NtpTimestamp> {
+        let error_queue = self.error_queue.as_ref()?;
+        let expected = self.send_counter.fetch_add(1, Ordering::Relaxed);
+
+        let fetch = async {
+            loop {
+                trace!("waiting for send timestamp");
+                let mut guard = error_queue.readable().await?;
+                match guard.try_io(|_| recv_send_timestamp(self.as_ref())) {
+                    Err(_would_block) => continue,
+                    // Timestamps of earlier packets may arrive after we gave up on them. When the
+                    // kernel counted a packet that failed to send, we get a later id than expected.
+                    Ok(Ok(Some((id, timestamp)))) if (id.wrapping_sub(expected) as i32) >= 0 => {
+                        self.send_counter
+                            .store(id.wrapping_add(1), Ordering::Relaxed);
+                        return Ok(timestamp);
+                    }
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(error)) => return Err(error),
+                }
+            }
+        };
+
+        match tokio::time::timeout(SEND_TIMESTAMP_TIMEOUT, fetch).await {
+            Ok(Ok(timestamp)) => {
+                trace!(ts = debug(timestamp), "fetched send timestamp");
+                timestamp
+            }
+            Ok(Err(error)) => {
+                debug!(?error, "could not fetch send timestamp");
+                None
+            }
+            Err(_elapsed) => {
+                debug!("no send timestamp within the timeout");
+                None
+            }
+        }
+    }
 }
 
 impl AsRef<std::net::UdpSocket> for UdpSocket {
@@ -98,27 +347,224 @@ impl AsRef<std::net::UdpSocket> for UdpSocket {
     }
 }
 
-fn init_socket(socket: &std::net::UdpSocket) -> io::Result<()> {
-    let fd = socket.as_raw_fd();
-    let enable_ts: libc::c_int = 1;
-    unsafe {
+/// An epoll instance that becomes readable when there are messages in the error queue of a socket,
+/// which is where the kernel puts send timestamps. Tokio cannot wait for those by itself.
+struct ErrorQueue(RawFd);
+
+impl ErrorQueue {
+    fn new(socket: &std::net::UdpSocket) -> io::Result<Self> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let error_queue = ErrorQueue(fd);
+
+        // Pending errors are always reported, the events we ask for do not matter
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLERR as u32,
+            u64: 0,
+        };
+        if unsafe { libc::epoll_ctl(fd, libc::EPOLL_CTL_ADD, socket.as_raw_fd(), &mut event) } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(error_queue)
+    }
+}
+
+impl AsRawFd for ErrorQueue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for ErrorQueue {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn set_socket_option(fd: RawFd, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
         libc::setsockopt(
             fd,
             libc::SOL_SOCKET,
-            libc::SO_TIMESTAMPNS,
-            &enable_ts as *const _ as *const libc::c_void,
+            name,
+            &value as *const _ as *const libc::c_void,
             std::mem::size_of::<libc::c_int>() as libc::socklen_t,
         )
     };
-    Ok(())
+
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Enable the most accurate timestamping that is available to the socket
+fn init_socket(socket: &std::net::UdpSocket) -> io::Result<TimestampingMode> {
+    let fd = socket.as_raw_fd();
+    let local_ip = socket.local_addr()?.ip();
+
+    if !local_ip.is_unspecified()
+        && hardware_timestamping_enabled(fd, local_ip)
+        && set_socket_option(fd, libc::SO_TIMESTAMPING, HARDWARE_FLAGS as _).is_ok()
+    {
+        return Ok(TimestampingMode::Hardware);
+    }
+
+    match set_socket_option(fd, libc::SO_TIMESTAMPING, SOFTWARE_FLAGS as _) {
+        Ok(()) => return Ok(TimestampingMode::Software),
+        Err(error) => debug!(?error, "could not enable software timestamping"),
+    }
+
+    match set_socket_option(fd, libc::SO_TIMESTAMPNS, 1) {
+        Ok(()) => Ok(TimestampingMode::ReceiveOnly),
+        Err(error) => {
+            warn!(?error, "could not enable timestamping");
+            Ok(TimestampingMode::None)
+        }
+    }
+}
+
+/// `struct hwtstamp_config` from `linux/net_tstamp.h`
+#[repr(C)]
+struct HwTimestampConfig {
+    flags: libc::c_int,
+    tx_type: libc::c_int,
+    rx_filter: libc::c_int,
 }
 
-fn recv(socket: &std::net::UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<NtpTimestamp>)> {
+/// `struct ifreq` from `linux/if.h`, with the union holding a pointer to the configuration
+#[repr(C)]
+struct HwTimestampRequest {
+    name: [libc::c_char; libc::IF_NAMESIZE],
+    config: *mut HwTimestampConfig,
+    _union_padding: [u8; 16],
+}
+
+/// Whether the network card with address `ip` is configured to timestamp sent and received
+/// packets. We never change this configuration ourselves, as it affects the whole system.
+fn hardware_timestamping_enabled(fd: RawFd, ip: IpAddr) -> bool {
+    let name = match interface_name(ip) {
+        Some(name) => name,
+        None => return false,
+    };
+
+    let mut config = HwTimestampConfig {
+        flags: 0,
+        tx_type: 0,
+        rx_filter: 0,
+    };
+    let mut request = HwTimestampRequest {
+        name,
+        config: &mut config,
+        _union_padding: [0; 16],
+    };
+
+    // Safety: the request contains a valid interface name and points to a configuration for the
+    // kernel to fill in, which lives until after the call
+    if unsafe {
+        libc::ioctl(
+            fd,
+            SIOCGHWTSTAMP as _,
+            &mut request as *mut HwTimestampRequest,
+        )
+    } == -1
+    {
+        trace!(
+            error = debug(io::Error::last_os_error()),
+            "no hardware timestamping"
+        );
+        return false;
+    }
+
+    config.tx_type == HWTSTAMP_TX_ON && config.rx_filter != HWTSTAMP_FILTER_NONE
+}
+
+/// The name of the network interface that has address `ip`
+fn interface_name(ip: IpAddr) -> Option<[libc::c_char; libc::IF_NAMESIZE]> {
+    let mut addresses: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addresses) } == -1 {
+        return None;
+    }
+
+    let mut result = None;
+    // Safety: getifaddrs returns a linked list that is valid until it is freed below
+    let mut current = unsafe { addresses.as_ref() };
+    while let Some(interface) = current {
+        if sockaddr_to_ip(interface.ifa_addr) == Some(ip) {
+            let name = unsafe { CStr::from_ptr(interface.ifa_name) }.to_bytes();
+            if name.len() < libc::IF_NAMESIZE {
+                let mut buf = [0; libc::IF_NAMESIZE];
+                for (target, byte) in buf.iter_mut().zip(name) {
+                    *target = *byte as libc::c_char;
+                }
+                result = Some(buf);
+            }
+            break;
+        }
+        current = unsafe { interface.ifa_next.as_ref() };
+    }
+
+    unsafe { libc::freeifaddrs(addresses) };
+    result
+}
+
+fn sockaddr_to_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    // Safety: the pointer is either null or points to a socket address of the indicated family
+    match unsafe { addr.as_ref() }?.sa_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const libc::sockaddr_in) };
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                addr.sin_addr.s_addr,
+            ))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const libc::sockaddr_in6) };
+            Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+fn sockaddr_storage_to_socket_addr(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // Safety: the address family indicates this storage contains a sockaddr_in
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // Safety: the address family indicates this storage contains a sockaddr_in6
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+fn recv(
+    socket: &std::net::UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, Option<SocketAddr>, Option<NtpTimestamp>)> {
     let mut buf_slice = IoSliceMut::new(buf);
+    // Safety: sockaddr_storage is a plain C struct for which all zeroes is a valid value
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
 
     // could be on the stack if const extern fn is stable
     let control_size =
-        unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::timespec>() as _) } as usize;
+        unsafe { libc::CMSG_SPACE(std::mem::size_of::<[libc::timespec; 3]>() as _) } as usize;
     let mut control_buf = vec![0; control_size];
     let mut mhdr = libc::msghdr {
         msg_control: control_buf.as_mut_ptr().cast::<libc::c_void>(),
@@ -126,8 +572,8 @@ fn recv(socket: &std::net::UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Opti
         msg_iov: (&mut buf_slice as *mut IoSliceMut).cast::<libc::iovec>(),
         msg_iovlen: 1,
         msg_flags: 0,
-        msg_name: std::ptr::null_mut(),
-        msg_namelen: 0,
+        msg_name: (&mut addr as *mut libc::sockaddr_storage).cast::<libc::c_void>(),
+        msg_namelen: std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
     };
 
     // loops for when we receive an interrupt during the recv
@@ -164,22 +610,116 @@ fn recv(socket: &std::net::UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Opti
     // Loops through the control messages, but we should only get a single message
     let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&mhdr).as_ref() };
     while let Some(msg) = cmsg {
-        if let (libc::SOL_SOCKET, libc::SO_TIMESTAMPNS) = (msg.cmsg_level, msg.cmsg_type) {
-            // Safety: SCM_TIMESTAMPNS always has a timespec in the data, so this operation should be safe
-            let ts: libc::timespec =
-                unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(msg) as *const _) };
-            recv_ts = Some(NtpTimestamp::from_seconds_nanos_since_ntp_era(
-                (ts.tv_sec as u32).wrapping_add(EPOCH_OFFSET), // truncates the higher bits of the i64
-                ts.tv_nsec as u32,                             // tv_nsec is always within [0, 1e10)
-            ));
-            break;
+        match (msg.cmsg_level, msg.cmsg_type) {
+            (libc::SOL_SOCKET, libc::SO_TIMESTAMPNS) => {
+                // Safety: SCM_TIMESTAMPNS always has a timespec in the data, so this operation should be safe
+                let ts: libc::timespec =
+                    unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(msg) as *const _) };
+                recv_ts = timespec_to_ntp(ts);
+                break;
+            }
+            (libc::SOL_SOCKET, libc::SO_TIMESTAMPING) => {
+                recv_ts = read_timestamping(msg);
+                break;
+            }
+            _ => {}
         }
 
         // grab the next control message
         cmsg = unsafe { libc::CMSG_NXTHDR(&mhdr, msg).as_ref() };
     }
 
-    Ok((n as usize, recv_ts))
+    Ok((n as usize, sockaddr_storage_to_socket_addr(&addr), recv_ts))
+}
+
+fn timespec_to_ntp(ts: libc::timespec) -> Option<NtpTimestamp> {
+    // an all-zero timestamp means the kernel did not take one
+    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+        return None;
+    }
+
+    // tv_nsec is always within [0, 1e10)
+    Some(NtpTimestamp::from_unix(ts.tv_sec, ts.tv_nsec as u32))
+}
+
+/// Read a SCM_TIMESTAMPING control message. Of its three timestamps, the first is taken by the
+/// kernel and the last by the network card. Only one of them is set, depending on the
+/// timestamping mode.
+fn read_timestamping(msg: &libc::cmsghdr) -> Option<NtpTimestamp> {
+    // Safety: SCM_TIMESTAMPING always has three timespecs in the data
+    let [software, _, hardware]: [libc::timespec; 3] =
+        unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(msg) as *const _) };
+
+    timespec_to_ntp(hardware).or_else(|| timespec_to_ntp(software))
+}
+
+/// Read a send timestamp from the error queue of the socket, together with the id that the
+/// kernel gave to the packet it belongs to. Other messages in the error queue are skipped.
+fn recv_send_timestamp(
+    socket: &std::net::UdpSocket,
+) -> io::Result<Option<(u32, Option<NtpTimestamp>)>> {
+    let control_size = unsafe {
+        libc::CMSG_SPACE(std::mem::size_of::<[libc::timespec; 3]>() as _)
+            + libc::CMSG_SPACE(
+                (std::mem::size_of::<libc::sock_extended_err>()
+                    + std::mem::size_of::<libc::sockaddr_in6>()) as _,
+            )
+    } as usize;
+    let mut control_buf = vec![0; control_size];
+    let mut mhdr = libc::msghdr {
+        msg_control: control_buf.as_mut_ptr().cast::<libc::c_void>(),
+        msg_controllen: control_buf.len(),
+        msg_iov: std::ptr::null_mut(),
+        msg_iovlen: 0,
+        msg_flags: 0,
+        msg_name: std::ptr::null_mut(),
+        msg_namelen: 0,
+    };
+
+    loop {
+        let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut mhdr, libc::MSG_ERRQUEUE) };
+
+        if n == -1 {
+            let e = io::Error::last_os_error();
+
+            if let ErrorKind::Interrupted = e.kind() {
+                trace!("recv of the error queue was interrupted, retrying");
+                continue;
+            }
+
+            return Err(e);
+        }
+        break;
+    }
+
+    if mhdr.msg_flags & libc::MSG_CTRUNC > 0 {
+        warn!("truncated control messages");
+    }
+
+    let mut id = None;
+    let mut timestamp = None;
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&mhdr).as_ref() };
+    while let Some(msg) = cmsg {
+        match (msg.cmsg_level, msg.cmsg_type) {
+            (libc::SOL_SOCKET, libc::SO_TIMESTAMPING) => {
+                timestamp = read_timestamping(msg);
+            }
+            (libc::SOL_IP, libc::IP_RECVERR) | (libc::SOL_IPV6, libc::IPV6_RECVERR) => {
+                // Safety: these control messages always start with a sock_extended_err
+                let error: libc::sock_extended_err =
+                    unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(msg) as *const _) };
+                if error.ee_origin == libc::SO_EE_ORIGIN_TIMESTAMPING {
+                    id = Some(error.ee_data);
+                }
+            }
+            _ => {}
+        }
+
+        cmsg = unsafe { libc::CMSG_NXTHDR(&mhdr, msg).as_ref() };
+    }
+
+    Ok(id.map(|id| (id, timestamp)))
 }
 
 #[cfg(test)]
@@ -215,4 +755,82 @@ mod tests {
             assert!(delta.to_seconds() > 0.15 && delta.to_seconds() < 0.25);
         });
     }
+
+    #[test]
+    fn test_server_socket() {
+        tokio_test::block_on(async {
+            let server = UdpSocket::server("127.0.0.1:8010").await.unwrap();
+            let client = UdpSocket::new("127.0.0.1:8011", "127.0.0.1:8010")
+                .await
+                .unwrap();
+
+            client.send(&[1; 48]).await.unwrap();
+
+            let mut buf = [0; 48];
+            let (size, addr, timestamp) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(size, 48);
+            assert_eq!(addr, "127.0.0.1:8011".parse().unwrap());
+            assert!(timestamp.is_some());
+
+            server.send_to(&[2; 48], addr).await.unwrap();
+
+            let (size, _) = client.recv(&mut buf).await.unwrap();
+            assert_eq!(size, 48);
+            assert_eq!(buf, [2; 48]);
+        });
+    }
+
+    #[test]
+    fn test_send_timestamp() {
+        tokio_test::block_on(async {
+            let a = UdpSocket::new("127.0.0.1:8018", "127.0.0.1:8019")
+                .await
+                .unwrap();
+            let b = UdpSocket::new("127.0.0.1:8019", "127.0.0.1:8018")
+                .await
+                .unwrap();
+
+            // the loopback interface has no hardware timestamping
+            assert_eq!(a.timestamping_mode(), TimestampingMode::Software);
+
+            let mut buf = [0; 48];
+            for i in 0..3 {
+                let (size, send_timestamp) = a.send(&[i; 48]).await.unwrap();
+                assert_eq!(size, 48);
+
+                let (size, recv_timestamp) = b.recv(&mut buf).await.unwrap();
+                assert_eq!(size, 48);
+                assert_eq!(buf, [i; 48]);
+
+                let delta = recv_timestamp.unwrap() - send_timestamp.unwrap();
+                assert!(delta.to_seconds() >= 0.0 && delta.to_seconds() < 0.01);
+            }
+        });
+    }
+    #[test]
+    fn test_send_untimestamped() {
+        tokio_test::block_on(async {
+            let server = UdpSocket::server("127.0.0.1:9082").await.unwrap();
+            let client = UdpSocket::new("127.0.0.1:9083", "127.0.0.1:9082")
+                .await
+                .unwrap();
+            let addr = "127.0.0.1:9083".parse().unwrap();
+
+            let mut buf = [0; 48];
+            for i in 0..3 {
+                let size = server.send_to_untimestamped(&[i; 48], addr).await.unwrap();
+                assert_eq!(size, 48);
+                client.recv(&mut buf).await.unwrap();
+            }
+
+            // a later packet does not get the timestamp of one of the packets before it
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let (_, send_timestamp) = server.send_to(&[3; 48], addr).await.unwrap();
+            let (_, recv_timestamp) = client.recv(&mut buf).await.unwrap();
+            assert_eq!(buf, [3; 48]);
+
+            let delta = recv_timestamp.unwrap() - send_timestamp.unwrap();
+            assert!(delta.to_seconds() >= 0.0 && delta.to_seconds() < 0.01);
+        });
+    }
 }
@@ -1,4 +1,4 @@
-use ntp_daemon::config::PeerConfig;
+use ntp_daemon::config::{Config, PeerConfig};
 use ntp_proto::SystemConfig;
 use std::{error::Error, sync::Arc};
 use tokio::sync::RwLock;
@@ -7,14 +7,29 @@ use tokio::sync::RwLock;
 async fn main() -> Result<(), Box<dyn Error>> {
     tracing_subscriber::fmt::init();
 
-    let config = Arc::new(RwLock::new(SystemConfig::default()));
+    let system_config = Arc::new(RwLock::new(SystemConfig::default()));
 
-    let peer_configs = [PeerConfig::try_from("0.0.0.0:8080").unwrap()];
+    let config = Config {
+        peers: vec![PeerConfig::try_from("0.0.0.0:8080").unwrap()],
+        ..Default::default()
+    };
 
     let peers = Default::default();
     let system = Default::default();
+    let (_, peer_commands) = tokio::sync::mpsc::channel(1);
+    let (_, shutdown) = tokio::sync::watch::channel(());
+    let (_, leap_seconds) = tokio::sync::watch::channel(None);
 
-    ntp_daemon::spawn(config, &peer_configs, peers, system).await?;
+    ntp_daemon::spawn(
+        &config,
+        system_config,
+        leap_seconds,
+        peers,
+        peer_commands,
+        system,
+        shutdown,
+    )
+    .await?;
 
     Ok(())
 }